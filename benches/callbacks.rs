@@ -0,0 +1,363 @@
+// What the glue between the broker and the plugin costs per call:
+//   options:    turning the mosquitto_opt array of init and reload into a MosquittoOpt, and
+//               reading typed values from it
+//   acl_check:  the trampoline registered for MOSQ_EVT_ACL_CHECK against calling the plugin
+//               directly, for the same check
+//   on_message: the same for MOSQ_EVT_MESSAGE, unchanged and with the topic rewritten
+//
+// Run with cargo bench --bench callbacks. The broker functions are stubbed below, callbacks are
+// kept when registered and called the way mosquitto calls them. The stubs the plugin doesn't call
+// only have to exist for linking, MosquittoClient refers to all of them.
+#![allow(clippy::missing_safety_doc)]
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mosquitto_plugin::mosquitto_dev::*;
+use mosquitto_plugin::opts::opt_or;
+use mosquitto_plugin::*;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::hint::black_box;
+use std::os::raw::{c_char, c_int, c_void};
+use std::time::Duration;
+
+thread_local! {
+    static CALLBACKS: RefCell<Vec<(c_int, MOSQ_FUNC_generic_callback, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_callback_register(
+    _identifier: *mut mosquitto_plugin_id_t,
+    event: c_int,
+    cb_func: MOSQ_FUNC_generic_callback,
+    _event_data: *const c_void,
+    userdata: *mut c_void,
+) -> c_int {
+    CALLBACKS.with(|c| c.borrow_mut().push((event, cb_func, userdata as usize)));
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_callback_unregister(
+    _identifier: *mut mosquitto_plugin_id_t,
+    event: c_int,
+    _cb_func: MOSQ_FUNC_generic_callback,
+    _event_data: *const c_void,
+) -> c_int {
+    CALLBACKS.with(|c| c.borrow_mut().retain(|(e, _, _)| *e != event));
+    0
+}
+
+// Called with a "%s" format and one string, see mosquitto_calls::log_printf
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_log_printf(
+    _level: c_int,
+    _fmt: *const c_char,
+    message: *const c_char,
+) {
+    black_box(message);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_id(_client: *const mosquitto) -> *const c_char {
+    b"sensor-1\0".as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_username(_client: *const mosquitto) -> *const c_char {
+    b"alice\0".as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_set_username(
+    _client: *mut mosquitto,
+    _username: *const c_char,
+) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_address(_client: *const mosquitto) -> *const c_char {
+    b"127.0.0.1\0".as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_clean_session(_client: *const mosquitto) -> bool {
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_keepalive(_client: *const mosquitto) -> c_int {
+    60
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_protocol(_client: *const mosquitto) -> c_int {
+    mosquitto_protocol_mp_mqtt as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_protocol_version(_client: *const mosquitto) -> c_int {
+    5
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_sub_count(_client: *const mosquitto) -> c_int {
+    0
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_session_expiry_interval(
+    _client: *const mosquitto,
+) -> u32 {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_validate_utf8(_str: *const c_char, _len: c_int) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_broker_publish(
+    _clientid: *const c_char,
+    _topic: *const c_char,
+    _payloadlen: c_int,
+    payload: *mut c_void,
+    _qos: c_int,
+    _retain: bool,
+    _properties: *mut mosquitto_property,
+) -> c_int {
+    libc::free(payload);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_int32(
+    _proplist: *mut *mut mosquitto_property,
+    _identifier: c_int,
+    _value: u32,
+) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_string(
+    _proplist: *mut *mut mosquitto_property,
+    _identifier: c_int,
+    _value: *const c_char,
+) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_binary(
+    _proplist: *mut *mut mosquitto_property,
+    _identifier: c_int,
+    _value: *const c_void,
+    _len: u16,
+) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_string_pair(
+    _proplist: *mut *mut mosquitto_property,
+    _identifier: c_int,
+    _name: *const c_char,
+    _value: *const c_char,
+) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_free_all(_properties: *mut *mut mosquitto_property) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_malloc(size: usize) -> *mut c_void {
+    libc::malloc(size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_free(mem: *mut c_void) {
+    libc::free(mem)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_pub_topic_check(_topic: *const c_char) -> c_int {
+    0
+}
+
+// Allows what its own devices publish, rewrites their status topics
+struct Devices;
+
+impl MosquittoPlugin for Devices {
+    fn init(_opts: MosquittoOpt) -> Self {
+        Devices
+    }
+
+    fn acl_check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        _level: AclCheckAccessLevel,
+        msg: MosquittoMessage,
+    ) -> AuthDecision {
+        match client.get_username() {
+            Some(username)
+                if msg.topic.starts_with("devices/") && msg.topic[8..].starts_with(&username) =>
+            {
+                AuthDecision::Allow
+            }
+            _ => AuthDecision::deny("not a device of the user"),
+        }
+    }
+
+    fn on_message_mut(
+        &mut self,
+        _client: &dyn MosquittoClientContext,
+        msg: MosquittoMessage,
+    ) -> Result<MessageRewrite, MessageVeto> {
+        match msg.topic.strip_suffix("/status") {
+            Some(device) => {
+                Ok(MessageRewrite::unchanged().with_topic(&format!("{}/state", device)))
+            }
+            None => Ok(MessageRewrite::unchanged()),
+        }
+    }
+}
+
+fn callback(
+    event: MosquittoPluginEvent,
+) -> (
+    unsafe extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int,
+    *mut c_void,
+) {
+    let event = event as c_int;
+    CALLBACKS.with(|c| {
+        let (_, cb, userdata) = *c.borrow().iter().find(|(e, _, _)| *e == event).unwrap();
+        (cb.unwrap(), userdata as *mut c_void)
+    })
+}
+
+fn options(c: &mut Criterion) {
+    let mut group = c.benchmark_group("options");
+    for &count in &[8, 64] {
+        let strings: Vec<(CString, CString)> = (0..count)
+            .map(|i| {
+                let key = CString::new(format!("plugin_opt_key_{}", i)).unwrap();
+                (key, CString::new(format!("{}s", i)).unwrap())
+            })
+            .collect();
+        let mut raw: Vec<mosquitto_opt> = strings
+            .iter()
+            .map(|(key, value)| mosquitto_opt {
+                key: key.as_ptr() as *mut _,
+                value: value.as_ptr() as *mut _,
+            })
+            .collect();
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(
+            BenchmarkId::new("from_ptr_and_size", count),
+            &count,
+            |b, _| b.iter(|| __from_ptr_and_size(black_box(raw.as_mut_ptr()), raw.len())),
+        );
+        let opts = __from_ptr_and_size(raw.as_mut_ptr(), raw.len());
+        group.bench_with_input(BenchmarkId::new("opt_or", count), &count, |b, _| {
+            b.iter(|| opt_or::<Duration>(&opts, black_box("key_3"), "30s").unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut id = 0u8;
+    let mut user_data: *mut c_void = std::ptr::null_mut();
+    let info = PluginInfo {
+        callbacks: Callbacks::ACL_CHECK.union(Callbacks::MESSAGE),
+        ..DEFAULT_PLUGIN_INFO
+    };
+    unsafe {
+        plugin_init_with::<Devices>(
+            &mut id as *mut u8 as *mut c_void,
+            &mut user_data,
+            std::ptr::null_mut(),
+            0,
+            &info,
+        );
+    }
+    let client = MosquittoClient {
+        client: std::ptr::NonNull::dangling().as_ptr(),
+    };
+    let topic = CString::new("devices/alice/lamp/on").unwrap();
+    let payload = b"1";
+
+    let mut group = c.benchmark_group("acl_check");
+    let mut plugin = Devices;
+    group.bench_function("direct", |b| {
+        b.iter(|| {
+            let msg = MosquittoMessage {
+                topic: "devices/alice/lamp/on",
+                payload,
+                qos: QOS::AtMostOnce,
+                retain: false,
+                properties: properties::MessageProperties::none(),
+            };
+            plugin.acl_check(&client, AclCheckAccessLevel::Write, black_box(msg))
+        })
+    });
+    let (acl_check, userdata) = callback(MosquittoPluginEvent::MosqEvtAclCheck);
+    group.bench_function("callback", |b| {
+        b.iter(|| {
+            let mut event: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+            event.client = client.client;
+            event.topic = topic.as_ptr();
+            event.payload = payload.as_ptr() as *const c_void;
+            event.payloadlen = payload.len() as u32;
+            event.access = AccessLevel::Write as c_int;
+            let rc = unsafe {
+                acl_check(
+                    MosquittoPluginEvent::MosqEvtAclCheck as c_int,
+                    black_box(&mut event) as *mut _ as *mut c_void,
+                    userdata,
+                )
+            };
+            assert_eq!(rc, 0);
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("on_message");
+    let (on_message, userdata) = callback(MosquittoPluginEvent::MosqEvtMessage);
+    let status = CString::new("devices/alice/lamp/status").unwrap();
+    for (name, topic) in &[("unchanged", &topic), ("rewritten", &status)] {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut event: mosquitto_evt_message = unsafe { std::mem::zeroed() };
+                event.client = client.client;
+                event.topic = topic.as_ptr() as *mut c_char;
+                event.payload = payload.as_ptr() as *mut c_void;
+                event.payloadlen = payload.len() as u32;
+                let rc = unsafe {
+                    on_message(
+                        MosquittoPluginEvent::MosqEvtMessage as c_int,
+                        black_box(&mut event) as *mut _ as *mut c_void,
+                        userdata,
+                    )
+                };
+                assert_eq!(rc, 0);
+                // The broker frees the topic it was handed in place of its own
+                if !std::ptr::eq(event.topic, topic.as_ptr()) {
+                    unsafe { mosquitto_free(event.topic as *mut c_void) };
+                }
+            })
+        });
+    }
+    group.finish();
+
+    unsafe {
+        plugin_cleanup::<Devices>(user_data, std::ptr::null_mut(), 0);
+    }
+}
+
+criterion_group!(benches, options, dispatch);
+criterion_main!(benches);
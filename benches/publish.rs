@@ -0,0 +1,122 @@
+// Compares the ways of handing a payload to the broker:
+//   slice:  the caller serializes into a Vec and passes &vec
+//   owned:  the caller serializes into a Vec and passes it by value
+//   writer: the caller serializes straight into the broker buffer with publish_broadcast_with
+// and the ways of sending many messages at once:
+//   loop:   publish_broadcast once per message
+//   batch:  publish_batch, which reuses one topic buffer for the whole batch
+//
+// Run with cargo bench --bench publish. The broker functions are stubbed below, the stub takes
+// ownership of the payload and frees it like mosquitto does, so only the plugin side is measured.
+#![allow(clippy::missing_safety_doc)]
+
+use mosquitto_plugin::mosquitto_calls::{publish_batch, publish_broadcast, publish_broadcast_with};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mosquitto_plugin::QOS;
+use std::hint::black_box;
+use std::os::raw::{c_char, c_int, c_void};
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_malloc(size: usize) -> *mut c_void {
+    libc::malloc(size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_calloc(nmemb: usize, size: usize) -> *mut c_void {
+    libc::calloc(nmemb, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_free(mem: *mut c_void) {
+    libc::free(mem)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_broker_publish(
+    _clientid: *const c_char,
+    _topic: *const c_char,
+    _payloadlen: c_int,
+    payload: *mut c_void,
+    _qos: c_int,
+    _retain: bool,
+    _properties: *mut c_void,
+) -> c_int {
+    black_box(payload);
+    libc::free(payload);
+    0
+}
+
+// The topic is checked like mosquitto checks the topic of a PUBLISH packet, every topic passes
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_pub_topic_check(_topic: *const c_char) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_validate_utf8(_str: *const c_char, _len: c_int) -> c_int {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_free_all(_properties: *mut *mut c_void) {}
+
+fn serialize(buf: &mut [u8], seed: u32) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (i as u32 ^ seed) as u8;
+    }
+}
+
+fn payloads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish_broadcast");
+    for &size in &[64, 4096, 65536] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("slice", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut payload = vec![0; size];
+                serialize(&mut payload, 7);
+                publish_broadcast("bench", &payload, QOS::AtMostOnce, false).unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("owned", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut payload = vec![0; size];
+                serialize(&mut payload, 7);
+                publish_broadcast("bench", payload, QOS::AtMostOnce, false).unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("writer", size), &size, |b, &size| {
+            b.iter(|| {
+                publish_broadcast_with("bench", size, QOS::AtMostOnce, false, |buf| serialize(buf, 7)).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn batches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish_batch");
+    for &count in &[16, 256] {
+        let topics: Vec<String> = (0..count).map(|i| format!("bench/device/{}/state", i)).collect();
+        let payload = [7u8; 64];
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::new("loop", count), &topics, |b, topics| {
+            b.iter(|| {
+                for topic in topics {
+                    publish_broadcast(topic, payload, QOS::AtMostOnce, false).unwrap();
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("batch", count), &topics, |b, topics| {
+            let messages: Vec<_> = topics.iter().map(|t| (t.as_str(), &payload, QOS::AtMostOnce, false)).collect();
+            b.iter(|| {
+                for result in publish_batch(&messages) {
+                    result.unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, payloads, batches);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+// Compares TopicMatcher with checking every pattern in a loop, for an ACL of 20,000 patterns.
+//
+// Run with cargo bench --bench topic_matcher
+use mosquitto_plugin::topic::{pattern_is_subset_of, TopicMatcher};
+use std::hint::black_box;
+use std::time::Instant;
+
+const PATTERNS: usize = 20_000;
+const ITERATIONS: u32 = 1_000;
+
+fn main() {
+    let patterns: Vec<String> = (0..PATTERNS)
+        .map(|i| match i % 3 {
+            0 => format!("tenant/{}/devices/+/telemetry", i),
+            1 => format!("tenant/{}/commands/#", i),
+            _ => format!("tenant/{}/status", i),
+        })
+        .collect();
+    let mut matcher = TopicMatcher::new();
+    for pattern in &patterns {
+        matcher.insert(pattern, ());
+    }
+    let topic = format!(
+        "tenant/{}/devices/sensor-1/telemetry",
+        PATTERNS / 2 - PATTERNS / 2 % 3
+    );
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let matched = patterns
+            .iter()
+            .filter(|p| pattern_is_subset_of(p, black_box(&topic)))
+            .count();
+        assert_eq!(matched, 1);
+    }
+    println!("  loop {:>10?}/topic", start.elapsed() / ITERATIONS);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(matcher.matches(black_box(&topic)).count(), 1);
+    }
+    println!("  trie {:>10?}/topic", start.elapsed() / ITERATIONS);
+}
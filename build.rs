@@ -1,9 +1,97 @@
 extern crate bindgen;
 
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Looks up NAME_<target> (e.g. MOSQUITTO_INCLUDE_DIR_aarch64_unknown_linux_gnu) before NAME, so
+// cross builds can point at a different sysroot than native ones.
+fn target_env(name: &str, target: &str) -> Option<String> {
+    let target_name = format!("{}_{}", name, target.replace('-', "_"));
+    println!("cargo:rerun-if-env-changed={}", target_name);
+    println!("cargo:rerun-if-env-changed={}", name);
+    env::var(target_name).or_else(|_| env::var(name)).ok()
+}
+
+// Asks pkg-config about libmosquitto, None when it or libmosquitto.pc isn't installed
+fn pkg_config(args: &[&str]) -> Option<String> {
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+    let output = Command::new("pkg-config")
+        .args(args)
+        .arg("libmosquitto")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.trim().split('.').map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), revision) => {
+            Some((major, minor, revision.and_then(|r| r.ok()).unwrap_or(0)))
+        }
+        _ => None,
+    }
+}
+
+// The LIBMOSQUITTO_MAJOR/MINOR/REVISION defines, in mosquitto.h up to 2.0 and in the headers under
+// mosquitto/ it includes from 2.1 on
+fn header_version(dir: &Path) -> Option<(u32, u32, u32)> {
+    let mut headers = vec![dir.join("mosquitto.h")];
+    if let Ok(entries) = fs::read_dir(dir.join("mosquitto")) {
+        headers.extend(
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "h")),
+        );
+    }
+    let (mut major, mut minor, mut revision) = (None, None, None);
+    for header in headers {
+        let text = match fs::read_to_string(&header) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        println!("cargo:rerun-if-changed={}", header.display());
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("#define") {
+                continue;
+            }
+            let slot = match words.next() {
+                Some("LIBMOSQUITTO_MAJOR") => &mut major,
+                Some("LIBMOSQUITTO_MINOR") => &mut minor,
+                Some("LIBMOSQUITTO_REVISION") => &mut revision,
+                _ => continue,
+            };
+            *slot = words.next().and_then(|v| v.parse::<u32>().ok());
+        }
+    }
+    Some((major?, minor?, revision.unwrap_or(0)))
+}
 
 fn main() {
+    let target = env::var("TARGET").unwrap();
+    let host = env::var("HOST").unwrap();
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+
+    // Plugins call functions that live in the mosquitto executable and are only resolved when
+    // the broker loads the plugin. On Windows the broker symbols have to come from an import
+    // library of mosquitto.exe instead, which isn't supported.
+    if target_os == "windows" {
+        panic!(
+            "mosquitto-plugin does not support Windows targets ({}): plugins there have to link \
+             against the import library of the broker executable, build on Linux or macOS",
+            target
+        );
+    }
+
     // Tell cargo to tell rustc to link the system bzip2
     // shared library.
     println!("cargo:rustc-link-lib=bz2");
@@ -14,13 +102,104 @@ fn main() {
     // The bindgen::Builder is the main entry point
     // to bindgen, and lets you build up options for
     // the resulting bindings.
-    let bindings = bindgen::Builder::default()
+    let mut builder = bindgen::Builder::default()
         // The input header we would like to generate
         // bindings for.
         .header("wrapper.h")
         // Tell cargo to invalidate the built crate whenever any of the
         // included header files changed.
-        .parse_callbacks(Box::new(bindgen::CargoCallbacks))
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks));
+
+    // Cross builds, e.g. for aarch64 gateways, need clang to parse the headers for the target
+    // and find them in the target sysroot rather than in /usr/include.
+    if target != host {
+        builder = builder.clang_arg(format!("--target={}", target));
+    }
+    if let Some(sysroot) = target_env("MOSQUITTO_SYSROOT", &target) {
+        builder = builder.clang_arg(format!("--sysroot={}", sysroot));
+    }
+    let override_version = target_env("MOSQUITTO_VERSION", &target).map(|version| {
+        parse_version(&version)
+            .unwrap_or_else(|| panic!("MOSQUITTO_VERSION is not a version like 2.1.0: {}", version))
+    });
+    let required = env::var_os("CARGO_FEATURE_MOSQUITTO_2_1").is_some();
+
+    let mut include_dirs = Vec::new();
+    if env::var_os("CARGO_FEATURE_VENDORED_HEADERS").is_some() {
+        // The copies in vendor/, so nothing of mosquitto has to be installed, e.g. for cross builds.
+        // 2.1 when asked for, 2.0 binds what every 2.x broker has.
+        let vendored = if required || override_version.is_some_and(|v| v >= (2, 1, 0)) {
+            "mosquitto-2.1"
+        } else {
+            "mosquitto-2.0"
+        };
+        let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        include_dirs.push(manifest_dir.join("vendor").join(vendored).join("include"));
+    } else if let Some(include_dir) = target_env("MOSQUITTO_INCLUDE_DIR", &target) {
+        include_dirs.push(PathBuf::from(include_dir));
+    } else if target == host {
+        // pkg-config describes the headers of the host, cross builds name theirs
+        if let Some(cflags) = pkg_config(&["--cflags-only-I"]) {
+            include_dirs.extend(
+                cflags
+                    .split_whitespace()
+                    .filter_map(|f| f.strip_prefix("-I"))
+                    .map(PathBuf::from),
+            );
+        }
+    }
+    for include_dir in &include_dirs {
+        builder = builder.clang_arg(format!("-I{}", include_dir.display()));
+    }
+
+    // The version of the headers decides which APIs are bound: the events, callbacks and
+    // functions added in mosquitto 2.1 are behind cfg(mosquitto_2_1). MOSQUITTO_VERSION overrides
+    // what is found in the headers, which are looked for where clang looks for them.
+    let sysroot = target_env("MOSQUITTO_SYSROOT", &target)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/"));
+    let searched: Vec<PathBuf> = include_dirs
+        .iter()
+        .cloned()
+        .chain(
+            ["usr/local/include", "usr/include"]
+                .iter()
+                .map(|d| sysroot.join(d)),
+        )
+        .collect();
+    let version = override_version.or_else(|| {
+        searched
+            .iter()
+            .find_map(|dir| header_version(dir))
+            .or_else(|| {
+                if target == host {
+                    pkg_config(&["--modversion"]).and_then(|v| parse_version(&v))
+                } else {
+                    None
+                }
+            })
+    });
+    let has_2_1 = match version {
+        Some(version) if version < (2, 1, 0) && required => panic!(
+            "the mosquitto-2-1 feature needs the mosquitto 2.1 headers, found {}.{}.{}: install them, \
+             point MOSQUITTO_INCLUDE_DIR at them or turn the feature off",
+            version.0, version.1, version.2
+        ),
+        Some(version) => version >= (2, 1, 0),
+        // Headers that don't say, the feature decides
+        None => required,
+    };
+    println!("cargo:rustc-check-cfg=cfg(mosquitto_2_1)");
+    if has_2_1 {
+        println!("cargo:rustc-cfg=mosquitto_2_1");
+    }
+    let (major, minor, revision) = version.unwrap_or(if has_2_1 { (2, 1, 0) } else { (2, 0, 0) });
+    println!(
+        "cargo:rustc-env=MOSQUITTO_HEADERS_VERSION={}.{}.{}",
+        major, minor, revision
+    );
+
+    let bindings = builder
         // Finish the builder and generate the bindings.
         .generate()
         // Unwrap the Result and panic on failure.
@@ -1,28 +1,26 @@
 // Has to be included, to get the errors and success parameters that are used in the
 // generate_dynamic_library macro invocation
+use mosquitto_plugin::acl::{self, AclPattern};
 use mosquitto_plugin::*;
 
-// Some simple nonsense structure to use as an example
+// Access is granted with acl_file style patterns from the plugin options, e.g.
+//     auth_opt_acl pattern readwrite devices/%u/%c/#; pattern read public/#
 #[derive(Debug)]
-pub struct Test {
-    i: i32,
-    s: String,
+pub struct Acl {
+    patterns: Vec<AclPattern>,
 }
 
 // Required trait implementation
-impl MosquittoPlugin for Test {
-    fn init(opts: std::collections::HashMap<&str, &str>) -> Self {
+impl MosquittoPlugin for Acl {
+    fn init(opts: MosquittoOpt) -> Self {
         // These are the strings provided after "auth_opt_<key> value" in the mosquitto.conf
         // only that they are provided on a hashmap form here
-        let default = "hej";
-        let topic = opts.get("topic").unwrap_or(&default);
-        let level = opts.get("level").unwrap_or(&default);
-        let level = level.parse().unwrap_or(0);
-
-        Test {
-            i: level,
-            s: topic.to_string(),
-        }
+        let patterns = match opts.get("acl").map(|list| AclPattern::parse_list(list)) {
+            Some(Ok(patterns)) => patterns,
+            Some(Err(_)) => panic!("invalid auth_opt_acl {:?}", opts["acl"]),
+            None => Vec::new(),
+        };
+        Acl { patterns }
     }
 
     fn username_password(
@@ -30,24 +28,15 @@ impl MosquittoPlugin for Test {
         client: &dyn MosquittoClientContext,
         u: Option<&str>,
         p: Option<&str>,
-    ) -> Result<Success, Error> {
+    ) -> AuthDecision {
         let client_id = client.get_id();
-        println!("USERNAME_PASSWORD({}) {:?} - {:?}", client_id, u, p);
-        if u.is_none() || p.is_none() {
-            return Err(Error::Auth);
-        }
-        let u = u.unwrap();
-        let p = p.unwrap();
+        let (u, p) = match (u, p) {
+            (Some(u), Some(p)) => (u, p),
+            _ => return AuthDecision::deny("username and password required"),
+        };
         // this will allow all username/password where the password is the username in reverse
         let rp: String = p.chars().rev().collect();
         if rp == u {
-            // Declare the accepted new client
-            self.broker_broadcast_publish(
-                "new_client",
-                "very_client is a friend. Lets make it feel at home!".as_bytes(),
-                QOS::AtMostOnce,
-                false,
-            )?;
             // Welcome the new client privately
             self.broker_publish_to_client(
                 &client_id,
@@ -55,18 +44,10 @@ impl MosquittoPlugin for Test {
                 format!("Welcome {}", client_id).as_bytes(),
                 QOS::AtMostOnce,
                 false,
-            )?;
-            Ok(Success)
+            )
+            .into()
         } else {
-            println!("USERNAME_PASSWORD failed for {}", client_id);
-            // Snitch to all other clients what a bad client that was.
-            self.broker_broadcast_publish(
-                "snitcheroo",
-                format!("{} is a bad bad client. No cookies for it.", client_id).as_bytes(),
-                QOS::AtMostOnce,
-                false,
-            )?;
-            Err(Error::Auth)
+            AuthDecision::deny(format!("wrong password for {}", u))
         }
     }
 
@@ -75,33 +56,49 @@ impl MosquittoPlugin for Test {
         client: &dyn MosquittoClientContext,
         level: AclCheckAccessLevel,
         msg: MosquittoMessage,
-    ) -> Result<Success, mosquitto_plugin::Error> {
-        println!("allowed topic: {}", self.s);
-        println!("topic: {}", msg.topic);
-        println!("level requested: {}", level);
-
-        // only the topic provided in the mosquitto.conf by the value auth_opt_topic <value> is
-        // allowed, errors will not be reported to the clients though, they will only not be able
-        // to send/receive messages and thus silently fail due to limitations in MQTT protocol
-        if msg.topic == self.s {
-            Ok(Success)
-        } else {
-            Err(Error::AclDenied)
-        }
+    ) -> AuthDecision {
+        // errors will not be reported to the clients, they will only not be able to send/receive
+        // messages and thus silently fail due to limitations in MQTT protocol
+        acl::check_client(&self.patterns, client, level, msg.topic).into()
     }
+}
+
+// This generates the dynamic c bindings functions that are exported and usable by mosquitto
+create_dynamic_library!(Acl);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
 
-    fn on_disconnect(&mut self, client: &dyn MosquittoClientContext, reason: i32) {
-        println!("Plugin on_disconnect, Client {} is disconnecting", client.get_id());
+    #[test]
+    fn patterns_from_options() {
+        let mut opts = HashMap::new();
+        opts.insert("acl", "pattern readwrite devices/%u/%c/#; pattern read public/#");
+        let plugin = Acl::init(opts);
+        let check = |client_id, username, level, topic| {
+            acl::check(&plugin.patterns, client_id, username, level, topic).is_ok()
+        };
+        assert!(check("c1", Some("alice"), AclCheckAccessLevel::Write, "devices/alice/c1/temp"));
+        assert!(!check("c1", Some("alice"), AclCheckAccessLevel::Write, "devices/bob/c1/temp"));
+        assert!(check("c1", None, AclCheckAccessLevel::Read, "public/news"));
+        assert!(!check("c1", None, AclCheckAccessLevel::Write, "public/news"));
     }
 
-    fn on_message(
-        &mut self,
-        client: &dyn MosquittoClientContext,
-        message: MosquittoMessage,
-    ) {
-        println!("Plugin on_message: client {}: Topic: {}, Payload: {:?}", client.get_id(), message.topic, message.payload)
+    #[test]
+    fn greets_clients_that_log_in() {
+        let broker = testing::MockBroker::new().with_client_id("c1");
+        let mut plugin: Acl = broker.init(&[("acl", "pattern readwrite devices/%u/%c/#")]).unwrap();
+        assert_eq!(
+            broker.username_password(&mut plugin, Some("alice"), Some("bob")),
+            AuthDecision::deny("wrong password for alice")
+        );
+        assert_eq!(broker.username_password(&mut plugin, Some("alice"), Some("ecila")), AuthDecision::Allow);
+        let greeting = &broker.published()[0];
+        assert_eq!((greeting.topic.as_str(), &greeting.payload[..]), ("greeting", &b"Welcome c1"[..]));
+        assert_eq!(
+            broker.acl_check(&mut plugin, AclCheckAccessLevel::Write, "devices/alice/c1/temp", b"21"),
+            AuthDecision::Allow
+        );
     }
 }
-
-// This generates the dynamic c bindings functions that are exported and usable by mosquitto
-create_dynamic_library!(Test);
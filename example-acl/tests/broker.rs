@@ -0,0 +1,46 @@
+// The plugin loaded into a real mosquitto, run with `cargo test -p example-acl -- --ignored`
+// when mosquitto is installed, or with MOSQUITTO pointing at the executable
+use mosquitto_plugin::test_broker::{ConnectError, TestBroker};
+use std::time::Duration;
+
+fn start() -> TestBroker {
+    let plugin = TestBroker::build_plugin(env!("CARGO_MANIFEST_DIR")).unwrap();
+    TestBroker::builder(plugin)
+        .opt("acl", "pattern readwrite devices/%u/%c/#; pattern read public/#")
+        .start()
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs the mosquitto executable"]
+fn logins_need_the_reversed_username() {
+    let broker = start();
+    assert!(broker.connect("c1", Some("alice"), Some("ecila")).is_ok());
+    assert!(matches!(
+        broker.connect("c2", Some("alice"), Some("alice")),
+        Err(ConnectError::Refused(5))
+    ));
+    assert!(broker.log().contains("wrong password for alice"));
+}
+
+#[test]
+#[ignore = "needs the mosquitto executable"]
+fn clients_stay_within_their_patterns() {
+    let broker = start();
+    let mut client = broker.connect("c1", Some("alice"), Some("ecila")).unwrap();
+    // the greeting published at login
+    assert_eq!(
+        client.receive(Duration::from_secs(2)).unwrap(),
+        Some(("greeting".to_string(), b"Welcome c1".to_vec()))
+    );
+    assert!(client.subscribe("devices/alice/c1/#", 1).unwrap());
+    assert!(!client.subscribe("devices/#", 1).unwrap());
+
+    client.publish("devices/alice/c1/temp", b"21", 1).unwrap();
+    let received = client.receive(Duration::from_secs(2)).unwrap();
+    assert_eq!(received, Some(("devices/alice/c1/temp".to_string(), b"21".to_vec())));
+
+    let mut other = broker.connect("c2", Some("bob"), Some("bob")).unwrap();
+    other.publish("devices/alice/c1/temp", b"99", 1).unwrap();
+    assert_eq!(client.receive(Duration::from_millis(500)).unwrap(), None);
+}
@@ -0,0 +1,8 @@
+fn main() {
+    // The broker functions the plugin calls are resolved when mosquitto loads it. The macOS
+    // linker refuses undefined symbols in a dylib unless told to look them up at load time.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+        println!("cargo:rustc-cdylib-link-arg=-undefined");
+        println!("cargo:rustc-cdylib-link-arg=dynamic_lookup");
+    }
+}
@@ -0,0 +1,331 @@
+// Clients log in with a JSON Web Token as their MQTT password. The token has to be signed with
+// one of the keys in the JWKS file given by the jwks_file option, or with the public key in
+// public_key_file, and its claims decide what the client may do:
+//
+//     {"sub": "sensor-1", "exp": 1767225600, "read": ["public/#"], "write": ["alerts/sensor-1"]}
+//
+// lets sensor-1 read and write below devices/sensor-1/, read public/# and write alerts/sensor-1.
+// The expiry is checked again on every ACL check, clients whose token expired are kicked and
+// have to reconnect with a fresh token.
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use mosquitto_plugin::acl::AclAccess;
+use mosquitto_plugin::clients::ClientRegistry;
+use mosquitto_plugin::mosquitto_calls;
+use mosquitto_plugin::topic::{pattern_is_subset_of, TopicMatcher};
+use mosquitto_plugin::*;
+use serde::Deserialize;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Only algorithms verified with a public key. Accepting HS256 would let anyone who has the
+// public key sign tokens with it.
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+enum Keys {
+    Jwks(JwkSet),
+    Pem(DecodingKey),
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Allow,
+    Deny,
+    Expired,
+}
+
+// What a connected client may do, from the claims of its token
+struct Session {
+    expires_at: u64,
+    // for published and received messages
+    topics: TopicMatcher<AclAccess>,
+    // for subscriptions, which have to be within one of the filters
+    filters: Vec<(String, AclAccess)>,
+}
+
+impl Session {
+    fn from_claims(claims: Claims) -> Session {
+        let mut filters = vec![(format!("devices/{}/#", claims.sub), AclAccess::ReadWrite)];
+        filters.extend(claims.read.into_iter().map(|f| (f, AclAccess::Read)));
+        filters.extend(claims.write.into_iter().map(|f| (f, AclAccess::Write)));
+
+        let mut topics = TopicMatcher::new();
+        for (filter, access) in &filters {
+            // a filter listed under both read and write allows both
+            let access = match topics.get(filter) {
+                Some(granted) if granted != access => AclAccess::ReadWrite,
+                _ => *access,
+            };
+            topics.insert(filter, access);
+        }
+        Session {
+            expires_at: claims.exp,
+            topics,
+            filters,
+        }
+    }
+
+    fn decide(&self, now: u64, level: AclCheckAccessLevel, topic: &str) -> Decision {
+        if now >= self.expires_at {
+            return Decision::Expired;
+        }
+        let allowed = match level {
+            AclCheckAccessLevel::Subscribe | AclCheckAccessLevel::Unsubscribe => self.filters.iter().any(|(filter, access)| {
+                access.allows(level) && pattern_is_subset_of(filter, topic)
+            }),
+            _ => self
+                .topics
+                .matches(topic)
+                .any(|access| access.allows(level)),
+        };
+        if allowed {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub struct JwtAuth {
+    // Err with the reason when the keys couldn't be loaded, every login is refused then
+    keys: Result<Keys, String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    sessions: ClientRegistry<Session>,
+}
+
+impl JwtAuth {
+    fn from_opts(opts: &MosquittoOpt) -> Result<JwtAuth, String> {
+        let keys = if let Some(path) = opts.get("jwks_file") {
+            let json = fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path, e))?;
+            let jwks =
+                serde_json::from_str(&json).map_err(|e| format!("parsing {}: {}", path, e))?;
+            Keys::Jwks(jwks)
+        } else if let Some(path) = opts.get("public_key_file") {
+            let pem = fs::read(path).map_err(|e| format!("reading {}: {}", path, e))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .or_else(|_| DecodingKey::from_ec_pem(&pem))
+                .or_else(|_| DecodingKey::from_ed_pem(&pem))
+                .map_err(|e| format!("parsing {}: {}", path, e))?;
+            Keys::Pem(key)
+        } else {
+            return Err("jwks_file or public_key_file has to be set".to_string());
+        };
+        Ok(JwtAuth {
+            keys: Ok(keys),
+            issuer: opts.get("issuer").map(|s| s.to_string()),
+            audience: opts.get("audience").map(|s| s.to_string()),
+            sessions: ClientRegistry::new(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let keys = self.keys.as_ref().map_err(|_| Error::Auth)?;
+        let header = jsonwebtoken::decode_header(token).map_err(|_| Error::Auth)?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(Error::Auth);
+        }
+        let key = match keys {
+            Keys::Jwks(jwks) => {
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None if jwks.keys.len() == 1 => jwks.keys.first(),
+                    None => None,
+                };
+                DecodingKey::from_jwk(jwk.ok_or(Error::Auth)?).map_err(|_| Error::Auth)?
+            }
+            Keys::Pem(key) => key.clone(),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|_| Error::Auth)?
+            .claims;
+
+        // The subject becomes part of the topics, it must not widen them
+        if claims.sub.is_empty() || claims.sub.contains(&['/', '+', '#'][..]) {
+            return Err(Error::Auth);
+        }
+        Ok(claims)
+    }
+}
+
+#[mosquitto_plugin(name = "example-jwt", version = "0.1.0")]
+impl MosquittoPlugin for JwtAuth {
+    fn init(opts: MosquittoOpt) -> Self {
+        JwtAuth::from_opts(&opts).unwrap_or_else(|reason| {
+            mosquitto_calls::log_printf(
+                MOSQ_LOG_ERR,
+                &format!("example-jwt: {}, refusing all logins", reason),
+            );
+            JwtAuth {
+                keys: Err(reason),
+                issuer: None,
+                audience: None,
+                sessions: ClientRegistry::new(),
+            }
+        })
+    }
+
+    fn username_password(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        _username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let claims = match password.map(|token| self.verify(token)) {
+            Some(Ok(claims)) => claims,
+            Some(Err(e)) => return e.into(),
+            None => return AuthDecision::deny("the token has to be sent as the password"),
+        };
+        // Whatever username the client sent, the broker and the plugins after this one see the
+        // subject of the token
+        if let Err(e) = client.set_username(&claims.sub) {
+            return e.into();
+        }
+        self.sessions
+            .insert_for(client, Session::from_claims(claims));
+        AuthDecision::Allow
+    }
+
+    fn acl_check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        msg: MosquittoMessage,
+    ) -> AuthDecision {
+        let client_id = client.get_id();
+        let decision = match self.sessions.get(&client_id) {
+            Some(session) => session.decide(now(), level, msg.topic),
+            None => Decision::Deny,
+        };
+        match decision {
+            Decision::Allow => AuthDecision::Allow,
+            Decision::Deny => AuthDecision::deny(format!("{} isn't granted by the token", msg.topic)),
+            Decision::Expired => {
+                self.sessions.remove(&client_id);
+                if let Err(e) = mosquitto_calls::kick_client_by_clientid(&client_id, false) {
+                    return e.into();
+                }
+                AuthDecision::deny("the token expired")
+            }
+        }
+    }
+
+    fn client_registry(&mut self) -> Option<&mut dyn ClientLifecycle> {
+        Some(&mut self.sessions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use AclCheckAccessLevel::*;
+
+    // fixtures/*.jwt are signed with the key in fixtures/jwks.json, valid.jwt expires in 2100
+    const VALID: &str = include_str!("../fixtures/valid.jwt");
+    const EXPIRED: &str = include_str!("../fixtures/expired.jwt");
+    const WRONG_AUDIENCE: &str = include_str!("../fixtures/wrong_audience.jwt");
+
+    fn plugin() -> JwtAuth {
+        let jwks = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/jwks.json");
+        let mut opts = HashMap::new();
+        opts.insert("jwks_file", jwks);
+        opts.insert("issuer", "https://auth.example.com");
+        opts.insert("audience", "mqtt");
+        JwtAuth::from_opts(&opts).unwrap()
+    }
+
+    #[test]
+    fn tokens_are_verified() {
+        let plugin = plugin();
+        let claims = plugin.verify(VALID.trim()).unwrap();
+        assert_eq!(claims.sub, "sensor-1");
+        assert_eq!(claims.read, vec!["public/#"]);
+        assert_eq!(plugin.verify(EXPIRED.trim()).err(), Some(Error::Auth));
+        assert_eq!(
+            plugin.verify(WRONG_AUDIENCE.trim()).err(),
+            Some(Error::Auth)
+        );
+        assert_eq!(plugin.verify("not a token").err(), Some(Error::Auth));
+
+        // a changed payload doesn't match the signature anymore
+        let parts: Vec<&str> = VALID.trim().split('.').collect();
+        let forged = format!(
+            "{}.{}.{}",
+            parts[0],
+            parts[1].replacen('A', "B", 1),
+            parts[2]
+        );
+        assert_eq!(plugin.verify(&forged).err(), Some(Error::Auth));
+
+        assert!(JwtAuth::from_opts(&HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn claims_map_to_topics() {
+        let claims = plugin().verify(VALID.trim()).unwrap();
+        let session = Session::from_claims(claims);
+        let now = 1_700_000_000;
+        let cases = [
+            (Write, "devices/sensor-1/temp", Decision::Allow),
+            (Subscribe, "devices/sensor-1/#", Decision::Allow),
+            (Write, "devices/sensor-2/temp", Decision::Deny),
+            (Subscribe, "devices/+/temp", Decision::Deny),
+            (Read, "public/news", Decision::Allow),
+            (Subscribe, "public/#", Decision::Allow),
+            (Write, "public/news", Decision::Deny),
+            (Write, "alerts/sensor-1", Decision::Allow),
+            (Read, "alerts/sensor-1", Decision::Deny),
+        ];
+        for (level, topic, decision) in cases.iter() {
+            assert_eq!(
+                session.decide(now, *level, topic),
+                *decision,
+                "{:?} {}",
+                level,
+                topic
+            );
+        }
+        assert_eq!(
+            session.decide(4_102_444_800, Write, "devices/sensor-1/temp"),
+            Decision::Expired
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// The generated callbacks, fed the event structs the broker passes with strings that aren't UTF-8,
+// null pointers and payload lengths without a payload. The plugin rewrites and vetoes messages
+// as the input says, with topics, payloads and reasons the broker may not accept.
+//
+// The first byte picks the event and which pointers are null, the rest is the username or topic,
+// the password or payload and a value for the plugin, separated by NUL bytes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mosquitto_plugin::mosquitto_dev::*;
+use mosquitto_plugin::properties::Properties;
+use mosquitto_plugin::testing::{fire_event, outstanding_allocations, MockBroker};
+use mosquitto_plugin::*;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+
+struct Echo;
+
+impl MosquittoPlugin for Echo {
+    fn init(_opts: MosquittoOpt) -> Self {
+        Echo
+    }
+
+    fn username_password(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let _ = (client.get_id(), client.get_username());
+        if username.is_some() && username == password {
+            AuthDecision::Allow
+        } else {
+            AuthDecision::deny("wrong password")
+        }
+    }
+
+    fn acl_check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        _level: AclCheckAccessLevel,
+        msg: MosquittoMessage,
+    ) -> AuthDecision {
+        let _ = (
+            client.get_id(),
+            msg.properties.user_properties(),
+            msg.properties.content_type(),
+        );
+        if msg.payload.is_empty() {
+            AuthDecision::deny("empty")
+        } else {
+            AuthDecision::Allow
+        }
+    }
+
+    // The first byte of the payload says what to do, the rest is the new value
+    fn on_message_mut(
+        &mut self,
+        _client: &dyn MosquittoClientContext,
+        msg: MosquittoMessage,
+    ) -> Result<MessageRewrite, MessageVeto> {
+        let value = msg.payload.get(1..).unwrap_or_default();
+        let text = String::from_utf8_lossy(value);
+        match msg.payload.first() {
+            Some(b't') => Ok(MessageRewrite::unchanged().with_topic(&text)),
+            Some(b'p') => Ok(MessageRewrite::unchanged()
+                .with_payload(value)
+                .with_retain(true)),
+            Some(b'u') => Ok(MessageRewrite::unchanged()
+                .with_properties(Properties::new().user_property(&text, &text))),
+            Some(b'v') => Err(MessageVeto::new(Error::AclDenied).with_reason(&text)),
+            _ => Ok(MessageRewrite::unchanged()),
+        }
+    }
+}
+
+const NULL_EVENT: u8 = 1 << 2;
+const NULL_CLIENT: u8 = 1 << 3;
+const NULL_FIRST: u8 = 1 << 4;
+const NULL_SECOND: u8 = 1 << 5;
+
+fuzz_target!(|data: &[u8]| {
+    let (flags, rest) = match data.split_first() {
+        Some((flags, rest)) => (*flags, rest),
+        None => return,
+    };
+    let mut parts = rest.split(|b| *b == 0);
+    let first = CString::new(parts.next().unwrap_or_default()).unwrap();
+    let second = parts.next().unwrap_or_default();
+    let value = CString::new(parts.next().unwrap_or_default()).unwrap();
+    let str_ptr = |s: &CString, null: u8| {
+        if flags & null != 0 {
+            std::ptr::null_mut()
+        } else {
+            s.as_ptr() as *mut c_char
+        }
+    };
+    let payload_ptr = if flags & NULL_SECOND != 0 {
+        std::ptr::null_mut()
+    } else {
+        second.as_ptr() as *mut c_void
+    };
+    let client = if flags & NULL_CLIENT != 0 {
+        std::ptr::null_mut()
+    } else {
+        std::ptr::NonNull::dangling().as_ptr()
+    };
+
+    let _broker = MockBroker::new().with_raw_client_id(Some(value.as_bytes()));
+    let mut id = 0u8;
+    let mut user_data: *mut c_void = std::ptr::null_mut();
+    unsafe {
+        plugin_init::<Echo>(
+            &mut id as *mut u8 as *mut c_void,
+            &mut user_data,
+            std::ptr::null_mut(),
+            0,
+        );
+    }
+
+    let fire = |event: MosquittoPluginEvent, event_data: *mut c_void| unsafe {
+        fire_event(
+            event,
+            if flags & NULL_EVENT != 0 {
+                std::ptr::null_mut()
+            } else {
+                event_data
+            },
+        )
+    };
+    match flags & 3 {
+        0 => {
+            let password = CString::new(second).unwrap();
+            let mut event: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+            event.client = client;
+            event.username = str_ptr(&first, NULL_FIRST);
+            event.password = str_ptr(&password, NULL_SECOND);
+            fire(
+                MosquittoPluginEvent::MosqEvtBasicAuth,
+                &mut event as *mut _ as *mut c_void,
+            );
+        }
+        1 => {
+            let mut event: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+            event.client = client;
+            event.topic = str_ptr(&first, NULL_FIRST);
+            event.payload = payload_ptr;
+            event.payloadlen = second.len() as u32;
+            event.access = value.as_bytes().first().map_or(0, |b| (*b % 16) as i32);
+            fire(
+                MosquittoPluginEvent::MosqEvtAclCheck,
+                &mut event as *mut _ as *mut c_void,
+            );
+        }
+        _ => {
+            let mut event: mosquitto_evt_message = unsafe { std::mem::zeroed() };
+            event.client = client;
+            event.topic = str_ptr(&first, NULL_FIRST);
+            event.payload = payload_ptr;
+            event.payloadlen = second.len() as u32;
+            let (sent_topic, sent_payload) = (event.topic, event.payload);
+            fire(
+                MosquittoPluginEvent::MosqEvtMessage,
+                &mut event as *mut _ as *mut c_void,
+            );
+            // What the broker does with a rewritten or vetoed message, freeing what it was handed
+            unsafe {
+                if event.topic != sent_topic {
+                    assert!(topic::is_valid_publish_topic(
+                        std::ffi::CStr::from_ptr(event.topic).to_str().unwrap()
+                    ));
+                    mosquitto_free(event.topic as *mut c_void);
+                }
+                if event.payload != sent_payload && !event.payload.is_null() {
+                    mosquitto_free(event.payload);
+                }
+                if !event.properties.is_null() {
+                    mosquitto_property_free_all(&mut event.properties);
+                }
+                if !event.reason_string.is_null() {
+                    mosquitto_free(event.reason_string as *mut c_void);
+                }
+            }
+        }
+    }
+
+    unsafe {
+        plugin_cleanup::<Echo>(user_data, std::ptr::null_mut(), 0);
+    }
+    assert_eq!(outstanding_allocations(), 0);
+});
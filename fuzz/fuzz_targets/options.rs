@@ -0,0 +1,50 @@
+// The options of mosquitto_plugin_init and of reload events, turned into a MosquittoOpt.
+//
+// The input is a list of keys and values separated by NUL bytes, a single 0x01 byte standing for
+// a null pointer. Keys and values may be anything but NUL, UTF-8 or not.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mosquitto_plugin::mosquitto_dev::mosquitto_opt;
+use mosquitto_plugin::{__from_ptr_and_size, __own_string};
+use std::ffi::CString;
+
+fuzz_target!(|data: &[u8]| {
+    let strings: Vec<Option<CString>> = data
+        .split(|b| *b == 0)
+        .map(|s| match s {
+            [1] => None,
+            s => Some(CString::new(s).unwrap()),
+        })
+        .collect();
+    let ptr = |s: &Option<CString>| {
+        s.as_ref()
+            .map_or(std::ptr::null_mut(), |s| s.as_ptr() as *mut _)
+    };
+    let mut opts: Vec<mosquitto_opt> = strings
+        .chunks(2)
+        .map(|pair| mosquitto_opt {
+            key: ptr(&pair[0]),
+            value: pair.get(1).map_or(std::ptr::null_mut(), ptr),
+        })
+        .collect();
+
+    let parsed = __from_ptr_and_size(opts.as_mut_ptr(), opts.len());
+    assert!(parsed.len() <= opts.len());
+    for (key, value) in &parsed {
+        let found = strings.chunks(2).any(|pair| match pair {
+            [Some(k), Some(v)] => {
+                k.to_bytes().ends_with(key.as_bytes()) && v.to_bytes() == value.as_bytes()
+            }
+            _ => false,
+        });
+        assert!(found, "{:?}={:?} isn't one of the options", key, value);
+    }
+    for s in &strings {
+        let owned = __own_string(ptr(s));
+        assert_eq!(
+            owned.is_empty(),
+            s.as_ref().is_none_or(|s| s.as_bytes().is_empty())
+        );
+    }
+});
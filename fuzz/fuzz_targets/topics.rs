@@ -0,0 +1,54 @@
+// The topic and payload conversions of mosquitto_calls: checking and splitting topics, and
+// copying the topic and payload of published messages into buffers for the broker.
+//
+// The input is a topic filter, a topic and a payload separated by newlines, the payload may be
+// any bytes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mosquitto_plugin::mosquitto_calls::{
+    check_publish_topic, check_subscribe_filter, publish_broadcast, publish_broadcast_with,
+    publish_to_client, tokenise_topic, topic_matches,
+};
+use mosquitto_plugin::testing::{outstanding_allocations, MockBroker};
+use mosquitto_plugin::{topic, QOS};
+
+fuzz_target!(|data: &[u8]| {
+    let mut parts = data.splitn(3, |b| *b == b'\n');
+    let (filter, topic, payload) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(filter), Some(topic), payload) => {
+            match (std::str::from_utf8(filter), std::str::from_utf8(topic)) {
+                (Ok(filter), Ok(topic)) => (filter, topic, payload.unwrap_or_default()),
+                _ => return,
+            }
+        }
+        _ => return,
+    };
+    let broker = MockBroker::new();
+
+    if let Ok(levels) = tokenise_topic(topic) {
+        assert_eq!(levels.join("/"), topic);
+    }
+    if check_subscribe_filter(filter).is_ok() && check_publish_topic(topic).is_ok() {
+        let _ = topic_matches(filter, topic);
+    }
+    let _ = topic::pattern_is_subset_of(filter, topic);
+    let _ = topic::patterns_overlap(filter, topic);
+
+    let sent = [
+        publish_broadcast(topic, payload, QOS::AtLeastOnce, false).is_ok(),
+        publish_broadcast_with(topic, payload.len(), QOS::AtMostOnce, true, |buf| {
+            buf.copy_from_slice(payload)
+        })
+        .is_ok(),
+        publish_to_client(filter, topic, payload, QOS::ExactlyOnce, false).is_ok(),
+    ];
+    let published = broker.published();
+    assert_eq!(published.len(), sent.iter().filter(|sent| **sent).count());
+    for message in &published {
+        assert_eq!(message.topic, topic);
+        assert_eq!(message.payload, payload);
+    }
+    // Buffers the broker refused are freed again, the stand-in frees the ones it took
+    assert_eq!(outstanding_allocations(), 0);
+});
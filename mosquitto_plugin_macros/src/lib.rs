@@ -0,0 +1,304 @@
+// The #[mosquitto_plugin] attribute and #[derive(PluginOpts)], re-exported by mosquitto-plugin.
+//
+// Placed on the `impl MosquittoPlugin for Type` block it generates the same exported functions
+// as create_dynamic_library!, and registers callbacks only for the trait methods the impl block
+// defines. Only proc-macro2 and quote are used, the impl block is inspected on the token level.
+use proc_macro::TokenStream;
+use proc_macro2::{Delimiter, Span, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, quote_spanned};
+
+mod opts;
+
+type Error = (Span, String);
+
+// Trait methods and the broker event they are called from, see dynlib::Callbacks
+const CALLBACKS: &[(&str, &str)] = &[
+    ("on_reload", "RELOAD"),
+    ("acl_check", "ACL_CHECK"),
+    ("acl_check_subscribe", "ACL_CHECK"),
+    ("acl_check_unsubscribe", "ACL_CHECK"),
+    ("username_password", "BASIC_AUTH"),
+    ("on_connect", "CONNECT_CHECK"),
+    ("ext_auth_start", "EXT_AUTH"),
+    ("ext_auth_continue", "EXT_AUTH"),
+    ("on_control", "CONTROL"),
+    ("on_control_command", "CONTROL"),
+    ("control_topics", "CONTROL"),
+    ("on_message", "MESSAGE"),
+    ("on_message_check", "MESSAGE"),
+    ("on_message_mut", "MESSAGE"),
+    ("retained_topics", "MESSAGE"),
+    ("on_psk", "PSK_KEY"),
+    ("psk_key", "PSK_KEY"),
+    ("on_tick", "TICK"),
+    ("tick", "TICK"),
+    ("scheduler", "TICK"),
+    ("stats", "TICK"),
+    ("on_disconnect", "DISCONNECT"),
+    ("client_registry", "DISCONNECT"),
+    // Depend on the broker version, which only mosquitto-plugin knows
+    ("on_session_takeover", "SESSION_TAKEOVER"),
+    ("connected_clients", "CONNECTED_CLIENTS"),
+    ("on_subscribe", "SUBSCRIBE"),
+    ("on_unsubscribe", "UNSUBSCRIBE"),
+];
+
+/// Generates the functions mosquitto looks for when loading a plugin, from the
+/// `impl MosquittoPlugin for Type` block it is placed on.
+///
+/// ```ignore
+/// #[mosquitto_plugin(name = "my-plugin", version = "1.2.3")]
+/// impl MosquittoPlugin for MyPlugin {
+///     fn init(opts: MosquittoOpt) -> Self { MyPlugin }
+///     fn acl_check(...) -> AuthDecision { ... }
+/// }
+/// ```
+///
+/// Only the events whose methods are defined in the block are registered with the broker, a
+/// plugin without username_password leaves logins to the broker configuration. name and version
+/// are optional and written to the broker log at init. `persistence` registers the persist
+/// events of mosquitto 2.1 too, for types implementing mosquitto_plugin::persist::PersistencePlugin.
+#[proc_macro_attribute]
+pub fn mosquitto_plugin(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item: TokenStream2 = item.into();
+    match expand(attr.into(), &item) {
+        Ok(tokens) => tokens.into(),
+        Err((span, message)) => {
+            let error = quote_spanned!(span=> compile_error!(#message););
+            quote!(#item #error).into()
+        }
+    }
+}
+
+/// Implements mosquitto_plugin::opts::PluginOpts for a struct with named fields, each field is
+/// read from the option of the same name.
+///
+/// ```ignore
+/// #[derive(PluginOpts)]
+/// struct Config {
+///     db_host: String,
+///     #[opt(name = "db_timeout", default = "30s")]
+///     timeout: Duration,
+///     #[opt(default)]
+///     max_clients: u32,
+///     cache_dir: Option<PathBuf>,
+/// }
+/// ```
+///
+/// Fields are required unless they are an Option or have a default. `default = "..."` is parsed
+/// like a value from mosquitto.conf, a plain `default` uses Default::default().
+#[proc_macro_derive(PluginOpts, attributes(opt))]
+pub fn plugin_opts(item: TokenStream) -> TokenStream {
+    match opts::expand(item.into()) {
+        Ok(tokens) => tokens.into(),
+        Err((span, message)) => quote_spanned!(span=> compile_error!(#message);).into(),
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    name: Option<String>,
+    version: Option<String>,
+    persistence: bool,
+}
+
+fn expand(attr: TokenStream2, item: &TokenStream2) -> Result<TokenStream2, Error> {
+    let options = parse_options(attr)?;
+    let (self_ty, methods) = parse_impl(item)?;
+
+    let mut callbacks = quote!(::mosquitto_plugin::dynlib::Callbacks::NONE);
+    for (method, callback) in CALLBACKS {
+        if methods.iter().any(|m| m == method) {
+            let callback = proc_macro2::Ident::new(callback, Span::call_site());
+            callbacks = quote!(#callbacks.union(::mosquitto_plugin::dynlib::Callbacks::#callback));
+        }
+    }
+    let name = option_tokens(&options.name);
+    let version = option_tokens(&options.version);
+    let glue = if options.persistence { quote!(persist) } else { quote!(dynlib) };
+
+    Ok(quote! {
+        #item
+
+        #[no_mangle]
+        pub unsafe extern "C" fn mosquitto_plugin_version(
+            supported_version_count: ::std::os::raw::c_int,
+            supported_versions: *const ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int {
+            unsafe { ::mosquitto_plugin::dynlib::plugin_version(supported_version_count, supported_versions) }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_plugin_init(
+            identifier: *mut ::std::os::raw::c_void,
+            user_data: *mut *mut ::std::os::raw::c_void,
+            opts: *mut ::mosquitto_plugin::mosquitto_dev::mosquitto_opt,
+            opt_count: ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int {
+            const INFO: ::mosquitto_plugin::dynlib::PluginInfo = ::mosquitto_plugin::dynlib::PluginInfo {
+                name: #name,
+                version: #version,
+                callbacks: #callbacks,
+            };
+            unsafe { ::mosquitto_plugin::#glue::plugin_init_with::<#self_ty>(identifier, user_data, opts, opt_count, &INFO) }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_plugin_cleanup(
+            user_data: *mut ::std::os::raw::c_void,
+            opts: *mut ::mosquitto_plugin::mosquitto_dev::mosquitto_opt,
+            opt_count: ::std::os::raw::c_int,
+        ) -> ::std::os::raw::c_int {
+            unsafe { ::mosquitto_plugin::#glue::plugin_cleanup::<#self_ty>(user_data, opts, opt_count) }
+        }
+
+        ::mosquitto_plugin::__auth_plugin_v4_symbols!(#self_ty, ::mosquitto_plugin::dynlib::PluginInfo {
+            name: #name,
+            version: #version,
+            callbacks: #callbacks,
+        });
+    })
+}
+
+fn option_tokens(value: &Option<String>) -> TokenStream2 {
+    match value {
+        Some(value) => quote!(::std::option::Option::Some(#value)),
+        None => quote!(::std::option::Option::None),
+    }
+}
+
+// name = "...", version = "...", persistence
+fn parse_options(attr: TokenStream2) -> Result<Options, Error> {
+    let mut options = Options::default();
+    let mut tokens = attr.into_iter();
+    while let Some(token) = tokens.next() {
+        let key = match token {
+            TokenTree::Ident(key) => key,
+            other => return Err((other.span(), "expected `name = \"...\"`, `version = \"...\"` or `persistence`".to_string())),
+        };
+        if key == "persistence" {
+            if options.persistence {
+                return Err((key.span(), "`persistence` is given more than once".to_string()));
+            }
+            options.persistence = true;
+            match tokens.next() {
+                None => break,
+                Some(TokenTree::Punct(p)) if p.as_char() == ',' => continue,
+                Some(other) => return Err((other.span(), "`persistence` takes no value".to_string())),
+            }
+        }
+        match tokens.next() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '=' => {}
+            _ => return Err((key.span(), format!("expected `=` after `{}`", key))),
+        }
+        let value = match tokens.next() {
+            Some(TokenTree::Literal(lit)) => match string_value(&lit.to_string()) {
+                Some(value) => value,
+                None => return Err((lit.span(), format!("`{}` has to be a plain string literal", key))),
+            },
+            _ => return Err((key.span(), format!("expected a string after `{} =`", key))),
+        };
+        let slot = match key.to_string().as_str() {
+            "name" => &mut options.name,
+            "version" => &mut options.version,
+            other => {
+                return Err((
+                    key.span(),
+                    format!("unknown option `{}`, expected `name`, `version` or `persistence`", other),
+                ))
+            }
+        };
+        if slot.replace(value).is_some() {
+            return Err((key.span(), format!("`{}` is given more than once", key)));
+        }
+        match tokens.next() {
+            None => break,
+            Some(TokenTree::Punct(p)) if p.as_char() == ',' => {}
+            Some(other) => return Err((other.span(), "expected `,` between options".to_string())),
+        }
+    }
+    Ok(options)
+}
+
+fn string_value(literal: &str) -> Option<String> {
+    let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+    if inner.contains('\\') || inner.contains('"') {
+        return None;
+    }
+    Some(inner.to_string())
+}
+
+// Returns the self type and the names of the methods defined in the impl block
+fn parse_impl(item: &TokenStream2) -> Result<(TokenStream2, Vec<String>), Error> {
+    let tokens: Vec<TokenTree> = item.clone().into_iter().collect();
+    let first_span = tokens.first().map_or_else(Span::call_site, |t| t.span());
+    let not_impl = |span| {
+        (
+            span,
+            "#[mosquitto_plugin] has to be placed on an `impl MosquittoPlugin for YourType` block".to_string(),
+        )
+    };
+
+    // Skip outer attributes like #[allow(...)]
+    let mut i = 0;
+    while matches!(tokens.get(i), Some(TokenTree::Punct(p)) if p.as_char() == '#') {
+        i += 2;
+    }
+    match tokens.get(i) {
+        Some(TokenTree::Ident(ident)) if ident == "impl" => i += 1,
+        _ => return Err(not_impl(first_span)),
+    }
+    if let Some(TokenTree::Punct(p)) = tokens.get(i) {
+        if p.as_char() == '<' {
+            return Err((
+                p.span(),
+                "#[mosquitto_plugin] can't be used on a generic impl, the exported functions need a \
+                 concrete type. Use create_dynamic_library!(YourType<Concrete>) instead"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let for_pos = tokens[i..]
+        .iter()
+        .position(|t| matches!(t, TokenTree::Ident(ident) if ident == "for"))
+        .map(|pos| pos + i)
+        .ok_or_else(|| not_impl(first_span))?;
+    match tokens[..for_pos].last() {
+        Some(TokenTree::Ident(ident)) if ident == "MosquittoPlugin" => {}
+        Some(other) => return Err(not_impl(other.span())),
+        None => return Err(not_impl(first_span)),
+    }
+
+    let body = match tokens.last() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => group,
+        _ => return Err(not_impl(first_span)),
+    };
+    let self_ty = &tokens[for_pos + 1..tokens.len() - 1];
+    if self_ty.is_empty() {
+        return Err(not_impl(first_span));
+    }
+    if let Some(where_token) = self_ty
+        .iter()
+        .find(|t| matches!(t, TokenTree::Ident(ident) if ident == "where"))
+    {
+        return Err((
+            where_token.span(),
+            "#[mosquitto_plugin] can't be used on an impl with a where clause, the exported \
+             functions need a concrete type"
+                .to_string(),
+        ));
+    }
+
+    // Methods are `fn name` at the top level of the block, their bodies are nested groups
+    let body: Vec<TokenTree> = body.stream().into_iter().collect();
+    let methods = body
+        .windows(2)
+        .filter_map(|pair| match pair {
+            [TokenTree::Ident(kw), TokenTree::Ident(name)] if kw == "fn" => Some(name.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    Ok((self_ty.iter().cloned().collect(), methods))
+}
@@ -0,0 +1,250 @@
+// #[derive(PluginOpts)], see mosquitto_plugin::opts. Like #[mosquitto_plugin] the struct is read
+// on the token level, only the field names and their #[opt(...)] attributes are needed, the
+// field types are left to inference in the generated code.
+use crate::{string_value, Error};
+use proc_macro2::{Delimiter, Ident, Span, TokenStream as TokenStream2, TokenTree};
+use quote::quote;
+
+enum FieldDefault {
+    Required,
+    // Parsed like a value from mosquitto.conf
+    Value(String),
+    // Default::default()
+    Trait,
+}
+
+struct Field {
+    ident: Ident,
+    key: String,
+    default: FieldDefault,
+}
+
+pub(crate) fn expand(item: TokenStream2) -> Result<TokenStream2, Error> {
+    let (name, body) = parse_struct(item)?;
+    let fields = parse_fields(body)?;
+
+    let values = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let key = &field.key;
+        let value = match &field.default {
+            FieldDefault::Required => quote!(::mosquitto_plugin::opts::opt(opts, #key)?),
+            FieldDefault::Value(default) => {
+                quote!(::mosquitto_plugin::opts::opt_or(opts, #key, #default)?)
+            }
+            FieldDefault::Trait => quote!(
+                ::mosquitto_plugin::opts::opt::<::std::option::Option<_>>(opts, #key)?
+                    .unwrap_or_default()
+            ),
+        };
+        quote!(#ident: #value)
+    });
+
+    Ok(quote! {
+        impl ::mosquitto_plugin::opts::PluginOpts for #name {
+            fn from_opts(
+                opts: &::mosquitto_plugin::MosquittoOpt,
+            ) -> ::std::result::Result<Self, ::mosquitto_plugin::opts::OptError> {
+                ::std::result::Result::Ok(#name { #(#values),* })
+            }
+        }
+    })
+}
+
+// Returns the name of the struct and the contents of its braces
+fn parse_struct(item: TokenStream2) -> Result<(Ident, TokenStream2), Error> {
+    let tokens: Vec<TokenTree> = item.into_iter().collect();
+    let not_named = |span| {
+        (
+            span,
+            "#[derive(PluginOpts)] can only be used on a struct with named fields".to_string(),
+        )
+    };
+
+    // Skip attributes, including doc comments, and the visibility
+    let mut i = 0;
+    while matches!(tokens.get(i), Some(TokenTree::Punct(p)) if p.as_char() == '#') {
+        i += 2;
+    }
+    i = skip_visibility(&tokens, i);
+    match tokens.get(i) {
+        Some(TokenTree::Ident(ident)) if ident == "struct" => i += 1,
+        Some(other) => return Err(not_named(other.span())),
+        None => return Err(not_named(Span::call_site())),
+    }
+    let name = match tokens.get(i) {
+        Some(TokenTree::Ident(name)) => name.clone(),
+        _ => return Err(not_named(Span::call_site())),
+    };
+    match tokens.get(i + 1) {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => {
+            Ok((name, group.stream()))
+        }
+        Some(TokenTree::Punct(p)) if p.as_char() == '<' => Err((
+            p.span(),
+            "#[derive(PluginOpts)] can't be used on a generic struct".to_string(),
+        )),
+        _ => Err(not_named(name.span())),
+    }
+}
+
+// pub, pub(crate), pub(super), ...
+fn skip_visibility(tokens: &[TokenTree], mut i: usize) -> usize {
+    if matches!(tokens.get(i), Some(TokenTree::Ident(ident)) if ident == "pub") {
+        i += 1;
+        if matches!(tokens.get(i), Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Parenthesis)
+        {
+            i += 1;
+        }
+    }
+    i
+}
+
+fn parse_fields(body: TokenStream2) -> Result<Vec<Field>, Error> {
+    let tokens: Vec<TokenTree> = body.into_iter().collect();
+    let mut fields = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let mut key = None;
+        let mut default = FieldDefault::Required;
+        while let Some(TokenTree::Punct(p)) = tokens.get(i) {
+            if p.as_char() != '#' {
+                break;
+            }
+            if let Some(TokenTree::Group(attr)) = tokens.get(i + 1) {
+                let attr: Vec<TokenTree> = attr.stream().into_iter().collect();
+                if let [TokenTree::Ident(name), TokenTree::Group(args)] = &attr[..] {
+                    if name == "opt" {
+                        parse_attribute(args.stream(), &mut key, &mut default)?;
+                    }
+                }
+            }
+            i += 2;
+        }
+        i = skip_visibility(&tokens, i);
+        let ident = match tokens.get(i) {
+            Some(TokenTree::Ident(ident)) => ident.clone(),
+            Some(other) => return Err((other.span(), "expected a field name".to_string())),
+            None => return Err((Span::call_site(), "expected a field name".to_string())),
+        };
+        match tokens.get(i + 1) {
+            Some(TokenTree::Punct(p)) if p.as_char() == ':' => {}
+            _ => return Err((ident.span(), format!("expected `:` after `{}`", ident))),
+        }
+        i += 2;
+
+        // The type ends at the first comma outside of <>, skipping the > of ->
+        let mut depth = 0usize;
+        let mut arrow = false;
+        while let Some(token) = tokens.get(i) {
+            i += 1;
+            if let TokenTree::Punct(p) = token {
+                match p.as_char() {
+                    ',' if depth == 0 => break,
+                    '<' => depth += 1,
+                    '>' if !arrow => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                arrow = p.as_char() == '-';
+            } else {
+                arrow = false;
+            }
+        }
+
+        let name = ident.to_string();
+        let key = key.unwrap_or_else(|| name.trim_start_matches("r#").to_string());
+        fields.push(Field {
+            ident,
+            key,
+            default,
+        });
+    }
+    Ok(fields)
+}
+
+// name = "...", default = "...", default
+fn parse_attribute(
+    args: TokenStream2,
+    key: &mut Option<String>,
+    default: &mut FieldDefault,
+) -> Result<(), Error> {
+    let mut tokens = args.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let option = match token {
+            TokenTree::Ident(option) => option,
+            other => {
+                return Err((
+                    other.span(),
+                    "expected `name = \"...\"`, `default = \"...\"` or `default`".to_string(),
+                ))
+            }
+        };
+        let value = match tokens.peek() {
+            Some(TokenTree::Punct(p)) if p.as_char() == '=' => {
+                tokens.next();
+                match tokens.next() {
+                    // Numbers can be given without quotes
+                    Some(TokenTree::Literal(lit)) => {
+                        let text = lit.to_string();
+                        let value = if text.starts_with('"') {
+                            string_value(&text)
+                        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+                            Some(text)
+                        } else {
+                            None
+                        };
+                        match value {
+                            Some(value) => Some(value),
+                            None => {
+                                return Err((
+                                    lit.span(),
+                                    format!("`{}` has to be a plain string literal", option),
+                                ))
+                            }
+                        }
+                    }
+                    _ => {
+                        return Err((
+                            option.span(),
+                            format!("expected a string after `{} =`", option),
+                        ))
+                    }
+                }
+            }
+            _ => None,
+        };
+        match (option.to_string().as_str(), value) {
+            ("name", Some(name)) => {
+                if key.replace(name).is_some() {
+                    return Err((option.span(), "`name` is given more than once".to_string()));
+                }
+            }
+            ("name", None) => {
+                return Err((option.span(), "expected `name = \"...\"`".to_string()));
+            }
+            ("default", value) => {
+                if !matches!(default, FieldDefault::Required) {
+                    return Err((
+                        option.span(),
+                        "`default` is given more than once".to_string(),
+                    ));
+                }
+                *default = match value {
+                    Some(value) => FieldDefault::Value(value),
+                    None => FieldDefault::Trait,
+                };
+            }
+            (other, _) => {
+                return Err((
+                    option.span(),
+                    format!("unknown option `{}`, expected `name` or `default`", other),
+                ))
+            }
+        }
+        match tokens.next() {
+            None => break,
+            Some(TokenTree::Punct(p)) if p.as_char() == ',' => {}
+            Some(other) => return Err((other.span(), "expected `,` between options".to_string())),
+        }
+    }
+    Ok(())
+}
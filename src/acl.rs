@@ -0,0 +1,523 @@
+// ACL rules in the syntax of the mosquitto acl_file, so existing patterns can be reused in
+// plugin options:
+//
+//     pattern readwrite devices/%u/%c/#
+//     pattern read public/#
+//     pattern deny devices/%u/secret
+//
+// %c is replaced with the client id and %u with the username when checking, %% is a literal %.
+// As in mosquitto, patterns using %u don't apply to clients without a username, and clients
+// whose id or username contains + or # are not matched at all so they can't widen a pattern.
+// TopicTemplate is the same substitution without the access, for plugins enforcing a topic like
+// `devices/%c/up` in their own checks.
+//
+// AclFile reads a whole acl_file, for plugins replacing the one of the broker:
+//
+//     topic read public/#          # before any user line, for anonymous clients
+//     user alice
+//     topic readwrite alice/#      # for alice, without substitution
+//     pattern write inbox/%c       # for everyone
+//
+// Like mosquitto it checks the topic lines of the user first and the patterns after, in both
+// the deny lines before the others, and the first line matching the topic decides.
+use crate::topic::pattern_is_subset_of;
+use crate::{AclCheckAccessLevel, Error, MosquittoClientContext, Success};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AclAccess {
+    /// Receive messages and subscribe
+    Read,
+    /// Publish
+    Write,
+    ReadWrite,
+    /// Deny access, takes precedence over any pattern allowing it
+    Deny,
+}
+
+impl AclAccess {
+    /// Whether this access covers the requested level, Read includes subscribing and
+    /// unsubscribing
+    pub fn allows(self, level: AclCheckAccessLevel) -> bool {
+        matches!(
+            (self, level),
+            (AclAccess::ReadWrite, _)
+                | (AclAccess::Read, AclCheckAccessLevel::Read)
+                | (AclAccess::Read, AclCheckAccessLevel::Subscribe)
+                | (AclAccess::Read, AclCheckAccessLevel::Unsubscribe)
+                | (AclAccess::Write, AclCheckAccessLevel::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    ClientId,
+    Username,
+}
+
+/// A topic or filter with %c and %u placeholders, like `devices/%c/up`, for per client topics
+/// without a whole AclPattern:
+///
+/// ```
+/// use mosquitto_plugin::acl::TopicTemplate;
+/// let up = TopicTemplate::new("devices/%c/up");
+/// assert!(up.matches("lamp-1", None, "devices/lamp-1/up"));
+/// assert!(!up.matches("lamp-1", None, "devices/lamp-2/up"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicTemplate {
+    parts: Vec<Part>,
+}
+
+impl TopicTemplate {
+    /// %c is the client id, %u the username and %% a literal %, anything else is kept as is
+    pub fn new(template: &str) -> TopicTemplate {
+        TopicTemplate {
+            parts: parse_parts(template),
+        }
+    }
+
+    /// The topic or filter for this client. None when the template has %u and the client has no
+    /// username, or when the client id or username contains + or #, so they can't widen it.
+    pub fn expand(&self, client_id: &str, username: Option<&str>) -> Option<String> {
+        let dangerous = |s: &str| s.contains('+') || s.contains('#');
+        if dangerous(client_id) || username.is_some_and(dangerous) {
+            return None;
+        }
+        let mut topic = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => topic.push_str(s),
+                Part::ClientId => topic.push_str(client_id),
+                Part::Username => topic.push_str(username?),
+            }
+        }
+        Some(topic)
+    }
+
+    /// Whether the template, expanded for the client, covers the topic. For subscriptions the
+    /// topic is the requested filter, which has to be entirely within the template.
+    pub fn matches(&self, client_id: &str, username: Option<&str>, topic: &str) -> bool {
+        match self.expand(client_id, username) {
+            Some(filter) => pattern_is_subset_of(&filter, topic),
+            None => false,
+        }
+    }
+
+    /// Same as expand, with the client id and username taken from the client
+    pub fn expand_for(&self, client: &dyn MosquittoClientContext) -> Option<String> {
+        self.expand(&client.get_id(), client.get_username().as_deref())
+    }
+
+    /// Same as matches, with the client id and username taken from the client
+    pub fn matches_client(&self, client: &dyn MosquittoClientContext, topic: &str) -> bool {
+        self.matches(&client.get_id(), client.get_username().as_deref(), topic)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclPattern {
+    access: AclAccess,
+    topic: TopicTemplate,
+}
+
+impl AclPattern {
+    /// Parses a line like `pattern readwrite devices/%u/#`. The leading `pattern` keyword is
+    /// optional, and so is the access, which defaults to readwrite like in the acl_file.
+    pub fn parse(line: &str) -> Result<AclPattern, Error> {
+        let line = line.trim();
+        if line == "pattern" {
+            return Err(Error::Inval);
+        }
+        let line = match line.strip_prefix("pattern ") {
+            Some(rest) => rest.trim_start(),
+            None => line,
+        };
+        let (access, topic) = split_access(line).ok_or(Error::Inval)?;
+        Ok(AclPattern {
+            access,
+            topic: TopicTemplate::new(topic),
+        })
+    }
+
+    /// Parses several patterns separated by `;`, as a list fits in a single plugin option
+    pub fn parse_list(list: &str) -> Result<Vec<AclPattern>, Error> {
+        list.split(';')
+            .filter(|line| !line.trim().is_empty())
+            .map(AclPattern::parse)
+            .collect()
+    }
+
+    pub fn access(&self) -> AclAccess {
+        self.access
+    }
+
+    /// The topic filter for this client, None when the pattern doesn't apply to it
+    pub fn expand(&self, client_id: &str, username: Option<&str>) -> Option<String> {
+        self.topic.expand(client_id, username)
+    }
+
+    /// Whether the pattern, expanded for the client, covers the topic, see TopicTemplate::matches
+    pub fn matches(&self, client_id: &str, username: Option<&str>, topic: &str) -> bool {
+        self.topic.matches(client_id, username, topic)
+    }
+}
+
+// The access of a line like `read a/b`, readwrite when there is none, and the topic
+fn split_access(line: &str) -> Option<(AclAccess, &str)> {
+    let (access, topic) = match line.split_once(' ') {
+        Some(("read", topic)) => (AclAccess::Read, topic),
+        Some(("write", topic)) => (AclAccess::Write, topic),
+        Some(("readwrite", topic)) => (AclAccess::ReadWrite, topic),
+        Some(("deny", topic)) => (AclAccess::Deny, topic),
+        _ => (AclAccess::ReadWrite, line),
+    };
+    let topic = topic.trim();
+    if topic.is_empty() {
+        None
+    } else {
+        Some((access, topic))
+    }
+}
+
+fn parse_parts(topic: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = topic.chars().peekable();
+    while let Some(c) = chars.next() {
+        let part = match (c, chars.peek()) {
+            ('%', Some('c')) => Part::ClientId,
+            ('%', Some('u')) => Part::Username,
+            ('%', Some('%')) => {
+                chars.next();
+                literal.push('%');
+                continue;
+            }
+            _ => {
+                literal.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(part);
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    parts
+}
+
+/// Checks a request against a list of patterns the way the acl_file does: denied if any
+/// matching pattern denies it, allowed if a matching pattern grants the access level, and
+/// denied otherwise.
+pub fn check(
+    patterns: &[AclPattern],
+    client_id: &str,
+    username: Option<&str>,
+    level: AclCheckAccessLevel,
+    topic: &str,
+) -> Result<Success, Error> {
+    let mut allowed = false;
+    for pattern in patterns.iter().filter(|p| p.matches(client_id, username, topic)) {
+        if pattern.access == AclAccess::Deny {
+            return Err(Error::AclDenied);
+        }
+        allowed |= pattern.access.allows(level);
+    }
+    if allowed {
+        Ok(Success)
+    } else {
+        Err(Error::AclDenied)
+    }
+}
+
+/// Same as check, with the client id and username taken from the client
+pub fn check_client(
+    patterns: &[AclPattern],
+    client: &dyn MosquittoClientContext,
+    level: AclCheckAccessLevel,
+    topic: &str,
+) -> Result<Success, Error> {
+    let username = client.get_username();
+    check(patterns, &client.get_id(), username.as_deref(), level, topic)
+}
+
+/// Why an acl_file couldn't be read
+#[derive(Debug)]
+pub enum AclFileError {
+    Io(io::Error),
+    /// Line number `line`, counted from 1, isn't a comment, user, topic or pattern line
+    Invalid { line: usize, content: String },
+}
+
+impl fmt::Display for AclFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclFileError::Io(e) => write!(f, "can't read the acl file: {}", e),
+            AclFileError::Invalid { line, content } => {
+                write!(f, "invalid line {} in the acl file: {:?}", line, content)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AclFileError {}
+
+// A topic line, matched without substitution
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AclTopic {
+    access: AclAccess,
+    topic: String,
+}
+
+/// The rules of a mosquitto acl_file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AclFile {
+    anonymous: Vec<AclTopic>,
+    users: HashMap<String, Vec<AclTopic>>,
+    patterns: Vec<AclPattern>,
+}
+
+impl AclFile {
+    pub fn parse(contents: &str) -> Result<AclFile, AclFileError> {
+        let mut file = AclFile::default();
+        let mut user: Option<String> = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || AclFileError::Invalid {
+                line: i + 1,
+                content: line.to_string(),
+            };
+            let (keyword, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let rest = rest.trim();
+            match keyword {
+                "user" if !rest.is_empty() => user = Some(rest.to_string()),
+                "topic" => {
+                    let (access, topic) = split_access(rest).ok_or_else(invalid)?;
+                    let topic = AclTopic {
+                        access,
+                        topic: topic.to_string(),
+                    };
+                    match &user {
+                        Some(user) => file.users.entry(user.clone()).or_default().push(topic),
+                        None => file.anonymous.push(topic),
+                    }
+                }
+                "pattern" => file
+                    .patterns
+                    .push(AclPattern::parse(rest).map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        // Deny lines are checked first, the order of the others is kept
+        file.anonymous.sort_by_key(|t| t.access != AclAccess::Deny);
+        for topics in file.users.values_mut() {
+            topics.sort_by_key(|t| t.access != AclAccess::Deny);
+        }
+        file.patterns.sort_by_key(|p| p.access != AclAccess::Deny);
+        Ok(file)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<AclFile, AclFileError> {
+        let contents = std::fs::read_to_string(path).map_err(AclFileError::Io)?;
+        AclFile::parse(&contents)
+    }
+
+    /// Whether the client may access the topic, Err(Error::AclDenied) when no line allows it
+    pub fn check(
+        &self,
+        username: Option<&str>,
+        client_id: &str,
+        topic: &str,
+        access: AclCheckAccessLevel,
+    ) -> Result<Success, Error> {
+        let topics = match username {
+            Some(username) => self.users.get(username).map_or(&[][..], Vec::as_slice),
+            None => &self.anonymous,
+        };
+        let decisions = topics
+            .iter()
+            .filter(|t| pattern_is_subset_of(&t.topic, topic))
+            .map(|t| t.access)
+            .chain(
+                self.patterns
+                    .iter()
+                    .filter(|p| p.matches(client_id, username, topic))
+                    .map(|p| p.access),
+            );
+        for line in decisions {
+            if line == AclAccess::Deny {
+                return Err(Error::AclDenied);
+            }
+            if line.allows(access) {
+                return Ok(Success);
+            }
+        }
+        Err(Error::AclDenied)
+    }
+
+    /// Same as check, with the username and client id taken from the client
+    pub fn check_client(
+        &self,
+        client: &dyn MosquittoClientContext,
+        topic: &str,
+        access: AclCheckAccessLevel,
+    ) -> Result<Success, Error> {
+        let username = client.get_username();
+        self.check(username.as_deref(), &client.get_id(), topic, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AclCheckAccessLevel::*;
+
+    #[test]
+    fn parses_acl_file_lines() {
+        let p = AclPattern::parse("pattern read sensors/%u/#").unwrap();
+        assert_eq!(p.access(), AclAccess::Read);
+        assert_eq!(p.expand("c1", Some("alice")).as_deref(), Some("sensors/alice/#"));
+        assert_eq!(AclPattern::parse("devices/%c").unwrap().access(), AclAccess::ReadWrite);
+        assert_eq!(AclPattern::parse("pattern deny a").unwrap().access(), AclAccess::Deny);
+        // a lone access word is the topic, like in the acl_file
+        assert_eq!(AclPattern::parse("read").unwrap().expand("c", None).as_deref(), Some("read"));
+        assert_eq!(AclPattern::parse("pattern "), Err(Error::Inval));
+        assert_eq!(AclPattern::parse(""), Err(Error::Inval));
+        assert_eq!(AclPattern::parse_list("read a; ;write b").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn substitution() {
+        let cases = [
+            // pattern, client id, username, expanded
+            ("devices/%u/%c/#", "c1", Some("alice"), Some("devices/alice/c1/#")),
+            ("devices/%u/#", "c1", None, None),
+            ("devices/%c/#", "c1", None, Some("devices/c1/#")),
+            ("%c%u", "c1", Some("alice"), Some("c1alice")),
+            ("100%%/%c", "c1", None, Some("100%/c1")),
+            ("a/%x/%", "c1", None, Some("a/%x/%")),
+            ("a/%%c", "c1", None, Some("a/%c")),
+            ("devices/%c", "c+", None, None),
+            ("devices/%c", "c1", Some("#"), None),
+        ];
+        for (pattern, client_id, username, expanded) in cases.iter() {
+            let p = AclPattern::parse(pattern).unwrap();
+            assert_eq!(p.expand(client_id, *username).as_deref(), *expanded, "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn templates_match_the_topics_of_the_client() {
+        let up = TopicTemplate::new("devices/%c/up");
+        assert!(up.matches("lamp-1", None, "devices/lamp-1/up"));
+        assert!(!up.matches("lamp-1", None, "devices/lamp-2/up"));
+        assert!(!up.matches("lamp-1", None, "devices/+/up"));
+        let inbox = TopicTemplate::new("users/%u/#");
+        assert!(inbox.matches("c1", Some("alice"), "users/alice/inbox"));
+        assert!(inbox.matches("c1", Some("alice"), "users/alice/+"));
+        assert!(!inbox.matches("c1", None, "users//inbox"));
+
+        crate::stub_ffi::reset();
+        let client = crate::stub_ffi::client();
+        client.set_username("alice").unwrap();
+        assert_eq!(inbox.expand_for(&client).as_deref(), Some("users/alice/#"));
+        assert!(TopicTemplate::new("devices/%c/%u").matches_client(&client, "devices/stub-client/alice"));
+    }
+
+    #[test]
+    fn checks_like_the_acl_file() {
+        let patterns = AclPattern::parse_list(
+            "pattern readwrite devices/%u/%c/#; pattern read public/+/status; \
+             pattern write inbox/%c; pattern deny devices/%u/%c/secret",
+        )
+        .unwrap();
+        let cases = [
+            // client id, username, level, topic, allowed
+            ("c1", Some("alice"), Write, "devices/alice/c1/temp", true),
+            ("c1", Some("alice"), Read, "devices/alice/c1", true),
+            ("c1", Some("alice"), Subscribe, "devices/alice/c1/#", true),
+            ("c1", Some("alice"), Subscribe, "devices/alice/+/temp", false),
+            ("c1", Some("alice"), Write, "devices/alice/c2/temp", false),
+            ("c1", Some("alice"), Write, "devices/bob/c1/temp", false),
+            ("c1", Some("alice"), Write, "devices/alice/c1/secret", false),
+            ("c1", Some("alice"), Read, "public/x/status", true),
+            ("c1", Some("alice"), Subscribe, "public/+/status", true),
+            ("c1", Some("alice"), Unsubscribe, "public/+/status", true),
+            ("c1", Some("alice"), Unsubscribe, "inbox/c1", false),
+            ("c1", Some("alice"), Write, "public/x/status", false),
+            ("c1", Some("alice"), Write, "inbox/c1", true),
+            ("c1", Some("alice"), Read, "inbox/c1", false),
+            ("c1", None, Write, "devices//c1/temp", false),
+            ("c1", None, Write, "inbox/c1", true),
+            ("c#", None, Write, "inbox/c#", false),
+            ("c1", Some("alice"), Read, "$SYS/broker/uptime", false),
+        ];
+        for (client_id, username, level, topic, allowed) in cases.iter() {
+            let res = check(&patterns, client_id, *username, *level, topic);
+            assert_eq!(res.is_ok(), *allowed, "{} {:?} {:?} {}", client_id, username, level, topic);
+        }
+    }
+
+    #[test]
+    fn acl_files_check_like_the_broker() {
+        let file = AclFile::parse(
+            "# anonymous clients\n\
+             topic read public/#\n\
+             \n\
+             user alice\n\
+             topic readwrite alice/#\n\
+             topic deny alice/secret\n\
+             topic %c/literal\n\
+             user bob\n\
+             topic write public/bob\n\
+             pattern read devices/%c/#\n\
+             \tpattern deny devices/+/config\n",
+        )
+        .unwrap();
+        let cases = [
+            // username, client id, topic, access, allowed
+            (None, "c1", "public/news", Read, true),
+            (None, "c1", "public/news", Write, false),
+            (None, "c1", "devices/c1/temp", Read, true),
+            (None, "c1", "devices/c1/config", Read, false),
+            (Some("alice"), "c1", "alice/x", Write, true),
+            (Some("alice"), "c1", "alice/secret", Read, false),
+            (Some("alice"), "c1", "%c/literal", Write, true),
+            (Some("alice"), "c1", "c1/literal", Write, false),
+            (Some("alice"), "c1", "public/news", Read, false),
+            // a topic line of the user decides before the patterns
+            (Some("alice"), "c1", "alice/#", Subscribe, true),
+            (Some("bob"), "c2", "public/bob", Write, true),
+            (Some("bob"), "c2", "devices/c2/+", Subscribe, true),
+            (Some("carol"), "c3", "devices/c3/temp", Read, true),
+            (Some("carol"), "c3", "devices/c3/temp", Write, false),
+        ];
+        for (username, client_id, topic, access, allowed) in cases.iter() {
+            let res = file.check(*username, client_id, topic, *access);
+            assert_eq!(res.is_ok(), *allowed, "{:?} {} {} {:?}", username, client_id, topic, access);
+        }
+    }
+
+    #[test]
+    fn invalid_acl_file_lines_are_reported() {
+        let error = AclFile::parse("user alice\ntopic read a\nuser\n").unwrap_err();
+        assert_eq!(error.to_string(), "invalid line 3 in the acl file: \"user\"");
+        for line in ["topic", "pattern  ", "acl read a"] {
+            assert!(AclFile::parse(line).is_err(), "{}", line);
+        }
+        assert!(matches!(AclFile::load("/nonexistent/acl"), Err(AclFileError::Io(_))));
+        assert_eq!(AclFile::parse("# nothing\n").unwrap(), AclFile::default());
+    }
+}
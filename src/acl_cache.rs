@@ -0,0 +1,309 @@
+// Remembering ACL decisions for a while, so acl_check doesn't ask a slow backend about every
+// message:
+//
+//     let decision = self.cache.get_or_insert_with(&client.get_id(), msg.topic, level, || {
+//         self.backend.check(&username, msg.topic, level)
+//     });
+//
+// Entries are keyed on the client id, the topic (or the filter, for subscribe checks) and the
+// access level, and expire after the ttl. When the cache is full the entries closest to expiring
+// are dropped first. Errors aren't cached, the next check asks the backend again. A client id can
+// be reused by another user, so the entries of a client should be dropped when it connects or
+// disconnects, which the generated callbacks do when the cache is returned from
+// MosquittoPlugin::client_registry. After permissions change in the backend, invalidate_client
+// and invalidate_topic drop what is known about a client or a part of the topic tree.
+use crate::clients::ClientLifecycle;
+use crate::ratelimit::{Clock, MonotonicClock};
+use crate::topic::patterns_overlap;
+use crate::{AclCheckAccessLevel, AuthDecision, DisconnectReason, MosquittoClientContext};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Entry {
+    decision: AuthDecision,
+    expires: Instant,
+}
+
+// Where an entry is, in the order the entries expire
+#[derive(Debug)]
+struct Expiry {
+    client_id: String,
+    access: AclCheckAccessLevel,
+    topic: String,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub struct AclCache<C: Clock = MonotonicClock> {
+    ttl: Duration,
+    max_entries: usize,
+    // client id -> access level -> topic -> entry
+    entries: HashMap<String, HashMap<AclCheckAccessLevel, HashMap<String, Entry>>>,
+    len: usize,
+    // The ttl is the same for all entries, so they expire in the order they were inserted.
+    // Invalidated and replaced entries stay in here until they would have expired.
+    expiries: VecDeque<Expiry>,
+    clock: C,
+}
+
+impl AclCache<MonotonicClock> {
+    /// Keeps decisions for ttl, at most max_entries of them
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        AclCache::with_clock(ttl, max_entries, MonotonicClock)
+    }
+}
+
+impl<C: Clock> AclCache<C> {
+    pub fn with_clock(ttl: Duration, max_entries: usize, clock: C) -> Self {
+        AclCache {
+            ttl,
+            max_entries,
+            entries: HashMap::new(),
+            len: 0,
+            expiries: VecDeque::new(),
+            clock,
+        }
+    }
+
+    /// The decision cached for the check, None when there is none or it expired
+    pub fn get(
+        &self,
+        client_id: &str,
+        topic: &str,
+        access: AclCheckAccessLevel,
+    ) -> Option<AuthDecision> {
+        let entry = self.entries.get(client_id)?.get(&access)?.get(topic)?;
+        if entry.expires > self.clock.now() {
+            Some(entry.decision.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Remembers the decision of a check, AuthDecision::Error is ignored
+    pub fn insert(
+        &mut self,
+        client_id: &str,
+        topic: &str,
+        access: AclCheckAccessLevel,
+        decision: &AuthDecision,
+    ) {
+        if matches!(decision, AuthDecision::Error(_)) || self.max_entries == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        self.drop_expired(now);
+        let replaced = self.remove(client_id, access, topic);
+        if replaced.is_none() {
+            while self.len >= self.max_entries {
+                self.drop_oldest();
+            }
+        }
+        let expires = now + self.ttl;
+        self.entries
+            .entry(client_id.to_string())
+            .or_default()
+            .entry(access)
+            .or_default()
+            .insert(
+                topic.to_string(),
+                Entry {
+                    decision: decision.clone(),
+                    expires,
+                },
+            );
+        self.len += 1;
+        self.expiries.push_back(Expiry {
+            client_id: client_id.to_string(),
+            access,
+            topic: topic.to_string(),
+            expires,
+        });
+    }
+
+    /// The cached decision, or the one of check, which is then cached
+    pub fn get_or_insert_with(
+        &mut self,
+        client_id: &str,
+        topic: &str,
+        access: AclCheckAccessLevel,
+        check: impl FnOnce() -> AuthDecision,
+    ) -> AuthDecision {
+        if let Some(decision) = self.get(client_id, topic, access) {
+            return decision;
+        }
+        let decision = check();
+        self.insert(client_id, topic, access, &decision);
+        decision
+    }
+
+    /// Drops the decisions about a client
+    pub fn invalidate_client(&mut self, client_id: &str) {
+        if let Some(levels) = self.entries.remove(client_id) {
+            self.len -= levels.values().map(HashMap::len).sum::<usize>();
+        }
+    }
+
+    /// Drops the decisions about topics matched by the pattern, and about subscription filters
+    /// overlapping it
+    pub fn invalidate_topic(&mut self, pattern: &str) {
+        let mut removed = 0;
+        self.entries.retain(|_, levels| {
+            levels.retain(|_, topics| {
+                let before = topics.len();
+                topics.retain(|topic, _| !patterns_overlap(pattern, topic));
+                removed += before - topics.len();
+                !topics.is_empty()
+            });
+            !levels.is_empty()
+        });
+        self.len -= removed;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expiries.clear();
+        self.len = 0;
+    }
+
+    /// Number of decisions cached, including expired ones not dropped yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn drop_expired(&mut self, now: Instant) {
+        while self.expiries.front().is_some_and(|e| e.expires <= now) {
+            self.drop_oldest();
+        }
+    }
+
+    // Drops the entry that expires first, skipping what was invalidated or replaced since
+    fn drop_oldest(&mut self) {
+        while let Some(expiry) = self.expiries.pop_front() {
+            let current = self
+                .entries
+                .get(&expiry.client_id)
+                .and_then(|levels| levels.get(&expiry.access))
+                .and_then(|topics| topics.get(&expiry.topic))
+                .is_some_and(|entry| entry.expires == expiry.expires);
+            if current {
+                self.remove(&expiry.client_id, expiry.access, &expiry.topic);
+                return;
+            }
+        }
+    }
+
+    fn remove(
+        &mut self,
+        client_id: &str,
+        access: AclCheckAccessLevel,
+        topic: &str,
+    ) -> Option<Entry> {
+        let levels = self.entries.get_mut(client_id)?;
+        let topics = levels.get_mut(&access)?;
+        let entry = topics.remove(topic)?;
+        if topics.is_empty() {
+            levels.remove(&access);
+            if levels.is_empty() {
+                self.entries.remove(client_id);
+            }
+        }
+        self.len -= 1;
+        Some(entry)
+    }
+}
+
+/// Forgets the decisions about a client when it logs in and when it disconnects
+impl<C: Clock> ClientLifecycle for AclCache<C> {
+    fn authenticated(&mut self, client: &dyn MosquittoClientContext) {
+        self.invalidate_client(&client.get_id());
+    }
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, _reason: DisconnectReason) {
+        self.invalidate_client(&client.get_id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::ManualClock;
+    use crate::AclCheckAccessLevel::{Read, Subscribe, Write};
+    use crate::Error;
+    use std::cell::Cell;
+
+    #[test]
+    fn decisions_expire_after_the_ttl() {
+        let clock = ManualClock::new();
+        let mut cache = AclCache::with_clock(Duration::from_secs(10), 100, clock.clone());
+        let checks = Cell::new(0);
+        let check = || {
+            checks.set(checks.get() + 1);
+            AuthDecision::Allow
+        };
+        assert_eq!(
+            cache.get_or_insert_with("c1", "a/b", Write, check),
+            AuthDecision::Allow
+        );
+        cache.get_or_insert_with("c1", "a/b", Write, check);
+        assert_eq!(checks.get(), 1);
+        // other access levels and clients are checked on their own
+        assert_eq!(cache.get("c1", "a/b", Read), None);
+        assert_eq!(cache.get("c2", "a/b", Write), None);
+
+        cache.get_or_insert_with("c1", "a/c", Write, || AuthDecision::Error(Error::Timeout));
+        assert_eq!(cache.get("c1", "a/c", Write), None);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get("c1", "a/b", Write), None);
+        cache.get_or_insert_with("c1", "a/b", Write, check);
+        assert_eq!(checks.get(), 2);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn a_full_cache_drops_the_oldest() {
+        let clock = ManualClock::new();
+        let mut cache = AclCache::with_clock(Duration::from_secs(10), 2, clock.clone());
+        let deny = AuthDecision::deny("no");
+        cache.insert("c1", "t/1", Write, &deny);
+        clock.advance(Duration::from_secs(1));
+        cache.insert("c1", "t/2", Write, &deny);
+        // replacing doesn't make room
+        cache.insert("c1", "t/1", Write, &AuthDecision::Allow);
+        assert_eq!(cache.len(), 2);
+        cache.insert("c1", "t/3", Write, &deny);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("c1", "t/2", Write), None);
+        assert_eq!(cache.get("c1", "t/1", Write), Some(AuthDecision::Allow));
+        assert_eq!(cache.get("c1", "t/3", Write), Some(deny));
+    }
+
+    #[test]
+    fn invalidation_by_client_and_topic() {
+        let mut cache = AclCache::new(Duration::from_secs(60), 100);
+        let allow = AuthDecision::Allow;
+        cache.insert("c1", "sensors/1/temp", Write, &allow);
+        cache.insert("c1", "sensors/+/temp", Subscribe, &allow);
+        cache.insert("c1", "logs/1", Write, &allow);
+        cache.insert("c2", "sensors/2/temp", Read, &allow);
+        cache.insert("c2", "logs/2", Read, &allow);
+
+        cache.invalidate_topic("sensors/2/#");
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get("c1", "sensors/+/temp", Subscribe), None);
+        assert_eq!(cache.get("c2", "sensors/2/temp", Read), None);
+        assert!(cache.get("c1", "sensors/1/temp", Write).is_some());
+
+        cache.invalidate_client("c1");
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("c2", "logs/2", Read).is_some());
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+// ACL checks put together from smaller ones, instead of one acl_check deciding everything:
+//
+//     let acl = cached(
+//         first_match(vec![
+//             AclFile::load("/etc/mosquitto/acl")?.boxed(),
+//             from_fn(|client, level, topic| backend.check(client, level, topic)).boxed(),
+//         ]),
+//         AclCache::new(Duration::from_secs(60), 10000),
+//     );
+//
+// and in MosquittoPlugin::acl_check `self.acl.check(client, level, msg.topic)`.
+//
+// first_match asks the providers in order and the first one that doesn't defer decides.
+// require_all allows what every provider allows, allow_any what at least one of them allows.
+// A list of acl::AclPattern and acl::AclFile are providers as they are, they deny what they
+// don't allow, other checks become providers with from_fn. cached remembers the decisions of
+// a provider, and has to be returned from MosquittoPlugin::client_registry so they are dropped
+// when a client logs in again or disconnects, see acl_cache.
+use crate::acl::{self, AclFile, AclPattern};
+use crate::acl_cache::AclCache;
+use crate::clients::ClientLifecycle;
+use crate::{AclCheckAccessLevel, AuthDecision, DisconnectReason, MosquittoClientContext};
+
+/// A part of the ACL checks of a plugin
+pub trait AclProvider {
+    /// Whether the client may access the topic, AuthDecision::Defer when the provider doesn't
+    /// know
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision;
+
+    /// For the lists taken by the combinators
+    fn boxed(self) -> Box<dyn AclProvider>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(self)
+    }
+}
+
+impl<P: AclProvider + ?Sized> AclProvider for Box<P> {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        (**self).check(client, level, topic)
+    }
+}
+
+/// acl_file style patterns, checked like acl::check_client does
+impl AclProvider for Vec<AclPattern> {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        acl::check_client(self, client, level, topic).into()
+    }
+}
+
+impl AclProvider for AclFile {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        self.check_client(client, topic, level).into()
+    }
+}
+
+/// A provider calling f, see from_fn
+pub struct FromFn<F>(F);
+
+/// Turns a closure into a provider
+pub fn from_fn<F>(f: F) -> FromFn<F>
+where
+    F: FnMut(&dyn MosquittoClientContext, AclCheckAccessLevel, &str) -> AuthDecision,
+{
+    FromFn(f)
+}
+
+impl<F> AclProvider for FromFn<F>
+where
+    F: FnMut(&dyn MosquittoClientContext, AclCheckAccessLevel, &str) -> AuthDecision,
+{
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        (self.0)(client, level, topic)
+    }
+}
+
+/// See first_match
+pub struct FirstMatch(Vec<Box<dyn AclProvider>>);
+
+/// The decision of the first provider that doesn't defer, Defer when all of them do
+pub fn first_match(providers: Vec<Box<dyn AclProvider>>) -> FirstMatch {
+    FirstMatch(providers)
+}
+
+impl AclProvider for FirstMatch {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        for provider in &mut self.0 {
+            match provider.check(client, level, topic) {
+                AuthDecision::Defer => {}
+                decision => return decision,
+            }
+        }
+        AuthDecision::Defer
+    }
+}
+
+/// See require_all
+pub struct RequireAll(Vec<Box<dyn AclProvider>>);
+
+/// Allowed when every provider allows. The first denial or error is returned as soon as it is
+/// seen, without asking the providers after it, otherwise Defer when a provider defers. An empty
+/// list defers.
+pub fn require_all(providers: Vec<Box<dyn AclProvider>>) -> RequireAll {
+    RequireAll(providers)
+}
+
+impl AclProvider for RequireAll {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let mut deferred = self.0.is_empty();
+        for provider in &mut self.0 {
+            match provider.check(client, level, topic) {
+                AuthDecision::Allow => {}
+                AuthDecision::Defer => deferred = true,
+                decision => return decision,
+            }
+        }
+        if deferred {
+            AuthDecision::Defer
+        } else {
+            AuthDecision::Allow
+        }
+    }
+}
+
+/// See allow_any
+pub struct AllowAny(Vec<Box<dyn AclProvider>>);
+
+/// Allowed as soon as a provider allows. Otherwise the first error, as the provider that failed
+/// might have allowed, then the first denial, and Defer when all of them defer.
+pub fn allow_any(providers: Vec<Box<dyn AclProvider>>) -> AllowAny {
+    AllowAny(providers)
+}
+
+impl AclProvider for AllowAny {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let mut decision = AuthDecision::Defer;
+        for provider in &mut self.0 {
+            match provider.check(client, level, topic) {
+                AuthDecision::Allow => return AuthDecision::Allow,
+                AuthDecision::Defer => {}
+                error @ AuthDecision::Error(_) => {
+                    if !matches!(decision, AuthDecision::Error(_)) {
+                        decision = error;
+                    }
+                }
+                denied => {
+                    if decision == AuthDecision::Defer {
+                        decision = denied;
+                    }
+                }
+            }
+        }
+        decision
+    }
+}
+
+/// See cached
+pub struct Cached<P> {
+    provider: P,
+    cache: AclCache,
+}
+
+/// Keeps the decisions of the provider in the cache, errors aren't kept
+pub fn cached<P: AclProvider>(provider: P, cache: AclCache) -> Cached<P> {
+    Cached { provider, cache }
+}
+
+impl<P> Cached<P> {
+    /// For invalidating decisions after permissions changed in the provider
+    pub fn cache(&mut self) -> &mut AclCache {
+        &mut self.cache
+    }
+
+    pub fn provider(&mut self) -> &mut P {
+        &mut self.provider
+    }
+}
+
+impl<P: AclProvider> AclProvider for Cached<P> {
+    fn check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let provider = &mut self.provider;
+        self.cache
+            .get_or_insert_with(&client.get_id(), topic, level, || {
+                provider.check(client, level, topic)
+            })
+    }
+}
+
+impl<P> ClientLifecycle for Cached<P> {
+    fn authenticated(&mut self, client: &dyn MosquittoClientContext) {
+        self.cache.authenticated(client);
+    }
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, reason: DisconnectReason) {
+        self.cache.disconnected(client, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::{self, client};
+    use crate::AclCheckAccessLevel::{Read, Write};
+    use crate::Error;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    fn always(decision: AuthDecision) -> Box<dyn AclProvider> {
+        from_fn(move |_, _, _| decision.clone()).boxed()
+    }
+
+    fn patterns(list: &str) -> Box<dyn AclProvider> {
+        AclPattern::parse_list(list).unwrap().boxed()
+    }
+
+    #[test]
+    fn first_match_skips_deferring_providers() {
+        stub_ffi::reset();
+        let client = client();
+        let mut acl = first_match(vec![
+            from_fn(|_, _, topic| {
+                if topic.starts_with("remote/") {
+                    AuthDecision::Allow
+                } else {
+                    AuthDecision::Defer
+                }
+            })
+            .boxed(),
+            patterns("pattern read public/#"),
+        ]);
+        assert_eq!(acl.check(&client, Write, "remote/a"), AuthDecision::Allow);
+        assert_eq!(acl.check(&client, Read, "public/news"), AuthDecision::Allow);
+        assert_eq!(
+            acl.check(&client, Write, "public/news"),
+            AuthDecision::deny("")
+        );
+        assert_eq!(
+            first_match(vec![]).check(&client, Read, "a"),
+            AuthDecision::Defer
+        );
+    }
+
+    #[test]
+    fn require_all_needs_every_provider() {
+        stub_ffi::reset();
+        let client = client();
+        let mut acl = require_all(vec![
+            patterns("pattern readwrite devices/#"),
+            patterns("pattern deny devices/secret; pattern readwrite #"),
+        ]);
+        assert_eq!(acl.check(&client, Write, "devices/a"), AuthDecision::Allow);
+        assert_eq!(
+            acl.check(&client, Write, "devices/secret"),
+            AuthDecision::deny("")
+        );
+        let mut acl = require_all(vec![
+            always(AuthDecision::Allow),
+            always(AuthDecision::Defer),
+        ]);
+        assert_eq!(acl.check(&client, Read, "a"), AuthDecision::Defer);
+        assert_eq!(
+            require_all(vec![]).check(&client, Read, "a"),
+            AuthDecision::Defer
+        );
+    }
+
+    #[test]
+    fn allow_any_prefers_errors_over_denials() {
+        stub_ffi::reset();
+        let client = client();
+        let mut acl = allow_any(vec![
+            patterns("pattern read public/#"),
+            always(AuthDecision::Error(Error::Unknown)),
+        ]);
+        assert_eq!(acl.check(&client, Read, "public/a"), AuthDecision::Allow);
+        assert_eq!(
+            acl.check(&client, Read, "private/a"),
+            AuthDecision::Error(Error::Unknown)
+        );
+        let mut acl = allow_any(vec![
+            always(AuthDecision::Defer),
+            patterns("pattern read public/#"),
+        ]);
+        assert_eq!(
+            acl.check(&client, Read, "private/a"),
+            AuthDecision::deny("")
+        );
+    }
+
+    #[test]
+    fn cached_asks_the_provider_once() {
+        stub_ffi::reset();
+        let client = client();
+        let asked = Rc::new(Cell::new(0));
+        let counter = asked.clone();
+        let mut acl = cached(
+            from_fn(move |_, _, _| {
+                counter.set(counter.get() + 1);
+                AuthDecision::Allow
+            }),
+            AclCache::new(Duration::from_secs(60), 100),
+        );
+        for _ in 0..3 {
+            assert_eq!(acl.check(&client, Read, "a"), AuthDecision::Allow);
+        }
+        assert_eq!(asked.get(), 1);
+        acl.authenticated(&client);
+        acl.check(&client, Read, "a");
+        assert_eq!(asked.get(), 2);
+    }
+}
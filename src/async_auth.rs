@@ -0,0 +1,269 @@
+// Auth backends written as async code, enabled with the "async" feature:
+//
+//     struct Backend { db: PgPool }
+//
+//     impl AsyncAuth for Backend {
+//         async fn username_password(&self, client: ClientInfo, username: Option<String>, password: Option<String>) -> AuthDecision {
+//             ...
+//         }
+//     }
+//
+//     impl MosquittoPlugin for Plugin {
+//         fn username_password(&mut self, client: &dyn MosquittoClientContext, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+//             self.auth.username_password(client, username, password)
+//         }
+//     }
+//
+// with `auth: AsyncAuthRuntime<Backend>` created in init. The checks run on a tokio runtime owned
+// by the AsyncAuthRuntime. username_password answers the broker with AuthDelayed and hands the
+// decision over on a later tick through delayed_auth, so the broker keeps serving its other
+// clients while the backend is asked. mosquitto can't delay ACL checks: acl_check waits for the
+// answer on the broker thread, up to the acl timeout, so slow backends should be cached.
+use crate::delayed_auth::AuthCompletion;
+use crate::{
+    AclCheckAccessLevel, AuthDecision, Error, MosquittoClientContext,
+    MosquittoClientProtocolVersion, MosquittoMessage, MosquittoMessageOwned,
+};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
+
+/// What the async checks get to know about the client, owned so it can be moved into futures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub id: String,
+    pub username: Option<String>,
+    pub address: Option<IpAddr>,
+    pub protocol_version: MosquittoClientProtocolVersion,
+}
+
+impl ClientInfo {
+    pub fn for_client(client: &dyn MosquittoClientContext) -> ClientInfo {
+        ClientInfo {
+            id: client.get_id(),
+            username: client.get_username(),
+            address: client.get_address(),
+            protocol_version: client.get_protocol_version(),
+        }
+    }
+}
+
+/// The async counterparts of MosquittoPlugin::username_password and acl_check, they can be
+/// implemented with `async fn`. Default implementations defer to the next plugin.
+pub trait AsyncAuth: Send + Sync + 'static {
+    #[allow(unused)]
+    fn username_password(
+        &self,
+        client: ClientInfo,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> impl Future<Output = AuthDecision> + Send {
+        async { AuthDecision::Defer }
+    }
+
+    #[allow(unused)]
+    fn acl_check(
+        &self,
+        client: ClientInfo,
+        acl: AclCheckAccessLevel,
+        msg: MosquittoMessageOwned,
+    ) -> impl Future<Output = AuthDecision> + Send {
+        async { AuthDecision::Defer }
+    }
+}
+
+/// Runs an AsyncAuth on a tokio runtime, for the MosquittoPlugin methods to forward to.
+/// Dropping it shuts the runtime down, logins still being checked are refused.
+pub struct AsyncAuthRuntime<A> {
+    auth: Arc<A>,
+    runtime: Runtime,
+    acl_timeout: Duration,
+}
+
+impl<A: AsyncAuth> AsyncAuthRuntime<A> {
+    /// On a multi threaded runtime with a worker per core
+    pub fn new(auth: A) -> std::io::Result<AsyncAuthRuntime<A>> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("mosquitto-plugin")
+            .build()?;
+        Ok(AsyncAuthRuntime::with_runtime(auth, runtime))
+    }
+
+    pub fn with_runtime(auth: A, runtime: Runtime) -> AsyncAuthRuntime<A> {
+        AsyncAuthRuntime {
+            auth: Arc::new(auth),
+            runtime,
+            acl_timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// How long acl_check keeps the broker waiting, 1 second by default. A check that takes
+    /// longer is AuthDecision::Error(Error::Timeout), which the broker treats as a deny.
+    pub fn with_acl_timeout(mut self, timeout: Duration) -> Self {
+        self.acl_timeout = timeout;
+        self
+    }
+
+    pub fn auth(&self) -> &A {
+        &self.auth
+    }
+
+    /// For spawning other work, like refreshing caches, onto the same runtime
+    pub fn handle(&self) -> &Handle {
+        self.runtime.handle()
+    }
+
+    /// Starts AsyncAuth::username_password and returns AuthDecision::Error(Error::AuthDelayed),
+    /// the broker gets the decision on the tick after it is made. A check that panics refuses
+    /// the client.
+    pub fn username_password(
+        &self,
+        client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let completion = AuthCompletion::for_client(client);
+        let client = ClientInfo::for_client(client);
+        let username = username.map(str::to_string);
+        let password = password.map(str::to_string);
+        let auth = Arc::clone(&self.auth);
+        self.runtime.spawn(async move {
+            let decision = auth.username_password(client, username, password).await;
+            completion.decide(decision);
+        });
+        AuthDecision::Error(Error::AuthDelayed)
+    }
+
+    /// Runs AsyncAuth::acl_check to completion, blocking the broker thread up to the acl timeout
+    pub fn acl_check(
+        &self,
+        client: &dyn MosquittoClientContext,
+        acl: AclCheckAccessLevel,
+        msg: MosquittoMessage,
+    ) -> AuthDecision {
+        let check = self
+            .auth
+            .acl_check(ClientInfo::for_client(client), acl, msg.into());
+        self.runtime
+            .block_on(tokio::time::timeout(self.acl_timeout, check))
+            .unwrap_or(AuthDecision::Error(Error::Timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::properties::MessageProperties;
+    use crate::{MosquittoClientProtocol, Success, QOS};
+    use std::sync::mpsc;
+
+    struct Client;
+
+    impl MosquittoClientContext for Client {
+        fn get_address(&self) -> Option<IpAddr> {
+            None
+        }
+        fn is_clean_session(&self) -> bool {
+            true
+        }
+        fn get_id(&self) -> String {
+            "sensor-1".to_string()
+        }
+        fn get_keepalive(&self) -> i32 {
+            60
+        }
+        fn get_certificate(&self) -> Option<crate::certificate::ClientCertificate> {
+            None
+        }
+        fn get_protocol(&self) -> MosquittoClientProtocol {
+            MosquittoClientProtocol::Mqtt
+        }
+        fn get_protocol_version(&self) -> MosquittoClientProtocolVersion {
+            MosquittoClientProtocolVersion::Mqtt5
+        }
+        fn get_sub_count(&self) -> i32 {
+            0
+        }
+        fn get_username(&self) -> Option<String> {
+            Some("sensor".to_string())
+        }
+        fn set_username(&self, _username: &str) -> Result<Success, Error> {
+            Ok(Success)
+        }
+    }
+
+    struct Backend {
+        logins: mpsc::Sender<(ClientInfo, Option<String>)>,
+    }
+
+    impl AsyncAuth for Backend {
+        async fn username_password(
+            &self,
+            client: ClientInfo,
+            username: Option<String>,
+            _password: Option<String>,
+        ) -> AuthDecision {
+            self.logins.send((client, username)).unwrap();
+            AuthDecision::Allow
+        }
+
+        async fn acl_check(
+            &self,
+            _client: ClientInfo,
+            _acl: AclCheckAccessLevel,
+            msg: MosquittoMessageOwned,
+        ) -> AuthDecision {
+            if msg.topic == "slow" {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            if msg.topic.starts_with("sensors/") {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::deny("only sensors/")
+            }
+        }
+    }
+
+    fn message(topic: &str) -> MosquittoMessage<'_> {
+        MosquittoMessage {
+            topic,
+            payload: b"",
+            qos: QOS::AtMostOnce,
+            retain: false,
+            properties: MessageProperties::none(),
+        }
+    }
+
+    #[test]
+    fn logins_are_delayed_and_acl_checks_wait() {
+        let (logins, checked) = mpsc::channel();
+        let runtime = AsyncAuthRuntime::new(Backend { logins })
+            .unwrap()
+            .with_acl_timeout(Duration::from_millis(50));
+
+        assert_eq!(
+            runtime.username_password(&Client, Some("sensor"), Some("secret")),
+            AuthDecision::Error(Error::AuthDelayed)
+        );
+        let (client, username) = checked.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(client.id, "sensor-1");
+        assert_eq!(username.as_deref(), Some("sensor"));
+
+        let write = AclCheckAccessLevel::Write;
+        assert_eq!(
+            runtime.acl_check(&Client, write, message("sensors/1")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            runtime.acl_check(&Client, write, message("other")),
+            AuthDecision::deny("only sensors/")
+        );
+        assert_eq!(
+            runtime.acl_check(&Client, write, message("slow")),
+            AuthDecision::Error(Error::Timeout)
+        );
+    }
+}
@@ -0,0 +1,451 @@
+// An audit trail of the authentication and access decisions of the plugin, one JSON object per
+// line:
+//
+//     {"time":"2026-10-15T08:12:03.120Z","event":"acl_check","client_id":"sensor-1","username":"alice","address":"10.0.0.7","topic":"sensors/1","access":"Write","decision":"deny","reason":"not your topic"}
+//
+// Returned from MosquittoPlugin::audit_log, the generated username_password and acl_check
+// callbacks record every decision the plugin makes, events the broker refused before they reached
+// the plugin aren't recorded. Fields that don't apply, like the topic of a login, are left out.
+// Records go to a file, which is rotated once it would grow over a size, or to the local syslog
+// daemon with the authpriv facility.
+//
+// Options, for AuditLog::from_opts: `audit_file` with `audit_max_bytes` (default 10 MiB) and
+// `audit_keep`, the number of rotated files kept as audit.log.1 etc. (default 5), or
+// `audit_syslog`, the socket of the daemon or `true` for /dev/log.
+use crate::opts::OptError;
+use crate::{AclCheckAccessLevel, AuthDecision, Error, MosquittoClientContext, MosquittoOpt};
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 5;
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+// authpriv (10) with severity info (6)
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// One decision
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    /// The callback that decided, e.g. `username_password`
+    pub event: &'static str,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub address: Option<IpAddr>,
+    pub topic: Option<String>,
+    pub access: Option<AclCheckAccessLevel>,
+    /// allow, deny, defer, delayed or error
+    pub decision: &'static str,
+    /// Why the client was denied, or what failed
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    /// A decision about client made now
+    pub fn new(
+        event: &'static str,
+        client: &dyn MosquittoClientContext,
+        decision: &AuthDecision,
+    ) -> AuditRecord {
+        let (decision, reason) = match decision {
+            AuthDecision::Allow => ("allow", None),
+            AuthDecision::Deny { reason } if reason.is_empty() => ("deny", None),
+            AuthDecision::Deny { reason } => ("deny", Some(reason.clone())),
+            AuthDecision::Defer => ("defer", None),
+            AuthDecision::Error(Error::AuthDelayed) => ("delayed", None),
+            AuthDecision::Error(e) => ("error", Some(e.to_string())),
+        };
+        AuditRecord {
+            time: SystemTime::now(),
+            event,
+            client_id: client.get_id(),
+            username: client.get_username(),
+            address: client.get_address(),
+            topic: None,
+            access: None,
+            decision,
+            reason,
+        }
+    }
+
+    /// For access checks, the topic and what the client wants to do with it
+    pub fn with_access(mut self, topic: &str, access: AclCheckAccessLevel) -> Self {
+        self.topic = Some(topic.to_string());
+        self.access = Some(access);
+        self
+    }
+
+    /// The record as a line of JSON, without the newline
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = write!(json, "{{\"time\":\"{}\"", Rfc3339(self.time));
+        let mut field = |name: &str, value: &str| {
+            let _ = write!(json, ",\"{}\":\"{}\"", name, JsonEscaped(value));
+        };
+        field("event", self.event);
+        field("client_id", &self.client_id);
+        if let Some(username) = &self.username {
+            field("username", username);
+        }
+        if let Some(address) = self.address {
+            field("address", &address.to_string());
+        }
+        if let Some(topic) = &self.topic {
+            field("topic", topic);
+        }
+        if let Some(access) = self.access {
+            field("access", &access.to_string());
+        }
+        field("decision", self.decision);
+        if let Some(reason) = &self.reason {
+            field("reason", reason);
+        }
+        json.push('}');
+        json
+    }
+}
+
+enum Sink {
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog {
+        socket: std::os::unix::net::UnixDatagram,
+        path: PathBuf,
+        tag: String,
+    },
+    Writer(Box<dyn Write + Send>),
+}
+
+pub struct AuditLog {
+    sink: Sink,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sink = match &self.sink {
+            Sink::File(file) => format!("file {}", file.path.display()),
+            #[cfg(unix)]
+            Sink::Syslog { path, .. } => format!("syslog {}", path.display()),
+            Sink::Writer(_) => "writer".to_string(),
+        };
+        f.debug_struct("AuditLog").field("sink", &sink).finish()
+    }
+}
+
+impl AuditLog {
+    /// Appends to the file at path. Before a record would make it larger than max_bytes it is
+    /// renamed to path.1, path.1 to path.2 and so on, keeping keep of them.
+    pub fn to_file(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            sink: Sink::File(RotatingFile::open(path.into(), max_bytes, keep)?),
+        })
+    }
+
+    /// Sends the records to the syslog daemon listening on /dev/log, tagged with tag, usually the
+    /// name of the plugin
+    #[cfg(unix)]
+    pub fn to_syslog(tag: &str) -> io::Result<AuditLog> {
+        AuditLog::to_syslog_at(SYSLOG_SOCKET, tag)
+    }
+
+    /// Like to_syslog, with the daemon listening on socket
+    #[cfg(unix)]
+    pub fn to_syslog_at(socket: impl AsRef<Path>, tag: &str) -> io::Result<AuditLog> {
+        let path = socket.as_ref().to_path_buf();
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(&path)?;
+        Ok(AuditLog {
+            sink: Sink::Syslog {
+                socket,
+                path,
+                tag: tag.to_string(),
+            },
+        })
+    }
+
+    /// Writes the lines to writer, for sinks of the plugin's own
+    pub fn to_writer(writer: impl Write + Send + 'static) -> AuditLog {
+        AuditLog {
+            sink: Sink::Writer(Box::new(writer)),
+        }
+    }
+
+    /// Reads the `audit_file` or `audit_syslog` options, None when neither is given. tag is used
+    /// for syslog.
+    pub fn from_opts(opts: &MosquittoOpt, tag: &str) -> io::Result<Option<AuditLog>> {
+        if let Some(path) = opts.get("audit_file") {
+            let max_bytes = parse(opts, "audit_max_bytes", DEFAULT_MAX_BYTES)?;
+            let keep = parse(opts, "audit_keep", DEFAULT_KEEP)?;
+            return AuditLog::to_file(path, max_bytes, keep).map(Some);
+        }
+        match opts.get("audit_syslog").copied() {
+            None | Some("false") => Ok(None),
+            #[cfg(unix)]
+            Some("true") => AuditLog::to_syslog(tag).map(Some),
+            #[cfg(unix)]
+            Some(socket) => AuditLog::to_syslog_at(socket, tag).map(Some),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "audit_syslog: syslog is only supported on unix",
+            )),
+        }
+    }
+
+    /// Writes record, called by the generated callbacks for their decisions
+    pub fn record(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let line = record.to_json();
+        match &mut self.sink {
+            Sink::File(file) => file.write_line(&line),
+            #[cfg(unix)]
+            Sink::Syslog { socket, tag, .. } => socket
+                .send(format!("<{}>{}: {}", SYSLOG_PRIORITY, tag, line).as_bytes())
+                .map(|_| ()),
+            Sink::Writer(writer) => writeln!(writer, "{}", line),
+        }
+    }
+}
+
+fn parse<V: std::str::FromStr>(opts: &MosquittoOpt, key: &str, default: V) -> io::Result<V> {
+    match opts.get(key) {
+        Some(value) => value.parse().map_err(|_| {
+            let error = OptError::Invalid {
+                key: key.to_string(),
+                value: value.to_string(),
+                expected: "a number",
+            };
+            io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+        }),
+        None => Ok(default),
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        // A record larger than max_bytes still gets a file of its own
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = self.rotated(i);
+                if from.exists() {
+                    fs::rename(from, self.rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        *self = RotatingFile::open(self.path.clone(), self.max_bytes, self.keep)?;
+        Ok(())
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        path.into()
+    }
+}
+
+struct JsonEscaped<'a>(&'a str);
+
+impl fmt::Display for JsonEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// UTC with milliseconds, e.g. 2026-10-15T08:12:03.120Z
+struct Rfc3339(SystemTime);
+
+impl fmt::Display for Rfc3339 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs_of_day = secs % 86400;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+// The date of a day counted from 1970-01-01, from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    // A decision about the stub client, 127.0.0.1 logged in as alice, at a fixed time
+    fn decided(decision: &AuthDecision) -> AuditRecord {
+        let client = crate::stub_ffi::client();
+        let mut record = AuditRecord::new("acl_check", &client, decision);
+        record.time = UNIX_EPOCH + Duration::from_millis(1_792_051_923_120);
+        record.username = Some("alice".to_string());
+        record
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mosquitto-plugin-audit-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn records_are_json_lines() {
+        let deny = AuthDecision::deny("not \"your\" topic\n");
+        let record = decided(&deny).with_access("sensors/1", AclCheckAccessLevel::Write);
+        assert_eq!(
+            record.to_json(),
+            "{\"time\":\"2026-10-15T08:12:03.120Z\",\"event\":\"acl_check\",\"client_id\":\"stub-client\",\
+             \"username\":\"alice\",\"address\":\"127.0.0.1\",\"topic\":\"sensors/1\",\"access\":\"Write\",\
+             \"decision\":\"deny\",\"reason\":\"not \\\"your\\\" topic\\n\"}"
+        );
+
+        let mut login = decided(&AuthDecision::Allow);
+        login.event = "username_password";
+        login.username = None;
+        assert_eq!(
+            login.to_json(),
+            "{\"time\":\"2026-10-15T08:12:03.120Z\",\"event\":\"username_password\",\
+             \"client_id\":\"stub-client\",\"address\":\"127.0.0.1\",\"decision\":\"allow\"}"
+        );
+        assert_eq!(
+            decided(&AuthDecision::Error(Error::Timeout)).decision,
+            "error"
+        );
+        assert_eq!(Rfc3339(UNIX_EPOCH).to_string(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn files_are_rotated() {
+        let dir = scratch_dir("rotate");
+        let path = dir.join("audit.log");
+        let line = decided(&AuthDecision::Allow).to_json();
+        let mut opts = HashMap::new();
+        let path_opt = path.to_str().unwrap().to_string();
+        let max_bytes = (2 * (line.len() + 1)).to_string();
+        opts.insert("audit_file", path_opt.as_str());
+        opts.insert("audit_max_bytes", max_bytes.as_str());
+        opts.insert("audit_keep", "2");
+        let mut log = AuditLog::from_opts(&opts, "test").unwrap().unwrap();
+        for _ in 0..7 {
+            log.record(&decided(&AuthDecision::Allow)).unwrap();
+        }
+        let lines = |path: &Path| {
+            fs::read_to_string(path)
+                .map(|s| s.lines().count())
+                .unwrap_or(0)
+        };
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&dir.join("audit.log.1")), 2);
+        assert_eq!(lines(&dir.join("audit.log.2")), 2);
+        assert!(!dir.join("audit.log.3").exists());
+
+        // appends to what is there after a restart
+        drop(log);
+        let mut log = AuditLog::to_file(&path, 1024, 2).unwrap();
+        log.record(&decided(&AuthDecision::Defer)).unwrap();
+        assert_eq!(lines(&path), 2);
+
+        opts.insert("audit_keep", "some");
+        assert!(AuditLog::from_opts(&opts, "test").is_err());
+        assert!(AuditLog::from_opts(&HashMap::new(), "test")
+            .unwrap()
+            .is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn records_are_sent_to_syslog() {
+        let dir = scratch_dir("syslog");
+        let socket = dir.join("log");
+        let daemon = std::os::unix::net::UnixDatagram::bind(&socket).unwrap();
+        let mut opts = HashMap::new();
+        opts.insert("audit_syslog", socket.to_str().unwrap());
+        let mut log = AuditLog::from_opts(&opts, "acl-plugin").unwrap().unwrap();
+        let record = decided(&AuthDecision::Allow);
+        log.record(&record).unwrap();
+        let mut buf = [0; 1024];
+        let len = daemon.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            format!("<86>acl-plugin: {}", record.to_json())
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,811 @@
+// The auth plugin interface of mosquitto 1.6 (version 4), next to the plugin interface of 2.0, so
+// one build of a plugin loads into both. Enabled with the "auth-plugin-v4" feature, which makes
+// create_dynamic_library! and #[mosquitto_plugin] also export
+//
+//     mosquitto_auth_plugin_version, mosquitto_auth_plugin_init, mosquitto_auth_plugin_cleanup,
+//     mosquitto_auth_security_init, mosquitto_auth_security_cleanup, mosquitto_auth_acl_check,
+//     mosquitto_auth_unpwd_check, mosquitto_auth_psk_key_get, mosquitto_auth_start,
+//     mosquitto_auth_continue
+//
+// mosquitto 2.0 and later see mosquitto_plugin_version and use the new interface, 1.6 only knows
+// the old one. Its calls are turned into the events of the new interface and go through the same
+// checks and trampolines, so the MosquittoPlugin doesn't know which broker it runs in, except by
+// PluginContext::broker_version. The old interface has username_password, the acl checks,
+// on_psk, the extended auth methods and on_reload (when security_init is called with reload),
+// nothing else is ever called: no on_message, on_tick or on_disconnect, and no control topics.
+//
+// mosquitto 1.6 loads plugins with every symbol resolved, and doesn't have the functions 2.0 added
+// for plugins. With the feature those are looked up in the broker when first called instead of
+// being linked, see broker_2_0. Under 1.6 publishing, kicking clients, registering callbacks,
+// setting the username and MQTT v5 properties fail with Error::NotSupported. The broker version is
+// BrokerVersion::AUTH_PLUGIN_V4, 1.6.0, as only 1.6 calls mosquitto_auth_plugin_init.
+use crate::dynlib::{self, PluginInfo};
+use crate::*;
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_int, c_void};
+#[cfg(any(test, not(feature = "testing")))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What mosquitto_auth_plugin_version returns
+pub const AUTH_PLUGIN_VERSION: c_int = 4;
+
+/// Called from mosquitto_auth_plugin_init, creates the plugin like plugin_init_with without
+/// registering any callbacks, the broker calls the functions of the old interface instead.
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_auth_plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_init<T: MosquittoPlugin>(
+    user_data: *mut *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+    info: &PluginInfo,
+) -> c_int {
+    let registered = PluginInfo {
+        callbacks: dynlib::Callbacks::NONE,
+        ..*info
+    };
+    let rc = unsafe {
+        dynlib::plugin_init_as::<T>(
+            std::ptr::null_mut(),
+            user_data,
+            opts,
+            opt_count,
+            &registered,
+            BrokerVersion::AUTH_PLUGIN_V4,
+        )
+    };
+    // Nothing is registered, but the calls of the old interface are dispatched like the events
+    // would be, by what the plugin implements
+    if rc == 0 {
+        let internal = unsafe { &mut *(*user_data as *mut dynlib::InternalUserData<T>) };
+        internal.callbacks = info.callbacks;
+    }
+    rc
+}
+
+/// Called from mosquitto_auth_plugin_cleanup
+///
+/// # Safety
+/// user_data has to be the pointer set by plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_cleanup<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+) -> c_int {
+    unsafe { dynlib::plugin_cleanup::<T>(user_data, opts, opt_count) }
+}
+
+/// Called from mosquitto_auth_security_init, which the broker calls at start and again with
+/// reload when its configuration is reloaded. Only the reload reaches the plugin, as on_reload.
+///
+/// # Safety
+/// user_data has to be the pointer set by plugin_init, and opts has to point to opt_count options.
+#[doc(hidden)]
+pub unsafe fn security_init<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+    reload: bool,
+    info: &PluginInfo,
+) -> c_int {
+    if !reload || user_data.is_null() || !info.callbacks.contains(dynlib::Callbacks::RELOAD) {
+        return Success.into();
+    }
+    let mut event: mosquitto_evt_reload = unsafe { std::mem::zeroed() };
+    event.options = opts;
+    event.option_count = opt_count;
+    dynlib::on_reload_trampoline::<T>(
+        MosquittoPluginEvent::MosqEvtReload.into(),
+        &mut event as *mut _ as *mut c_void,
+        user_data,
+    )
+}
+
+/// Called from mosquitto_auth_acl_check, access is MOSQ_ACL_READ, MOSQ_ACL_WRITE or
+/// MOSQ_ACL_SUBSCRIBE. A subscription comes with the filter as the topic.
+///
+/// # Safety
+/// user_data has to be the pointer set by plugin_init, msg null or the message of the check.
+#[doc(hidden)]
+pub unsafe fn acl_check<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    access: c_int,
+    client: *mut mosquitto,
+    msg: *const mosquitto_acl_msg,
+    info: &PluginInfo,
+) -> c_int {
+    if user_data.is_null() || !info.callbacks.contains(dynlib::Callbacks::ACL_CHECK) {
+        return Error::PluginDefer.into();
+    }
+    let mut event: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+    event.client = client;
+    event.access = access;
+    if let Some(msg) = unsafe { msg.as_ref() } {
+        event.topic = msg.topic;
+        event.payload = msg.payload;
+        // A negative length can't be trusted any more than a null payload
+        event.payloadlen = u32::try_from(msg.payloadlen).unwrap_or(u32::MAX);
+        event.qos = msg.qos as u8;
+        event.retain = msg.retain;
+    }
+    dynlib::on_acl_check_trampoline::<T>(
+        MosquittoPluginEvent::MosqEvtAclCheck.into(),
+        &mut event as *mut _ as *mut c_void,
+        user_data,
+    )
+}
+
+/// Called from mosquitto_auth_unpwd_check
+///
+/// # Safety
+/// user_data has to be the pointer set by plugin_init, username and password null or C strings.
+#[doc(hidden)]
+pub unsafe fn unpwd_check<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    client: *mut mosquitto,
+    username: *const c_char,
+    password: *const c_char,
+    info: &PluginInfo,
+) -> c_int {
+    if user_data.is_null() || !info.callbacks.contains(dynlib::Callbacks::BASIC_AUTH) {
+        return Error::PluginDefer.into();
+    }
+    let mut event: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+    event.client = client;
+    event.username = username as *mut c_char;
+    event.password = password as *mut c_char;
+    dynlib::on_basic_auth_trampoline::<T>(
+        MosquittoPluginEvent::MosqEvtBasicAuth.into(),
+        &mut event as *mut _ as *mut c_void,
+        user_data,
+    )
+}
+
+/// Called from mosquitto_auth_psk_key_get
+///
+/// # Safety
+/// user_data has to be the pointer set by plugin_init, key has to hold max_key_len bytes.
+#[doc(hidden)]
+pub unsafe fn psk_key_get<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    client: *mut mosquitto,
+    hint: *const c_char,
+    identity: *const c_char,
+    key: *mut c_char,
+    max_key_len: c_int,
+    info: &PluginInfo,
+) -> c_int {
+    if user_data.is_null() || !info.callbacks.contains(dynlib::Callbacks::PSK_KEY) {
+        return Error::PluginDefer.into();
+    }
+    let mut event: mosquitto_evt_psk_key = unsafe { std::mem::zeroed() };
+    event.client = client;
+    event.hint = hint;
+    event.identity = identity;
+    event.key = key;
+    event.max_key_len = max_key_len;
+    dynlib::on_psk_key_trampoline::<T>(
+        MosquittoPluginEvent::MosqEvtPskKey.into(),
+        &mut event as *mut _ as *mut c_void,
+        user_data,
+    )
+}
+
+/// Called from mosquitto_auth_start, a reauthentication is started like the first one.
+///
+/// # Safety
+/// user_data has to be the pointer set by plugin_init, data_in has to hold data_in_len bytes and
+/// data_out and data_out_len have to be writable.
+#[doc(hidden)]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn auth_start<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    client: *mut mosquitto,
+    method: *const c_char,
+    _reauth: bool,
+    data_in: *const c_void,
+    data_in_len: u16,
+    data_out: *mut *mut c_void,
+    data_out_len: *mut u16,
+    info: &PluginInfo,
+) -> c_int {
+    unsafe {
+        ext_auth::<T>(
+            MosquittoPluginEvent::MosqEvtExtAuthStart,
+            user_data,
+            client,
+            method,
+            data_in,
+            data_in_len,
+            data_out,
+            data_out_len,
+            info,
+        )
+    }
+}
+
+/// Called from mosquitto_auth_continue
+///
+/// # Safety
+/// Same as auth_start
+#[doc(hidden)]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn auth_continue<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    client: *mut mosquitto,
+    method: *const c_char,
+    data_in: *const c_void,
+    data_in_len: u16,
+    data_out: *mut *mut c_void,
+    data_out_len: *mut u16,
+    info: &PluginInfo,
+) -> c_int {
+    unsafe {
+        ext_auth::<T>(
+            MosquittoPluginEvent::MosqEvtExtAuthContinue,
+            user_data,
+            client,
+            method,
+            data_in,
+            data_in_len,
+            data_out,
+            data_out_len,
+            info,
+        )
+    }
+}
+
+// The data the plugin answers with is handed over through data_out, the broker frees it
+#[allow(clippy::too_many_arguments)]
+unsafe fn ext_auth<T: MosquittoPlugin>(
+    step: MosquittoPluginEvent,
+    user_data: *mut c_void,
+    client: *mut mosquitto,
+    method: *const c_char,
+    data_in: *const c_void,
+    data_in_len: u16,
+    data_out: *mut *mut c_void,
+    data_out_len: *mut u16,
+    info: &PluginInfo,
+) -> c_int {
+    if user_data.is_null() || !info.callbacks.contains(dynlib::Callbacks::EXT_AUTH) {
+        return Error::PluginDefer.into();
+    }
+    let mut event: mosquitto_evt_extended_auth = unsafe { std::mem::zeroed() };
+    event.client = client;
+    event.auth_method = method;
+    event.data_in = data_in;
+    event.data_in_len = data_in_len;
+    let event_data = &mut event as *mut _ as *mut c_void;
+    let rc = match step {
+        MosquittoPluginEvent::MosqEvtExtAuthStart => {
+            dynlib::on_ext_auth_start_trampoline::<T>(step.into(), event_data, user_data)
+        }
+        _ => dynlib::on_ext_auth_continue_trampoline::<T>(step.into(), event_data, user_data),
+    };
+    if data_out.is_null() || data_out_len.is_null() {
+        if !event.data_out.is_null() {
+            unsafe { mosquitto_free(event.data_out) };
+        }
+        return rc;
+    }
+    unsafe {
+        *data_out = event.data_out;
+        *data_out_len = event.data_out_len;
+    }
+    rc
+}
+
+// The address of a function of the broker, None when it doesn't have it. Looked up once, the
+// cache is 0 before that and 1 when the function is missing.
+#[cfg(any(test, not(feature = "testing")))]
+fn look_up(cache: &AtomicUsize, name: &'static [u8]) -> Option<usize> {
+    match cache.load(Ordering::Relaxed) {
+        0 => {
+            let address =
+                unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr() as *const c_char) } as usize;
+            let address = if address == 0 { 1 } else { address };
+            cache.store(address, Ordering::Relaxed);
+            Some(address).filter(|a| *a != 1)
+        }
+        1 => None,
+        address => Some(address),
+    }
+}
+
+// A function of the same name and signature as the binding, calling the one of the broker when
+// it has it, and answering $missing otherwise
+#[cfg(not(any(test, feature = "testing")))]
+macro_rules! looked_up {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty = $missing:expr;)*) => {
+        $(
+            pub unsafe fn $name($($arg: $ty),*) -> $ret {
+                static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+                match look_up(&ADDRESS, concat!(stringify!($name), "\0").as_bytes()) {
+                    Some(address) => {
+                        let f: unsafe extern "C" fn($($ty),*) -> $ret = unsafe { std::mem::transmute(address) };
+                        unsafe { f($($arg),*) }
+                    }
+                    None => $missing,
+                }
+            }
+        )*
+    };
+}
+
+/// The functions added for plugins in mosquitto 2.0, which mosquitto_dev exports in place of the
+/// bindings with the feature. Tests keep the bindings, they link the stand-ins of the broker.
+#[cfg(not(any(test, feature = "testing")))]
+#[doc(hidden)]
+#[allow(clippy::missing_safety_doc)]
+pub mod broker_2_0 {
+    use super::look_up;
+    use crate::mosquitto_dev::bindings::{
+        mosq_err_t_MOSQ_ERR_NOT_SUPPORTED, mosquitto, mosquitto_plugin_id_t, mosquitto_property,
+        MOSQ_FUNC_generic_callback,
+    };
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::sync::atomic::AtomicUsize;
+
+    const NOT_SUPPORTED: c_int = mosq_err_t_MOSQ_ERR_NOT_SUPPORTED as c_int;
+
+    looked_up! {
+        fn mosquitto_broker_publish(clientid: *const c_char, topic: *const c_char, payloadlen: c_int, payload: *mut c_void, qos: c_int, retain: bool, properties: *mut mosquitto_property) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_callback_register(identifier: *mut mosquitto_plugin_id_t, event: c_int, cb_func: MOSQ_FUNC_generic_callback, event_data: *const c_void, userdata: *mut c_void) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_callback_unregister(identifier: *mut mosquitto_plugin_id_t, event: c_int, cb_func: MOSQ_FUNC_generic_callback, event_data: *const c_void) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_kick_client_by_clientid(clientid: *const c_char, with_will: bool) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_kick_client_by_username(username: *const c_char, with_will: bool) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_set_username(client: *mut mosquitto, username: *const c_char) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_client_protocol_version(client: *const mosquitto) -> c_int = 0;
+        fn mosquitto_validate_utf8(str_: *const c_char, len: c_int) -> c_int = unsafe { validate_utf8(str_, len) };
+        fn mosquitto_property_add_int32(proplist: *mut *mut mosquitto_property, identifier: c_int, value: u32) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_property_add_string(proplist: *mut *mut mosquitto_property, identifier: c_int, value: *const c_char) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_property_add_binary(proplist: *mut *mut mosquitto_property, identifier: c_int, value: *const c_void, len: u16) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_property_add_string_pair(proplist: *mut *mut mosquitto_property, identifier: c_int, name: *const c_char, value: *const c_char) -> c_int = NOT_SUPPORTED;
+        fn mosquitto_property_read_int32(proplist: *const mosquitto_property, identifier: c_int, value: *mut u32, skip_first: bool) -> *const mosquitto_property = std::ptr::null();
+        fn mosquitto_property_read_string(proplist: *const mosquitto_property, identifier: c_int, value: *mut *mut c_char, skip_first: bool) -> *const mosquitto_property = std::ptr::null();
+        fn mosquitto_property_read_string_pair(proplist: *const mosquitto_property, identifier: c_int, name: *mut *mut c_char, value: *mut *mut c_char, skip_first: bool) -> *const mosquitto_property = std::ptr::null();
+        fn mosquitto_property_read_binary(proplist: *const mosquitto_property, identifier: c_int, value: *mut *mut c_void, len: *mut u16, skip_first: bool) -> *const mosquitto_property = std::ptr::null();
+        fn mosquitto_property_free_all(properties: *mut *mut mosquitto_property) -> () = ();
+    }
+
+    // Valid UTF-8 without the control characters MQTT forbids, MOSQ_ERR_MALFORMED_UTF8 otherwise
+    unsafe fn validate_utf8(str_: *const c_char, len: c_int) -> c_int {
+        if str_.is_null() || len < 0 {
+            return crate::Error::Inval as c_int;
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(str_ as *const u8, len as usize) };
+        match std::str::from_utf8(bytes) {
+            Ok(s)
+                if !s.chars().any(|c| {
+                    c == '\0'
+                        || ('\u{1}'..='\u{1f}').contains(&c)
+                        || ('\u{7f}'..='\u{9f}').contains(&c)
+                }) =>
+            {
+                0
+            }
+            _ => crate::Error::MalformedUtf8 as c_int,
+        }
+    }
+}
+
+// Exports the functions of the old interface for $t, see the top of the module. Invoked by
+// create_dynamic_library! and #[mosquitto_plugin], with the PluginInfo of the latter.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __auth_plugin_v4_symbols {
+    ($t:ty) => {
+        $crate::__auth_plugin_v4_symbols!($t, $crate::dynlib::DEFAULT_PLUGIN_INFO);
+    };
+    ($t:ty, $info:expr) => {
+        const __MOSQUITTO_AUTH_PLUGIN_V4_INFO: $crate::dynlib::PluginInfo = $info;
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_plugin_version() -> std::os::raw::c_int {
+            $crate::auth_plugin_v4::AUTH_PLUGIN_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_plugin_init(
+            user_data: *mut *mut std::os::raw::c_void,
+            opts: *mut $crate::mosquitto_dev::mosquitto_opt,
+            opt_count: std::os::raw::c_int,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::plugin_init::<$t>(
+                    user_data,
+                    opts,
+                    opt_count,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_plugin_cleanup(
+            user_data: *mut std::os::raw::c_void,
+            opts: *mut $crate::mosquitto_dev::mosquitto_opt,
+            opt_count: std::os::raw::c_int,
+        ) -> std::os::raw::c_int {
+            unsafe { $crate::auth_plugin_v4::plugin_cleanup::<$t>(user_data, opts, opt_count) }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_security_init(
+            user_data: *mut std::os::raw::c_void,
+            opts: *mut $crate::mosquitto_dev::mosquitto_opt,
+            opt_count: std::os::raw::c_int,
+            reload: bool,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::security_init::<$t>(
+                    user_data,
+                    opts,
+                    opt_count,
+                    reload,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_security_cleanup(
+            _user_data: *mut std::os::raw::c_void,
+            _opts: *mut $crate::mosquitto_dev::mosquitto_opt,
+            _opt_count: std::os::raw::c_int,
+            _reload: bool,
+        ) -> std::os::raw::c_int {
+            0
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_acl_check(
+            user_data: *mut std::os::raw::c_void,
+            access: std::os::raw::c_int,
+            client: *mut $crate::mosquitto_dev::mosquitto,
+            msg: *const $crate::mosquitto_dev::mosquitto_acl_msg,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::acl_check::<$t>(
+                    user_data,
+                    access,
+                    client,
+                    msg,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_unpwd_check(
+            user_data: *mut std::os::raw::c_void,
+            client: *mut $crate::mosquitto_dev::mosquitto,
+            username: *const std::os::raw::c_char,
+            password: *const std::os::raw::c_char,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::unpwd_check::<$t>(
+                    user_data,
+                    client,
+                    username,
+                    password,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_psk_key_get(
+            user_data: *mut std::os::raw::c_void,
+            client: *mut $crate::mosquitto_dev::mosquitto,
+            hint: *const std::os::raw::c_char,
+            identity: *const std::os::raw::c_char,
+            key: *mut std::os::raw::c_char,
+            max_key_len: std::os::raw::c_int,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::psk_key_get::<$t>(
+                    user_data,
+                    client,
+                    hint,
+                    identity,
+                    key,
+                    max_key_len,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_start(
+            user_data: *mut std::os::raw::c_void,
+            client: *mut $crate::mosquitto_dev::mosquitto,
+            method: *const std::os::raw::c_char,
+            reauth: bool,
+            data_in: *const std::os::raw::c_void,
+            data_in_len: u16,
+            data_out: *mut *mut std::os::raw::c_void,
+            data_out_len: *mut u16,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::auth_start::<$t>(
+                    user_data,
+                    client,
+                    method,
+                    reauth,
+                    data_in,
+                    data_in_len,
+                    data_out,
+                    data_out_len,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mosquitto_auth_continue(
+            user_data: *mut std::os::raw::c_void,
+            client: *mut $crate::mosquitto_dev::mosquitto,
+            method: *const std::os::raw::c_char,
+            data_in: *const std::os::raw::c_void,
+            data_in_len: u16,
+            data_out: *mut *mut std::os::raw::c_void,
+            data_out_len: *mut u16,
+        ) -> std::os::raw::c_int {
+            unsafe {
+                $crate::auth_plugin_v4::auth_continue::<$t>(
+                    user_data,
+                    client,
+                    method,
+                    data_in,
+                    data_in_len,
+                    data_out,
+                    data_out_len,
+                    &__MOSQUITTO_AUTH_PLUGIN_V4_INFO,
+                )
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+    use std::ffi::CString;
+
+    struct Devices;
+
+    static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+    impl MosquittoPlugin for Devices {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Devices
+        }
+
+        fn on_reload(&mut self, _opts: MosquittoOpt) {
+            RELOADS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn username_password(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            username: Option<&str>,
+            password: Option<&str>,
+        ) -> AuthDecision {
+            if username.is_some() && username == password {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::deny("wrong password")
+            }
+        }
+
+        fn acl_check(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            level: AclCheckAccessLevel,
+            msg: MosquittoMessage,
+        ) -> AuthDecision {
+            match level {
+                AclCheckAccessLevel::Write
+                    if msg.topic.starts_with("devices/") && !msg.payload.is_empty() =>
+                {
+                    AuthDecision::Allow
+                }
+                AclCheckAccessLevel::Write => AuthDecision::deny("not a device"),
+                _ => AuthDecision::Defer,
+            }
+        }
+
+        fn acl_check_subscribe(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            topic: &str,
+            _opts: SubscriptionOptions,
+        ) -> AuthDecision {
+            if topic == "devices/#" {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::deny("not a device filter")
+            }
+        }
+    }
+
+    const CLIENT: *mut mosquitto = std::ptr::NonNull::dangling().as_ptr();
+    const ALL: PluginInfo = dynlib::DEFAULT_PLUGIN_INFO;
+
+    fn loaded(test: impl FnOnce(*mut c_void)) {
+        stub_ffi::reset();
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        assert_eq!(
+            unsafe { plugin_init::<Devices>(&mut user_data, std::ptr::null_mut(), 0, &ALL) },
+            0
+        );
+        // The old interface has no callbacks to register
+        assert!(stub_ffi::registered_events().is_empty());
+        test(user_data);
+        assert_eq!(
+            unsafe { plugin_cleanup::<Devices>(user_data, std::ptr::null_mut(), 0) },
+            0
+        );
+    }
+
+    fn acl_msg(topic: &CString, payload: &[u8]) -> mosquitto_acl_msg {
+        mosquitto_acl_msg {
+            topic: topic.as_ptr(),
+            payload: payload.as_ptr() as *const c_void,
+            payloadlen: payload.len() as _,
+            qos: 1,
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn old_calls_reach_the_plugin() {
+        loaded(|user_data| unsafe {
+            let alice = CString::new("alice").unwrap();
+            let bob = CString::new("bob").unwrap();
+            assert_eq!(
+                unpwd_check::<Devices>(user_data, CLIENT, alice.as_ptr(), alice.as_ptr(), &ALL),
+                0
+            );
+            assert_eq!(
+                unpwd_check::<Devices>(user_data, CLIENT, alice.as_ptr(), bob.as_ptr(), &ALL),
+                Error::Auth as c_int
+            );
+            assert_eq!(
+                unpwd_check::<Devices>(user_data, CLIENT, alice.as_ptr(), std::ptr::null(), &ALL),
+                Error::Auth as c_int
+            );
+
+            let lamp = CString::new("devices/lamp").unwrap();
+            let write = AccessLevel::Write as c_int;
+            assert_eq!(
+                acl_check::<Devices>(user_data, write, CLIENT, &acl_msg(&lamp, b"on"), &ALL),
+                0
+            );
+            assert_eq!(
+                acl_check::<Devices>(user_data, write, CLIENT, &acl_msg(&lamp, b""), &ALL),
+                Error::AclDenied as c_int
+            );
+            let read = AccessLevel::Read as c_int;
+            assert_eq!(
+                acl_check::<Devices>(user_data, read, CLIENT, &acl_msg(&lamp, b"on"), &ALL),
+                Error::PluginDefer as c_int
+            );
+            let filter = CString::new("devices/#").unwrap();
+            let subscribe = AccessLevel::Subscribe as c_int;
+            assert_eq!(
+                acl_check::<Devices>(user_data, subscribe, CLIENT, &acl_msg(&filter, b""), &ALL),
+                0
+            );
+        });
+    }
+
+    #[test]
+    fn malformed_calls_are_refused() {
+        loaded(|user_data| unsafe {
+            let lamp = CString::new("devices/lamp").unwrap();
+            let write = AccessLevel::Write as c_int;
+            assert_eq!(
+                acl_check::<Devices>(user_data, write, CLIENT, std::ptr::null(), &ALL),
+                Error::AclDenied as c_int
+            );
+            let mut msg = acl_msg(&lamp, b"on");
+            msg.payloadlen = -1;
+            msg.payload = std::ptr::null();
+            assert_eq!(
+                acl_check::<Devices>(user_data, write, CLIENT, &msg, &ALL),
+                Error::AclDenied as c_int
+            );
+            assert_eq!(
+                acl_check::<Devices>(
+                    user_data,
+                    write,
+                    std::ptr::null_mut(),
+                    &acl_msg(&lamp, b"on"),
+                    &ALL
+                ),
+                Error::AclDenied as c_int
+            );
+        });
+    }
+
+    #[test]
+    fn only_reloads_and_implemented_callbacks_reach_the_plugin() {
+        loaded(|user_data| unsafe {
+            assert_eq!(
+                security_init::<Devices>(user_data, std::ptr::null_mut(), 0, false, &ALL),
+                0
+            );
+            assert_eq!(
+                security_init::<Devices>(user_data, std::ptr::null_mut(), 0, true, &ALL),
+                0
+            );
+            assert_eq!(RELOADS.load(Ordering::Relaxed), 1);
+
+            let logins_only = PluginInfo {
+                callbacks: dynlib::Callbacks::BASIC_AUTH,
+                ..ALL
+            };
+            let lamp = CString::new("devices/lamp").unwrap();
+            let write = AccessLevel::Write as c_int;
+            assert_eq!(
+                acl_check::<Devices>(
+                    user_data,
+                    write,
+                    CLIENT,
+                    &acl_msg(&lamp, b"on"),
+                    &logins_only
+                ),
+                Error::PluginDefer as c_int
+            );
+        });
+    }
+
+    struct VersionCheck(BrokerVersion);
+
+    impl MosquittoPlugin for VersionCheck {
+        fn init(_opts: MosquittoOpt) -> Self {
+            unreachable!("init_with_context is implemented")
+        }
+
+        fn init_with_context(_opts: MosquittoOpt, context: &PluginContext) -> Self {
+            VersionCheck(context.broker_version)
+        }
+    }
+
+    #[test]
+    fn the_old_interface_is_loaded_by_1_6() {
+        stub_ffi::reset();
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                plugin_init::<VersionCheck>(&mut user_data, std::ptr::null_mut(), 0, &ALL),
+                0
+            );
+            let plugin = &*(user_data as *mut dynlib::InternalUserData<VersionCheck>);
+            assert_eq!(plugin.external_user_data.0.to_string(), "1.6.0");
+            assert_eq!(
+                plugin_cleanup::<VersionCheck>(user_data, std::ptr::null_mut(), 0),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn broker_functions_are_looked_up() {
+        let cache = AtomicUsize::new(0);
+        assert!(look_up(&cache, b"strlen\0").is_some());
+        assert_eq!(
+            look_up(&cache, b"strlen\0"),
+            Some(cache.load(Ordering::Relaxed))
+        );
+        let cache = AtomicUsize::new(0);
+        assert_eq!(look_up(&cache, b"mosquitto_no_such_function\0"), None);
+        assert_eq!(cache.load(Ordering::Relaxed), 1);
+    }
+}
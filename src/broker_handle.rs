@@ -0,0 +1,247 @@
+// Publishing from threads other than the broker's, e.g. with results of async work:
+//
+//     let broker = BrokerHandle::new();
+//     std::thread::spawn(move || {
+//         let reading = sensor.read();
+//         broker.publish_broadcast("sensors/temperature", reading.to_string(), QOS::AtMostOnce, false)
+//     });
+//
+// mosquitto functions may only be called from the broker thread, so messages are queued and
+// published on the next tick, in the order they were queued. The plugin has to get the tick
+// event: create_dynamic_library! registers every callback, with #[mosquitto_plugin] define tick or
+// on_tick. Publishes the broker refuses at that point are written to the broker log, messages
+// still queued when the plugin is cleaned up are dropped.
+use crate::mosquitto_calls::{self, log_printf};
+use crate::mosquitto_dev::MOSQ_LOG_ERR;
+use crate::{topic, Error, Success, QOS};
+use std::sync::Mutex;
+
+static QUEUE: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+
+struct Pending {
+    // None broadcasts the message
+    client_id: Option<String>,
+    topic: String,
+    payload: Vec<u8>,
+    qos: QOS,
+    retain: bool,
+}
+
+/// Queues publishes for the broker thread, cheap to clone and usable from any thread
+#[derive(Clone, Copy)]
+pub struct BrokerHandle {
+    queue: &'static Mutex<Vec<Pending>>,
+}
+
+impl Default for BrokerHandle {
+    fn default() -> Self {
+        BrokerHandle::new()
+    }
+}
+
+impl std::fmt::Debug for BrokerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BrokerHandle")
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+impl BrokerHandle {
+    pub fn new() -> BrokerHandle {
+        BrokerHandle { queue: &QUEUE }
+    }
+
+    /// Queues a broadcast, see mosquitto_calls::publish_broadcast. Topics that can never be
+    /// published to are refused right away, the rest is checked by the broker on the tick.
+    pub fn publish_broadcast(
+        &self,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+        qos: QOS,
+        retain: bool,
+    ) -> Result<Success, Error> {
+        self.queue(None, topic, payload.into(), qos, retain)
+    }
+
+    /// Queues a publish to one client, see mosquitto_calls::publish_to_client. A client that isn't
+    /// connected by the time of the tick is only noticed then.
+    pub fn publish_to_client(
+        &self,
+        client_id: &str,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+        qos: QOS,
+        retain: bool,
+    ) -> Result<Success, Error> {
+        if client_id.contains('\0') {
+            return Err(Error::Inval);
+        }
+        self.queue(
+            Some(client_id.to_string()),
+            topic,
+            payload.into(),
+            qos,
+            retain,
+        )
+    }
+
+    /// Number of messages waiting for the next tick
+    pub fn queued(&self) -> usize {
+        lock(self.queue).len()
+    }
+
+    fn queue(
+        &self,
+        client_id: Option<String>,
+        topic: &str,
+        payload: Vec<u8>,
+        qos: QOS,
+        retain: bool,
+    ) -> Result<Success, Error> {
+        if !topic::is_valid_publish_topic(topic) {
+            return Err(mosquitto_calls::topic_error(topic, false).into());
+        }
+        lock(self.queue).push(Pending {
+            client_id,
+            topic: topic.to_string(),
+            payload,
+            qos,
+            retain,
+        });
+        Ok(Success)
+    }
+}
+
+fn lock(queue: &Mutex<Vec<Pending>>) -> std::sync::MutexGuard<'_, Vec<Pending>> {
+    queue.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Called from the tick callback on the broker thread
+pub(crate) fn publish_pending() {
+    publish_queued(&QUEUE);
+}
+
+// Called from plugin_cleanup, nothing may reach the broker from an unloaded plugin
+pub(crate) fn discard_pending() {
+    lock(&QUEUE).clear();
+}
+
+fn publish_queued(queue: &Mutex<Vec<Pending>>) {
+    // Taken first so threads can keep queueing while the broker publishes
+    let pending = std::mem::take(&mut *lock(queue));
+    for message in pending {
+        let result = match &message.client_id {
+            Some(client_id) => mosquitto_calls::publish_to_client(
+                client_id,
+                &message.topic,
+                &message.payload,
+                message.qos,
+                message.retain,
+            ),
+            None => mosquitto_calls::publish_broadcast(
+                &message.topic,
+                &message.payload,
+                message.qos,
+                message.retain,
+            ),
+        };
+        if let Err(e) = result {
+            let recipient = message.client_id.as_deref().unwrap_or("everyone");
+            log_printf(
+                MOSQ_LOG_ERR,
+                &format!(
+                    "BrokerHandle: publish to {} for {} failed: {}",
+                    message.topic, recipient, e
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    // A queue of its own, the tick tests elsewhere publish what is in QUEUE
+    fn handle() -> BrokerHandle {
+        BrokerHandle {
+            queue: Box::leak(Box::new(Mutex::new(Vec::new()))),
+        }
+    }
+
+    #[test]
+    fn publishes_wait_for_the_tick() {
+        stub_ffi::reset();
+        let broker = handle();
+        let worker = broker;
+        std::thread::spawn(move || {
+            worker
+                .publish_broadcast("results/1", "done", QOS::AtLeastOnce, true)
+                .unwrap();
+            worker
+                .publish_to_client("client-1", "results/2", vec![1, 2], QOS::AtMostOnce, false)
+                .unwrap();
+            worker
+                .publish_to_client("client-2", "results/3", "late", QOS::AtMostOnce, false)
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+        assert_eq!(
+            broker.publish_broadcast("results/#", "x", QOS::AtMostOnce, false),
+            Err(Error::TopicWildcard)
+        );
+        assert_eq!(
+            broker.publish_to_client("a\0b", "results", "x", QOS::AtMostOnce, false),
+            Err(Error::Inval)
+        );
+        assert_eq!(broker.queued(), 3);
+        assert!(stub_ffi::published().is_empty());
+
+        let logged = stub_ffi::logged().len();
+        publish_queued(broker.queue);
+        let published = stub_ffi::published();
+        let sent: Vec<_> = published
+            .iter()
+            .map(|p| {
+                (
+                    p.client_id.as_deref(),
+                    p.topic.as_str(),
+                    &p.payload[..],
+                    p.qos,
+                    p.retain,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                (None, "results/1", &b"done"[..], 1, true),
+                (Some("client-1"), "results/2", &[1, 2][..], 0, false),
+                (Some("client-2"), "results/3", &b"late"[..], 0, false),
+            ]
+        );
+        assert_eq!(stub_ffi::logged().len(), logged);
+        assert_eq!(broker.queued(), 0);
+
+        // the broker refusing a message is logged, the thread that queued it has moved on
+        broker
+            .publish_to_client("client-1", "results/4", "lost", QOS::AtMostOnce, false)
+            .unwrap();
+        stub_ffi::fail_publishes(Error::NoMem.into());
+        publish_queued(broker.queue);
+        assert_eq!(
+            stub_ffi::logged()[logged..],
+            [(
+                MOSQ_LOG_ERR as i32,
+                format!(
+                    "BrokerHandle: publish to results/4 for client-1 failed: {}",
+                    Error::NoMem
+                )
+            )]
+        );
+        assert_eq!(broker.queued(), 0);
+    }
+}
@@ -0,0 +1,334 @@
+// The X.509 certificate a client presented on a listener with require_certificate, see
+// MosquittoClientContext::get_certificate.
+//
+// mosquitto hands out the OpenSSL X509 of the peer, it is serialized to DER with i2d_X509 from
+// the libcrypto the broker is linked against, so the "tls" feature needs a broker built with TLS.
+// Only the fields needed to map a certificate to an identity are parsed here, the DER is there
+// for anything else. The broker keeps only the client certificate, not the chain.
+#[cfg(feature = "tls")]
+use crate::mosquitto_dev::{mosquitto, mosquitto_client_certificate};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(feature = "tls")]
+use std::os::raw::{c_int, c_void};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+
+// 2.5.4.3 and 2.5.29.17
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// An entry of the subject alternative name extension. Other kinds of names are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    der: Vec<u8>,
+    common_name: Option<String>,
+    subject_alt_names: Vec<SubjectAltName>,
+}
+
+impl ClientCertificate {
+    /// None when der isn't an X.509 certificate
+    pub fn from_der(der: Vec<u8>) -> Option<ClientCertificate> {
+        let (common_name, subject_alt_names) = parse(&der)?;
+        Some(ClientCertificate {
+            der,
+            common_name,
+            subject_alt_names,
+        })
+    }
+
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn to_pem(&self) -> String {
+        let encoded = base64(&self.der);
+        let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+        pem
+    }
+
+    /// The first CN of the subject, what use_identity_as_username makes the username
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    pub fn subject_alt_names(&self) -> &[SubjectAltName] {
+        &self.subject_alt_names
+    }
+}
+
+// From libcrypto, which the broker already loaded
+#[cfg(feature = "tls")]
+extern "C" {
+    fn i2d_X509(x509: *mut c_void, out: *mut *mut u8) -> c_int;
+    fn X509_free(x509: *mut c_void);
+}
+
+/// # Safety
+/// client has to be a client the broker passed to a callback
+#[cfg(feature = "tls")]
+pub(crate) unsafe fn of_client(client: *const mosquitto) -> Option<ClientCertificate> {
+    let x509 = unsafe { mosquitto_client_certificate(client) };
+    if x509.is_null() {
+        return None;
+    }
+    let len = unsafe { i2d_X509(x509, std::ptr::null_mut()) };
+    let mut der = vec![0u8; len.max(0) as usize];
+    let mut out = der.as_mut_ptr();
+    let written = if len > 0 {
+        unsafe { i2d_X509(x509, &mut out) }
+    } else {
+        0
+    };
+    // The broker took a reference for us
+    unsafe { X509_free(x509) };
+    if written != len || len <= 0 {
+        return None;
+    }
+    ClientCertificate::from_der(der)
+}
+
+// Reads DER elements one after the other
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Tag and contents of the next element
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0..=0x7f => (first as usize, rest),
+            0x81..=0x84 => {
+                let n = (first & 0x7f) as usize;
+                if rest.len() < n {
+                    return None;
+                }
+                let len = rest[..n]
+                    .iter()
+                    .fold(0usize, |len, b| len << 8 | *b as usize);
+                (len, &rest[n..])
+            }
+            _ => return None,
+        };
+        if rest.len() < len {
+            return None;
+        }
+        self.0 = &rest[len..];
+        Some((tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (t, contents) if t == tag => Some(contents),
+            _ => None,
+        }
+    }
+}
+
+fn parse(der: &[u8]) -> Option<(Option<String>, Vec<SubjectAltName>)> {
+    let certificate = Der(der).expect(SEQUENCE)?;
+    let mut tbs = Der(Der(certificate).expect(SEQUENCE)?);
+    let (tag, _) = tbs.read()?;
+    // v1 certificates start with the serial number
+    if tag == VERSION {
+        tbs.read()?;
+    }
+    // signature algorithm, issuer, validity
+    for _ in 0..3 {
+        tbs.read()?;
+    }
+    let common_name = common_name(tbs.expect(SEQUENCE)?)?;
+    // subject public key info
+    tbs.read()?;
+    let mut subject_alt_names = Vec::new();
+    while !tbs.is_empty() {
+        let (tag, contents) = tbs.read()?;
+        if tag == EXTENSIONS {
+            subject_alt_names = extensions(contents)?;
+        }
+    }
+    Some((common_name, subject_alt_names))
+}
+
+// Outer None when the name is malformed, inner None when it has no CN
+fn common_name(name: &[u8]) -> Option<Option<String>> {
+    let mut rdns = Der(name);
+    while !rdns.is_empty() {
+        let mut attributes = Der(rdns.expect(SET)?);
+        while !attributes.is_empty() {
+            let mut attribute = Der(attributes.expect(SEQUENCE)?);
+            if attribute.expect(OID)? == COMMON_NAME {
+                let (tag, value) = attribute.read()?;
+                return Some(Some(directory_string(tag, value)?));
+            }
+        }
+    }
+    Some(None)
+}
+
+fn directory_string(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString, IA5String
+        0x0c | 0x13 | 0x16 => String::from_utf8(value.to_vec()).ok(),
+        // TeletexString, in practice Latin-1
+        0x14 => Some(value.iter().map(|b| *b as char).collect()),
+        // BMPString
+        0x1e => {
+            let chunks = value.chunks_exact(2);
+            if !chunks.remainder().is_empty() {
+                return None;
+            }
+            let units: Vec<u16> = chunks.map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).ok()
+        }
+        _ => None,
+    }
+}
+
+fn extensions(contents: &[u8]) -> Option<Vec<SubjectAltName>> {
+    let mut extensions = Der(Der(contents).expect(SEQUENCE)?);
+    while !extensions.is_empty() {
+        let mut extension = Der(extensions.expect(SEQUENCE)?);
+        if extension.expect(OID)? != SUBJECT_ALT_NAME {
+            continue;
+        }
+        let (mut tag, mut value) = extension.read()?;
+        if tag == BOOLEAN {
+            let (t, v) = extension.read()?;
+            tag = t;
+            value = v;
+        }
+        if tag != OCTET_STRING {
+            return None;
+        }
+        return general_names(value);
+    }
+    Some(Vec::new())
+}
+
+fn general_names(value: &[u8]) -> Option<Vec<SubjectAltName>> {
+    let mut names = Der(Der(value).expect(SEQUENCE)?);
+    let mut parsed = Vec::new();
+    let text = |value: &[u8]| String::from_utf8(value.to_vec()).ok();
+    while !names.is_empty() {
+        let (tag, value) = names.read()?;
+        let name = match tag {
+            0x81 => SubjectAltName::Email(text(value)?),
+            0x82 => SubjectAltName::Dns(text(value)?),
+            0x86 => SubjectAltName::Uri(text(value)?),
+            0x87 => match value.len() {
+                4 => SubjectAltName::Ip(IpAddr::V4(Ipv4Addr::new(
+                    value[0], value[1], value[2], value[3],
+                ))),
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(value);
+                    SubjectAltName::Ip(IpAddr::V6(Ipv6Addr::from(octets)))
+                }
+                _ => return None,
+            },
+            _ => continue,
+        };
+        parsed.push(name);
+    }
+    Some(parsed)
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Self signed with openssl req -x509 -subj "/O=Example/CN=sensor-1" -addext
+    // "subjectAltName=DNS:sensor-1.example.com,email:ops@example.com,URI:spiffe://example.com/sensor-1,IP:10.0.0.7,IP:::1"
+    const DER: &[u8] = include_bytes!("../fixtures/client.der");
+    const PEM: &str = include_str!("../fixtures/client.pem");
+
+    #[test]
+    fn identities_are_read() {
+        let certificate = ClientCertificate::from_der(DER.to_vec()).unwrap();
+        assert_eq!(certificate.common_name(), Some("sensor-1"));
+        assert_eq!(
+            certificate.subject_alt_names(),
+            &[
+                SubjectAltName::Dns("sensor-1.example.com".to_string()),
+                SubjectAltName::Email("ops@example.com".to_string()),
+                SubjectAltName::Uri("spiffe://example.com/sensor-1".to_string()),
+                SubjectAltName::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7))),
+                SubjectAltName::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            ][..]
+        );
+        assert_eq!(certificate.to_pem(), PEM);
+        assert_eq!(certificate.der(), DER);
+    }
+
+    #[test]
+    fn malformed_certificates_are_refused() {
+        assert_eq!(ClientCertificate::from_der(Vec::new()), None);
+        assert_eq!(
+            ClientCertificate::from_der(DER[..DER.len() / 2].to_vec()),
+            None
+        );
+        let mut wrong_tag = DER.to_vec();
+        wrong_tag[0] = SET;
+        assert_eq!(ClientCertificate::from_der(wrong_tag), None);
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            directory_string(0x14, b"Bj\xf6rn").as_deref(),
+            Some("Björn")
+        );
+        assert_eq!(
+            directory_string(0x1e, &[0, b'B', 0, b'j', 0, 0xf6]).as_deref(),
+            Some("Bjö")
+        );
+        assert_eq!(directory_string(0x0c, b"\xff"), None);
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+    }
+}
@@ -0,0 +1,778 @@
+// Per client data that follows the connections of the clients.
+//
+// ClientRegistry is returned from MosquittoPlugin::client_registry, the generated callbacks then
+// tell it when a client authenticated and when it disconnected, so entries don't have to be
+// removed by hand in on_disconnect.
+//
+// When a client id connects again while the old connection is still around (session takeover),
+// the broker first authenticates the new connection and only then disconnects the old one. The
+// registry remembers which connection an entry belongs to, so that late disconnect doesn't remove
+// the entry of the new connection, and hands the old entry to the takeover hook instead.
+//
+// ClientStore is the simpler alternative for state that only lives as long as one connection. It
+// is keyed by the connection rather than the client id, so a reconnecting client starts without
+// the state of its previous connection and the two never mix during a takeover.
+//
+// ConnectedClients lists who is online, for reporting from a $CONTROL command or a scheduled job
+// and for kicking groups of clients. The plugin interface has no way to enumerate the clients of
+// the broker, so it is filled from MosquittoPlugin::connected_clients: with mosquitto 2.1 on the
+// connect event, with 2.0 on the username and password login. The subscriptions of each client
+// are followed the same way, from the subscribe and unsubscribe events of 2.1 and from the
+// subscribe and unsubscribe ACL checks with 2.0. A 2.0 plugin that doesn't check logins or ACLs
+// itself still registers for them and defers, so the list is kept whatever decides them, but
+// then a subscription another check denies stays listed until the client disconnects. Only what
+// was subscribed during the current connection is known, a resumed session starts out empty, see
+// MosquittoClientContext::get_sub_count for what the broker counts.
+//
+// That order is also how MosquittoPlugin::on_session_takeover finds out about takeovers: the
+// generated callbacks remember the latest connection of each client id they saw connect, and a
+// disconnect of any other connection with that id was taken over by it.
+use crate::mosquitto_calls;
+use crate::stats::Stats;
+use crate::{
+    DisconnectReason, Error, MosquittoClientContext, MosquittoClientProtocol,
+    MosquittoClientProtocolVersion, Success,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+
+/// What the generated callbacks tell the registry, implemented by ClientRegistry and ClientStore
+pub trait ClientLifecycle {
+    /// username_password allowed the client
+    fn authenticated(&mut self, client: &dyn MosquittoClientContext);
+    /// The client disconnected, called after MosquittoPlugin::on_disconnect
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, reason: DisconnectReason);
+}
+
+/// Decides what the new connection of a client id gets: called with the client id, the entry of
+/// the previous connection and the entry inserted for the new one, if any.
+pub type TakeoverHook<T> = Box<dyn FnMut(&str, T, Option<T>) -> Option<T>>;
+/// Called when a connected client goes away, returns whether to keep the entry for when the
+/// client comes back.
+pub type EvictionHook<T> = Box<dyn FnMut(&str, &T, DisconnectReason) -> bool>;
+
+struct Entry<T> {
+    // None once the client disconnected and the eviction hook kept the entry
+    connection: Option<usize>,
+    value: T,
+}
+
+pub struct ClientRegistry<T> {
+    entries: HashMap<String, Entry<T>>,
+    takeover: TakeoverHook<T>,
+    eviction: EvictionHook<T>,
+}
+
+impl<T> Default for ClientRegistry<T> {
+    fn default() -> Self {
+        ClientRegistry::new()
+    }
+}
+
+impl<T> ClientRegistry<T> {
+    /// By default a new connection keeps the entry inserted for it, or takes over the old one,
+    /// and entries are removed on disconnect.
+    pub fn new() -> ClientRegistry<T> {
+        ClientRegistry {
+            entries: HashMap::new(),
+            takeover: Box::new(|_, old, new| new.or(Some(old))),
+            eviction: Box::new(|_, _, _| false),
+        }
+    }
+
+    pub fn with_takeover(
+        mut self,
+        hook: impl FnMut(&str, T, Option<T>) -> Option<T> + 'static,
+    ) -> Self {
+        self.takeover = Box::new(hook);
+        self
+    }
+
+    /// E.g. `|_, _, reason| reason != DisconnectReason::AdministrativeAction` keeps the data of
+    /// clients that may resume their session, and purges kicked clients.
+    pub fn with_eviction(
+        mut self,
+        hook: impl FnMut(&str, &T, DisconnectReason) -> bool + 'static,
+    ) -> Self {
+        self.eviction = Box::new(hook);
+        self
+    }
+
+    /// Sets the entry of the client's connection, returns the value it replaced. An entry of a
+    /// previous connection with the same client id goes through the takeover hook first.
+    pub fn insert_for(&mut self, client: &dyn MosquittoClientContext, value: T) -> Option<T> {
+        let client_id = client.get_id();
+        let connection = client.connection_id();
+        let (value, replaced) = match self.entries.remove(&client_id) {
+            Some(old) if old.connection == Some(connection) => (Some(value), Some(old.value)),
+            Some(old) => ((self.takeover)(&client_id, old.value, Some(value)), None),
+            None => (Some(value), None),
+        };
+        if let Some(value) = value {
+            self.entries.insert(
+                client_id,
+                Entry {
+                    connection: Some(connection),
+                    value,
+                },
+            );
+        }
+        replaced
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<&T> {
+        self.entries.get(client_id).map(|e| &e.value)
+    }
+
+    pub fn get_mut(&mut self, client_id: &str) -> Option<&mut T> {
+        self.entries.get_mut(client_id).map(|e| &mut e.value)
+    }
+
+    pub fn remove(&mut self, client_id: &str) -> Option<T> {
+        self.entries.remove(client_id).map(|e| e.value)
+    }
+
+    /// Whether the entry belongs to a connected client, false for entries kept after a disconnect
+    pub fn is_connected(&self, client_id: &str) -> bool {
+        self.entries
+            .get(client_id)
+            .is_some_and(|e| e.connection.is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &T)> {
+        self.entries.iter().map(|(id, e)| (id.as_str(), &e.value))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut T)> {
+        self.entries
+            .iter_mut()
+            .map(|(id, e)| (id.as_str(), &mut e.value))
+    }
+
+    /// Number of entries, including those kept for disconnected clients
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of entries of connected clients
+    pub fn connected(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|e| e.connection.is_some())
+            .count()
+    }
+
+    /// Sets the `clients/tracked` and `clients/connected` counters
+    pub fn update_stats(&self, stats: &Stats) {
+        stats.set("clients/tracked", self.len() as u64);
+        stats.set("clients/connected", self.connected() as u64);
+    }
+}
+
+impl<T> ClientLifecycle for ClientRegistry<T> {
+    fn authenticated(&mut self, client: &dyn MosquittoClientContext) {
+        let client_id = client.get_id();
+        let connection = client.connection_id();
+        // Entries inserted during username_password already belong to this connection
+        let old = match self.entries.remove(&client_id) {
+            Some(old) if old.connection == Some(connection) => {
+                self.entries.insert(client_id, old);
+                return;
+            }
+            Some(old) => old,
+            None => return,
+        };
+        if let Some(value) = (self.takeover)(&client_id, old.value, None) {
+            self.entries.insert(
+                client_id,
+                Entry {
+                    connection: Some(connection),
+                    value,
+                },
+            );
+        }
+    }
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, reason: DisconnectReason) {
+        let client_id = client.get_id();
+        let connection = client.connection_id();
+        let entry = match self.entries.get_mut(&client_id) {
+            // The disconnect of a connection whose session was taken over
+            Some(entry) if entry.connection != Some(connection) => return,
+            Some(entry) => entry,
+            None => return,
+        };
+        if (self.eviction)(&client_id, &entry.value, reason) {
+            entry.connection = None;
+        } else {
+            self.entries.remove(&client_id);
+        }
+    }
+}
+
+/// State of connected clients, keyed by their connection. Entries are dropped when the
+/// connection goes away, once the store is returned from MosquittoPlugin::client_registry.
+pub struct ClientStore<T> {
+    entries: HashMap<usize, T>,
+}
+
+impl<T> Default for ClientStore<T> {
+    fn default() -> Self {
+        ClientStore::new()
+    }
+}
+
+impl<T> ClientStore<T> {
+    pub fn new() -> ClientStore<T> {
+        ClientStore {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Sets the entry of the client's connection, returns the value it replaced
+    pub fn insert(&mut self, client: &dyn MosquittoClientContext, value: T) -> Option<T> {
+        self.entries.insert(client.connection_id(), value)
+    }
+
+    pub fn get(&self, client: &dyn MosquittoClientContext) -> Option<&T> {
+        self.entries.get(&client.connection_id())
+    }
+
+    pub fn get_mut(&mut self, client: &dyn MosquittoClientContext) -> Option<&mut T> {
+        self.entries.get_mut(&client.connection_id())
+    }
+
+    /// The entry of the client's connection, inserting the result of f if there is none
+    pub fn get_or_insert_with(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        f: impl FnOnce() -> T,
+    ) -> &mut T {
+        self.entries.entry(client.connection_id()).or_insert_with(f)
+    }
+
+    pub fn remove(&mut self, client: &dyn MosquittoClientContext) -> Option<T> {
+        self.entries.remove(&client.connection_id())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.entries.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T> ClientLifecycle for ClientStore<T> {
+    fn authenticated(&mut self, _client: &dyn MosquittoClientContext) {}
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, _reason: DisconnectReason) {
+        self.entries.remove(&client.connection_id());
+    }
+}
+
+/// A client as it was when it connected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectedClient {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub address: Option<IpAddr>,
+    pub protocol: MosquittoClientProtocol,
+    pub protocol_version: MosquittoClientProtocolVersion,
+    connection: usize,
+    // Topic filter and qos
+    subscriptions: BTreeMap<String, i32>,
+}
+
+impl ConnectedClient {
+    /// The topic filters the client subscribed to with their qos, ordered by filter
+    pub fn subscriptions(&self) -> impl Iterator<Item = (&str, i32)> {
+        self.subscriptions.iter().map(|(f, qos)| (f.as_str(), *qos))
+    }
+
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+}
+
+/// The clients currently connected, by client id, once returned from
+/// MosquittoPlugin::connected_clients
+#[derive(Debug, Default)]
+pub struct ConnectedClients {
+    clients: BTreeMap<String, ConnectedClient>,
+}
+
+impl ConnectedClients {
+    pub fn new() -> ConnectedClients {
+        ConnectedClients::default()
+    }
+
+    pub fn get(&self, client_id: &str) -> Option<&ConnectedClient> {
+        self.clients.get(client_id)
+    }
+
+    /// The connected clients, ordered by client id
+    pub fn iter(&self) -> impl Iterator<Item = &ConnectedClient> {
+        self.clients.values()
+    }
+
+    pub fn with_username<'a>(
+        &'a self,
+        username: &'a str,
+    ) -> impl Iterator<Item = &'a ConnectedClient> + 'a {
+        self.iter()
+            .filter(move |c| c.username.as_deref() == Some(username))
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// The client subscribed to the filter, subscribing again replaces the qos. Called by the
+    /// generated callbacks, clients that aren't listed are ignored.
+    pub fn subscribed(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        topic_filter: &str,
+        qos: i32,
+    ) {
+        if let Some(c) = self.of_connection(client) {
+            c.subscriptions.insert(topic_filter.to_string(), qos);
+        }
+    }
+
+    pub fn unsubscribed(&mut self, client: &dyn MosquittoClientContext, topic_filter: &str) {
+        if let Some(c) = self.of_connection(client) {
+            c.subscriptions.remove(topic_filter);
+        }
+    }
+
+    // The entry of the client, unless it belongs to a connection whose session was taken over
+    fn of_connection(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+    ) -> Option<&mut ConnectedClient> {
+        self.clients
+            .get_mut(&client.get_id())
+            .filter(|c| c.connection == client.connection_id())
+    }
+
+    /// Disconnects every client the filter selects, e.g. `|c| c.client_id.starts_with("tenant-a/")`,
+    /// and returns the result of each kick with the client id. The clients stay listed until the
+    /// broker reports them disconnected.
+    pub fn kick_where(
+        &self,
+        mut filter: impl FnMut(&ConnectedClient) -> bool,
+        with_will: bool,
+    ) -> Vec<(String, Result<Success, Error>)> {
+        self.iter()
+            .filter(|c| filter(c))
+            .map(|c| {
+                (
+                    c.client_id.clone(),
+                    mosquitto_calls::kick_client_by_clientid(&c.client_id, with_will),
+                )
+            })
+            .collect()
+    }
+}
+
+impl ClientLifecycle for ConnectedClients {
+    // Also called on the connect event, which comes after a username set during authentication
+    fn authenticated(&mut self, client: &dyn MosquittoClientContext) {
+        let subscriptions = self
+            .of_connection(client)
+            .map(|c| std::mem::take(&mut c.subscriptions))
+            .unwrap_or_default();
+        let client = ConnectedClient {
+            client_id: client.get_id(),
+            username: client.get_username(),
+            address: client.get_address(),
+            protocol: client.get_protocol(),
+            protocol_version: client.get_protocol_version(),
+            connection: client.connection_id(),
+            subscriptions,
+        };
+        self.clients.insert(client.client_id.clone(), client);
+    }
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, _reason: DisconnectReason) {
+        let client_id = client.get_id();
+        // The disconnect of a connection whose session was taken over
+        if self
+            .clients
+            .get(&client_id)
+            .is_some_and(|c| c.connection == client.connection_id())
+        {
+            self.clients.remove(&client_id);
+        }
+    }
+}
+
+/// The connection that took over the session of a client id, handed to
+/// MosquittoPlugin::on_session_takeover
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionTakeover {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub address: Option<IpAddr>,
+    pub protocol_version: MosquittoClientProtocolVersion,
+}
+
+// The latest connection of each client id, kept by the generated callbacks to tell the disconnect
+// of a connection that was taken over from a plain one. Only connections that were recorded
+// themselves can be taken over, a rejected login reusing the id of a connected client is not.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    latest: HashMap<String, (usize, SessionTakeover)>,
+    recorded: HashSet<usize>,
+}
+
+impl Connections {
+    pub(crate) fn connected(&mut self, client: &dyn MosquittoClientContext) {
+        let takeover = SessionTakeover {
+            client_id: client.get_id(),
+            username: client.get_username(),
+            address: client.get_address(),
+            protocol_version: client.get_protocol_version(),
+        };
+        self.recorded.insert(client.connection_id());
+        self.latest.insert(
+            takeover.client_id.clone(),
+            (client.connection_id(), takeover),
+        );
+    }
+
+    // The connection that took over when it isn't the latest one of its client id
+    pub(crate) fn disconnected(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+    ) -> Option<SessionTakeover> {
+        if !self.recorded.remove(&client.connection_id()) {
+            return None;
+        }
+        let client_id = client.get_id();
+        match self.latest.get(&client_id) {
+            Some((connection, _)) if *connection == client.connection_id() => {
+                self.latest.remove(&client_id);
+                None
+            }
+            Some((_, takeover)) => Some(takeover.clone()),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+    use std::net::Ipv4Addr;
+
+    struct Client {
+        id: &'static str,
+        connection: usize,
+    }
+
+    impl MosquittoClientContext for Client {
+        fn get_address(&self) -> Option<IpAddr> {
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        }
+        fn is_clean_session(&self) -> bool {
+            true
+        }
+        fn get_id(&self) -> String {
+            self.id.to_string()
+        }
+        fn get_keepalive(&self) -> i32 {
+            60
+        }
+        fn get_certificate(&self) -> Option<crate::certificate::ClientCertificate> {
+            None
+        }
+        fn get_protocol(&self) -> MosquittoClientProtocol {
+            MosquittoClientProtocol::Mqtt
+        }
+        fn get_protocol_version(&self) -> MosquittoClientProtocolVersion {
+            MosquittoClientProtocolVersion::Mqtt5
+        }
+        fn get_sub_count(&self) -> i32 {
+            0
+        }
+        fn get_username(&self) -> Option<String> {
+            None
+        }
+        fn set_username(&self, _username: &str) -> Result<Success, Error> {
+            Ok(Success)
+        }
+        fn connection_id(&self) -> usize {
+            self.connection
+        }
+    }
+
+    #[test]
+    fn entries_follow_the_connection() {
+        let mut registry = ClientRegistry::new();
+        let client = Client {
+            id: "c1",
+            connection: 1,
+        };
+        assert_eq!(registry.insert_for(&client, 10), None);
+        registry.authenticated(&client);
+        assert_eq!(registry.get("c1"), Some(&10));
+        *registry.get_mut("c1").unwrap() += 1;
+        assert_eq!(registry.insert_for(&client, 20), Some(11));
+        assert_eq!(registry.connected(), 1);
+
+        registry.disconnected(&client, DisconnectReason::Normal);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn takeover_keeps_the_new_connection() {
+        let mut registry = ClientRegistry::new();
+        let old = Client {
+            id: "c1",
+            connection: 1,
+        };
+        let new = Client {
+            id: "c1",
+            connection: 2,
+        };
+        registry.insert_for(&old, "old");
+        // the new connection authenticates without inserting and takes the old entry over,
+        // the broker then disconnects the old connection
+        registry.authenticated(&new);
+        registry.disconnected(&old, DisconnectReason::Normal);
+        assert_eq!(registry.get("c1"), Some(&"old"));
+        assert!(registry.is_connected("c1"));
+
+        registry.disconnected(&new, DisconnectReason::ConnectionLost);
+        assert_eq!(registry.get("c1"), None);
+    }
+
+    #[test]
+    fn takeover_hook_decides() {
+        let mut registry = ClientRegistry::new().with_takeover(|_, old: Vec<&str>, new| {
+            let mut merged = old;
+            merged.extend(new.unwrap_or_default());
+            Some(merged)
+        });
+        let old = Client {
+            id: "c1",
+            connection: 1,
+        };
+        let new = Client {
+            id: "c1",
+            connection: 2,
+        };
+        registry.insert_for(&old, vec!["a"]);
+        assert_eq!(registry.insert_for(&new, vec!["b"]), None);
+        registry.authenticated(&new);
+        registry.disconnected(&old, DisconnectReason::Normal);
+        assert_eq!(registry.get("c1"), Some(&vec!["a", "b"]));
+
+        let mut dropping = ClientRegistry::new().with_takeover(|_, _, _| None);
+        dropping.insert_for(&old, 1);
+        dropping.authenticated(&new);
+        assert!(dropping.is_empty());
+    }
+
+    #[test]
+    fn eviction_gets_the_reason() {
+        let mut registry = ClientRegistry::new()
+            .with_eviction(|_, _, reason| reason != DisconnectReason::AdministrativeAction);
+        let c1 = Client {
+            id: "c1",
+            connection: 1,
+        };
+        let c2 = Client {
+            id: "c2",
+            connection: 2,
+        };
+        registry.insert_for(&c1, 1);
+        registry.insert_for(&c2, 2);
+
+        registry.disconnected(&c1, DisconnectReason::ConnectionLost);
+        registry.disconnected(&c2, 30.into());
+        assert_eq!(registry.get("c1"), Some(&1));
+        assert!(!registry.is_connected("c1"));
+        assert_eq!(registry.get("c2"), None);
+        assert_eq!((registry.len(), registry.connected()), (1, 0));
+
+        // the client comes back on a new connection and resumes its entry
+        let c1_again = Client {
+            id: "c1",
+            connection: 3,
+        };
+        registry.authenticated(&c1_again);
+        assert!(registry.is_connected("c1"));
+
+        let stats = Stats::new(
+            "$SYS/broker/plugin/test",
+            std::time::Duration::from_secs(10),
+        );
+        registry.update_stats(&stats);
+        assert_eq!(stats.get("clients/connected"), 1);
+        assert_eq!(stats.get("clients/tracked"), 1);
+    }
+
+    #[test]
+    fn store_entries_belong_to_one_connection() {
+        let mut store = ClientStore::new();
+        let old = Client {
+            id: "c1",
+            connection: 1,
+        };
+        let new = Client {
+            id: "c1",
+            connection: 2,
+        };
+        assert_eq!(store.insert(&old, 10), None);
+        *store.get_or_insert_with(&old, || 0) += 1;
+        assert_eq!(store.get(&old), Some(&11));
+        // a takeover starts from scratch and the late disconnect leaves it alone
+        assert_eq!(store.get(&new), None);
+        store.get_or_insert_with(&new, || 20);
+        store.disconnected(&old, DisconnectReason::Normal);
+        assert_eq!(store.get(&new), Some(&20));
+        assert_eq!(store.len(), 1);
+
+        store.disconnected(&new, DisconnectReason::ConnectionLost);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn connected_clients_are_listed_until_they_disconnect() {
+        stub_ffi::reset();
+        let mut clients = ConnectedClients::new();
+        let a1 = Client {
+            id: "tenant-a/1",
+            connection: 1,
+        };
+        let b1 = Client {
+            id: "tenant-b/1",
+            connection: 2,
+        };
+        clients.authenticated(&b1);
+        clients.authenticated(&a1);
+        let ids: Vec<_> = clients.iter().map(|c| c.client_id.as_str()).collect();
+        assert_eq!(ids, vec!["tenant-a/1", "tenant-b/1"]);
+        assert_eq!(
+            clients.get("tenant-a/1").unwrap().address,
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+
+        let kicked = clients.kick_where(|c| c.client_id.starts_with("tenant-a/"), false);
+        assert_eq!(kicked, vec![("tenant-a/1".to_string(), Ok(Success))]);
+        assert_eq!(stub_ffi::kicked(), vec![("tenant-a/1".to_string(), false)]);
+
+        // a late disconnect of a connection that was taken over leaves the new one listed
+        let b1_old = Client {
+            id: "tenant-b/1",
+            connection: 3,
+        };
+        clients.disconnected(&b1_old, DisconnectReason::Normal);
+        clients.disconnected(&a1, DisconnectReason::AdministrativeAction);
+        assert_eq!(clients.len(), 1);
+        assert!(clients.get("tenant-b/1").is_some());
+    }
+
+    #[test]
+    fn subscriptions_follow_the_connection() {
+        let mut clients = ConnectedClients::new();
+        let old = Client {
+            id: "dev1",
+            connection: 1,
+        };
+        let new = Client {
+            id: "dev1",
+            connection: 2,
+        };
+        clients.authenticated(&old);
+        clients.subscribed(&old, "cmd/dev1/#", 1);
+        clients.subscribed(&old, "fw/+", 0);
+        clients.subscribed(&old, "cmd/dev1/#", 2);
+        clients.unsubscribed(&old, "fw/+");
+        // the connect event after username_password keeps them
+        clients.authenticated(&old);
+        let subscriptions: Vec<_> = clients.get("dev1").unwrap().subscriptions().collect();
+        assert_eq!(subscriptions, vec![("cmd/dev1/#", 2)]);
+
+        // a takeover starts empty and the old connection can't add to it
+        clients.authenticated(&new);
+        clients.subscribed(&old, "late/#", 0);
+        assert_eq!(clients.get("dev1").unwrap().subscription_count(), 0);
+    }
+
+    #[test]
+    fn takeovers_name_the_new_connection() {
+        let mut connections = Connections::default();
+        let old = Client {
+            id: "dev1",
+            connection: 1,
+        };
+        let new = Client {
+            id: "dev1",
+            connection: 2,
+        };
+        connections.connected(&old);
+        connections.connected(&new);
+        let takeover = connections.disconnected(&old).unwrap();
+        assert_eq!(takeover.client_id, "dev1");
+        assert_eq!(takeover.address, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(connections.disconnected(&new), None);
+        // a client id that reconnects after its disconnect is no takeover
+        connections.connected(&old);
+        assert_eq!(connections.disconnected(&old), None);
+        assert!(connections.latest.is_empty());
+        assert!(connections.recorded.is_empty());
+    }
+
+    #[test]
+    fn connections_that_were_never_recorded_are_not_taken_over() {
+        let mut connections = Connections::default();
+        let live = Client {
+            id: "dev1",
+            connection: 1,
+        };
+        let rejected = Client {
+            id: "dev1",
+            connection: 2,
+        };
+        connections.connected(&live);
+        assert_eq!(connections.disconnected(&rejected), None);
+        assert_eq!(connections.disconnected(&live), None);
+        assert!(connections.latest.is_empty());
+    }
+
+    #[test]
+    fn disconnect_reasons() {
+        assert_eq!(DisconnectReason::from(0), DisconnectReason::Normal);
+        assert_eq!(DisconnectReason::from(7), DisconnectReason::ConnectionLost);
+        assert_eq!(
+            DisconnectReason::from(19),
+            DisconnectReason::KeepaliveTimeout
+        );
+        assert_eq!(DisconnectReason::from(21), DisconnectReason::ProtocolError);
+        assert_eq!(
+            DisconnectReason::from(30),
+            DisconnectReason::AdministrativeAction
+        );
+        assert_eq!(DisconnectReason::from(25), DisconnectReason::PacketTooLarge);
+        assert_eq!(DisconnectReason::from(99), DisconnectReason::Unknown(99));
+    }
+}
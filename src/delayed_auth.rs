@@ -0,0 +1,192 @@
+// Finishing username_password later, for backends too slow to ask on the broker thread:
+//
+//     let completion = AuthCompletion::for_client(client);
+//     std::thread::spawn(move || completion.complete(backend.check(&username, &password)));
+//     AuthDecision::Error(Error::AuthDelayed)
+//
+// The broker keeps the client waiting until the decision arrives. mosquitto functions may only
+// be called from the broker thread, so decisions are queued and handed to
+// mosquitto_complete_basic_auth on the next tick. Needs mosquitto 2.1.
+use crate::mosquitto_calls::log_printf;
+use crate::mosquitto_dev::{mosquitto_complete_basic_auth, MOSQ_LOG_DEBUG, MOSQ_LOG_ERR};
+use crate::{AuthDecision, Error, MosquittoClientContext, Success};
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::sync::Mutex;
+
+static PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct Pending {
+    client_id: String,
+    rc: c_int,
+    // Written to the broker log with the decision, the log is only available on the broker thread
+    log: Option<(u32, String)>,
+}
+
+/// The outstanding decision for one client, sendable to another thread. Dropping it without
+/// calling complete refuses the client, so it isn't left waiting forever.
+#[derive(Debug)]
+pub struct AuthCompletion {
+    client_id: Option<String>,
+    queue: &'static Mutex<Vec<Pending>>,
+}
+
+impl AuthCompletion {
+    pub fn for_client(client: &dyn MosquittoClientContext) -> AuthCompletion {
+        AuthCompletion {
+            client_id: Some(client.get_id()),
+            queue: &PENDING,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or_default()
+    }
+
+    /// Let the client in or refuse it, any error refuses it
+    pub fn complete(mut self, result: Result<Success, Error>) {
+        let rc = match result {
+            Ok(success) => success.into(),
+            Err(_) => Error::Auth as c_int,
+        };
+        self.queue(rc, None);
+    }
+
+    /// Like complete, with what username_password would have returned. Deny reasons and errors
+    /// are logged like those of username_password. Defer refuses the client, a delayed decision
+    /// can't be passed on to the next plugin.
+    pub fn decide(mut self, decision: AuthDecision) {
+        let auth = Error::Auth as c_int;
+        match decision {
+            AuthDecision::Allow => self.queue(Success.into(), None),
+            AuthDecision::Deny { reason } if !reason.is_empty() => {
+                let log = format!("denied {}: {}", self.client_id(), reason);
+                self.queue(auth, Some((MOSQ_LOG_DEBUG, log)))
+            }
+            AuthDecision::Deny { .. } | AuthDecision::Defer => self.queue(auth, None),
+            AuthDecision::Error(e) => {
+                let log = format!("failed for {}: {}", self.client_id(), e);
+                self.queue(auth, Some((MOSQ_LOG_ERR, log)))
+            }
+        }
+    }
+
+    // For decisions already given to the broker, dropping it afterwards completes nothing
+    pub(crate) fn cancel(mut self) {
+        self.client_id = None;
+    }
+
+    fn queue(&mut self, rc: c_int, log: Option<(u32, String)>) {
+        if let Some(client_id) = self.client_id.take() {
+            let mut pending = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+            pending.push(Pending { client_id, rc, log });
+        }
+    }
+}
+
+impl Drop for AuthCompletion {
+    fn drop(&mut self) {
+        self.queue(Error::Auth as c_int, None);
+    }
+}
+
+// Called from the tick callback on the broker thread
+pub(crate) fn complete_pending() {
+    complete_queued(&PENDING);
+}
+
+fn complete_queued(queue: &Mutex<Vec<Pending>>) {
+    let pending = std::mem::take(&mut *queue.lock().unwrap_or_else(|e| e.into_inner()));
+    for Pending { client_id, rc, log } in pending {
+        if let Some((level, message)) = log {
+            log_printf(level, &format!("username_password: {}", message));
+        }
+        // Client ids can't contain NUL, a client that left in the meantime is ignored by the broker
+        if let Ok(client_id) = CString::new(client_id) {
+            unsafe {
+                mosquitto_complete_basic_auth(client_id.as_ptr(), rc);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    // Completions on a queue of their own, the tick tests elsewhere complete what is in PENDING
+    fn completion(client_id: &str, queue: &'static Mutex<Vec<Pending>>) -> AuthCompletion {
+        AuthCompletion {
+            client_id: Some(client_id.to_string()),
+            queue,
+        }
+    }
+
+    fn queue() -> &'static Mutex<Vec<Pending>> {
+        Box::leak(Box::new(Mutex::new(Vec::new())))
+    }
+
+    #[test]
+    fn decisions_reach_the_broker_on_the_tick() {
+        stub_ffi::reset();
+        let queue = queue();
+        let allowed = completion("sensor-1", queue);
+        std::thread::spawn(move || allowed.complete(Ok(Success)))
+            .join()
+            .unwrap();
+        completion("sensor-2", queue).complete(Err(Error::NotFound));
+        drop(completion("sensor-3", queue));
+        assert!(stub_ffi::completed().is_empty());
+
+        complete_queued(queue);
+        let auth = Error::Auth as c_int;
+        assert_eq!(
+            stub_ffi::completed(),
+            vec![
+                ("sensor-1".to_string(), 0),
+                ("sensor-2".to_string(), auth),
+                ("sensor-3".to_string(), auth)
+            ]
+        );
+        complete_queued(queue);
+        assert_eq!(stub_ffi::completed().len(), 3);
+    }
+
+    #[test]
+    fn decisions_are_logged_like_username_password() {
+        stub_ffi::reset();
+        let queue = queue();
+        completion("c1", queue).decide(AuthDecision::Allow);
+        completion("c2", queue).decide(AuthDecision::deny("expired"));
+        completion("c3", queue).decide(AuthDecision::Defer);
+        completion("c4", queue).decide(AuthDecision::Error(Error::ConnLost));
+        let logged = stub_ffi::logged().len();
+
+        complete_queued(queue);
+        let auth = Error::Auth as c_int;
+        assert_eq!(
+            stub_ffi::completed(),
+            vec![
+                ("c1".to_string(), 0),
+                ("c2".to_string(), auth),
+                ("c3".to_string(), auth),
+                ("c4".to_string(), auth)
+            ]
+        );
+        assert_eq!(
+            stub_ffi::logged()[logged..],
+            [
+                (
+                    MOSQ_LOG_DEBUG as c_int,
+                    "username_password: denied c2: expired".to_string()
+                ),
+                (
+                    MOSQ_LOG_ERR as c_int,
+                    format!("username_password: failed for c4: {}", Error::ConnLost)
+                ),
+            ]
+        );
+    }
+}
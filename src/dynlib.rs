@@ -1,322 +1,2622 @@
-// This generates the dynamic c bindings functions that are exported and usable by mosquitto, as
-// well as allocating memory for the structure and handles recreating this from raw memory, to
-// allow the generated plugin to use member functions, and thus have mutable state
-#[macro_export]
-macro_rules! create_dynamic_library {
-    ($t:ty) => {
-        use mosquitto_dev::*;
-        use std::os::raw::c_int;
-        use std::os::raw::c_void;
+// The glue between mosquitto and a MosquittoPlugin implementation. create_dynamic_library!
+// exports the symbols mosquitto looks for when loading the plugin, they forward to plugin_init and
+// plugin_cleanup below which allocate the plugin structure and register the trampolines for the
+// events. The trampolines recreate the structure from the raw user data pointer mosquitto hands
+// back, which lets the plugin use member functions and thus have mutable state.
+use crate::clients::Connections;
+use crate::mosquitto_calls::BrokerPayload;
+use crate::properties::{MessageProperties, Properties};
+use crate::*;
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+
+// Enters a tracing span for the rest of the enclosing block when the "tracing" feature is
+// enabled, and expands to nothing otherwise, so the field expressions are never evaluated.
+macro_rules! callback_span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($name $(, $($fields)*)?).entered();
+    };
+}
+
+/// Structure internal to the plugin binder.
+/// identifier is the plugin identifier recievied in mosquitto_plugin_init
+/// external_user_data is the struct defined by the library user.
+#[doc(hidden)]
+pub struct InternalUserData<T> {
+    pub(crate) identifier: *mut c_void,
+    pub(crate) external_user_data: T,
+    // Only kept while the disconnect event is registered, which forgets the connections again
+    pub(crate) connections: Option<Connections>,
+    // What the plugin implements, see PluginInfo
+    pub(crate) callbacks: Callbacks,
+}
 
-        fn __assert_sync()
-        where
-            $t: MosquittoPlugin,
-        {
+// Trampoline functions that are used as callback for the mosquitto_callback_register
+// These function satisfy the types of the C bindings and then call their corresponding safer rust calls.
+
+// The event data comes straight from the broker. Null pointers where the plugin expects a value,
+// text that isn't UTF-8 and a payload length without a payload are logged, and the event is
+// denied (checks) or ignored (notifications) rather than crashing the broker.
+
+fn malformed(callback: &str, what: &str) {
+    mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("{}: ignoring event with {}", callback, what));
+}
+
+// The event struct, None when the pointer is null
+pub(crate) unsafe fn event<'a, E>(callback: &str, event_data: *mut c_void) -> Option<&'a mut E> {
+    let event = unsafe { (event_data as *mut E).as_mut() };
+    if event.is_none() {
+        malformed(callback, "no event data");
+    }
+    event
+}
+
+// A string that may be absent, like the username of an anonymous client
+pub(crate) unsafe fn optional_str<'a>(callback: &str, name: &str, ptr: *const c_char) -> Result<Option<&'a str>, ()> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    match unsafe { std::ffi::CStr::from_ptr(ptr) }.to_str() {
+        Ok(s) => Ok(Some(s)),
+        Err(_) => {
+            malformed(callback, &format!("a {} that isn't UTF-8", name));
+            Err(())
         }
+    }
+}
 
-        /// Structure internal to the plugin binder.
-        /// identifier is the plugin identifier recievied in mosquitto_plugin_init
-        /// external_user_data is the struct defined by the library user.
-        struct InternalUserData {
-            identifier: *mut c_void,
-            external_user_data: $t
+pub(crate) unsafe fn required_str<'a>(callback: &str, name: &str, ptr: *const c_char) -> Option<&'a str> {
+    match unsafe { optional_str(callback, name, ptr) } {
+        Ok(Some(s)) => Some(s),
+        Ok(None) => {
+            malformed(callback, &format!("a null {}", name));
+            None
         }
+        Err(()) => None,
+    }
+}
 
-        #[no_mangle]
-        pub extern "C" fn mosquitto_plugin_version() -> isize {
-            mosquitto_dev::MOSQ_PLUGIN_VERSION as isize
+// Zero length payloads may come without a buffer
+pub(crate) unsafe fn payload<'a>(callback: &str, ptr: *const c_void, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if ptr.is_null() {
+        malformed(callback, &format!("a null payload of {} bytes", len));
+        None
+    } else {
+        Some(unsafe { std::slice::from_raw_parts(ptr as *const u8, len) })
+    }
+}
+
+fn qos(callback: &str, qos: u8) -> Option<QOS> {
+    let qos = QOS::try_from(i32::from(qos)).ok();
+    if qos.is_none() {
+        malformed(callback, "a qos other than 0, 1 or 2");
+    }
+    qos
+}
+
+fn client(callback: &str, client: *mut mosquitto) -> Option<MosquittoClient> {
+    if client.is_null() {
+        malformed(callback, "no client");
+        None
+    } else {
+        Some(MosquittoClient { client })
+    }
+}
+
+// The return code for an AuthDecision, deny being MOSQ_ERR_AUTH or MOSQ_ERR_ACL_DENIED. Reasons
+// for denying are logged at debug level, failures as errors.
+fn decision_code(callback: &str, client: &MosquittoClient, decision: AuthDecision, deny: Error) -> c_int {
+    match decision {
+        AuthDecision::Allow => Success.into(),
+        AuthDecision::Deny { reason } => {
+            if !reason.is_empty() {
+                mosquitto_calls::log_printf(MOSQ_LOG_DEBUG, &format!("{}: denied {}: {}", callback, client.get_id(), reason));
+            }
+            deny.into()
         }
+        AuthDecision::Defer => Error::PluginDefer.into(),
+        AuthDecision::Error(Error::AuthDelayed) => Error::AuthDelayed.into(),
+        AuthDecision::Error(e) => {
+            mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("{}: failed for {}: {}", callback, client.get_id(), e));
+            e.into()
+        }
+    }
+}
 
-        // Trampoline functions that are used as callback for the mosquitto_callback_register
-        // These function satisfy the types of the C bindings and then call their corresponding safer rust calls.
+// Writes record to the audit log of the plugin, if it has one, and passes the decision on
+fn audited<T: MosquittoPlugin>(user_data: &mut InternalUserData<T>, event: &'static str, client: &MosquittoClient, access: Option<(&str, AclCheckAccessLevel)>, decision: AuthDecision) -> AuthDecision {
+    if let Some(log) = user_data.external_user_data.audit_log() {
+        let mut record = audit::AuditRecord::new(event, client, &decision);
+        if let Some((topic, level)) = access {
+            record = record.with_access(topic, level);
+        }
+        if let Err(e) = log.record(&record) {
+            mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("{}: writing the audit log failed: {}", event, e));
+        }
+    }
+    decision
+}
 
-        #[no_mangle]
-        extern "C" fn on_reload_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_reload = unsafe { &mut *(event_data as *mut mosquitto_evt_reload) };
-            let opts = __from_ptr_and_size(event_data.options, event_data.option_count as _);
-            user_data.external_user_data.on_reload(opts);
-            0
+// Asks on_connect about a client that is about to log in. None lets it go on to the login,
+// anything else is the decision for it.
+fn connect_check<T: MosquittoPlugin>(user_data: &mut InternalUserData<T>, client: &MosquittoClient) -> Option<AuthDecision> {
+    if !user_data.callbacks.contains(Callbacks::CONNECT_CHECK) {
+        return None;
+    }
+    match user_data.external_user_data.on_connect(client, &ConnectInfo::of(client)) {
+        AuthDecision::Allow => None,
+        decision => Some(audited(user_data, "on_connect", client, None, decision)),
+    }
+}
+
+// A panic unwinding out of a trampoline would abort the broker. It is caught and logged, the
+// plugin is told about it in on_panic and the event gets what the returned policy says, deny
+// being what a check of the event would return when refusing.
+pub(crate) fn guarded<T: MosquittoPlugin>(callback: &'static str, user_data: *mut c_void, deny: c_int, body: impl FnOnce() -> c_int) -> c_int {
+    let started = std::time::Instant::now();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(body));
+    timed::<T>(callback, user_data, started.elapsed());
+    let payload = match result {
+        Ok(rc) => return rc,
+        Err(payload) => payload,
+    };
+    let message = panic_message(payload.as_ref());
+    mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("{}: plugin panicked: {}", callback, message));
+    // The plugin may be left half updated by the panic, so on_panic panicking as well is possible
+    let policy = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+        user_data.external_user_data.on_panic(callback, &message)
+    }))
+    .unwrap_or(PanicPolicy::Deny);
+    match policy {
+        PanicPolicy::Deny => deny,
+        PanicPolicy::Defer if deny == 0 => 0,
+        PanicPolicy::Defer => Error::PluginDefer as c_int,
+        PanicPolicy::Abort => std::process::abort(),
+    }
+}
+
+// Records the call when the plugin keeps latency::CallbackTimings, slow calls are logged
+fn timed<T: MosquittoPlugin>(callback: &'static str, user_data: *mut c_void, elapsed: std::time::Duration) {
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+        if let Some(timings) = user_data.external_user_data.callback_timings() {
+            if timings.record(callback, elapsed) {
+                mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("{}: slow, took {:.1} ms", callback, elapsed.as_secs_f64() * 1000.0));
+            }
         }
+    }));
+}
 
-        #[no_mangle]
-        extern "C" fn on_acl_check_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_acl_check = unsafe { &mut *(event_data as *mut mosquitto_evt_acl_check) };
-            let access_level: AccessLevel = event_data.access.into();
-            let access_level = if let Some(level) = access_level.into() {
-                level
-            } else {
-                println!("Unexpected access level for acl check. {:?}", access_level);
-                return Error::Unknown.into();
-            };
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "no message".to_string()
+    }
+}
 
-            let topic: &str = unsafe {
-                let c_str = std::ffi::CStr::from_ptr(event_data.topic);
-                c_str.to_str().expect("Acl trampoline, failed to create &str from CStr pointer")
-            };
+// The extern "C" function registered with the broker, running $body through guarded
+macro_rules! guarded_trampoline {
+    ($name:ident, $callback:literal, $deny:expr, $body:ident) => {
+        pub(crate) extern "C" fn $name<T: MosquittoPlugin>(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+            guarded::<T>($callback, user_data, $deny, || $body::<T>(event_data, user_data))
+        }
+    };
+}
 
-            let payload: &[u8] = unsafe {
-                std::slice::from_raw_parts(event_data.payload as *const u8, event_data.payloadlen as usize)
-            };
+guarded_trampoline!(on_reload_trampoline, "on_reload", 0, on_reload_event);
 
-            let msg = MosquittoMessage {
-                topic,
-                payload,
-                qos: event_data.qos.into(),
-                retain: event_data.retain,
-            };
-            match user_data.external_user_data.acl_check(&MosquittoClient{client: event_data.client}, access_level, msg) {
-                Ok(s) => s.into(),
-                Err(e) => e.into(),
+fn on_reload_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_reload = match unsafe { event("on_reload", event_data) } {
+        Some(event_data) => event_data,
+        None => return 0,
+    };
+    let opts = __from_ptr_and_size(event_data.options, event_data.option_count.max(0) as _);
+    mosquitto_calls::log_printf(MOSQ_LOG_DEBUG, &format!("on_reload options: {}", describe_opts(&opts)));
+    callback_span!("on_reload");
+    user_data.external_user_data.on_reload(opts);
+    0
+}
+
+guarded_trampoline!(on_acl_check_trampoline, "acl_check", Error::AclDenied as c_int, on_acl_check_event);
+
+fn on_acl_check_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    const DENY: c_int = Error::AclDenied as c_int;
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_acl_check = match unsafe { event("acl_check", event_data) } {
+        Some(event_data) => event_data,
+        None => return DENY,
+    };
+    let access_level: AccessLevel = event_data.access.into();
+    let access_level = if let Some(level) = access_level.into() {
+        level
+    } else {
+        malformed("acl_check", &format!("the unknown access level {}", event_data.access));
+        return Error::Unknown.into();
+    };
+
+    let (client, topic) = match (client("acl_check", event_data.client), unsafe { required_str("acl_check", "topic", event_data.topic) }) {
+        (Some(client), Some(topic)) => (client, topic),
+        _ => return DENY,
+    };
+    callback_span!("acl_check", client_id = %client.get_id(), topic = %topic, level = %access_level);
+
+    // Registered to track subscriptions only, the check is up to the other plugins and the broker
+    if !user_data.callbacks.contains(Callbacks::ACL_CHECK) {
+        if let Some(clients) = user_data.external_user_data.connected_clients() {
+            match access_level {
+                AclCheckAccessLevel::Subscribe => clients.subscribed(&client, topic, event_data.qos.into()),
+                AclCheckAccessLevel::Unsubscribe => clients.unsubscribed(&client, topic),
+                _ => {}
             }
         }
+        return Error::PluginDefer.into();
+    }
 
-        #[no_mangle]
-        extern "C" fn on_basic_auth_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_basic_auth = unsafe { &mut *(event_data as *mut mosquitto_evt_basic_auth) };
-            let username: Option<&str> = unsafe {
-                if event_data.username.is_null() {
-                    None
-                } else {
-                    let c_str = std::ffi::CStr::from_ptr(event_data.username);
-                    Some(c_str.to_str().expect("basic auth trampoline failed to create username &str from CStr pointer"))
-                }
-            };
-            let password: Option<&str> = unsafe {
-                if event_data.password.is_null() {
-                    None
-                } else {
-                    let c_str = std::ffi::CStr::from_ptr(event_data.password);
-                    Some(c_str.to_str().expect("basic auth trampoline failed to create password &str from CStr pointer"))
-                }
-            };
+    if access_level == AclCheckAccessLevel::Subscribe {
+        // Subscriptions carry no payload, the topic is the subscription pattern
+        let opts = SubscriptionOptions { qos: event_data.qos.into() };
+        let decision = user_data.external_user_data.acl_check_subscribe(&client, topic, opts);
+        let decision = audited(user_data, "acl_check", &client, Some((topic, access_level)), decision);
+        // mosquitto 2.1 reports the subscription itself, in on_subscribe
+        #[cfg(not(mosquitto_2_1))]
+        if decision == AuthDecision::Allow {
+            if let Some(clients) = user_data.external_user_data.connected_clients() {
+                clients.subscribed(&client, topic, opts.qos);
+            }
+        }
+        return decision_code("acl_check", &client, decision, Error::AclDenied);
+    }
+    if access_level == AclCheckAccessLevel::Unsubscribe {
+        let decision = user_data.external_user_data.acl_check_unsubscribe(&client, topic);
+        let decision = audited(user_data, "acl_check", &client, Some((topic, access_level)), decision);
+        #[cfg(not(mosquitto_2_1))]
+        if decision == AuthDecision::Allow {
+            if let Some(clients) = user_data.external_user_data.connected_clients() {
+                clients.unsubscribed(&client, topic);
+            }
+        }
+        return decision_code("acl_check", &client, decision, Error::AclDenied);
+    }
+
+    let (payload, qos) = match (unsafe { payload("acl_check", event_data.payload, event_data.payloadlen as usize) }, qos("acl_check", event_data.qos)) {
+        (Some(payload), Some(qos)) => (payload, qos),
+        _ => return DENY,
+    };
+
+    let msg = MosquittoMessage {
+        topic,
+        payload,
+        qos,
+        retain: event_data.retain,
+        properties: unsafe { MessageProperties::from_ptr(event_data.properties) },
+    };
+    let decision = user_data.external_user_data.acl_check(&client, access_level, msg);
+    let decision = audited(user_data, "acl_check", &client, Some((topic, access_level)), decision);
+    decision_code("acl_check", &client, decision, Error::AclDenied)
+}
+
+guarded_trampoline!(on_basic_auth_trampoline, "username_password", Error::Auth as c_int, on_basic_auth_event);
+
+fn on_basic_auth_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    const DENY: c_int = Error::Auth as c_int;
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_basic_auth = match unsafe { event("username_password", event_data) } {
+        Some(event_data) => event_data,
+        None => return DENY,
+    };
+    // Anonymous clients have neither, and a username can come without a password
+    let (username, password) = unsafe {
+        match (
+            optional_str("username_password", "username", event_data.username),
+            optional_str("username_password", "password", event_data.password),
+        ) {
+            (Ok(username), Ok(password)) => (username, password),
+            _ => return DENY,
+        }
+    };
+
+    let client = match client("username_password", event_data.client) {
+        Some(client) => client,
+        None => return DENY,
+    };
+    callback_span!("username_password", client_id = %client.get_id(), username = ?username);
+    if let Some(decision) = connect_check(user_data, &client) {
+        return decision_code("on_connect", &client, decision, Error::Auth);
+    }
+    // Registered for on_connect or to track clients only, the login is up to the other plugins
+    // and the broker. A login they refuse is followed by a disconnect, which forgets the client.
+    if !user_data.callbacks.contains(Callbacks::BASIC_AUTH) {
+        if user_data.callbacks.contains(Callbacks::TRACK_LOGINS) {
+            track_login(user_data, &client);
+        }
+        return Error::PluginDefer.into();
+    }
+    let decision = user_data.external_user_data.username_password(&client, username, password);
+    let decision = audited(user_data, "username_password", &client, None, decision);
+    if decision == AuthDecision::Allow {
+        track_login(user_data, &client);
+        if let Some(registry) = user_data.external_user_data.client_registry() {
+            registry.authenticated(&client);
+        }
+    }
+    decision_code("username_password", &client, decision, Error::Auth)
+}
+
+// Remembered for the disconnect, on_session_takeover and connected_clients
+fn track_login<T: MosquittoPlugin>(user_data: &mut InternalUserData<T>, client: &MosquittoClient) {
+    if let Some(connections) = &mut user_data.connections {
+        connections.connected(client);
+    }
+    if let Some(clients) = user_data.external_user_data.connected_clients() {
+        clients.authenticated(client);
+    }
+}
+
+guarded_trampoline!(on_ext_auth_start_trampoline, "ext_auth_start", Error::Auth as c_int, on_ext_auth_start_event);
+
+fn on_ext_auth_start_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    callback_span!("ext_auth_start");
+    on_ext_auth::<T>("ext_auth_start", event_data, user_data, T::ext_auth_start)
+}
+
+guarded_trampoline!(on_ext_auth_continue_trampoline, "ext_auth_continue", Error::Auth as c_int, on_ext_auth_continue_event);
+
+fn on_ext_auth_continue_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    callback_span!("ext_auth_continue");
+    on_ext_auth::<T>("ext_auth_continue", event_data, user_data, T::ext_auth_continue)
+}
+
+type ExtAuthStep<T> = fn(&mut T, &dyn MosquittoClientContext, &str, &[u8]) -> AuthStep;
+
+fn on_ext_auth<T: MosquittoPlugin>(callback: &str, event_data: *mut c_void, user_data: *mut c_void, step: ExtAuthStep<T>) -> c_int {
+    const DENY: c_int = Error::Auth as c_int;
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_extended_auth = match unsafe { event(callback, event_data) } {
+        Some(event_data) => event_data,
+        None => return DENY,
+    };
+    let (client, method, data) = unsafe {
+        match (
+            client(callback, event_data.client),
+            required_str(callback, "auth method", event_data.auth_method),
+            payload(callback, event_data.data_in, event_data.data_in_len as usize),
+        ) {
+            (Some(client), Some(method), Some(data)) => (client, method, data),
+            _ => return DENY,
+        }
+    };
+    if callback == "ext_auth_start" {
+        if let Some(decision) = connect_check(user_data, &client) {
+            return decision_code("on_connect", &client, decision, Error::Auth);
+        }
+    }
 
-            match user_data.external_user_data.username_password(&MosquittoClient{client: event_data.client}, username, password) {
-                Ok(r) => r.into(),
-                Err(e) => e.into(),
+    let (data_out, rc) = match step(&mut user_data.external_user_data, &client, method, data) {
+        AuthStep::Accept(data_out) => {
+            if let Some(registry) = user_data.external_user_data.client_registry() {
+                registry.authenticated(&client);
+            }
+            if let Some(clients) = user_data.external_user_data.connected_clients() {
+                clients.authenticated(&client);
             }
+            (data_out.unwrap_or_default(), Success.into())
         }
+        AuthStep::Continue(data_out) => (data_out, Error::AuthContinue.into()),
+        AuthStep::Reject => return DENY,
+        AuthStep::Defer => return Error::PluginDefer.into(),
+    };
+    // The AUTH packet carries at most 65535 bytes of data
+    if data_out.len() > u16::MAX as usize {
+        mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("{}: refusing client, {} bytes of auth data is too long", callback, data_out.len()));
+        return DENY;
+    }
+    if !data_out.is_empty() {
+        // The broker sends the data and frees it
+        let (ptr, len) = match BrokerPayload::copy_from(&data_out) {
+            buffer if buffer.is_allocated() => buffer.into_raw(),
+            _ => return Error::NoMem.into(),
+        };
+        event_data.data_out = ptr;
+        event_data.data_out_len = len as u16;
+    }
+    rc
+}
 
-        #[no_mangle]
-        extern "C" fn on_auth_start_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_extended_auth = unsafe { &mut *(event_data as *mut mosquitto_evt_extended_auth) };
-            unimplemented!();
+guarded_trampoline!(on_control_trampoline, "on_control", 0, on_control_event);
+
+fn on_control_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_control = match unsafe { event("on_control", event_data) } {
+        Some(event_data) => event_data,
+        None => return 0,
+    };
+    let (client, topic, payload, qos) = unsafe {
+        match (
+            client("on_control", event_data.client),
+            required_str("on_control", "topic", event_data.topic),
+            payload("on_control", event_data.payload, event_data.payloadlen as usize),
+            qos("on_control", event_data.qos),
+        ) {
+            (Some(client), Some(topic), Some(payload), Some(qos)) => (client, topic, payload, qos),
+            _ => return 0,
         }
+    };
 
-        #[no_mangle]
-        extern "C" fn on_auth_continue_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_extended_auth = unsafe { &mut *(event_data as *mut mosquitto_evt_extended_auth) };
-            unimplemented!();
+    let msg = MosquittoMessage {
+        topic,
+        payload,
+        qos,
+        retain: event_data.retain,
+        properties: unsafe { MessageProperties::from_ptr(event_data.properties) },
+    };
+
+    callback_span!("on_control", client_id = %client.get_id(), topic = %topic);
+    if let ControlResponse::Reply(reply) = user_data.external_user_data.on_control_command(&client, msg) {
+        let response_topic = format!("{}/response", topic);
+        if let Err(e) = mosquitto_calls::publish_to_client(&client.get_id(), &response_topic, reply, QOS::AtMostOnce, false) {
+            mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("on_control: can't publish the reply to {}: {:?}", response_topic, e));
         }
+    }
+    0
+}
 
-        #[no_mangle]
-        extern "C" fn on_control_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_control = unsafe { &mut *(event_data as *mut mosquitto_evt_control) };
-            let topic: &str = unsafe {
-                let c_str = std::ffi::CStr::from_ptr(event_data.topic);
-                c_str.to_str().expect("control trampoline failed to create topic &str from CStr pointer")
-            };
+guarded_trampoline!(on_message_trampoline, "on_message", Error::AclDenied as c_int, on_message_event);
 
-            let payload: &[u8] = unsafe {
-                std::slice::from_raw_parts(event_data.payload as *const u8, event_data.payloadlen as usize)
-            };
+fn on_message_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    const DENY: c_int = Error::AclDenied as c_int;
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_message = match unsafe { event("on_message", event_data) } {
+        Some(event_data) => event_data,
+        None => return DENY,
+    };
+    let (client, topic, payload, qos) = unsafe {
+        match (
+            client("on_message", event_data.client),
+            required_str("on_message", "topic", event_data.topic),
+            payload("on_message", event_data.payload, event_data.payloadlen as usize),
+            qos("on_message", event_data.qos),
+        ) {
+            (Some(client), Some(topic), Some(payload), Some(qos)) => (client, topic, payload, qos),
+            _ => return DENY,
+        }
+    };
 
-            let msg = MosquittoMessage {
-                topic,
-                payload,
-                qos: event_data.qos.into(),
-                retain: event_data.retain,
-            };
+    let msg = MosquittoMessage {
+        topic,
+        payload,
+        qos,
+        retain: event_data.retain,
+        properties: unsafe { MessageProperties::from_ptr(event_data.properties) },
+    };
 
-            user_data.external_user_data.on_control(&MosquittoClient{client: event_data.client}, msg);
-            0
+    callback_span!("on_message", client_id = %client.get_id(), topic = %topic);
+    let rc = match user_data.external_user_data.on_message_mut(&client, msg) {
+        Ok(rewrite) if rewrite.is_unchanged() => 0,
+        Ok(rewrite) => match apply_rewrite(event_data, &rewrite) {
+            Ok(()) => 0,
+            Err(e) => {
+                mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("on_message: dropping message, can't rewrite it: {:?}", e));
+                DENY
+            }
+        },
+        Err(veto) => {
+            if let Some(reason) = veto.reason_string {
+                set_reason_string(event_data, &reason);
+            }
+            veto.error.into()
+        }
+    };
+    // What the broker retains is the message as rewritten
+    if rc == 0 && event_data.retain {
+        if let Some(retained) = user_data.external_user_data.retained_topics() {
+            if let Ok(Some(topic)) = unsafe { optional_str("on_message", "topic", event_data.topic) } {
+                retained.observed(topic, event_data.payloadlen > 0);
+            }
         }
+    }
+    rc
+}
 
-        #[no_mangle]
-        extern "C" fn on_message_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_message = unsafe { &mut *(event_data as *mut mosquitto_evt_message) };
-            let topic: &str = unsafe {
-                let c_str = std::ffi::CStr::from_ptr(event_data.topic);
-                c_str.to_str().expect("message trampoline failed to create topic &str from CStr pointer")
-            };
+// Writes the rewrite into the event for the broker. Everything is allocated before the event is
+// touched, so on error the message is as it was and nothing leaks. The broker frees the topic,
+// payload and properties that were replaced.
+fn apply_rewrite(event_data: &mut mosquitto_evt_message, rewrite: &MessageRewrite) -> Result<(), Error> {
+    let topic = match &rewrite.topic {
+        Some(topic) if crate::topic::is_valid_publish_topic(topic) => {
+            let mut bytes = Vec::with_capacity(topic.len() + 1);
+            bytes.extend_from_slice(topic.as_bytes());
+            bytes.push(0);
+            Some(BrokerPayload::copy_from(&bytes))
+        }
+        Some(_) => return Err(Error::Inval),
+        None => None,
+    };
+    let payload = match &rewrite.payload {
+        Some(payload) if payload.len() > i32::MAX as usize => return Err(Error::PayloadSize),
+        Some(payload) => Some(BrokerPayload::copy_from(payload)),
+        None => None,
+    };
+    if !topic.iter().chain(payload.iter()).all(BrokerPayload::is_allocated) {
+        return Err(Error::NoMem);
+    }
+    let properties = rewrite.properties.as_ref().map(Properties::to_list).transpose()?;
 
-            let payload: &[u8] = unsafe {
-                std::slice::from_raw_parts(event_data.payload as *const u8, event_data.payloadlen as usize)
-            };
+    if let Some(topic) = topic {
+        event_data.topic = topic.into_raw().0 as *mut _;
+    }
+    if let Some(payload) = payload {
+        let (ptr, len) = payload.into_raw();
+        event_data.payload = ptr;
+        event_data.payloadlen = len as _;
+    }
+    if let Some(retain) = rewrite.retain {
+        event_data.retain = retain;
+    }
+    if let Some(mut properties) = properties {
+        event_data.properties = std::mem::replace(&mut properties.ptr, std::ptr::null_mut());
+    }
+    Ok(())
+}
 
-            let msg = MosquittoMessage {
-                topic,
-                payload,
-                qos: event_data.qos.into(),
-                retain: event_data.retain,
-            };
+// The broker frees the reason string with mosquitto_free once the acknowledgement is sent
+fn set_reason_string(event_data: &mut mosquitto_evt_message, reason: &str) {
+    let reason = reason.split('\0').next().unwrap_or_default();
+    unsafe {
+        let ptr = mosquitto_malloc(reason.len() + 1) as *mut u8;
+        if ptr.is_null() {
+            return;
+        }
+        std::ptr::copy_nonoverlapping(reason.as_ptr(), ptr, reason.len());
+        *ptr.add(reason.len()) = 0;
+        if !event_data.reason_string.is_null() {
+            mosquitto_free(event_data.reason_string as *mut c_void);
+        }
+        event_data.reason_string = ptr as *mut _;
+    }
+}
 
-            user_data.external_user_data.on_message(&MosquittoClient{client: event_data.client}, msg);
-            0
+guarded_trampoline!(on_psk_key_trampoline, "on_psk", Error::Auth as c_int, on_psk_key_event);
+
+fn on_psk_key_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    const DENY: c_int = Error::Auth as c_int;
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_psk_key = match unsafe { event("on_psk", event_data) } {
+        Some(event_data) => event_data,
+        None => return DENY,
+    };
+
+    // A listener without psk_hint passes no hint
+    let (client, hint, identity) = unsafe {
+        match (
+            client("on_psk", event_data.client),
+            optional_str("on_psk", "hint", event_data.hint),
+            required_str("on_psk", "identity", event_data.identity),
+        ) {
+            (Some(client), Ok(hint), Some(identity)) => (client, hint.unwrap_or_default(), identity),
+            _ => return DENY,
         }
+    };
 
-        #[no_mangle]
-        extern "C" fn on_psk_key_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_psk_key = unsafe { &mut *(event_data as *mut mosquitto_evt_psk_key) };
+    callback_span!("on_psk", client_id = %client.get_id(), identity = %identity);
+    if let Some(psk) = user_data.external_user_data.psk_key(&client, identity, hint) {
+        return match write_psk(event_data, &psk) {
+            Ok(()) => 0,
+            Err(what) => {
+                mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("on_psk: refusing {}, the key {}", identity, what));
+                DENY
+            }
+        };
+    }
+    let key = match unsafe { required_str("on_psk", "key", event_data.key) } {
+        Some(key) => key,
+        None => return DENY,
+    };
+    user_data.external_user_data.on_psk(&client, hint, identity, key, event_data.max_key_len)
+}
 
-            let hint: &str = unsafe {
-                let c_str = std::ffi::CStr::from_ptr(event_data.hint);
-                c_str.to_str().expect("psk key trampoline failed to create hint &str from CStr pointer")
-            };
+// Copies the hex key into the buffer of the broker, which has room for max_key_len bytes
+// including the terminating NUL
+fn write_psk(event_data: &mut mosquitto_evt_psk_key, psk: &str) -> Result<(), String> {
+    if psk.is_empty() || !psk.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("is not hex".to_string());
+    }
+    if event_data.key.is_null() || psk.len() >= event_data.max_key_len.max(0) as usize {
+        return Err(format!("doesn't fit into {} bytes", event_data.max_key_len));
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(psk.as_ptr(), event_data.key as *mut u8, psk.len());
+        *event_data.key.add(psk.len()) = 0;
+    }
+    Ok(())
+}
 
-            let identity: &str = unsafe {
-                let c_str = std::ffi::CStr::from_ptr(event_data.identity);
-                c_str.to_str().expect("psk key trampoline failed to create identity &str from CStr pointer")
-            };
+guarded_trampoline!(on_tick_trampoline, "on_tick", 0, on_tick_event);
 
-            let key: &str = unsafe {
-                let c_str = std::ffi::CStr::from_ptr(event_data.key);
-                c_str.to_str().expect("psk key trampoline failed to create key &str from CStr pointer")
-            };
+fn on_tick_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+    let event_data: &mut mosquitto_evt_tick = match unsafe { event("on_tick", event_data) } {
+        Some(event_data) => event_data,
+        None => return 0,
+    };
 
-            user_data.external_user_data.on_psk(&MosquittoClient{client: event_data.client}, hint, identity, key, event_data.max_key_len as i32)
+    #[cfg(mosquitto_2_1)]
+    crate::delayed_auth::complete_pending();
+    crate::broker_handle::publish_pending();
+    crate::worker_pool::run_completions();
+    user_data.external_user_data.on_tick(event_data.now_ns as i64, event_data.next_ns as i64, event_data.now_s as i32, event_data.next_s as i32);
+    // mosquitto 2.0 sends the tick without a time, so it is taken here
+    let now = std::time::Instant::now();
+    user_data.external_user_data.tick(now);
+    if let Some(scheduler) = user_data.external_user_data.scheduler() {
+        scheduler.run_due(now);
+    }
+    if let Some(stats) = user_data.external_user_data.stats() {
+        if let Err(e) = stats.tick_at(now) {
+            mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("on_tick: publishing stats failed: {}", e));
         }
+    }
+    0
+}
 
-        #[no_mangle]
-        extern "C" fn on_tick_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
-            let event_data: &mut mosquitto_evt_tick = unsafe { &mut *(event_data as *mut mosquitto_evt_tick) };
+guarded_trampoline!(on_disconnect_trampoline, "on_disconnect", 0, on_disconnect_event);
 
-            user_data.external_user_data.on_tick(event_data.now_ns as i64, event_data.next_ns as i64, event_data.now_s as i32, event_data.next_s as i32);
-            0
+fn on_disconnect_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+
+    let event_data: &mut mosquitto_evt_disconnect = match unsafe { event("on_disconnect", event_data) } {
+        Some(event_data) => event_data,
+        None => return 0,
+    };
+    let client = match client("on_disconnect", event_data.client) {
+        Some(client) => client,
+        None => return 0,
+    };
+    callback_span!("on_disconnect", client_id = %client.get_id(), reason = event_data.reason);
+    let reason = match user_data.connections.as_mut().and_then(|c| c.disconnected(&client)) {
+        Some(takeover) => {
+            user_data.external_user_data.on_session_takeover(&client, &takeover);
+            DisconnectReason::SessionTakenOver
         }
+        None => event_data.reason.into(),
+    };
+    user_data.external_user_data.on_disconnect(&client, reason);
+    if let Some(registry) = user_data.external_user_data.client_registry() {
+        registry.disconnected(&client, reason);
+    }
+    if let Some(clients) = user_data.external_user_data.connected_clients() {
+        clients.disconnected(&client, reason);
+    }
+    0
+}
 
-        #[no_mangle]
-        extern "C" fn on_disconnect_trampoline(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
+#[cfg(mosquitto_2_1)]
+guarded_trampoline!(on_connect_trampoline, "connect", 0, on_connect_event);
 
-            let event_data: &mut mosquitto_evt_disconnect = unsafe { &mut *(event_data as *mut mosquitto_evt_disconnect) };
-            user_data.external_user_data.on_disconnect(&MosquittoClient{client: event_data.client}, event_data.reason);
-            0
+// MosquittoPlugin::on_connect is asked before the login, this comes after it. Only the
+// connected_clients list and the takeover detection hear about it, it includes the
+// clients other plugins or the password_file of the broker let in
+#[cfg(mosquitto_2_1)]
+fn on_connect_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+
+    let event_data: &mut mosquitto_evt_connect = match unsafe { event("connect", event_data) } {
+        Some(event_data) => event_data,
+        None => return 0,
+    };
+    let client = match client("connect", event_data.client) {
+        Some(client) => client,
+        None => return 0,
+    };
+    callback_span!("connect", client_id = %client.get_id());
+    if let Some(connections) = &mut user_data.connections {
+        connections.connected(&client);
+    }
+    if let Some(clients) = user_data.external_user_data.connected_clients() {
+        clients.authenticated(&client);
+    }
+    0
+}
+
+#[cfg(mosquitto_2_1)]
+guarded_trampoline!(on_subscribe_trampoline, "on_subscribe", Error::AclDenied as c_int, on_subscribe_event);
+
+#[cfg(mosquitto_2_1)]
+fn on_subscribe_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+
+    let event_data: &mut mosquitto_evt_subscribe = match unsafe { event("on_subscribe", event_data) } {
+        Some(event_data) => event_data,
+        None => return Error::AclDenied as c_int,
+    };
+    let (client, topic_filter) = unsafe {
+        match (
+            client("on_subscribe", event_data.client),
+            required_str("on_subscribe", "topic filter", event_data.data.topic_filter),
+        ) {
+            (Some(client), Some(topic_filter)) => (client, topic_filter),
+            _ => return Error::AclDenied as c_int,
+        }
+    };
+    callback_span!("on_subscribe", client_id = %client.get_id(), topic_filter = %topic_filter);
+    let mut subscription = Subscription::from_options(topic_filter.to_string(), event_data.data.options, event_data.data.identifier);
+    let original_filter = subscription.topic_filter.clone();
+    if let Err(e) = user_data.external_user_data.on_subscribe(&client, &mut subscription) {
+        return e as c_int;
+    }
+    if subscription.topic_filter != original_filter {
+        if let Err(e) = replace_topic_filter(&mut event_data.data.topic_filter, &subscription.topic_filter) {
+            mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("on_subscribe: refusing {:?}, it is no valid topic filter", subscription.topic_filter));
+            return e as c_int;
+        }
+    }
+    event_data.data.options = subscription.options();
+    event_data.data.identifier = subscription.identifier;
+    if let Some(clients) = user_data.external_user_data.connected_clients() {
+        clients.subscribed(&client, &subscription.topic_filter, subscription.qos);
+    }
+    0
+}
+
+#[cfg(mosquitto_2_1)]
+guarded_trampoline!(on_unsubscribe_trampoline, "on_unsubscribe", Error::AclDenied as c_int, on_unsubscribe_event);
+
+#[cfg(mosquitto_2_1)]
+fn on_unsubscribe_event<T: MosquittoPlugin>(event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+
+    let event_data: &mut mosquitto_evt_unsubscribe = match unsafe { event("on_unsubscribe", event_data) } {
+        Some(event_data) => event_data,
+        None => return Error::AclDenied as c_int,
+    };
+    let (client, topic_filter) = unsafe {
+        match (
+            client("on_unsubscribe", event_data.client),
+            required_str("on_unsubscribe", "topic filter", event_data.data.topic_filter),
+        ) {
+            (Some(client), Some(topic_filter)) => (client, topic_filter),
+            _ => return Error::AclDenied as c_int,
+        }
+    };
+    callback_span!("on_unsubscribe", client_id = %client.get_id(), topic_filter = %topic_filter);
+    let mut rewritten = topic_filter.to_string();
+    if let Err(e) = user_data.external_user_data.on_unsubscribe(&client, &mut rewritten) {
+        return e as c_int;
+    }
+    if rewritten != topic_filter {
+        if let Err(e) = replace_topic_filter(&mut event_data.data.topic_filter, &rewritten) {
+            mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("on_unsubscribe: refusing {:?}, it is no valid topic filter", rewritten));
+            return e as c_int;
+        }
+    }
+    if let Some(clients) = user_data.external_user_data.connected_clients() {
+        clients.unsubscribed(&client, &rewritten);
+    }
+    0
+}
+
+// Like the topic of a rewritten message, the broker frees the filter it passed in once it sees
+// it was replaced
+#[cfg(mosquitto_2_1)]
+fn replace_topic_filter(slot: &mut *mut c_char, filter: &str) -> Result<(), Error> {
+    if !crate::topic::is_valid_subscribe_filter(filter) {
+        return Err(Error::Inval);
+    }
+    let mut bytes = Vec::with_capacity(filter.len() + 1);
+    bytes.extend_from_slice(filter.as_bytes());
+    bytes.push(0);
+    let copy = BrokerPayload::copy_from(&bytes);
+    if !copy.is_allocated() {
+        return Err(Error::NoMem);
+    }
+    *slot = copy.into_raw().0 as *mut c_char;
+    Ok(())
+}
+
+/// Plugin interface versions the generated code implements, newest first
+pub const SUPPORTED_PLUGIN_VERSIONS: &[i32] = &[MOSQ_PLUGIN_VERSION as i32];
+
+/// Picks the newest plugin interface version supported by both the broker and this crate, or -1
+/// when there is none, which makes the broker refuse to load the plugin.
+pub fn negotiate_plugin_version(broker_supported: &[i32]) -> i32 {
+    broker_supported
+        .iter()
+        .copied()
+        .filter(|v| SUPPORTED_PLUGIN_VERSIONS.contains(v))
+        .max()
+        .unwrap_or(-1)
+}
+
+/// Called from the mosquitto_plugin_version generated by create_dynamic_library!
+///
+/// # Safety
+/// supported_versions has to point to supported_version_count ints, as passed by mosquitto.
+#[doc(hidden)]
+pub unsafe fn plugin_version(supported_version_count: c_int, supported_versions: *const c_int) -> c_int {
+    if supported_versions.is_null() || supported_version_count <= 0 {
+        return -1;
+    }
+    let supported = unsafe { std::slice::from_raw_parts(supported_versions, supported_version_count as usize) };
+    negotiate_plugin_version(supported)
+}
+
+/// The set of broker events a plugin gets callbacks for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Callbacks(u32);
+
+impl Callbacks {
+    pub const NONE: Callbacks = Callbacks(0);
+    pub const RELOAD: Callbacks = Callbacks(1);
+    pub const ACL_CHECK: Callbacks = Callbacks(1 << 1);
+    pub const BASIC_AUTH: Callbacks = Callbacks(1 << 2);
+    pub const CONTROL: Callbacks = Callbacks(1 << 3);
+    pub const MESSAGE: Callbacks = Callbacks(1 << 4);
+    pub const PSK_KEY: Callbacks = Callbacks(1 << 5);
+    pub const TICK: Callbacks = Callbacks(1 << 6);
+    pub const DISCONNECT: Callbacks = Callbacks(1 << 7);
+    /// Both MOSQ_EVT_EXT_AUTH_START and MOSQ_EVT_EXT_AUTH_CONTINUE
+    pub const EXT_AUTH: Callbacks = Callbacks(1 << 8);
+    /// Only registered when built against the mosquitto 2.1 headers
+    pub const SUBSCRIBE: Callbacks = Callbacks(1 << 9);
+    pub const UNSUBSCRIBE: Callbacks = Callbacks(1 << 10);
+    /// Also only registered with the 2.1 headers
+    pub const CONNECT: Callbacks = Callbacks(1 << 11);
+    /// on_connect, asked from the MOSQ_EVT_BASIC_AUTH and MOSQ_EVT_EXT_AUTH_START callbacks
+    pub const CONNECT_CHECK: Callbacks = Callbacks(1 << 12);
+    /// MOSQ_EVT_BASIC_AUTH without BASIC_AUTH: the client is remembered and the login deferred,
+    /// for brokers before 2.1 that have no connect event
+    pub const TRACK_LOGINS: Callbacks = Callbacks(1 << 13);
+    /// MOSQ_EVT_ACL_CHECK without ACL_CHECK: subscriptions are remembered and the check deferred,
+    /// for brokers before 2.1 that have no subscribe events
+    pub const TRACK_SUBSCRIPTIONS: Callbacks = Callbacks(1 << 14);
+    pub const ALL: Callbacks = Callbacks((1 << 15) - 1);
+
+    /// What connected_clients needs to be kept up to date
+    #[cfg(mosquitto_2_1)]
+    pub const CONNECTED_CLIENTS: Callbacks = Callbacks::CONNECT
+        .union(Callbacks::DISCONNECT)
+        .union(Callbacks::SUBSCRIBE)
+        .union(Callbacks::UNSUBSCRIBE);
+    #[cfg(not(mosquitto_2_1))]
+    pub const CONNECTED_CLIENTS: Callbacks = Callbacks::TRACK_LOGINS
+        .union(Callbacks::DISCONNECT)
+        .union(Callbacks::TRACK_SUBSCRIPTIONS);
+    /// What on_session_takeover needs to see the connections come and go
+    #[cfg(mosquitto_2_1)]
+    pub const SESSION_TAKEOVER: Callbacks = Callbacks::CONNECT.union(Callbacks::DISCONNECT);
+    #[cfg(not(mosquitto_2_1))]
+    pub const SESSION_TAKEOVER: Callbacks = Callbacks::TRACK_LOGINS.union(Callbacks::DISCONNECT);
+
+    pub const fn union(self, other: Callbacks) -> Callbacks {
+        Callbacks(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Callbacks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Describes a plugin to plugin_init_with, generated by the #[mosquitto_plugin] attribute
+#[derive(Debug, Copy, Clone)]
+pub struct PluginInfo {
+    /// Name and version for the log line written at init
+    pub name: Option<&'static str>,
+    pub version: Option<&'static str>,
+    /// Events to register callbacks for, only those the plugin implements
+    pub callbacks: Callbacks,
+}
+
+/// What create_dynamic_library! uses: no name, and every callback registered
+pub const DEFAULT_PLUGIN_INFO: PluginInfo = PluginInfo {
+    name: None,
+    version: None,
+    callbacks: Callbacks::ALL,
+};
+
+type Trampoline = extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int;
+
+// The events plugin_init registers for the callbacks, besides the $CONTROL topics, and
+// plugin_cleanup unregisters. Those brokers before 2.1 refuse are named for the log.
+fn events<T: MosquittoPlugin>(callbacks: Callbacks) -> Vec<(c_int, Trampoline, Option<&'static str>)> {
+    let mut events: Vec<(c_int, Trampoline, Option<&'static str>)> = Vec::new();
+    if callbacks.contains(Callbacks::RELOAD) {
+        events.push((MosquittoPluginEvent::MosqEvtReload as _, on_reload_trampoline::<T>, None));
+    }
+    if callbacks.contains(Callbacks::ACL_CHECK) || callbacks.contains(Callbacks::TRACK_SUBSCRIPTIONS) {
+        events.push((MosquittoPluginEvent::MosqEvtAclCheck as _, on_acl_check_trampoline::<T>, None));
+    }
+    if callbacks.contains(Callbacks::BASIC_AUTH) || callbacks.contains(Callbacks::CONNECT_CHECK) || callbacks.contains(Callbacks::TRACK_LOGINS) {
+        events.push((MosquittoPluginEvent::MosqEvtBasicAuth as _, on_basic_auth_trampoline::<T>, None));
+    }
+    if callbacks.contains(Callbacks::EXT_AUTH) {
+        events.push((MosquittoPluginEvent::MosqEvtExtAuthStart as _, on_ext_auth_start_trampoline::<T>, None));
+        events.push((MosquittoPluginEvent::MosqEvtExtAuthContinue as _, on_ext_auth_continue_trampoline::<T>, None));
+    }
+    if callbacks.contains(Callbacks::MESSAGE) {
+        events.push((MosquittoPluginEvent::MosqEvtMessage as _, on_message_trampoline::<T>, None));
+    }
+    if callbacks.contains(Callbacks::PSK_KEY) {
+        events.push((MosquittoPluginEvent::MosqEvtPskKey as _, on_psk_key_trampoline::<T>, None));
+    }
+    // Delayed basic auth decisions are handed to the broker on the tick
+    if callbacks.contains(Callbacks::TICK) || (cfg!(mosquitto_2_1) && callbacks.contains(Callbacks::BASIC_AUTH)) {
+        events.push((MosquittoPluginEvent::MosqEvtTick as _, on_tick_trampoline::<T>, None));
+    }
+    if callbacks.contains(Callbacks::DISCONNECT) {
+        events.push((MosquittoPluginEvent::MosqEvtDisconnect as _, on_disconnect_trampoline::<T>, None));
+    }
+    #[cfg(mosquitto_2_1)]
+    {
+        if callbacks.contains(Callbacks::SUBSCRIBE) {
+            events.push((mosquitto_plugin_event_MOSQ_EVT_SUBSCRIBE as _, on_subscribe_trampoline::<T>, Some("on_subscribe")));
+        }
+        if callbacks.contains(Callbacks::UNSUBSCRIBE) {
+            events.push((mosquitto_plugin_event_MOSQ_EVT_UNSUBSCRIBE as _, on_unsubscribe_trampoline::<T>, Some("on_unsubscribe")));
+        }
+        if callbacks.contains(Callbacks::CONNECT) {
+            events.push((mosquitto_plugin_event_MOSQ_EVT_CONNECT as _, on_connect_trampoline::<T>, Some("the connect event")));
+        }
+    }
+    events
+}
+
+/// Called from the mosquitto_plugin_init generated by create_dynamic_library!
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_init<T: MosquittoPlugin>(
+    identifier: *mut c_void,
+    user_data: *mut *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+) -> c_int {
+    unsafe { plugin_init_with::<T>(identifier, user_data, opts, opt_count, &DEFAULT_PLUGIN_INFO) }
+}
+
+/// Same as plugin_init, registering only the callbacks in info. Called from the
+/// mosquitto_plugin_init generated by #[mosquitto_plugin].
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_init_with<T: MosquittoPlugin>(
+    identifier: *mut c_void,
+    user_data: *mut *mut c_void, // When this pointer is set, every other call will get this pointer as well. Only for v4 plugins?
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+    info: &PluginInfo,
+) -> c_int {
+    unsafe { plugin_init_as::<T>(identifier, user_data, opts, opt_count, info, BrokerVersion::headers()) }
+}
+
+/// Same as plugin_init_with, telling the plugin that broker_version loaded it
+pub(crate) unsafe fn plugin_init_as<T: MosquittoPlugin>(
+    identifier: *mut c_void,
+    user_data: *mut *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+    info: &PluginInfo,
+    broker_version: BrokerVersion,
+) -> c_int {
+    #[cfg(feature = "log")]
+    crate::logger::init();
+    #[cfg(feature = "tracing")]
+    crate::trace::init();
+    let opts = __from_ptr_and_size(opts, opt_count as _);
+    mosquitto_calls::log_printf(MOSQ_LOG_DEBUG, &format!("mosquitto_plugin_init options: {}", describe_opts(&opts)));
+
+    // Lets init and the callbacks get at the identifier through raw::PluginHandle::current
+    let handle = raw::PluginHandle::from_identifier(identifier);
+    raw::PluginHandle::set_current(Some(handle));
+    let context = PluginContext {
+        broker_version,
+        handle,
+    };
+    let init = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| T::try_init(opts, &context)));
+    let instance: T = match init {
+        Ok(Ok(instance)) => instance,
+        Ok(Err(e)) => return init_failed::<T>(info, &e.to_string()),
+        Err(payload) => return init_failed::<T>(info, &format!("panicked: {}", panic_message(payload.as_ref()))),
+    };
+    if let Some(name) = info.name {
+        mosquitto_calls::log_printf(
+            MOSQ_LOG_INFO,
+            &format!("Plugin {} {} loaded, built for mosquitto {}", name, info.version.unwrap_or(""), context.broker_version),
+        );
+        // mosquitto 2.1 lists the name and version with the loaded plugins
+        #[cfg(mosquitto_2_1)]
+        set_plugin_info(identifier, name, info.version);
+    }
+    let connections = info.callbacks.contains(Callbacks::DISCONNECT).then(Connections::default);
+    let internal_user_data = InternalUserData{identifier, external_user_data: instance, connections, callbacks: info.callbacks};
+    let internal_user_data = Box::new(internal_user_data);
+    let instance_rawptr: *mut InternalUserData<T> = Box::into_raw(internal_user_data);
+
+    unsafe {
+        *user_data = instance_rawptr as _;
+    }
+
+    unsafe {
+        for (event, trampoline, needs_2_1) in events::<T>(info.callbacks) {
+            let rc = mosquitto_callback_register(identifier as _, event, Some(trampoline), std::ptr::null(), instance_rawptr as _);
+            if let (Some(callback), true) = (needs_2_1, rc != 0) {
+                mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("Can't register {}, it needs mosquitto 2.1: error {}", callback, rc));
+            }
+        }
+
+        if info.callbacks.contains(Callbacks::CONTROL) {
+            // mosquitto copies the topic, it has to look like $CONTROL/<name>/<version>
+            for topic in (*instance_rawptr).external_user_data.control_topics() {
+                let rc = match std::ffi::CString::new(topic.as_str()) {
+                    Ok(cstr) => mosquitto_callback_register(
+                        identifier as _,
+                        MosquittoPluginEvent::MosqEvtControl as _,
+                        Some(on_control_trampoline::<T>),
+                        cstr.as_ptr() as *const c_void,
+                        instance_rawptr as _,
+                    ),
+                    Err(_) => Error::Inval as c_int,
+                };
+                if rc != 0 {
+                    mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("Can't register control topic {:?}: error {}", topic, rc));
+                }
+            }
+        }
+    }
+
+    Success.into()
+}
+
+#[cfg(mosquitto_2_1)]
+fn set_plugin_info(identifier: *mut c_void, name: &str, version: Option<&str>) {
+    // The old auth plugin interface has no identifier to describe
+    if identifier.is_null() {
+        return;
+    }
+    let name = std::ffi::CString::new(name).unwrap_or_default();
+    let version = version.map(|v| std::ffi::CString::new(v).unwrap_or_default());
+    let version_ptr = version.as_ref().map_or(std::ptr::null(), |v| v.as_ptr());
+    let rc = unsafe { mosquitto_plugin_set_info(identifier as _, name.as_ptr(), version_ptr) };
+    if rc != 0 {
+        mosquitto_calls::log_printf(MOSQ_LOG_WARNING, &format!("Can't set the plugin info: error {}", rc));
+    }
+}
+
+// The broker refuses to start when init returns an error
+fn init_failed<T>(info: &PluginInfo, reason: &str) -> c_int {
+    let name = info.name.unwrap_or_else(std::any::type_name::<T>);
+    mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("Plugin {} can't be initialized: {}", name, reason));
+    raw::PluginHandle::set_current(None);
+    Error::Inval as c_int
+}
+
+/// Called from the mosquitto_plugin_cleanup generated by create_dynamic_library!
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_plugin_cleanup, user_data
+/// being the pointer set by plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_cleanup<T: MosquittoPlugin>(
+    user_data: *mut c_void,
+    _opts: *mut mosquitto_opt,
+    _opt_count: c_int,
+) -> c_int {
+    // try_init failed, the broker cleans up every plugin it loaded before exiting
+    if user_data.is_null() {
+        return Success.into();
+    }
+    let user_data: &mut InternalUserData<T> = unsafe { &mut *(user_data as *mut InternalUserData<T>) };
+
+    // Events the broker still has registered would reach the dropped plugin. The old auth plugin
+    // interface has no identifier and registered nothing.
+    if !user_data.identifier.is_null() {
+        unsafe {
+            for (event, trampoline, _) in events::<T>(user_data.callbacks) {
+                mosquitto_callback_unregister(user_data.identifier as _, event, Some(trampoline), std::ptr::null());
+            }
+            if user_data.callbacks.contains(Callbacks::CONTROL) {
+                for topic in user_data.external_user_data.control_topics() {
+                    if let Ok(cstr) = std::ffi::CString::new(topic) {
+                        mosquitto_callback_unregister(user_data.identifier as _, MosquittoPluginEvent::MosqEvtControl as _, Some(on_control_trampoline::<T>), cstr.as_ptr() as *const c_void);
+                    }
+                }
+            }
         }
+    }
+    crate::broker_handle::discard_pending();
+    crate::worker_pool::discard_completions();
+
+    // The broker is shutting down either way, a panic only loses what on_cleanup had left to do
+    let cleanup = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        user_data.external_user_data.on_cleanup();
+        drop(unsafe { Box::from_raw(user_data as *mut InternalUserData<T>) });
+    }));
+    if let Err(payload) = cleanup {
+        mosquitto_calls::log_printf(MOSQ_LOG_ERR, &format!("on_cleanup: plugin panicked: {}", panic_message(payload.as_ref())));
+    }
+    raw::PluginHandle::set_current(None);
+
+    Success.into()
+}
 
+// This generates the dynamic c bindings functions that are exported and usable by mosquitto.
+//
+// Takes any type implementing MosquittoPlugin, such as `Plugin`, `plugins::Plugin` or
+// `Plugin<Backend>`. It has to be invoked once, at the root of the plugin crate.
+// `create_dynamic_library!(Plugin, persistence)` registers the persist events of mosquitto 2.1
+// as well, for plugins implementing persist::PersistencePlugin.
+#[macro_export]
+macro_rules! create_dynamic_library {
+    (@symbols $t:ty, $glue:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn mosquitto_plugin_version(
+            supported_version_count: std::os::raw::c_int,
+            supported_versions: *const std::os::raw::c_int,
+        ) -> std::os::raw::c_int {
+            unsafe { $crate::dynlib::plugin_version(supported_version_count, supported_versions) }
+        }
 
         #[no_mangle]
         pub extern "C" fn mosquitto_plugin_init(
-            identifier: *mut c_void,
-            user_data: *mut *mut c_void, // When this pointer is set, every other call will get this pointer as well. Only for v4 plugins?
-            opts: *mut mosquitto_opt,
-            opt_count: c_int,
-        ) -> c_int {
-            let opts = __from_ptr_and_size(opts, opt_count as _);
-            println!("mosquitto_plugin_init {:?}", opts);
-
-            let instance: $t = <$t>::init(opts);
-            let instance = instance;
-            println!("external_user_data addr {:?}", instance);
-            let internal_user_data = InternalUserData{identifier, external_user_data: instance};
-            let internal_user_data = Box::new(internal_user_data);
-            let instance_rawptr: *mut InternalUserData = Box::into_raw(internal_user_data);
-
-            unsafe {
-                *user_data = instance_rawptr as _;
-            }
-
-            unsafe {
-                mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtReload as _,
-                    Some(on_reload_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-
-                mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtAclCheck as _,
-                    Some(on_acl_check_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-
-                mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtBasicAuth as _,
-                    Some(on_basic_auth_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-
-                let event_data = "$CONTROL";
-                let cstr = &std::ffi::CString::new(event_data).unwrap();
-                let bytes = cstr.as_bytes_with_nul();
-                let topic = bytes.as_ptr() as *const c_void;
-                // TODO the event_data parameter (4th param) has a meaning for the MOSQ_EVT_CONTROL callback
-                // Something to do with the topic the control events are triggered on?
-                //https://github.com/eclipse/mosquitto/blob/master/plugins/dynamic-security/plugin.c#L494
-                mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtControl as _,
-                    Some(on_control_trampoline),
-                    topic,
-                    instance_rawptr as _,
-                );
-
-                let res = mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtMessage as _,
-                    Some(on_message_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-
-                mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtPskKey as _,
-                    Some(on_psk_key_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-
-                mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtTick as _,
-                    Some(on_tick_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-
-                let res = mosquitto_callback_register(
-                    identifier as _,
-                    MosquittoPluginEvent::MosqEvtDisconnect as _,
-                    Some(on_disconnect_trampoline),
-                    std::ptr::null(),
-                    instance_rawptr as _,
-                );
-            }
-
-            Success.into()
+            identifier: *mut std::os::raw::c_void,
+            user_data: *mut *mut std::os::raw::c_void,
+            opts: *mut $crate::mosquitto_dev::mosquitto_opt,
+            opt_count: std::os::raw::c_int,
+        ) -> std::os::raw::c_int {
+            unsafe { $crate::$glue::plugin_init::<$t>(identifier, user_data, opts, opt_count) }
         }
 
         #[no_mangle]
-        extern "C" fn mosquitto_plugin_cleanup(
-            user_data: *mut c_void,
-            opts: *mut mosquitto_opt,
-            opt_count: c_int,
-        ) -> c_int {
-            let opts = __from_ptr_and_size(opts, opt_count as _);
-            let user_data: &mut InternalUserData = unsafe { &mut *(user_data as *mut InternalUserData) };
+        pub extern "C" fn mosquitto_plugin_cleanup(
+            user_data: *mut std::os::raw::c_void,
+            opts: *mut $crate::mosquitto_dev::mosquitto_opt,
+            opt_count: std::os::raw::c_int,
+        ) -> std::os::raw::c_int {
+            unsafe { $crate::$glue::plugin_cleanup::<$t>(user_data, opts, opt_count) }
+        }
+
+        $crate::__auth_plugin_v4_symbols!($t);
+    };
+    ($t:ty) => {
+        $crate::create_dynamic_library!(@symbols $t, dynlib);
+    };
+    ($t:ty, persistence) => {
+        $crate::create_dynamic_library!(@symbols $t, persist);
+    };
+}
+
+// Without the auth-plugin-v4 feature only the symbols of the plugin interface are exported
+#[cfg(not(feature = "auth-plugin-v4"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __auth_plugin_v4_symbols {
+    ($t:ty) => {};
+    ($t:ty, $info:expr) => {};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    struct SchemaCheck;
 
-            unsafe {
-                mosquitto_callback_unregister(user_data.identifier as _, MosquittoPluginEvent::MosqEvtDisconnect as _, Some(on_disconnect_trampoline), std::ptr::null());
+    impl MosquittoPlugin for SchemaCheck {
+        fn init(_opts: MosquittoOpt) -> Self {
+            SchemaCheck
+        }
+
+        fn on_message_check(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            message: MosquittoMessage,
+        ) -> Result<Success, MessageVeto> {
+            if message.payload.starts_with(b"{") {
+                Ok(Success)
+            } else {
+                Err(MessageVeto::new(Error::AclDenied).with_reason("payload is not json"))
             }
-            println!("plugincleanup 2");
+        }
+    }
 
-            drop(unsafe { Box::from_raw(user_data as *mut InternalUserData) });
+    // The stubbed client accessors don't look at the pointer, it only has to be non-null
+    const STUB_CLIENT: *mut mosquitto = std::ptr::NonNull::dangling().as_ptr();
 
-            Success.into()
+    fn message_event(topic: &std::ffi::CStr, payload: &[u8], qos: u8) -> mosquitto_evt_message {
+        let mut event: mosquitto_evt_message = unsafe { std::mem::zeroed() };
+        event.client = STUB_CLIENT;
+        event.topic = topic.as_ptr() as *mut _;
+        event.payload = payload.as_ptr() as *mut c_void;
+        event.payloadlen = payload.len() as _;
+        event.qos = qos as _;
+        event
+    }
+
+    #[test]
+    fn vetoed_message_is_denied_with_reason() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<SchemaCheck>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
         }
-    };
+        let topic = std::ffi::CString::new("sensors/1").unwrap();
+
+        let mut accepted = message_event(&topic, b"{\"t\": 21}", 1);
+        let rc = unsafe { stub_ffi::fire_event(MosquittoPluginEvent::MosqEvtMessage as _, &mut accepted as *mut _ as *mut c_void) };
+        assert_eq!(rc, mosq_err_t_MOSQ_ERR_SUCCESS);
+        assert!(accepted.reason_string.is_null());
+
+        let mut rejected = message_event(&topic, b"21", 1);
+        let rc = unsafe { stub_ffi::fire_event(MosquittoPluginEvent::MosqEvtMessage as _, &mut rejected as *mut _ as *mut c_void) };
+        assert_eq!(rc, mosq_err_t_MOSQ_ERR_ACL_DENIED);
+        let reason = unsafe { std::ffi::CStr::from_ptr(rejected.reason_string) };
+        assert_eq!(reason.to_str(), Ok("payload is not json"));
+        unsafe {
+            mosquitto_free(rejected.reason_string as *mut c_void);
+            plugin_cleanup::<SchemaCheck>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Moves messages below redacted/, strips their payload unless it is "keep" and marks them
+    // with a property. Messages to "bad" get a topic with a wildcard.
+    struct Redactor;
+
+    impl MosquittoPlugin for Redactor {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Redactor
+        }
+
+        fn on_message_mut(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            message: MosquittoMessage,
+        ) -> Result<MessageRewrite, MessageVeto> {
+            if message.topic == "bad" {
+                return Ok(MessageRewrite::unchanged().with_topic("bad/#"));
+            }
+            if message.payload == b"keep" {
+                return Ok(MessageRewrite::unchanged());
+            }
+            Ok(MessageRewrite::unchanged()
+                .with_topic(&format!("redacted/{}", message.topic))
+                .with_payload(&b""[..])
+                .with_retain(false)
+                .with_properties(Properties::new().user_property("redacted", "true")))
+        }
+    }
+
+    #[test]
+    fn rewritten_messages_are_written_back_to_the_event() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Redactor>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let topic = std::ffi::CString::new("sensors/1").unwrap();
+
+        let mut kept = message_event(&topic, b"keep", 1);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut kept), 0);
+        assert_eq!(kept.topic as *const c_char, topic.as_ptr());
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+
+        let mut redacted = message_event(&topic, b"secret", 1);
+        redacted.retain = true;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut redacted), 0);
+        unsafe {
+            assert_eq!(std::ffi::CStr::from_ptr(redacted.topic).to_str(), Ok("redacted/sensors/1"));
+            assert!(redacted.payload.is_null());
+            assert_eq!(redacted.payloadlen, 0);
+            assert!(!redacted.retain);
+            assert_eq!(
+                stub_ffi::properties_of(redacted.properties),
+                vec![stub_ffi::StubProperty::StringPair(38, "redacted".to_string(), "true".to_string())]
+            );
+            // the broker owns the new buffers now
+            mosquitto_free(redacted.topic as *mut c_void);
+            mosquitto_property_free_all(&mut redacted.properties);
+        }
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+
+        let bad_topic = std::ffi::CString::new("bad").unwrap();
+        let mut bad = message_event(&bad_topic, b"x", 0);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut bad), Error::AclDenied as c_int);
+        assert_eq!(bad.topic as *const c_char, bad_topic.as_ptr());
+        assert!(warned("on_message: dropping message, can't rewrite it: Inval"));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        unsafe {
+            plugin_cleanup::<Redactor>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    struct Pruner {
+        retained: crate::retained::RetainedTopics,
+    }
+
+    impl MosquittoPlugin for Pruner {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Pruner { retained: crate::retained::RetainedTopics::new() }
+        }
+
+        fn retained_topics(&mut self) -> Option<&mut crate::retained::RetainedTopics> {
+            Some(&mut self.retained)
+        }
+    }
+
+    #[test]
+    fn retained_messages_are_tracked_for_deleting_by_pattern() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Pruner>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        for name in &["sensors/1/t", "sensors/2/t"] {
+            let topic = std::ffi::CString::new(*name).unwrap();
+            let mut retained = message_event(&topic, b"21", 0);
+            retained.retain = true;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut retained), 0);
+        }
+        let topic = std::ffi::CString::new("sensors/3/t").unwrap();
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut message_event(&topic, b"21", 0)), 0);
+
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Pruner>)).external_user_data };
+        assert_eq!(plugin.broker_delete_retained("sensors/+/t"), Ok(2));
+        assert!(plugin.retained.is_empty());
+        unsafe {
+            plugin_cleanup::<Pruner>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    #[cfg(mosquitto_2_1)]
+    struct Presence {
+        clients: crate::clients::ConnectedClients,
+    }
+
+    #[cfg(mosquitto_2_1)]
+    impl MosquittoPlugin for Presence {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Presence { clients: crate::clients::ConnectedClients::new() }
+        }
+
+        fn connected_clients(&mut self) -> Option<&mut crate::clients::ConnectedClients> {
+            Some(&mut self.clients)
+        }
+    }
+
+    #[cfg(mosquitto_2_1)]
+    #[test]
+    fn connected_clients_follow_connect_subscribe_and_disconnect() {
+        stub_ffi::reset();
+        stub_ffi::set_username(Some("alice"));
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Presence>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let mut connect: mosquitto_evt_connect = unsafe { std::mem::zeroed() };
+        connect.client = STUB_CLIENT;
+        assert_eq!(unsafe { stub_ffi::fire_event(mosquitto_plugin_event_MOSQ_EVT_CONNECT as c_int, &mut connect as *mut _ as *mut c_void) }, 0);
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Presence>)).external_user_data };
+        let online: Vec<_> = plugin.clients.with_username("alice").map(|c| c.client_id.clone()).collect();
+        assert_eq!(online, vec!["stub-client".to_string()]);
+
+        let filter = std::ffi::CString::new("sensors/#").unwrap();
+        let mut subscribe: mosquitto_evt_subscribe = unsafe { std::mem::zeroed() };
+        subscribe.client = STUB_CLIENT;
+        subscribe.data.topic_filter = filter.as_ptr() as *mut c_char;
+        subscribe.data.options = 0x01;
+        assert_eq!(unsafe { stub_ffi::fire_event(mosquitto_plugin_event_MOSQ_EVT_SUBSCRIBE as c_int, &mut subscribe as *mut _ as *mut c_void) }, 0);
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Presence>)).external_user_data };
+        let subscriptions: Vec<_> = plugin.clients.get("stub-client").unwrap().subscriptions().map(|(f, qos)| (f.to_string(), qos)).collect();
+        assert_eq!(subscriptions, vec![("sensors/#".to_string(), 1)]);
+        let mut unsubscribe: mosquitto_evt_unsubscribe = unsafe { std::mem::zeroed() };
+        unsubscribe.client = STUB_CLIENT;
+        unsubscribe.data.topic_filter = filter.as_ptr() as *mut c_char;
+        assert_eq!(unsafe { stub_ffi::fire_event(mosquitto_plugin_event_MOSQ_EVT_UNSUBSCRIBE as c_int, &mut unsubscribe as *mut _ as *mut c_void) }, 0);
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Presence>)).external_user_data };
+        assert_eq!(plugin.clients.get("stub-client").unwrap().subscription_count(), 0);
+
+        let mut disconnect: mosquitto_evt_disconnect = unsafe { std::mem::zeroed() };
+        disconnect.client = STUB_CLIENT;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Presence>)).external_user_data };
+        assert!(plugin.clients.is_empty());
+        unsafe {
+            plugin_cleanup::<Presence>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Neither checks logins nor ACLs, only follows the clients
+    #[cfg(not(mosquitto_2_1))]
+    struct Watcher {
+        clients: crate::clients::ConnectedClients,
+        takeovers: Vec<usize>,
+    }
+
+    #[cfg(not(mosquitto_2_1))]
+    impl MosquittoPlugin for Watcher {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Watcher { clients: crate::clients::ConnectedClients::new(), takeovers: Vec::new() }
+        }
+
+        fn connected_clients(&mut self) -> Option<&mut crate::clients::ConnectedClients> {
+            Some(&mut self.clients)
+        }
+
+        fn on_session_takeover(&mut self, client: &dyn MosquittoClientContext, _takeover: &crate::clients::SessionTakeover) {
+            self.takeovers.push(client.connection_id());
+        }
+    }
+
+    #[cfg(not(mosquitto_2_1))]
+    #[test]
+    fn without_2_1_clients_are_followed_through_deferred_logins_and_acl_checks() {
+        stub_ffi::reset();
+        stub_ffi::set_username(Some("alice"));
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        // What #[mosquitto_plugin] generates for the two methods
+        let info = PluginInfo { name: None, version: None, callbacks: Callbacks::CONNECTED_CLIENTS.union(Callbacks::SESSION_TAKEOVER) };
+        unsafe {
+            plugin_init_with::<Watcher>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info);
+        }
+        assert_eq!(
+            stub_ffi::registered_events(),
+            vec![MosquittoPluginEvent::MosqEvtAclCheck as c_int, MosquittoPluginEvent::MosqEvtBasicAuth as c_int, MosquittoPluginEvent::MosqEvtDisconnect as c_int]
+        );
+        let watcher = || unsafe { &mut (*(user_data as *mut InternalUserData<Watcher>)).external_user_data };
+
+        let mut second = 0u8;
+        let (old, new) = (STUB_CLIENT, &mut second as *mut u8 as *mut mosquitto);
+        let mut auth: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        auth.client = old;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::PluginDefer as c_int);
+        let online: Vec<_> = watcher().clients.with_username("alice").map(|c| c.client_id.clone()).collect();
+        assert_eq!(online, vec!["stub-client".to_string()]);
+
+        let filter = CString::new("sensors/#").unwrap();
+        let mut check: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+        check.client = old;
+        check.topic = filter.as_ptr();
+        check.access = AccessLevel::Subscribe as c_int;
+        check.qos = 1;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut check), Error::PluginDefer as c_int);
+        let subscriptions: Vec<_> = watcher().clients.get("stub-client").unwrap().subscriptions().map(|(f, qos)| (f.to_string(), qos)).collect();
+        assert_eq!(subscriptions, vec![("sensors/#".to_string(), 1)]);
+        check.access = AccessLevel::Write as c_int;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut check), Error::PluginDefer as c_int);
+
+        // The same client id logs in again and takes the session over
+        auth.client = new;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::PluginDefer as c_int);
+        let mut disconnect: mosquitto_evt_disconnect = unsafe { std::mem::zeroed() };
+        disconnect.client = old;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        assert_eq!(watcher().takeovers, vec![old as usize]);
+        assert_eq!(watcher().clients.get("stub-client").unwrap().subscription_count(), 0);
+        disconnect.client = new;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        assert!(watcher().clients.is_empty());
+        unsafe {
+            plugin_cleanup::<Watcher>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Lets everyone but eve in and writes down the takeovers and disconnects it hears about
+    struct Sharing {
+        events: Vec<String>,
+    }
+
+    impl MosquittoPlugin for Sharing {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Sharing { events: Vec::new() }
+        }
+
+        fn username_password(&mut self, _client: &dyn MosquittoClientContext, username: Option<&str>, _password: Option<&str>) -> AuthDecision {
+            match username {
+                Some("eve") => AuthDecision::deny("shared credentials"),
+                _ => AuthDecision::Allow,
+            }
+        }
+
+        fn on_session_takeover(&mut self, client: &dyn MosquittoClientContext, takeover: &crate::clients::SessionTakeover) {
+            self.events.push(format!("takeover of {} by {:?} from {:?}", client.connection_id(), takeover.username, takeover.address));
+        }
+
+        fn on_disconnect(&mut self, client: &dyn MosquittoClientContext, reason: DisconnectReason) {
+            self.events.push(format!("disconnect of {}: {:?}", client.connection_id(), reason));
+        }
+    }
+
+    #[test]
+    fn takeovers_are_reported_before_the_disconnect_of_the_old_connection() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        let info = PluginInfo { name: None, version: None, callbacks: Callbacks::BASIC_AUTH.union(Callbacks::DISCONNECT) };
+        unsafe {
+            plugin_init_with::<Sharing>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info);
+        }
+        // Both connections use the client id of the stub
+        let mut second = 0u8;
+        let (old, new) = (STUB_CLIENT, &mut second as *mut u8 as *mut mosquitto);
+        let mut auth: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        auth.client = old;
+        stub_ffi::set_username(Some("alice"));
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), 0);
+        auth.client = new;
+        stub_ffi::set_username(Some("mallory"));
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), 0);
+
+        let mut disconnect: mosquitto_evt_disconnect = unsafe { std::mem::zeroed() };
+        disconnect.client = old;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        disconnect.client = new;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Sharing>)).external_user_data };
+        assert_eq!(
+            plugin.events,
+            vec![
+                format!("takeover of {} by Some(\"mallory\") from Some(127.0.0.1)", old as usize),
+                format!("disconnect of {}: SessionTakenOver", old as usize),
+                format!("disconnect of {}: Normal", new as usize),
+            ]
+        );
+        unsafe {
+            plugin_cleanup::<Sharing>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    #[test]
+    fn a_rejected_login_reusing_a_live_client_id_takes_nothing_over() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        let info = PluginInfo { name: None, version: None, callbacks: Callbacks::BASIC_AUTH.union(Callbacks::DISCONNECT) };
+        unsafe {
+            plugin_init_with::<Sharing>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info);
+        }
+        let mut second = 0u8;
+        let (live, rejected) = (STUB_CLIENT, &mut second as *mut u8 as *mut mosquitto);
+        let mut auth: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        auth.client = live;
+        stub_ffi::set_username(Some("alice"));
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), 0);
+        auth.client = rejected;
+        let eve = CString::new("eve").unwrap();
+        auth.username = eve.as_ptr() as *mut c_char;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::Auth as c_int);
+
+        let mut disconnect: mosquitto_evt_disconnect = unsafe { std::mem::zeroed() };
+        disconnect.client = rejected;
+        disconnect.reason = 7;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        disconnect.client = live;
+        disconnect.reason = 0;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        let plugin = unsafe { &mut (*(user_data as *mut InternalUserData<Sharing>)).external_user_data };
+        assert_eq!(
+            plugin.events,
+            vec![
+                format!("disconnect of {}: ConnectionLost", rejected as usize),
+                format!("disconnect of {}: Normal", live as usize),
+            ]
+        );
+        unsafe {
+            plugin_cleanup::<Sharing>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Connection policies only, the logins are up to the broker
+    struct Policies;
+
+    impl MosquittoPlugin for Policies {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Policies
+        }
+
+        fn on_connect(&mut self, _client: &dyn MosquittoClientContext, connect: &ConnectInfo) -> AuthDecision {
+            if connect.keepalive > 3600 {
+                return AuthDecision::deny(format!("keepalive of {}s", connect.keepalive));
+            }
+            if !connect.clean_start && connect.username.is_none() {
+                return AuthDecision::deny("anonymous clients get no persistent session");
+            }
+            AuthDecision::Allow
+        }
+    }
+
+    #[test]
+    fn on_connect_refuses_before_the_login() {
+        const DEFER: c_int = mosq_err_t_MOSQ_ERR_PLUGIN_DEFER as c_int;
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        let info = PluginInfo { name: None, version: None, callbacks: Callbacks::CONNECT_CHECK };
+        unsafe {
+            plugin_init_with::<Policies>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info);
+        }
+        assert_eq!(stub_ffi::registered_events(), vec![MosquittoPluginEvent::MosqEvtBasicAuth as c_int]);
+        let mut auth: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        auth.client = STUB_CLIENT;
+        // passing on_connect leaves the login to the broker rather than letting the client in
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), DEFER);
+        stub_ffi::set_keepalive(7200);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), AUTH);
+        stub_ffi::set_keepalive(60);
+        stub_ffi::set_clean_session(false);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), AUTH);
+        let denied: Vec<_> = stub_ffi::logged().into_iter().filter(|(level, _)| *level == MOSQ_LOG_DEBUG as c_int).map(|(_, line)| line).filter(|line| line.starts_with("on_connect")).collect();
+        assert_eq!(
+            denied,
+            vec![
+                "on_connect: denied stub-client: keepalive of 7200s".to_string(),
+                "on_connect: denied stub-client: anonymous clients get no persistent session".to_string(),
+            ]
+        );
+        unsafe {
+            plugin_cleanup::<Policies>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // A challenge/response over the made up method "EXAMPLE"
+    struct Challenge;
+
+    impl MosquittoPlugin for Challenge {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Challenge
+        }
+
+        fn ext_auth_start(&mut self, _client: &dyn MosquittoClientContext, method: &str, data: &[u8]) -> AuthStep {
+            match (method, data) {
+                ("EXAMPLE", b"hello") => AuthStep::Continue(b"challenge".to_vec()),
+                ("EXAMPLE", _) => AuthStep::Reject,
+                _ => AuthStep::Defer,
+            }
+        }
+
+        fn ext_auth_continue(&mut self, _client: &dyn MosquittoClientContext, _method: &str, data: &[u8]) -> AuthStep {
+            if data == b"response" {
+                AuthStep::Accept(Some(b"welcome".to_vec()))
+            } else {
+                AuthStep::Reject
+            }
+        }
+    }
+
+    #[test]
+    fn extended_auth_exchanges_data() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Challenge>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let example = std::ffi::CString::new("EXAMPLE").unwrap();
+        let auth_event = |method: &std::ffi::CStr, data: &[u8]| {
+            let mut event: mosquitto_evt_extended_auth = unsafe { std::mem::zeroed() };
+            event.client = STUB_CLIENT;
+            event.auth_method = method.as_ptr();
+            event.data_in = data.as_ptr() as *const c_void;
+            event.data_in_len = data.len() as u16;
+            event
+        };
+        // Takes the data the plugin handed to the broker
+        let data_out = |event: &mosquitto_evt_extended_auth| unsafe {
+            let data = std::slice::from_raw_parts(event.data_out as *const u8, event.data_out_len as usize).to_vec();
+            mosquitto_free(event.data_out);
+            data
+        };
+
+        let mut start = auth_event(&example, b"hello");
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtExtAuthStart, &mut start), Error::AuthContinue as c_int);
+        assert_eq!(data_out(&start), b"challenge");
+
+        let mut answer = auth_event(&example, b"response");
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtExtAuthContinue, &mut answer), 0);
+        assert_eq!(data_out(&answer), b"welcome");
+
+        let mut wrong = auth_event(&example, b"guess");
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtExtAuthContinue, &mut wrong), Error::Auth as c_int);
+        assert!(wrong.data_out.is_null());
+
+        let other = std::ffi::CString::new("SCRAM-SHA-256").unwrap();
+        let mut deferred = auth_event(&other, b"");
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtExtAuthStart, &mut deferred), Error::PluginDefer as c_int);
+
+        let mut no_method = auth_event(&example, b"hello");
+        no_method.auth_method = std::ptr::null();
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtExtAuthStart, &mut no_method), Error::Auth as c_int);
+        assert!(warned("ext_auth_start: ignoring event with a null auth method"));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        unsafe {
+            plugin_cleanup::<Challenge>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Answers "ping" on its own control topic, "bad" is no valid control topic
+    struct Admin;
+
+    impl MosquittoPlugin for Admin {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Admin
+        }
+
+        fn control_topics(&self) -> Vec<String> {
+            vec!["$CONTROL/admin/v1".to_string(), "bad".to_string()]
+        }
+
+        fn on_control_command(&mut self, _client: &dyn MosquittoClientContext, message: MosquittoMessage) -> ControlResponse {
+            if message.payload == b"ping" {
+                ControlResponse::reply("pong")
+            } else {
+                ControlResponse::None
+            }
+        }
+    }
+
+    #[test]
+    fn control_commands_are_answered_on_the_response_topic() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Admin>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        assert_eq!(stub_ffi::control_topics(), vec!["$CONTROL/admin/v1"]);
+        assert!(stub_ffi::logged().contains(&(MOSQ_LOG_ERR as c_int, "Can't register control topic \"bad\": error 3".to_string())));
+
+        let topic = std::ffi::CString::new("$CONTROL/admin/v1").unwrap();
+        let command = |payload: &'static [u8]| {
+            let mut event: mosquitto_evt_control = unsafe { std::mem::zeroed() };
+            event.client = STUB_CLIENT;
+            event.topic = topic.as_ptr();
+            event.payload = payload.as_ptr() as *const c_void;
+            event.payloadlen = payload.len() as u32;
+            event
+        };
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtControl, &mut command(b"ping")), 0);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtControl, &mut command(b"other")), 0);
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].client_id.as_deref(), Some("stub-client"));
+        assert_eq!(published[0].topic, "$CONTROL/admin/v1/response");
+        assert_eq!(published[0].payload, b"pong");
+
+        unsafe {
+            plugin_cleanup::<Admin>(user_data, std::ptr::null_mut(), 0);
+        }
+        assert!(stub_ffi::control_topics().is_empty());
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    // Leaves the decision to a delayed_auth::AuthCompletion, which is tested with that module
+    #[cfg(mosquitto_2_1)]
+    struct SlowBackend;
+
+    #[cfg(mosquitto_2_1)]
+    impl MosquittoPlugin for SlowBackend {
+        fn init(_opts: MosquittoOpt) -> Self {
+            SlowBackend
+        }
+
+        fn username_password(&mut self, _client: &dyn MosquittoClientContext, _username: Option<&str>, _password: Option<&str>) -> AuthDecision {
+            AuthDecision::Error(Error::AuthDelayed)
+        }
+    }
+
+    #[cfg(mosquitto_2_1)]
+    #[test]
+    fn delayed_basic_auth_needs_the_tick() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        let info = PluginInfo { name: None, version: None, callbacks: Callbacks::BASIC_AUTH };
+        unsafe {
+            plugin_init_with::<SlowBackend>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info);
+        }
+        assert!(stub_ffi::registered_events().contains(&(MosquittoPluginEvent::MosqEvtTick as c_int)));
+        let mut event: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        event.client = STUB_CLIENT;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut event), mosq_err_t_MOSQ_ERR_AUTH_DELAYED as c_int);
+        unsafe {
+            plugin_cleanup::<SlowBackend>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Keeps every client below tenant/, with at most qos 1
+    #[cfg(mosquitto_2_1)]
+    struct Tenants;
+
+    #[cfg(mosquitto_2_1)]
+    impl MosquittoPlugin for Tenants {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Tenants
+        }
+
+        fn on_subscribe(&mut self, _client: &dyn MosquittoClientContext, subscription: &mut Subscription) -> Result<Success, Error> {
+            if subscription.topic_filter.starts_with("$SYS") {
+                return Err(Error::AclDenied);
+            }
+            subscription.topic_filter = format!("tenant/{}", subscription.topic_filter);
+            subscription.qos = subscription.qos.min(1);
+            Ok(Success)
+        }
+
+        fn on_unsubscribe(&mut self, _client: &dyn MosquittoClientContext, topic_filter: &mut String) -> Result<Success, Error> {
+            *topic_filter = format!("tenant/{}", topic_filter);
+            Ok(Success)
+        }
+    }
+
+    #[cfg(mosquitto_2_1)]
+    #[test]
+    fn subscriptions_are_rewritten() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Tenants>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let subscribe = mosquitto_plugin_event_MOSQ_EVT_SUBSCRIBE as c_int;
+        let unsubscribe = mosquitto_plugin_event_MOSQ_EVT_UNSUBSCRIBE as c_int;
+        assert!(stub_ffi::registered_events().contains(&subscribe));
+
+        let filter = std::ffi::CString::new("sensors/+").unwrap();
+        let mut event: mosquitto_evt_subscribe = unsafe { std::mem::zeroed() };
+        event.client = STUB_CLIENT;
+        event.data.topic_filter = filter.as_ptr() as *mut c_char;
+        // qos 2, no local
+        event.data.options = 0x06;
+        assert_eq!(unsafe { stub_ffi::fire_event(subscribe, &mut event as *mut _ as *mut c_void) }, 0);
+        assert_eq!(unsafe { std::ffi::CStr::from_ptr(event.data.topic_filter) }.to_str(), Ok("tenant/sensors/+"));
+        assert_eq!(event.data.options, 0x05);
+        unsafe { mosquitto_free(event.data.topic_filter as *mut c_void) };
+
+        let sys = std::ffi::CString::new("$SYS/#").unwrap();
+        event.data.topic_filter = sys.as_ptr() as *mut c_char;
+        assert_eq!(unsafe { stub_ffi::fire_event(subscribe, &mut event as *mut _ as *mut c_void) }, DENIED);
+        assert_eq!(event.data.topic_filter as *const c_char, sys.as_ptr());
+
+        let mut event: mosquitto_evt_unsubscribe = unsafe { std::mem::zeroed() };
+        event.client = STUB_CLIENT;
+        event.data.topic_filter = filter.as_ptr() as *mut c_char;
+        assert_eq!(unsafe { stub_ffi::fire_event(unsubscribe, &mut event as *mut _ as *mut c_void) }, 0);
+        assert_eq!(unsafe { std::ffi::CStr::from_ptr(event.data.topic_filter) }.to_str(), Ok("tenant/sensors/+"));
+        unsafe { mosquitto_free(event.data.topic_filter as *mut c_void) };
+
+        event.data.topic_filter = std::ptr::null_mut();
+        assert_eq!(unsafe { stub_ffi::fire_event(unsubscribe, &mut event as *mut _ as *mut c_void) }, DENIED);
+        assert!(warned("on_unsubscribe: ignoring event with a null topic filter"));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        unsafe {
+            plugin_cleanup::<Tenants>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Knows one user and the topics below its own prefix, everything else is left to the
+    // other plugins and the broker configuration. Users in the database it can't reach fail.
+    struct Chained;
+
+    impl MosquittoPlugin for Chained {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Chained
+        }
+
+        fn username_password(&mut self, _client: &dyn MosquittoClientContext, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+            match (username, password) {
+                (Some("service"), Some("secret")) => AuthDecision::Allow,
+                (Some("service"), _) => AuthDecision::deny("wrong password for service"),
+                (Some(user), _) if user.starts_with("db-") => AuthDecision::Error(Error::Unknown),
+                _ => AuthDecision::Defer,
+            }
+        }
+
+        fn acl_check(&mut self, _client: &dyn MosquittoClientContext, _level: AclCheckAccessLevel, msg: MosquittoMessage) -> AuthDecision {
+            if msg.topic.starts_with("service/") {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::Defer
+            }
+        }
+    }
+
+    #[test]
+    fn auth_decisions_reach_the_broker() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Chained>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        const DEFER: c_int = mosq_err_t_MOSQ_ERR_PLUGIN_DEFER as c_int;
+
+        let (service, secret, other, db) = (std::ffi::CString::new("service").unwrap(), std::ffi::CString::new("secret").unwrap(), std::ffi::CString::new("alice").unwrap(), std::ffi::CString::new("db-bob").unwrap());
+        let mut auth: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        auth.client = STUB_CLIENT;
+        auth.username = service.as_ptr() as *mut c_char;
+        auth.password = secret.as_ptr() as *mut c_char;
+        // Lines logged by the callbacks, after the ones from init
+        let at_init = stub_ffi::logged().len();
+        let logged = || stub_ffi::logged().split_off(at_init);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), 0);
+        assert!(logged().is_empty());
+        auth.password = other.as_ptr() as *mut c_char;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), AUTH);
+        assert_eq!(logged(), vec![(MOSQ_LOG_DEBUG as c_int, "username_password: denied stub-client: wrong password for service".to_string())]);
+        auth.username = other.as_ptr() as *mut c_char;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), DEFER);
+        auth.username = db.as_ptr() as *mut c_char;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::Unknown as c_int);
+        assert_eq!(logged()[1], (MOSQ_LOG_ERR as c_int, format!("username_password: failed for stub-client: {}", Error::Unknown)));
+
+        let (own, foreign) = (std::ffi::CString::new("service/jobs").unwrap(), std::ffi::CString::new("sensors/1").unwrap());
+        let mut acl: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+        acl.client = STUB_CLIENT;
+        acl.access = MOSQ_ACL_WRITE as c_int;
+        acl.topic = own.as_ptr();
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut acl), 0);
+        acl.topic = foreign.as_ptr();
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut acl), DEFER);
+        acl.access = MOSQ_ACL_SUBSCRIBE as c_int;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut acl), DEFER);
+        unsafe {
+            plugin_cleanup::<Chained>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    struct PskKeys;
+
+    impl MosquittoPlugin for PskKeys {
+        fn init(_opts: MosquittoOpt) -> Self {
+            PskKeys
+        }
+
+        fn psk_key(&mut self, _client: &dyn MosquittoClientContext, identity: &str, hint: &str) -> Option<String> {
+            match (identity, hint) {
+                ("device-1", "gateway") => Some("deadbeef".to_string()),
+                ("not-hex", _) => Some("secret".to_string()),
+                ("too-long", _) => Some("ab".repeat(8)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn psk_keys_are_copied_into_the_broker_buffer() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<PskKeys>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let hint = std::ffi::CString::new("gateway").unwrap();
+        let mut key = [0 as c_char; 16];
+        let mut lookup = |identity: &str| {
+            key = [0; 16];
+            let identity = std::ffi::CString::new(identity).unwrap();
+            let mut event: mosquitto_evt_psk_key = unsafe { std::mem::zeroed() };
+            event.client = STUB_CLIENT;
+            event.hint = hint.as_ptr();
+            event.identity = identity.as_ptr();
+            event.key = key.as_mut_ptr();
+            event.max_key_len = key.len() as c_int;
+            let rc = fire(MosquittoPluginEvent::MosqEvtPskKey, &mut event);
+            let key = unsafe { std::ffi::CStr::from_ptr(key.as_ptr()) };
+            (rc, key.to_str().unwrap().to_string())
+        };
+
+        assert_eq!(lookup("device-1"), (0, "deadbeef".to_string()));
+        assert_eq!(lookup("not-hex"), (Error::Auth as c_int, String::new()));
+        assert!(warned("on_psk: refusing not-hex, the key is not hex"));
+        // 16 hex digits and the NUL don't fit into 16 bytes
+        assert_eq!(lookup("too-long"), (Error::Auth as c_int, String::new()));
+        assert!(warned("on_psk: refusing too-long, the key doesn't fit into 16 bytes"));
+        // unknown identities are left to the psk_file of the broker
+        assert_eq!(lookup("device-2"), (Error::PluginDefer as c_int, String::new()));
+        unsafe {
+            plugin_cleanup::<PskKeys>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    #[test]
+    fn init_logs_options_without_credentials() {
+        stub_ffi::reset();
+        let strings = [
+            (std::ffi::CString::new("plugin_opt_db_password").unwrap(), std::ffi::CString::new("hunter2").unwrap()),
+            (std::ffi::CString::new("auth_opt_host").unwrap(), std::ffi::CString::new("localhost").unwrap()),
+        ];
+        let mut opts: Vec<mosquitto_opt> = strings
+            .iter()
+            .map(|(k, v)| mosquitto_opt { key: k.as_ptr() as *mut _, value: v.as_ptr() as *mut _ })
+            .collect();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<SchemaCheck>(&mut id as *mut u8 as *mut c_void, &mut user_data, opts.as_mut_ptr(), opts.len() as c_int);
+            plugin_cleanup::<SchemaCheck>(user_data, std::ptr::null_mut(), 0);
+        }
+        let logged = stub_ffi::logged();
+        assert_eq!(
+            logged[0],
+            (MOSQ_LOG_DEBUG as c_int, "mosquitto_plugin_init options: db_password=<redacted>, host=localhost".to_string())
+        );
+    }
+
+    #[test]
+    fn reload_delivers_the_new_options() {
+        with_recorder(|| {
+            let strings = [
+                (std::ffi::CString::new("plugin_opt_db_password").unwrap(), std::ffi::CString::new("hunter3").unwrap()),
+                (std::ffi::CString::new("auth_opt_host").unwrap(), std::ffi::CString::new("db.local").unwrap()),
+            ];
+            let mut opts: Vec<mosquitto_opt> = strings
+                .iter()
+                .map(|(k, v)| mosquitto_opt { key: k.as_ptr() as *mut _, value: v.as_ptr() as *mut _ })
+                .collect();
+            let mut reload: mosquitto_evt_reload = unsafe { std::mem::zeroed() };
+            reload.options = opts.as_mut_ptr();
+            reload.option_count = opts.len() as c_int;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtReload, &mut reload), 0);
+            assert_eq!(calls(), vec![r#"reload [("db_password", "hunter3"), ("host", "db.local")]"#]);
+            assert!(stub_ffi::logged().contains(&(MOSQ_LOG_DEBUG as c_int, "on_reload options: db_password=<redacted>, host=db.local".to_string())));
+        });
+    }
+
+    #[test]
+    fn negotiates_highest_common_plugin_version() {
+        let v5 = MOSQ_PLUGIN_VERSION as i32;
+        assert_eq!(negotiate_plugin_version(&[v5, 4]), v5);
+        assert_eq!(negotiate_plugin_version(&[4, v5, 6]), v5);
+        assert_eq!(negotiate_plugin_version(&[2, 3, 4]), -1);
+        assert_eq!(negotiate_plugin_version(&[]), -1);
+        let versions = [4, v5];
+        assert_eq!(unsafe { plugin_version(versions.len() as c_int, versions.as_ptr()) }, v5);
+        assert_eq!(unsafe { plugin_version(2, std::ptr::null()) }, -1);
+    }
+
+    struct VersionCheck {
+        broker_version: BrokerVersion,
+    }
+
+    impl MosquittoPlugin for VersionCheck {
+        fn init(_opts: MosquittoOpt) -> Self {
+            unreachable!("init_with_context is implemented")
+        }
+
+        fn init_with_context(_opts: MosquittoOpt, context: &PluginContext) -> Self {
+            assert_eq!(raw::PluginHandle::current(), Some(context.handle));
+            VersionCheck {
+                broker_version: context.broker_version,
+            }
+        }
+    }
+
+    #[test]
+    fn init_gets_the_broker_version() {
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<VersionCheck>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+            let plugin = &*(user_data as *mut InternalUserData<VersionCheck>);
+            let version = plugin.external_user_data.broker_version;
+            assert_eq!(version.to_string(), HEADERS_VERSION);
+            assert!(version.at_least(2, 0));
+            assert_eq!(version.at_least(2, 1), cfg!(mosquitto_2_1));
+            plugin_cleanup::<VersionCheck>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Refuses to start without a backend option
+    struct Strict {
+        backend: String,
+    }
+
+    impl MosquittoPlugin for Strict {
+        fn init(_opts: MosquittoOpt) -> Self {
+            unreachable!("try_init is implemented")
+        }
+
+        fn try_init(opts: MosquittoOpt, _context: &PluginContext) -> Result<Self, InitError> {
+            Ok(Strict {
+                backend: crate::opts::opt(&opts, "backend")?,
+            })
+        }
+    }
+
+    #[test]
+    fn failing_init_refuses_to_start() {
+        stub_ffi::reset();
+        let info = PluginInfo { name: Some("strict"), version: None, callbacks: Callbacks::ALL };
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        let rc = unsafe { plugin_init_with::<Strict>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info) };
+        assert_eq!(rc, Error::Inval as c_int);
+        assert!(user_data.is_null());
+        assert!(stub_ffi::registered_events().is_empty());
+        assert_eq!(raw::PluginHandle::current(), None);
+        assert!(stub_ffi::logged().contains(&(MOSQ_LOG_ERR as c_int, "Plugin strict can't be initialized: option backend is required".to_string())));
+
+        let key = std::ffi::CString::new("plugin_opt_backend").unwrap();
+        let value = std::ffi::CString::new("ldap").unwrap();
+        let mut opts = [mosquitto_opt { key: key.as_ptr() as *mut _, value: value.as_ptr() as *mut _ }];
+        unsafe {
+            let rc = plugin_init_with::<Strict>(&mut id as *mut u8 as *mut c_void, &mut user_data, opts.as_mut_ptr(), 1, &info);
+            assert_eq!(rc, 0);
+            let plugin = &*(user_data as *mut InternalUserData<Strict>);
+            assert_eq!(plugin.external_user_data.backend, "ldap");
+            plugin_cleanup::<Strict>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Panics in every callback, on_panic too when it has no policy
+    struct Fragile {
+        policy: Option<PanicPolicy>,
+        panics: Vec<String>,
+    }
+
+    impl MosquittoPlugin for Fragile {
+        fn init(opts: MosquittoOpt) -> Self {
+            if opts.contains_key("broken") {
+                panic!("no config");
+            }
+            Fragile {
+                policy: Some(PanicPolicy::Deny),
+                panics: Vec::new(),
+            }
+        }
+
+        fn username_password(&mut self, _client: &dyn MosquittoClientContext, _username: Option<&str>, _password: Option<&str>) -> AuthDecision {
+            panic!("backend gone");
+        }
+
+        fn on_disconnect(&mut self, client: &dyn MosquittoClientContext, _reason: DisconnectReason) {
+            panic!("can't forget {}", client.get_id());
+        }
+
+        fn on_panic(&mut self, callback: &str, message: &str) -> PanicPolicy {
+            self.panics.push(format!("{}: {}", callback, message));
+            self.policy.expect("on_panic panicked as well")
+        }
+    }
+
+    #[test]
+    fn panics_are_caught_at_the_boundary() {
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Fragile>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let plugin = || unsafe { &mut (*(user_data as *mut InternalUserData<Fragile>)).external_user_data };
+        let mut auth: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+        auth.client = STUB_CLIENT;
+        let mut disconnect: mosquitto_evt_disconnect = unsafe { std::mem::zeroed() };
+        disconnect.client = STUB_CLIENT;
+
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::Auth as c_int);
+        assert!(stub_ffi::logged().contains(&(MOSQ_LOG_ERR as c_int, "username_password: plugin panicked: backend gone".to_string())));
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+        assert_eq!(plugin().panics, vec!["username_password: backend gone".to_string(), "on_disconnect: can't forget stub-client".to_string()]);
+
+        plugin().policy = Some(PanicPolicy::Defer);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::PluginDefer as c_int);
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+
+        // A panicking on_panic falls back to denying
+        plugin().policy = None;
+        assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut auth), Error::Auth as c_int);
+        unsafe {
+            plugin_cleanup::<Fragile>(user_data, std::ptr::null_mut(), 0);
+        }
+
+        let key = std::ffi::CString::new("plugin_opt_broken").unwrap();
+        let value = std::ffi::CString::new("yes").unwrap();
+        let mut opts = [mosquitto_opt { key: key.as_ptr() as *mut _, value: value.as_ptr() as *mut _ }];
+        let info = PluginInfo { name: Some("fragile"), version: None, callbacks: Callbacks::ALL };
+        let rc = unsafe { plugin_init_with::<Fragile>(&mut id as *mut u8 as *mut c_void, &mut user_data, opts.as_mut_ptr(), 1, &info) };
+        assert_eq!(rc, Error::Inval as c_int);
+        assert!(stub_ffi::logged().contains(&(MOSQ_LOG_ERR as c_int, "Plugin fragile can't be initialized: panicked: no config".to_string())));
+    }
+
+    #[test]
+    fn only_selected_callbacks_are_registered() {
+        stub_ffi::reset();
+        let info = PluginInfo {
+            name: Some("schema-check"),
+            version: Some("1.2.3"),
+            callbacks: Callbacks::MESSAGE.union(Callbacks::DISCONNECT),
+        };
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init_with::<SchemaCheck>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0, &info);
+        }
+        assert_eq!(
+            stub_ffi::registered_events(),
+            vec![MosquittoPluginEvent::MosqEvtMessage as c_int, MosquittoPluginEvent::MosqEvtDisconnect as c_int]
+        );
+        assert!(stub_ffi::logged().contains(&(MOSQ_LOG_INFO as c_int, format!("Plugin schema-check 1.2.3 loaded, built for mosquitto {}", HEADERS_VERSION))));
+        #[cfg(mosquitto_2_1)]
+        assert_eq!(stub_ffi::plugin_info(), Some(("schema-check".to_string(), Some("1.2.3".to_string()))));
+        unsafe {
+            plugin_cleanup::<SchemaCheck>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    #[test]
+    fn cleanup_calls_on_cleanup_before_dropping() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+        // on_cleanup sets the low digit, drop the next one, so the order shows up in the value
+        struct Saving;
+        impl MosquittoPlugin for Saving {
+            fn init(_opts: MosquittoOpt) -> Self {
+                Saving
+            }
+
+            fn on_cleanup(&mut self) {
+                EVENTS.store(EVENTS.load(Ordering::SeqCst) * 10 + 1, Ordering::SeqCst);
+            }
+        }
+        impl Drop for Saving {
+            fn drop(&mut self) {
+                EVENTS.store(EVENTS.load(Ordering::SeqCst) * 10 + 2, Ordering::SeqCst);
+            }
+        }
+
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Saving>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+            plugin_cleanup::<Saving>(user_data, std::ptr::null_mut(), 0);
+        }
+        assert_eq!(EVENTS.load(Ordering::SeqCst), 12);
+
+        // Nothing to clean up after a failed init
+        let rc = unsafe { plugin_cleanup::<Saving>(std::ptr::null_mut(), std::ptr::null_mut(), 0) };
+        assert_eq!(rc, 0);
+        assert_eq!(EVENTS.load(Ordering::SeqCst), 12);
+    }
+
+    #[test]
+    fn cleanup_unregisters_every_callback_before_on_cleanup() {
+        thread_local! {
+            static LEFT_AT_CLEANUP: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+        }
+
+        struct Everything;
+        impl MosquittoPlugin for Everything {
+            fn init(_opts: MosquittoOpt) -> Self {
+                Everything
+            }
+
+            fn control_topics(&self) -> Vec<String> {
+                vec!["$CONTROL/everything/v1".to_string()]
+            }
+
+            fn on_cleanup(&mut self) {
+                LEFT_AT_CLEANUP.with(|l| l.set(Some(stub_ffi::registered_events().len())));
+            }
+        }
+
+        stub_ffi::reset();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Everything>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        let registered = stub_ffi::registered_events();
+        assert_eq!(registered.len(), events::<Everything>(Callbacks::ALL).len() + 1);
+        assert!(registered.contains(&(MosquittoPluginEvent::MosqEvtReload as c_int)));
+        assert_eq!(stub_ffi::control_topics(), vec!["$CONTROL/everything/v1".to_string()]);
+        unsafe {
+            plugin_cleanup::<Everything>(user_data, std::ptr::null_mut(), 0);
+        }
+        assert_eq!(LEFT_AT_CLEANUP.with(|l| l.get()), Some(0));
+        assert!(stub_ffi::registered_events().is_empty());
+    }
+
+    thread_local! {
+        static CALLS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    thread_local! {
+        static TICKS: std::cell::RefCell<Vec<std::time::Instant>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn record(call: String) {
+        CALLS.with(|c| c.borrow_mut().push(call));
+    }
+
+    // The calls recorded since the last time
+    fn calls() -> Vec<String> {
+        CALLS.with(|c| c.borrow_mut().drain(..).collect())
+    }
+
+    // Writes down what each callback was called with and allows everything
+    struct Recorder;
+
+    impl MosquittoPlugin for Recorder {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Recorder
+        }
+
+        fn on_reload(&mut self, opts: MosquittoOpt) {
+            let mut keys: Vec<_> = opts.into_iter().collect();
+            keys.sort();
+            record(format!("reload {:?}", keys));
+        }
+
+        fn acl_check(&mut self, _client: &dyn MosquittoClientContext, level: AclCheckAccessLevel, msg: MosquittoMessage) -> AuthDecision {
+            record(format!("acl_check {:?} {} {:?}{}", level, msg.topic, msg.payload, describe(&msg.properties)));
+            AuthDecision::Allow
+        }
+
+        fn username_password(&mut self, _client: &dyn MosquittoClientContext, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+            record(format!("username_password {:?} {:?}", username, password));
+            AuthDecision::Allow
+        }
+
+        fn on_control(&mut self, _client: &dyn MosquittoClientContext, message: MosquittoMessage) {
+            record(format!("control {}", message.topic));
+        }
+
+        fn on_message(&mut self, _client: &dyn MosquittoClientContext, message: MosquittoMessage) {
+            record(format!("message {} {:?}{}", message.topic, message.payload, describe(&message.properties)));
+        }
+
+        fn on_psk(&mut self, _client: &dyn MosquittoClientContext, hint: &str, identity: &str, _key: &str, _max_key_len: i32) -> i32 {
+            record(format!("psk {:?} {}", hint, identity));
+            0
+        }
+
+        fn on_tick(&mut self, _now_ns: i64, _next_ns: i64, _now_s: i32, _next_s: i32) {
+            record("tick".to_string());
+        }
+
+        fn tick(&mut self, now: std::time::Instant) {
+            TICKS.with(|t| t.borrow_mut().push(now));
+        }
+
+        fn on_disconnect(&mut self, _client: &dyn MosquittoClientContext, reason: DisconnectReason) {
+            record(format!("disconnect {:?}", reason));
+        }
+    }
+
+    // Messages with properties are recorded with their content type and user properties
+    fn describe(properties: &MessageProperties) -> String {
+        if properties.is_empty() {
+            return String::new();
+        }
+        format!(" {:?} {:?}", properties.content_type(), properties.user_properties())
+    }
+
+    // Runs the test with a Recorder loaded into the stubbed broker
+    fn with_recorder(test: impl FnOnce()) {
+        stub_ffi::reset();
+        calls();
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            plugin_init::<Recorder>(&mut id as *mut u8 as *mut c_void, &mut user_data, std::ptr::null_mut(), 0);
+        }
+        test();
+        unsafe {
+            plugin_cleanup::<Recorder>(user_data, std::ptr::null_mut(), 0);
+        }
+    }
+
+    fn fire<E>(event: MosquittoPluginEvent, event_data: *mut E) -> c_int {
+        unsafe { stub_ffi::fire_event(event as c_int, event_data as *mut c_void) }
+    }
+
+    fn warned(what: &str) -> bool {
+        stub_ffi::logged().iter().any(|(level, line)| *level == MOSQ_LOG_WARNING as c_int && line.ends_with(what))
+    }
+
+    const DENIED: c_int = Error::AclDenied as c_int;
+    const AUTH: c_int = Error::Auth as c_int;
+    const NOT_UTF8: &[u8] = b"\xff\xfe\0";
+
+    #[test]
+    fn acl_check_with_malformed_events_is_denied() {
+        with_recorder(|| {
+            let topic = std::ffi::CString::new("a/b").unwrap();
+            let event = || {
+                let mut event: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+                event.client = STUB_CLIENT;
+                event.topic = topic.as_ptr();
+                event.access = MOSQ_ACL_WRITE as c_int;
+                event
+            };
+
+            // zero length payloads may come without a buffer
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut event()), 0);
+            assert_eq!(calls(), vec!["acl_check Write a/b []"]);
+
+            assert_eq!(fire::<mosquitto_evt_acl_check>(MosquittoPluginEvent::MosqEvtAclCheck, std::ptr::null_mut()), DENIED);
+            assert!(warned("acl_check: ignoring event with no event data"));
+
+            let mut no_topic = event();
+            no_topic.topic = std::ptr::null();
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut no_topic), DENIED);
+            assert!(warned("acl_check: ignoring event with a null topic"));
+
+            let mut bad_topic = event();
+            bad_topic.topic = NOT_UTF8.as_ptr() as *const c_char;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut bad_topic), DENIED);
+            assert!(warned("acl_check: ignoring event with a topic that isn't UTF-8"));
+
+            let mut bad_qos = event();
+            bad_qos.qos = 3;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut bad_qos), DENIED);
+            assert!(warned("acl_check: ignoring event with a qos other than 0, 1 or 2"));
+
+            let mut no_client = event();
+            no_client.client = std::ptr::null_mut();
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut no_client), DENIED);
+            assert!(warned("acl_check: ignoring event with no client"));
+
+            let mut missing_payload = event();
+            missing_payload.payloadlen = 5;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut missing_payload), DENIED);
+            assert!(warned("acl_check: ignoring event with a null payload of 5 bytes"));
+
+            assert!(calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe_checks_see_the_filter() {
+        with_recorder(|| {
+            let filter = std::ffi::CString::new("sensors/#").unwrap();
+            for access in [MOSQ_ACL_SUBSCRIBE, MOSQ_ACL_UNSUBSCRIBE, MOSQ_ACL_READ] {
+                let mut event: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+                event.client = STUB_CLIENT;
+                event.topic = filter.as_ptr();
+                event.access = access as c_int;
+                assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut event), 0);
+            }
+            assert_eq!(calls(), vec!["acl_check Subscribe sensors/# []", "acl_check Unsubscribe sensors/# []", "acl_check Read sensors/# []"]);
+        });
+    }
+
+    #[test]
+    fn basic_auth_without_credentials_reaches_the_plugin() {
+        with_recorder(|| {
+            let mut event: mosquitto_evt_basic_auth = unsafe { std::mem::zeroed() };
+            event.client = STUB_CLIENT;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut event), 0);
+
+            let username = std::ffi::CString::new("alice").unwrap();
+            event.username = username.as_ptr() as *mut c_char;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut event), 0);
+            assert_eq!(calls(), vec!["username_password None None", "username_password Some(\"alice\") None"]);
+
+            event.password = NOT_UTF8.as_ptr() as *mut c_char;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtBasicAuth, &mut event), AUTH);
+            assert_eq!(fire::<mosquitto_evt_basic_auth>(MosquittoPluginEvent::MosqEvtBasicAuth, std::ptr::null_mut()), AUTH);
+            assert!(calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn message_with_malformed_events_is_denied() {
+        with_recorder(|| {
+            let topic = std::ffi::CString::new("a/b").unwrap();
+            let mut empty = message_event(&topic, b"", 0);
+            empty.payload = std::ptr::null_mut();
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut empty), 0);
+            assert_eq!(calls(), vec!["message a/b []"]);
+
+            let mut missing_payload = empty;
+            missing_payload.payloadlen = 3;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut missing_payload), DENIED);
+
+            let mut no_topic = message_event(&topic, b"x", 0);
+            no_topic.topic = std::ptr::null_mut();
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut no_topic), DENIED);
+            assert!(warned("on_message: ignoring event with a null topic"));
+            assert!(calls().is_empty());
+        });
+    }
+
+    #[test]
+    fn properties_reach_acl_check_and_on_message() {
+        with_recorder(|| {
+            let list = crate::properties::Properties::new()
+                .content_type("text/plain")
+                .user_property("tenant", "a")
+                .to_list()
+                .unwrap();
+            let topic = std::ffi::CString::new("a/b").unwrap();
+
+            let mut acl: mosquitto_evt_acl_check = unsafe { std::mem::zeroed() };
+            acl.client = STUB_CLIENT;
+            acl.topic = topic.as_ptr();
+            acl.access = MOSQ_ACL_WRITE as c_int;
+            acl.properties = list.ptr;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtAclCheck, &mut acl), 0);
+
+            let mut message = message_event(&topic, b"x", 0);
+            message.properties = list.ptr;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtMessage, &mut message), 0);
+            assert_eq!(
+                calls(),
+                vec![
+                    "acl_check Write a/b [] Some(\"text/plain\") [(\"tenant\", \"a\")]",
+                    "message a/b [120] Some(\"text/plain\") [(\"tenant\", \"a\")]",
+                ]
+            );
+            drop(list);
+            assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        });
+    }
+
+    #[test]
+    fn ticks_come_with_the_time() {
+        with_recorder(|| {
+            let before = std::time::Instant::now();
+            let mut tick: mosquitto_evt_tick = unsafe { std::mem::zeroed() };
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtTick, &mut tick), 0);
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtTick, &mut tick), 0);
+            assert_eq!(calls(), vec!["tick", "tick"]);
+            let ticks = TICKS.with(|t| t.borrow_mut().drain(..).collect::<Vec<_>>());
+            assert_eq!(ticks.len(), 2);
+            assert!(before <= ticks[0] && ticks[0] <= ticks[1]);
+        });
+    }
+
+    #[test]
+    fn malformed_notifications_are_ignored() {
+        with_recorder(|| {
+            let mut control: mosquitto_evt_control = unsafe { std::mem::zeroed() };
+            control.client = STUB_CLIENT;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtControl, &mut control), 0);
+
+            let mut disconnect: mosquitto_evt_disconnect = unsafe { std::mem::zeroed() };
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtDisconnect, &mut disconnect), 0);
+            assert_eq!(fire::<mosquitto_evt_tick>(MosquittoPluginEvent::MosqEvtTick, std::ptr::null_mut()), 0);
+            assert!(calls().is_empty());
+
+            // a null option array is no options, whatever the count says
+            let mut reload: mosquitto_evt_reload = unsafe { std::mem::zeroed() };
+            reload.option_count = 3;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtReload, &mut reload), 0);
+            assert_eq!(calls(), vec!["reload []"]);
+        });
+    }
+
+    #[test]
+    fn psk_without_hint_reaches_the_plugin() {
+        with_recorder(|| {
+            let identity = std::ffi::CString::new("device-1").unwrap();
+            let mut key = [0 as c_char; 16];
+            let mut event: mosquitto_evt_psk_key = unsafe { std::mem::zeroed() };
+            event.client = STUB_CLIENT;
+            event.identity = identity.as_ptr();
+            event.key = key.as_mut_ptr();
+            event.max_key_len = key.len() as c_int;
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtPskKey, &mut event), 0);
+            assert_eq!(calls(), vec!["psk \"\" device-1"]);
+
+            event.identity = std::ptr::null();
+            assert_eq!(fire(MosquittoPluginEvent::MosqEvtPskKey, &mut event), AUTH);
+            assert!(calls().is_empty());
+        });
+    }
 }
@@ -0,0 +1,1315 @@
+// Clients, groups and roles managed at runtime, a replacement for the dynamic security plugin of
+// mosquitto enabled with the "dynsec" feature. It takes the same JSON commands, so mosquitto_ctrl
+// works unchanged:
+//
+//     mosquitto_ctrl -u admin dynsec createClient alice
+//     mosquitto_ctrl -u admin dynsec createRole sensors
+//     mosquitto_ctrl -u admin dynsec addRoleACL sensors publishClientSend sensors/%c/# allow
+//     mosquitto_ctrl -u admin dynsec addClientRole alice sensors
+//
+// The commands are published to $CONTROL/dynamic-security/v1 as `{"commands": [...]}` and
+// answered on $CONTROL/dynamic-security/v1/response. The plugin forwards to DynSec:
+//
+//     fn control_topics(&self) -> Vec<String> {
+//         vec![dynsec::CONTROL_TOPIC.to_string()]
+//     }
+//
+//     fn on_control_command(&mut self, _client: &dyn MosquittoClientContext, message: MosquittoMessage) -> ControlResponse {
+//         self.dynsec.on_control_command(message.payload)
+//     }
+//
+// and username_password and acl_check likewise. Commands are only accepted from clients allowed
+// to publish to the control topic, like the admin created from the dynsec_admin_username and
+// dynsec_admin_password options while there are no clients yet.
+//
+// The configuration is saved after every change through a StateStore named dynamic-security in
+// state_dir, inside it in the layout of dynamic-security.json, and files of mosquitto can be
+// brought over with import. Passwords are PBKDF2-SHA512 hashes as there.
+//
+// An ACL check goes through the roles of the client by priority, then through the roles of its
+// groups by the priority of the groups, and in each role through its ACLs by priority. The first
+// ACL of the type of the check whose topic covers the topic decides, for the literal types the
+// topics have to be the same. ACL topics may contain %c and %u. Without a matching ACL the
+// default access decides. Clients without a username get the roles of the anonymous group,
+// clients logged in by other plugins are deferred.
+use crate::acl::AclPattern;
+use crate::opts::{opt, OptError};
+use crate::passwd::{self, PasswordHash};
+use crate::state::{StateError, StateStore};
+use crate::topic::pattern_is_subset_of;
+use crate::{
+    mosquitto_calls, AclCheckAccessLevel, AuthDecision, ControlResponse, MosquittoClientContext,
+    MosquittoOpt,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The topic the commands are published to
+pub const CONTROL_TOPIC: &str = "$CONTROL/dynamic-security/v1";
+
+const STATE_NAME: &str = "dynamic-security";
+// For roles, groups and ACLs added without a priority, like in mosquitto
+const DEFAULT_PRIORITY: i64 = -1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclType {
+    /// Publishing
+    PublishClientSend,
+    /// Receiving messages
+    PublishClientReceive,
+    /// Subscribing to exactly the topic
+    SubscribeLiteral,
+    /// Subscribing to filters within the topic
+    SubscribePattern,
+    UnsubscribeLiteral,
+    UnsubscribePattern,
+}
+
+impl AclType {
+    const ALL: [AclType; 6] = [
+        AclType::PublishClientSend,
+        AclType::PublishClientReceive,
+        AclType::SubscribeLiteral,
+        AclType::SubscribePattern,
+        AclType::UnsubscribeLiteral,
+        AclType::UnsubscribePattern,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AclType::PublishClientSend => "publishClientSend",
+            AclType::PublishClientReceive => "publishClientReceive",
+            AclType::SubscribeLiteral => "subscribeLiteral",
+            AclType::SubscribePattern => "subscribePattern",
+            AclType::UnsubscribeLiteral => "unsubscribeLiteral",
+            AclType::UnsubscribePattern => "unsubscribePattern",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<AclType> {
+        AclType::ALL.iter().copied().find(|t| t.as_str() == s)
+    }
+
+    // The types deciding checks of the level, the literal ones first
+    fn for_level(level: AclCheckAccessLevel) -> &'static [AclType] {
+        match level {
+            AclCheckAccessLevel::Write => &[AclType::PublishClientSend],
+            AclCheckAccessLevel::Read => &[AclType::PublishClientReceive],
+            AclCheckAccessLevel::Subscribe => {
+                &[AclType::SubscribeLiteral, AclType::SubscribePattern]
+            }
+            AclCheckAccessLevel::Unsubscribe => {
+                &[AclType::UnsubscribeLiteral, AclType::UnsubscribePattern]
+            }
+        }
+    }
+
+    fn covers(self, acl_topic: &str, topic: &str) -> bool {
+        match self {
+            AclType::SubscribeLiteral | AclType::UnsubscribeLiteral => acl_topic == topic,
+            _ => pattern_is_subset_of(acl_topic, topic),
+        }
+    }
+}
+
+impl fmt::Display for AclType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoleAcl {
+    pub acl_type: AclType,
+    pub topic: String,
+    pub priority: i64,
+    pub allow: bool,
+}
+
+/// A role of a client or group, or a group of a client. Higher priorities are checked first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Membership {
+    pub name: String,
+    pub priority: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Client {
+    /// The only client id the client may connect with, any when None
+    pub client_id: Option<String>,
+    /// Clients without a password can't log in
+    pub password: Option<PasswordHash>,
+    pub disabled: bool,
+    pub text_name: Option<String>,
+    pub text_description: Option<String>,
+    pub roles: Vec<Membership>,
+    pub groups: Vec<Membership>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Group {
+    pub text_name: Option<String>,
+    pub text_description: Option<String>,
+    pub roles: Vec<Membership>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Role {
+    pub text_name: Option<String>,
+    pub text_description: Option<String>,
+    /// Sorted by priority, highest first
+    pub acls: Vec<RoleAcl>,
+}
+
+/// What is allowed when no ACL matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefaultAccess {
+    pub publish_client_send: bool,
+    pub publish_client_receive: bool,
+    pub subscribe: bool,
+    pub unsubscribe: bool,
+}
+
+/// Receiving and unsubscribing, like in mosquitto
+impl Default for DefaultAccess {
+    fn default() -> Self {
+        DefaultAccess {
+            publish_client_send: false,
+            publish_client_receive: true,
+            subscribe: false,
+            unsubscribe: true,
+        }
+    }
+}
+
+impl DefaultAccess {
+    fn allows(&self, level: AclCheckAccessLevel) -> bool {
+        match level {
+            AclCheckAccessLevel::Write => self.publish_client_send,
+            AclCheckAccessLevel::Read => self.publish_client_receive,
+            AclCheckAccessLevel::Subscribe => self.subscribe,
+            AclCheckAccessLevel::Unsubscribe => self.unsubscribe,
+        }
+    }
+
+    fn get_mut(&mut self, acl_type: &str) -> Option<&mut bool> {
+        match acl_type {
+            "publishClientSend" => Some(&mut self.publish_client_send),
+            "publishClientReceive" => Some(&mut self.publish_client_receive),
+            "subscribe" => Some(&mut self.subscribe),
+            "unsubscribe" => Some(&mut self.unsubscribe),
+            _ => None,
+        }
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "publishClientSend": self.publish_client_send,
+            "publishClientReceive": self.publish_client_receive,
+            "subscribe": self.subscribe,
+            "unsubscribe": self.unsubscribe,
+        })
+    }
+}
+
+/// The clients, groups and roles, by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub clients: BTreeMap<String, Client>,
+    pub groups: BTreeMap<String, Group>,
+    pub roles: BTreeMap<String, Role>,
+    pub anonymous_group: Option<String>,
+    pub default_access: DefaultAccess,
+}
+
+impl Config {
+    /// Reads the layout of dynamic-security.json, null is the empty configuration
+    pub fn from_json(json: &Value) -> Result<Config, String> {
+        let mut config = Config::default();
+        if json.is_null() {
+            return Ok(config);
+        }
+        if let Some(access) = json.get("defaultACLAccess").and_then(Value::as_object) {
+            for (acl_type, allow) in access {
+                if let (Some(slot), Some(allow)) =
+                    (config.default_access.get_mut(acl_type), allow.as_bool())
+                {
+                    *slot = allow;
+                }
+            }
+        }
+        for role in array(json, "roles") {
+            let mut acls = Vec::new();
+            for acl in array(role, "acls") {
+                add_acl(&mut acls, acl_from_json(acl)?);
+            }
+            config.roles.insert(
+                field(role, "rolename")?.to_string(),
+                Role {
+                    text_name: text(role, "textname"),
+                    text_description: text(role, "textdescription"),
+                    acls,
+                },
+            );
+        }
+        for client in array(json, "clients") {
+            let password = match text(client, "password") {
+                Some(hash) => Some(PasswordHash::Pbkdf2Sha512 {
+                    iterations: client
+                        .get("iterations")
+                        .and_then(Value::as_u64)
+                        .and_then(|i| u32::try_from(i).ok())
+                        .ok_or("Invalid/missing iterations")?,
+                    salt: decode(client, "salt")?,
+                    hash: STANDARD.decode(hash).map_err(|_| "Invalid password")?,
+                }),
+                None => None,
+            };
+            config.clients.insert(
+                field(client, "username")?.to_string(),
+                Client {
+                    client_id: text(client, "clientid"),
+                    password,
+                    disabled: client
+                        .get("disabled")
+                        .and_then(Value::as_bool)
+                        .unwrap_or(false),
+                    text_name: text(client, "textname"),
+                    text_description: text(client, "textdescription"),
+                    roles: memberships(client, "roles", "rolename")?,
+                    groups: Vec::new(),
+                },
+            );
+        }
+        for group in array(json, "groups") {
+            let name = field(group, "groupname")?;
+            for member in memberships(group, "clients", "username")? {
+                if let Some(client) = config.clients.get_mut(&member.name) {
+                    add_membership(&mut client.groups, name, member.priority);
+                }
+            }
+            config.groups.insert(
+                name.to_string(),
+                Group {
+                    text_name: text(group, "textname"),
+                    text_description: text(group, "textdescription"),
+                    roles: memberships(group, "roles", "rolename")?,
+                },
+            );
+        }
+        config.anonymous_group = text(json, "anonymousGroup");
+        Ok(config)
+    }
+
+    /// Writes the layout of dynamic-security.json
+    pub fn to_json(&self) -> Value {
+        let mut json = Map::new();
+        json.insert(
+            "defaultACLAccess".to_string(),
+            self.default_access.to_json(),
+        );
+        let clients = self.clients.keys().map(|name| self.client_json(name, true));
+        json.insert("clients".to_string(), clients.collect());
+        let groups = self.groups.keys().map(|name| self.group_json(name));
+        json.insert("groups".to_string(), groups.collect());
+        let roles = self.roles.keys().map(|name| self.role_json(name));
+        json.insert("roles".to_string(), roles.collect());
+        if let Some(group) = &self.anonymous_group {
+            json.insert("anonymousGroup".to_string(), group.as_str().into());
+        }
+        Value::Object(json)
+    }
+
+    // The file has the password of a client but not its groups, the groups list their clients
+    fn client_json(&self, username: &str, for_file: bool) -> Value {
+        let client = &self.clients[username];
+        let mut json = Map::new();
+        json.insert("username".to_string(), username.into());
+        insert_text(&mut json, "clientid", &client.client_id);
+        insert_text(&mut json, "textname", &client.text_name);
+        insert_text(&mut json, "textdescription", &client.text_description);
+        if client.disabled {
+            json.insert("disabled".to_string(), true.into());
+        }
+        json.insert(
+            "roles".to_string(),
+            memberships_json(&client.roles, "rolename"),
+        );
+        if for_file {
+            if let Some(PasswordHash::Pbkdf2Sha512 {
+                iterations,
+                salt,
+                hash,
+            }) = &client.password
+            {
+                json.insert("password".to_string(), STANDARD.encode(hash).into());
+                json.insert("salt".to_string(), STANDARD.encode(salt).into());
+                json.insert("iterations".to_string(), (*iterations).into());
+            }
+        } else {
+            json.insert(
+                "groups".to_string(),
+                memberships_json(&client.groups, "groupname"),
+            );
+        }
+        Value::Object(json)
+    }
+
+    fn group_json(&self, name: &str) -> Value {
+        let group = &self.groups[name];
+        let mut json = Map::new();
+        json.insert("groupname".to_string(), name.into());
+        insert_text(&mut json, "textname", &group.text_name);
+        insert_text(&mut json, "textdescription", &group.text_description);
+        json.insert(
+            "roles".to_string(),
+            memberships_json(&group.roles, "rolename"),
+        );
+        let clients = self.clients.iter().filter_map(|(username, client)| {
+            let member = client.groups.iter().find(|m| m.name == name)?;
+            Some(json!({"username": username, "priority": member.priority}))
+        });
+        json.insert("clients".to_string(), clients.collect());
+        Value::Object(json)
+    }
+
+    fn role_json(&self, name: &str) -> Value {
+        let role = &self.roles[name];
+        let mut json = Map::new();
+        json.insert("rolename".to_string(), name.into());
+        insert_text(&mut json, "textname", &role.text_name);
+        insert_text(&mut json, "textdescription", &role.text_description);
+        let acls = role.acls.iter().map(|acl| {
+            json!({
+                "acltype": acl.acl_type.as_str(),
+                "topic": acl.topic,
+                "priority": acl.priority,
+                "allow": acl.allow,
+            })
+        });
+        json.insert("acls".to_string(), acls.collect());
+        Value::Object(json)
+    }
+
+    fn client(&mut self, username: &str) -> Result<&mut Client, String> {
+        self.clients
+            .get_mut(username)
+            .ok_or_else(|| "Client not found".to_string())
+    }
+
+    fn group(&mut self, name: &str) -> Result<&mut Group, String> {
+        self.groups
+            .get_mut(name)
+            .ok_or_else(|| "Group not found".to_string())
+    }
+
+    fn role(&mut self, name: &str) -> Result<&mut Role, String> {
+        self.roles
+            .get_mut(name)
+            .ok_or_else(|| "Role not found".to_string())
+    }
+
+    // The roles deciding the checks of a client, in the order they are checked
+    fn roles_of<'a>(&'a self, roles: &[Membership], groups: &[Membership]) -> Vec<&'a Role> {
+        let group_roles = groups
+            .iter()
+            .filter_map(|group| self.groups.get(&group.name))
+            .flat_map(|group| &group.roles);
+        roles
+            .iter()
+            .chain(group_roles)
+            .filter_map(|role| self.roles.get(&role.name))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum DynSecError {
+    State(StateError),
+    Opt(OptError),
+    /// A configuration that can't be read, or a password that can't be hashed
+    Invalid(String),
+}
+
+impl fmt::Display for DynSecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DynSecError::State(e) => write!(f, "{}", e),
+            DynSecError::Opt(e) => write!(f, "{}", e),
+            DynSecError::Invalid(reason) => write!(f, "dynamic security: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DynSecError {}
+
+impl From<StateError> for DynSecError {
+    fn from(e: StateError) -> Self {
+        DynSecError::State(e)
+    }
+}
+
+impl From<OptError> for DynSecError {
+    fn from(e: OptError) -> Self {
+        DynSecError::Opt(e)
+    }
+}
+
+/// Decides logins and ACL checks with a Config, changed by the control commands
+#[derive(Debug)]
+pub struct DynSec {
+    config: Config,
+    store: Option<StateStore>,
+}
+
+impl DynSec {
+    /// Keeps the configuration in memory only
+    pub fn new(config: Config) -> DynSec {
+        DynSec {
+            config,
+            store: None,
+        }
+    }
+
+    /// Loads the configuration saved in the store, and saves every change back into it
+    pub fn load(store: StateStore) -> Result<DynSec, DynSecError> {
+        let json: Value = store.load()?;
+        let config = Config::from_json(&json).map_err(DynSecError::Invalid)?;
+        Ok(DynSec {
+            config,
+            store: Some(store),
+        })
+    }
+
+    /// Loads from state_dir, in memory without the option, and creates the admin from
+    /// dynsec_admin_username and dynsec_admin_password
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<DynSec, DynSecError> {
+        let dynsec = match StateStore::from_opts(opts, STATE_NAME) {
+            Some(store) => DynSec::load(store)?,
+            None => DynSec::new(Config::default()),
+        };
+        let username: Option<String> = opt(opts, "dynsec_admin_username")?;
+        match username {
+            Some(username) => {
+                let password: String = opt(opts, "dynsec_admin_password")?;
+                dynsec.with_admin(&username, &password)
+            }
+            None => Ok(dynsec),
+        }
+    }
+
+    /// Creates a client with the role admin, which may send commands and read $SYS, unless there
+    /// are clients already. An existing role admin is kept as it is.
+    pub fn with_admin(mut self, username: &str, password: &str) -> Result<Self, DynSecError> {
+        if !self.config.clients.is_empty() {
+            return Ok(self);
+        }
+        let acls = [
+            (AclType::PublishClientSend, "$CONTROL/dynamic-security/#"),
+            (AclType::PublishClientReceive, "$CONTROL/dynamic-security/#"),
+            (AclType::SubscribePattern, "$CONTROL/dynamic-security/#"),
+            (AclType::PublishClientReceive, "$SYS/#"),
+            (AclType::SubscribePattern, "$SYS/#"),
+            (AclType::UnsubscribePattern, "#"),
+        ];
+        if !self.config.roles.contains_key("admin") {
+            let mut role = Role::default();
+            for (acl_type, topic) in acls.iter() {
+                add_acl(
+                    &mut role.acls,
+                    RoleAcl {
+                        acl_type: *acl_type,
+                        topic: topic.to_string(),
+                        priority: 0,
+                        allow: true,
+                    },
+                );
+            }
+            self.config.roles.insert("admin".to_string(), role);
+        }
+        let mut client = Client {
+            password: Some(hash(password).map_err(DynSecError::Invalid)?),
+            ..Client::default()
+        };
+        add_membership(&mut client.roles, "admin", DEFAULT_PRIORITY);
+        self.config.clients.insert(username.to_string(), client);
+        self.save().map_err(DynSecError::Invalid)?;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replaces the configuration with a dynamic-security.json written by mosquitto
+    pub fn import(&mut self, json: &[u8]) -> Result<(), DynSecError> {
+        let json: Value =
+            serde_json::from_slice(json).map_err(|e| DynSecError::Invalid(e.to_string()))?;
+        self.config = Config::from_json(&json).map_err(DynSecError::Invalid)?;
+        self.save().map_err(DynSecError::Invalid)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.store {
+            Some(store) => store
+                .save(&self.config.to_json())
+                .map_err(|e| format!("Saving the configuration failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Allowed when the password is right, the client isn't disabled and uses the client id it
+    /// is restricted to. Usernames that aren't configured are deferred.
+    pub fn username_password(
+        &self,
+        client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let record = match username.and_then(|u| self.config.clients.get(u)) {
+            Some(record) => record,
+            None => return AuthDecision::Defer,
+        };
+        if record.disabled {
+            return AuthDecision::deny("client disabled");
+        }
+        if let Some(client_id) = &record.client_id {
+            if *client_id != client.get_id() {
+                return AuthDecision::deny("client id not allowed");
+            }
+        }
+        match (&record.password, password) {
+            (Some(hash), Some(password)) if passwd::verify(password, hash) => AuthDecision::Allow,
+            _ => AuthDecision::deny("wrong password"),
+        }
+    }
+
+    /// Decides the check with the roles of the client and the default access
+    pub fn acl_check(
+        &self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let username = client.get_username();
+        let roles = match &username {
+            Some(username) => match self.config.clients.get(username) {
+                Some(record) => self.config.roles_of(&record.roles, &record.groups),
+                None => return AuthDecision::Defer,
+            },
+            None => match &self.config.anonymous_group {
+                Some(group) => self.config.roles_of(
+                    &[],
+                    &[Membership {
+                        name: group.clone(),
+                        priority: 0,
+                    }],
+                ),
+                None => Vec::new(),
+            },
+        };
+        let client_id = client.get_id();
+        for role in roles {
+            for acl_type in AclType::for_level(level) {
+                for acl in role.acls.iter().filter(|acl| acl.acl_type == *acl_type) {
+                    let acl_topic = AclPattern::parse(&format!("readwrite {}", acl.topic))
+                        .ok()
+                        .and_then(|pattern| pattern.expand(&client_id, username.as_deref()));
+                    match acl_topic {
+                        Some(acl_topic) if acl_type.covers(&acl_topic, topic) => {
+                            return if acl.allow {
+                                AuthDecision::Allow
+                            } else {
+                                AuthDecision::deny("denied by a role of the client")
+                            };
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if self.config.default_access.allows(level) {
+            AuthDecision::Allow
+        } else {
+            AuthDecision::deny("denied by the default access")
+        }
+    }
+
+    /// Runs the commands of a message sent to CONTROL_TOPIC and replies with their responses
+    pub fn on_control_command(&mut self, payload: &[u8]) -> ControlResponse {
+        let responses: Vec<Value> = match serde_json::from_slice::<Value>(payload) {
+            Ok(json) => match json.get("commands").and_then(Value::as_array) {
+                Some(commands) => commands.iter().map(|c| self.execute(c)).collect(),
+                None => vec![unknown_command("Invalid/missing commands")],
+            },
+            Err(_) => vec![unknown_command("Payload not valid JSON")],
+        };
+        let reply = json!({ "responses": responses });
+        ControlResponse::reply(reply.to_string())
+    }
+
+    /// Runs a single command like `{"command": "createClient", "username": "alice"}` and
+    /// returns its response, which has an `error` when it failed
+    pub fn execute(&mut self, command: &Value) -> Value {
+        let name = command
+            .get("command")
+            .and_then(Value::as_str)
+            .unwrap_or("Unknown command");
+        let mut response = Map::new();
+        response.insert("command".to_string(), name.into());
+        let result = self.run(name, command).and_then(|data| {
+            if !name.starts_with("get") && !name.starts_with("list") {
+                self.save()?;
+            }
+            Ok(data)
+        });
+        match result {
+            Ok(Some(data)) => {
+                response.insert("data".to_string(), data);
+            }
+            Ok(None) => {}
+            Err(error) => {
+                response.insert("error".to_string(), error.into());
+            }
+        }
+        if let Some(correlation) = command.get("correlationData") {
+            response.insert("correlationData".to_string(), correlation.clone());
+        }
+        Value::Object(response)
+    }
+
+    fn run(&mut self, name: &str, cmd: &Value) -> Result<Option<Value>, String> {
+        let config = &mut self.config;
+        match name {
+            "createClient" => {
+                let username = field(cmd, "username")?;
+                if config.clients.contains_key(username) {
+                    return Err("Client already exists".to_string());
+                }
+                let mut client = Client {
+                    client_id: text(cmd, "clientid"),
+                    text_name: text(cmd, "textname"),
+                    text_description: text(cmd, "textdescription"),
+                    ..Client::default()
+                };
+                if let Some(password) = cmd.get("password").and_then(Value::as_str) {
+                    client.password = Some(hash(password)?);
+                }
+                for role in memberships(cmd, "roles", "rolename")? {
+                    config.role(&role.name)?;
+                    add_membership(&mut client.roles, &role.name, role.priority);
+                }
+                for group in memberships(cmd, "groups", "groupname")? {
+                    config.group(&group.name)?;
+                    add_membership(&mut client.groups, &group.name, group.priority);
+                }
+                config.clients.insert(username.to_string(), client);
+            }
+            "deleteClient" => {
+                let username = field(cmd, "username")?;
+                config.clients.remove(username).ok_or("Client not found")?;
+                kick(username);
+            }
+            "setClientPassword" => {
+                let username = field(cmd, "username")?;
+                let password = hash(field(cmd, "password")?)?;
+                config.client(username)?.password = Some(password);
+                kick(username);
+            }
+            "setClientId" => {
+                let username = field(cmd, "username")?;
+                config.client(username)?.client_id = text(cmd, "clientid");
+                kick(username);
+            }
+            "enableClient" | "disableClient" => {
+                let username = field(cmd, "username")?;
+                let disabled = name == "disableClient";
+                config.client(username)?.disabled = disabled;
+                if disabled {
+                    kick(username);
+                }
+            }
+            "addClientRole" => {
+                let username = field(cmd, "username")?;
+                let role = field(cmd, "rolename")?;
+                config.role(role)?;
+                add_membership(&mut config.client(username)?.roles, role, priority(cmd));
+            }
+            "removeClientRole" => {
+                let username = field(cmd, "username")?;
+                let role = field(cmd, "rolename")?;
+                config.role(role)?;
+                config.client(username)?.roles.retain(|m| m.name != role);
+            }
+            "getClient" => {
+                let username = field(cmd, "username")?;
+                config.client(username)?;
+                return Ok(Some(
+                    json!({ "client": config.client_json(username, false) }),
+                ));
+            }
+            "listClients" => {
+                let verbose = verbose(cmd);
+                let clients = config.clients.keys().map(|username| {
+                    if verbose {
+                        config.client_json(username, false)
+                    } else {
+                        username.as_str().into()
+                    }
+                });
+                return Ok(Some(list(cmd, "clients", clients.collect())));
+            }
+            "createGroup" => {
+                let group = field(cmd, "groupname")?;
+                if config.groups.contains_key(group) {
+                    return Err("Group already exists".to_string());
+                }
+                let roles = memberships(cmd, "roles", "rolename")?;
+                for role in &roles {
+                    config.role(&role.name)?;
+                }
+                config.groups.insert(
+                    group.to_string(),
+                    Group {
+                        text_name: text(cmd, "textname"),
+                        text_description: text(cmd, "textdescription"),
+                        roles,
+                    },
+                );
+            }
+            "deleteGroup" => {
+                let group = field(cmd, "groupname")?;
+                if config.anonymous_group.as_deref() == Some(group) {
+                    return Err("Deleting the anonymous group is forbidden".to_string());
+                }
+                config.groups.remove(group).ok_or("Group not found")?;
+                for client in config.clients.values_mut() {
+                    client.groups.retain(|m| m.name != group);
+                }
+            }
+            "addGroupClient" => {
+                let group = field(cmd, "groupname")?;
+                let username = field(cmd, "username")?;
+                config.group(group)?;
+                add_membership(&mut config.client(username)?.groups, group, priority(cmd));
+            }
+            "removeGroupClient" => {
+                let group = field(cmd, "groupname")?;
+                let username = field(cmd, "username")?;
+                config.group(group)?;
+                config.client(username)?.groups.retain(|m| m.name != group);
+            }
+            "addGroupRole" => {
+                let group = field(cmd, "groupname")?;
+                let role = field(cmd, "rolename")?;
+                config.role(role)?;
+                add_membership(&mut config.group(group)?.roles, role, priority(cmd));
+            }
+            "removeGroupRole" => {
+                let group = field(cmd, "groupname")?;
+                let role = field(cmd, "rolename")?;
+                config.role(role)?;
+                config.group(group)?.roles.retain(|m| m.name != role);
+            }
+            "getGroup" => {
+                let group = field(cmd, "groupname")?;
+                config.group(group)?;
+                return Ok(Some(json!({ "group": config.group_json(group) })));
+            }
+            "listGroups" => {
+                let verbose = verbose(cmd);
+                let groups = config.groups.keys().map(|group| {
+                    if verbose {
+                        config.group_json(group)
+                    } else {
+                        group.as_str().into()
+                    }
+                });
+                return Ok(Some(list(cmd, "groups", groups.collect())));
+            }
+            "setAnonymousGroup" => {
+                let group = field(cmd, "groupname")?;
+                config.group(group)?;
+                config.anonymous_group = Some(group.to_string());
+            }
+            "getAnonymousGroup" => {
+                let group = config.anonymous_group.as_deref().unwrap_or("");
+                return Ok(Some(json!({ "group": { "groupname": group } })));
+            }
+            "createRole" => {
+                let role = field(cmd, "rolename")?;
+                if config.roles.contains_key(role) {
+                    return Err("Role already exists".to_string());
+                }
+                let mut acls = Vec::new();
+                for acl in array(cmd, "acls") {
+                    add_acl(&mut acls, acl_from_json(acl)?);
+                }
+                config.roles.insert(
+                    role.to_string(),
+                    Role {
+                        text_name: text(cmd, "textname"),
+                        text_description: text(cmd, "textdescription"),
+                        acls,
+                    },
+                );
+            }
+            "deleteRole" => {
+                let role = field(cmd, "rolename")?;
+                config.roles.remove(role).ok_or("Role not found")?;
+                for client in config.clients.values_mut() {
+                    client.roles.retain(|m| m.name != role);
+                }
+                for group in config.groups.values_mut() {
+                    group.roles.retain(|m| m.name != role);
+                }
+            }
+            "addRoleACL" => {
+                let acl = acl_from_json(cmd)?;
+                let role = config.role(field(cmd, "rolename")?)?;
+                let exists = role
+                    .acls
+                    .iter()
+                    .any(|a| a.acl_type == acl.acl_type && a.topic == acl.topic);
+                if exists {
+                    return Err("ACL with this topic already exists".to_string());
+                }
+                add_acl(&mut role.acls, acl);
+            }
+            "removeRoleACL" => {
+                let acl_type = acl_type(cmd)?;
+                let topic = field(cmd, "topic")?;
+                let role = config.role(field(cmd, "rolename")?)?;
+                let before = role.acls.len();
+                role.acls
+                    .retain(|a| a.acl_type != acl_type || a.topic != topic);
+                if role.acls.len() == before {
+                    return Err("ACL not found".to_string());
+                }
+            }
+            "getRole" => {
+                let role = field(cmd, "rolename")?;
+                config.role(role)?;
+                return Ok(Some(json!({ "role": config.role_json(role) })));
+            }
+            "listRoles" => {
+                let verbose = verbose(cmd);
+                let roles = config.roles.keys().map(|role| {
+                    if verbose {
+                        config.role_json(role)
+                    } else {
+                        role.as_str().into()
+                    }
+                });
+                return Ok(Some(list(cmd, "roles", roles.collect())));
+            }
+            "setDefaultACLAccess" => {
+                let mut access = config.default_access;
+                for acl in array(cmd, "acls") {
+                    let slot = access
+                        .get_mut(field(acl, "acltype")?)
+                        .ok_or("Invalid acltype")?;
+                    *slot = acl.get("allow").and_then(Value::as_bool).unwrap_or(false);
+                }
+                config.default_access = access;
+            }
+            "getDefaultACLAccess" => {
+                let access = config.default_access;
+                let acls = [
+                    ("publishClientSend", access.publish_client_send),
+                    ("publishClientReceive", access.publish_client_receive),
+                    ("subscribe", access.subscribe),
+                    ("unsubscribe", access.unsubscribe),
+                ]
+                .iter()
+                .map(|(acl_type, allow)| json!({"acltype": acl_type, "allow": allow}))
+                .collect::<Vec<_>>();
+                return Ok(Some(json!({ "acls": acls })));
+            }
+            _ => return Err("Unknown command".to_string()),
+        }
+        Ok(None)
+    }
+}
+
+// Clients whose login is no longer valid are disconnected, like mosquitto does
+fn kick(username: &str) {
+    let _ = mosquitto_calls::kick_client_by_username(username, false);
+}
+
+fn hash(password: &str) -> Result<PasswordHash, String> {
+    passwd::hash_password(password, passwd::DEFAULT_ITERATIONS)
+        .and_then(|hash| passwd::parse_hash(&hash))
+        .map_err(|_| "Hashing the password failed".to_string())
+}
+
+fn unknown_command(error: &str) -> Value {
+    json!({"command": "Unknown command", "error": error})
+}
+
+fn field<'a>(json: &'a Value, key: &str) -> Result<&'a str, String> {
+    json.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("Invalid/missing {}", key))
+}
+
+fn text(json: &Value, key: &str) -> Option<String> {
+    json.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+fn decode(json: &Value, key: &str) -> Result<Vec<u8>, String> {
+    STANDARD
+        .decode(field(json, key)?)
+        .map_err(|_| format!("Invalid {}", key))
+}
+
+fn priority(json: &Value) -> i64 {
+    json.get("priority")
+        .and_then(Value::as_i64)
+        .unwrap_or(DEFAULT_PRIORITY)
+}
+
+fn verbose(json: &Value) -> bool {
+    json.get("verbose")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+fn array<'a>(json: &'a Value, key: &str) -> &'a [Value] {
+    json.get(key)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+fn acl_type(json: &Value) -> Result<AclType, String> {
+    AclType::parse(field(json, "acltype")?).ok_or_else(|| "Invalid acltype".to_string())
+}
+
+fn acl_from_json(json: &Value) -> Result<RoleAcl, String> {
+    Ok(RoleAcl {
+        acl_type: acl_type(json)?,
+        topic: field(json, "topic")?.to_string(),
+        priority: priority(json),
+        allow: json.get("allow").and_then(Value::as_bool).unwrap_or(false),
+    })
+}
+
+// Keeps the ACLs sorted by priority, after the ones with the same priority
+fn add_acl(acls: &mut Vec<RoleAcl>, acl: RoleAcl) {
+    let at = acls
+        .iter()
+        .position(|a| a.priority < acl.priority)
+        .unwrap_or(acls.len());
+    acls.insert(at, acl);
+}
+
+// Adds or moves the membership, keeping the list sorted by priority
+fn add_membership(list: &mut Vec<Membership>, name: &str, priority: i64) {
+    list.retain(|m| m.name != name);
+    let at = list
+        .iter()
+        .position(|m| m.priority < priority)
+        .unwrap_or(list.len());
+    list.insert(
+        at,
+        Membership {
+            name: name.to_string(),
+            priority,
+        },
+    );
+}
+
+fn memberships(json: &Value, key: &str, name_key: &str) -> Result<Vec<Membership>, String> {
+    let mut list = Vec::new();
+    for member in array(json, key) {
+        add_membership(&mut list, field(member, name_key)?, priority(member));
+    }
+    Ok(list)
+}
+
+fn memberships_json(list: &[Membership], name_key: &str) -> Value {
+    list.iter()
+        .map(|m| {
+            let mut json = Map::new();
+            json.insert(name_key.to_string(), m.name.as_str().into());
+            json.insert("priority".to_string(), m.priority.into());
+            Value::Object(json)
+        })
+        .collect()
+}
+
+fn insert_text(json: &mut Map<String, Value>, key: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        json.insert(key.to_string(), value.as_str().into());
+    }
+}
+
+// The page of a list command, with count -1 for all
+fn list(cmd: &Value, key: &str, items: Vec<Value>) -> Value {
+    let offset = cmd.get("offset").and_then(Value::as_u64).unwrap_or(0) as usize;
+    let count = match cmd.get("count").and_then(Value::as_i64) {
+        Some(count) if count >= 0 => count as usize,
+        _ => usize::MAX,
+    };
+    let total = items.len();
+    let page: Vec<Value> = items.into_iter().skip(offset).take(count).collect();
+    let mut json = Map::new();
+    json.insert("totalCount".to_string(), total.into());
+    json.insert(key.to_string(), Value::Array(page));
+    Value::Object(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::{self, client};
+    use crate::AclCheckAccessLevel::{Read, Subscribe, Write};
+
+    // Sends the commands like mosquitto_ctrl does and returns the responses
+    fn send(dynsec: &mut DynSec, commands: Value) -> Vec<Value> {
+        let payload = json!({ "commands": commands }).to_string();
+        let reply = match dynsec.on_control_command(payload.as_bytes()) {
+            ControlResponse::Reply(reply) => reply,
+            ControlResponse::None => panic!("no reply"),
+        };
+        let reply: Value = serde_json::from_slice(&reply).unwrap();
+        reply
+            .get("responses")
+            .and_then(Value::as_array)
+            .unwrap()
+            .clone()
+    }
+
+    fn errors(responses: &[Value]) -> Vec<Option<&str>> {
+        responses
+            .iter()
+            .map(|r| r.get("error").and_then(Value::as_str))
+            .collect()
+    }
+
+    #[test]
+    fn commands_manage_clients_and_roles() {
+        stub_ffi::reset();
+        let mut dynsec = DynSec::new(Config::default());
+        let responses = send(
+            &mut dynsec,
+            json!([
+                {"command": "createRole", "rolename": "sensors"},
+                {"command": "addRoleACL", "rolename": "sensors", "acltype": "publishClientSend",
+                    "topic": "sensors/%c/#", "allow": true},
+                {"command": "addRoleACL", "rolename": "sensors", "acltype": "subscribePattern",
+                    "topic": "commands/%u/#", "allow": true},
+                {"command": "createClient", "username": "alice", "password": "secret",
+                    "roles": [{"rolename": "sensors"}]},
+                {"command": "createClient", "username": "alice"},
+                {"command": "addClientRole", "username": "alice", "rolename": "missing"},
+                {"command": "launchRockets", "correlationData": "42"},
+            ]),
+        );
+        assert_eq!(
+            errors(&responses),
+            [
+                None,
+                None,
+                None,
+                None,
+                Some("Client already exists"),
+                Some("Role not found"),
+                Some("Unknown command")
+            ]
+        );
+        assert_eq!(
+            responses[6].get("correlationData").and_then(Value::as_str),
+            Some("42")
+        );
+
+        let client = client();
+        assert_eq!(
+            dynsec.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            dynsec.username_password(&client, Some("alice"), Some("guess")),
+            AuthDecision::deny("wrong password")
+        );
+        assert_eq!(
+            dynsec.username_password(&client, Some("bob"), Some("secret")),
+            AuthDecision::Defer
+        );
+
+        // the stub client is stub-client, logged in as alice
+        client.set_username("alice").unwrap();
+        let default = AuthDecision::deny("denied by the default access");
+        for (level, topic, expected) in [
+            (Write, "sensors/stub-client/temp", AuthDecision::Allow),
+            (Write, "sensors/other/temp", default.clone()),
+            (Subscribe, "commands/alice/#", AuthDecision::Allow),
+            (Subscribe, "commands/#", default.clone()),
+            (Read, "anything", AuthDecision::Allow),
+        ] {
+            assert_eq!(
+                dynsec.acl_check(&client, level, topic),
+                expected,
+                "{}",
+                topic
+            );
+        }
+
+        let responses = send(
+            &mut dynsec,
+            json!([{"command": "disableClient", "username": "alice"}]),
+        );
+        assert_eq!(errors(&responses), [None]);
+        assert_eq!(stub_ffi::kicked(), [("alice".to_string(), false)]);
+        assert_eq!(
+            dynsec.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::deny("client disabled")
+        );
+
+        let reply = dynsec.on_control_command(b"not json");
+        let expected = json!({"responses": [
+            {"command": "Unknown command", "error": "Payload not valid JSON"}
+        ]});
+        assert_eq!(reply, ControlResponse::reply(expected.to_string()));
+    }
+
+    #[test]
+    fn roles_are_checked_by_priority() {
+        stub_ffi::reset();
+        let mut dynsec = DynSec::new(Config::default());
+        let responses = send(
+            &mut dynsec,
+            json!([
+                {"command": "createRole", "rolename": "devices", "acls": [
+                    {"acltype": "publishClientSend", "topic": "devices/#", "allow": true}
+                ]},
+                {"command": "createRole", "rolename": "no-secrets", "acls": [
+                    {"acltype": "publishClientSend", "topic": "devices/secret", "allow": false}
+                ]},
+                {"command": "createRole", "rolename": "public", "acls": [
+                    {"acltype": "subscribePattern", "topic": "public/#", "allow": true}
+                ]},
+                {"command": "createGroup", "groupname": "staff", "roles": [{"rolename": "devices"}]},
+                {"command": "createClient", "username": "bob", "password": "hunter2"},
+                {"command": "addGroupClient", "groupname": "staff", "username": "bob"},
+                {"command": "addClientRole", "username": "bob", "rolename": "no-secrets"},
+                {"command": "createGroup", "groupname": "guests"},
+                {"command": "addGroupRole", "groupname": "guests", "rolename": "public"},
+                {"command": "setAnonymousGroup", "groupname": "guests"},
+                {"command": "setDefaultACLAccess", "acls": [
+                    {"acltype": "publishClientReceive", "allow": false}
+                ]},
+                {"command": "deleteGroup", "groupname": "guests"},
+                {"command": "listClients"},
+            ]),
+        );
+        let mut expected = vec![None; 11];
+        expected.push(Some("Deleting the anonymous group is forbidden"));
+        expected.push(None);
+        assert_eq!(errors(&responses), expected);
+        assert_eq!(
+            responses[12].get("data"),
+            Some(&json!({"totalCount": 1, "clients": ["bob"]}))
+        );
+
+        let client = client();
+        let default = AuthDecision::deny("denied by the default access");
+        // anonymous clients get the roles of the anonymous group
+        assert_eq!(
+            dynsec.acl_check(&client, Subscribe, "public/news"),
+            AuthDecision::Allow
+        );
+        assert_eq!(dynsec.acl_check(&client, Subscribe, "private"), default);
+        assert_eq!(dynsec.acl_check(&client, Read, "public/news"), default);
+
+        // the roles of the client come before the roles of its groups
+        client.set_username("bob").unwrap();
+        assert_eq!(
+            dynsec.acl_check(&client, Write, "devices/lamp"),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            dynsec.acl_check(&client, Write, "devices/secret"),
+            AuthDecision::deny("denied by a role of the client")
+        );
+        client.set_username("carol").unwrap();
+        assert_eq!(
+            dynsec.acl_check(&client, Write, "devices/lamp"),
+            AuthDecision::Defer
+        );
+    }
+
+    #[test]
+    fn configurations_are_saved_and_imported() {
+        stub_ffi::reset();
+        let dir =
+            std::env::temp_dir().join(format!("mosquitto-plugin-dynsec-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = || StateStore::new(&dir, STATE_NAME);
+
+        let mut dynsec = DynSec::load(store())
+            .unwrap()
+            .with_admin("admin", "secret")
+            .unwrap();
+        let client = client();
+        client.set_username("admin").unwrap();
+        assert_eq!(
+            dynsec.acl_check(&client, Write, CONTROL_TOPIC),
+            AuthDecision::Allow
+        );
+        send(
+            &mut dynsec,
+            json!([{"command": "createClient", "username": "dave", "clientid": "lamp-1",
+                "textname": "Dave", "groups": []}]),
+        );
+        let loaded = DynSec::load(store()).unwrap();
+        assert_eq!(loaded.config(), dynsec.config());
+        assert_eq!(
+            Config::from_json(&dynsec.config().to_json()).as_ref(),
+            Ok(dynsec.config())
+        );
+
+        // a dynamic-security.json of mosquitto
+        let hash = passwd::hash_password_with_salt("secret", 101, b"saltsaltsalt");
+        let (salt, hash) = match passwd::parse_hash(&hash).unwrap() {
+            PasswordHash::Pbkdf2Sha512 { salt, hash, .. } => {
+                (STANDARD.encode(salt), STANDARD.encode(hash))
+            }
+            other => panic!("{:?}", other),
+        };
+        let file = json!({
+            "defaultACLAccess": {"publishClientSend": true},
+            "clients": [{"username": "carol", "password": hash, "salt": salt, "iterations": 101,
+                "roles": [{"rolename": "readers", "priority": 5}]}],
+            "groups": [{"groupname": "everyone", "clients": [{"username": "carol", "priority": 2}]}],
+            "roles": [{"rolename": "readers", "acls": [
+                {"acltype": "subscribeLiteral", "topic": "news", "allow": true}
+            ]}],
+            "anonymousGroup": "everyone",
+        });
+        dynsec.import(file.to_string().as_bytes()).unwrap();
+        let config = DynSec::load(store()).unwrap().config().clone();
+        assert_eq!(config.clients.len(), 1);
+        assert_eq!(
+            config.clients["carol"].groups,
+            [Membership {
+                name: "everyone".to_string(),
+                priority: 2
+            }]
+        );
+        assert!(config.default_access.publish_client_send);
+        assert_eq!(config.anonymous_group.as_deref(), Some("everyone"));
+        assert_eq!(
+            dynsec.username_password(&client, Some("carol"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert!(dynsec
+            .import(br#"{"roles": [{"rolename": "r", "acls": [{"acltype": "publish", "topic": "a"}]}]}"#)
+            .is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,608 @@
+// OAuth2 access tokens as MQTT passwords, checked at an RFC 7662 introspection endpoint of the
+// authorization server, enabled with the "introspection" feature:
+//
+//     fn username_password(&mut self, client: &dyn MosquittoClientContext, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+//         self.introspection.username_password(client, username, password)
+//     }
+//
+//     fn acl_check(&mut self, client: &dyn MosquittoClientContext, level: AclCheckAccessLevel, msg: MosquittoMessage) -> AuthDecision {
+//         self.introspection.acl_check(client, level, msg.topic)
+//     }
+//
+// with `introspection: IntrospectionAuth` created in init and returned from
+// MosquittoPlugin::client_registry, so what is known about a client is dropped when it
+// disconnects. The endpoint is asked on worker threads and logins are completed through
+// delayed_auth, the broker keeps serving its other clients meanwhile. Answers are cached for a
+// while, up to the expiry of the token, so a reconnecting client is let in right away.
+//
+// Scopes of the token decide the ACL checks, each scope grants the acl_file patterns configured
+// for it (see acl). Clients logged in by other plugins are deferred, clients whose token expired
+// are denied and have to reconnect with a new one. token_info gives the plugin the whole answer
+// of the endpoint for decisions of its own.
+//
+// Options, for IntrospectionAuth::from_opts:
+//
+//     plugin_opt_introspection_url https://auth.example.com/oauth2/introspect
+//     plugin_opt_introspection_client_id mqtt-broker          # HTTP basic auth at the endpoint
+//     plugin_opt_introspection_client_secret ...
+//     plugin_opt_introspection_scope_telemetry write devices/%c/#; read commands/%c/#
+//     plugin_opt_introspection_scope_dashboard read devices/#
+//
+// and `introspection_cache_ttl` (default 60s), `introspection_cache_size` (10000 answers),
+// `introspection_timeout` (5s) and `introspection_threads` (4).
+use crate::acl::{self, AclPattern};
+use crate::clients::ClientLifecycle;
+use crate::opts::{opt, opt_or, OptError};
+use crate::worker_pool::WorkerPool;
+use crate::{
+    AclCheckAccessLevel, AuthDecision, DisconnectReason, Error, MosquittoClientContext,
+    MosquittoOpt,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SCOPE_OPT_PREFIX: &str = "introspection_scope_";
+// Logins waiting for a worker thread, more are denied
+const QUEUE_LEN: usize = 256;
+
+/// Why the endpoint couldn't tell whether a token is active
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntrospectionError {
+    /// The endpoint couldn't be reached
+    Http(String),
+    /// The endpoint answered with an HTTP error, like 401 for wrong client credentials
+    Status(u16),
+    /// The answer isn't an introspection response
+    InvalidResponse(String),
+}
+
+impl fmt::Display for IntrospectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntrospectionError::Http(reason) => write!(f, "introspection failed: {}", reason),
+            IntrospectionError::Status(status) => {
+                write!(f, "introspection endpoint answered {}", status)
+            }
+            IntrospectionError::InvalidResponse(reason) => {
+                write!(f, "invalid introspection response: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntrospectionError {}
+
+/// What the endpoint knows about a token
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    pub active: bool,
+    pub scopes: Vec<String>,
+    /// The `sub` member
+    pub subject: Option<String>,
+    pub username: Option<String>,
+    /// The OAuth2 client the token was issued to, not the MQTT client id
+    pub client_id: Option<String>,
+    /// The `exp` member, in seconds since the epoch
+    pub expires_at: Option<u64>,
+    members: Map<String, Value>,
+}
+
+impl TokenInfo {
+    /// Parses the JSON answer of the endpoint
+    pub fn parse(json: &str) -> Result<TokenInfo, IntrospectionError> {
+        let invalid = |reason: &str| IntrospectionError::InvalidResponse(reason.to_string());
+        let members = match serde_json::from_str(json) {
+            Ok(Value::Object(members)) => members,
+            Ok(_) => return Err(invalid("not a JSON object")),
+            Err(e) => return Err(invalid(&e.to_string())),
+        };
+        let active = members
+            .get("active")
+            .and_then(Value::as_bool)
+            .ok_or_else(|| invalid("no active member"))?;
+        let string = |name: &str| members.get(name).and_then(Value::as_str).map(String::from);
+        Ok(TokenInfo {
+            active,
+            scopes: members
+                .get("scope")
+                .and_then(Value::as_str)
+                .map(|scope| scope.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+            subject: string("sub"),
+            username: string("username"),
+            client_id: string("client_id"),
+            expires_at: members.get("exp").and_then(Value::as_u64),
+            members,
+        })
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    /// Any member of the answer, like `aud` or claims specific to the authorization server
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.members.get(name)
+    }
+
+    /// Whether the token has expired, tokens without an expiry don't
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|exp| exp <= unix_now())
+    }
+
+    // How long the answer may be cached, at most max
+    fn cache_for(&self, max: Duration) -> Duration {
+        match self.expires_at {
+            Some(exp) if self.active => {
+                max.min(Duration::from_secs(exp.saturating_sub(unix_now())))
+            }
+            _ => max,
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+type Post = dyn Fn(&str) -> Result<String, IntrospectionError> + Send + Sync;
+
+enum Transport {
+    Http,
+    // Answers the token without asking an endpoint
+    #[cfg(test)]
+    Fake(Box<Post>),
+}
+
+/// Asks the endpoint about tokens and caches the answers. Blocks while asking, use it from
+/// IntrospectionAuth or another thread than the broker's.
+pub struct Introspector {
+    endpoint: String,
+    credentials: Option<(String, String)>,
+    timeout: Duration,
+    transport: Transport,
+    cache_ttl: Duration,
+    cache_size: usize,
+    cache: Mutex<HashMap<String, (TokenInfo, Instant)>>,
+}
+
+impl fmt::Debug for Introspector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Introspector")
+            .field("endpoint", &self.endpoint)
+            .field("cache_ttl", &self.cache_ttl)
+            .finish()
+    }
+}
+
+impl Introspector {
+    /// Asks the endpoint at url, without client credentials, caching answers for 60 seconds
+    pub fn new(url: &str) -> Introspector {
+        Introspector {
+            endpoint: url.to_string(),
+            credentials: None,
+            timeout: Duration::from_secs(5),
+            transport: Transport::Http,
+            cache_ttl: Duration::from_secs(60),
+            cache_size: 10_000,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Authenticates at the endpoint with HTTP basic auth, most servers require it
+    pub fn with_client_credentials(mut self, client_id: &str, client_secret: &str) -> Self {
+        self.credentials = Some((client_id.to_string(), client_secret.to_string()));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Caches at most size answers for ttl, a ttl of zero asks the endpoint every time
+    pub fn with_cache(mut self, ttl: Duration, size: usize) -> Self {
+        self.cache_ttl = ttl;
+        self.cache_size = size;
+        self
+    }
+
+    /// The cached answer about the token, or the endpoint's. Errors aren't cached.
+    pub fn introspect(&self, token: &str) -> Result<TokenInfo, IntrospectionError> {
+        if let Some(info) = self.cached(token) {
+            return Ok(info);
+        }
+        let info = TokenInfo::parse(&self.post(token)?)?;
+        self.cache_answer(token, &info);
+        Ok(info)
+    }
+
+    /// The cached answer about the token, if there is one that hasn't expired
+    pub fn cached(&self, token: &str) -> Option<TokenInfo> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        match cache.get(token) {
+            Some((info, expires)) if *expires > Instant::now() => Some(info.clone()),
+            _ => None,
+        }
+    }
+
+    fn cache_answer(&self, token: &str, info: &TokenInfo) {
+        let ttl = info.cache_for(self.cache_ttl);
+        if ttl.is_zero() || self.cache_size == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.len() >= self.cache_size && !cache.contains_key(token) {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        // Still full, the answer closest to expiring makes room
+        while cache.len() >= self.cache_size && !cache.contains_key(token) {
+            let first = cache
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(token, _)| token.clone());
+            match first {
+                Some(first) => cache.remove(&first),
+                None => break,
+            };
+        }
+        cache.insert(token.to_string(), (info.clone(), now + ttl));
+    }
+
+    fn post(&self, token: &str) -> Result<String, IntrospectionError> {
+        match &self.transport {
+            Transport::Http => {}
+            #[cfg(test)]
+            Transport::Fake(post) => return post(token),
+        }
+        let mut request = ureq::post(&self.endpoint)
+            .timeout(self.timeout)
+            .set("Accept", "application/json");
+        if let Some((id, secret)) = &self.credentials {
+            let basic = STANDARD.encode(format!("{}:{}", form_encode(id), form_encode(secret)));
+            request = request.set("Authorization", &format!("Basic {}", basic));
+        }
+        let response = request
+            .send_form(&[("token", token), ("token_type_hint", "access_token")])
+            .map_err(|e| match e {
+                ureq::Error::Status(status, _) => IntrospectionError::Status(status),
+                e => IntrospectionError::Http(e.to_string()),
+            })?;
+        response
+            .into_string()
+            .map_err(|e| IntrospectionError::Http(e.to_string()))
+    }
+}
+
+// Client credentials are form encoded before they are put into basic auth, RFC 6749 2.3.1
+fn form_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                encoded.push(b as char)
+            }
+            b' ' => encoded.push('+'),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+type Sessions = Arc<Mutex<HashMap<String, TokenInfo>>>;
+
+/// Logins and ACL checks decided by token introspection
+pub struct IntrospectionAuth {
+    introspector: Arc<Introspector>,
+    pool: WorkerPool,
+    // The tokens of the logged in clients, by client id
+    sessions: Sessions,
+    scopes: HashMap<String, Vec<AclPattern>>,
+}
+
+impl fmt::Debug for IntrospectionAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IntrospectionAuth")
+            .field("introspector", &self.introspector)
+            .field("scopes", &self.scopes.keys())
+            .finish()
+    }
+}
+
+impl IntrospectionAuth {
+    /// Asks the introspector on threads worker threads
+    pub fn new(introspector: Introspector, threads: usize) -> IntrospectionAuth {
+        IntrospectionAuth {
+            introspector: Arc::new(introspector),
+            pool: WorkerPool::new(threads, QUEUE_LEN),
+            sessions: Arc::default(),
+            scopes: HashMap::new(),
+        }
+    }
+
+    /// Lets clients whose token has the scope do what the patterns allow, a `;` separated list
+    /// like `write devices/%c/#; read commands/%c/#`
+    pub fn with_scope(mut self, scope: &str, patterns: &str) -> Result<Self, Error> {
+        self.scopes
+            .insert(scope.to_string(), AclPattern::parse_list(patterns)?);
+        Ok(self)
+    }
+
+    /// Reads the endpoint, credentials, cache, threads and scopes from the options, see the top
+    /// of the module
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<IntrospectionAuth, OptError> {
+        let url: String = opt(opts, "introspection_url")?;
+        let mut introspector = Introspector::new(&url)
+            .with_timeout(opt_or(opts, "introspection_timeout", "5s")?)
+            .with_cache(
+                opt_or(opts, "introspection_cache_ttl", "60s")?,
+                opt_or(opts, "introspection_cache_size", "10000")?,
+            );
+        let id: Option<String> = opt(opts, "introspection_client_id")?;
+        if let Some(id) = id {
+            let secret: String = opt(opts, "introspection_client_secret")?;
+            introspector = introspector.with_client_credentials(&id, &secret);
+        }
+        let mut auth =
+            IntrospectionAuth::new(introspector, opt_or(opts, "introspection_threads", "4")?);
+        for (key, patterns) in opts {
+            if let Some(scope) = key.strip_prefix(SCOPE_OPT_PREFIX) {
+                auth = auth
+                    .with_scope(scope, patterns)
+                    .map_err(|_| OptError::Invalid {
+                        key: key.to_string(),
+                        value: patterns.to_string(),
+                        expected: "acl_file patterns separated by ;",
+                    })?;
+            }
+        }
+        Ok(auth)
+    }
+
+    pub fn introspector(&self) -> &Introspector {
+        &self.introspector
+    }
+
+    /// Decides a login with the password as the token, with or without a `Bearer ` in front.
+    /// Cached answers are decided right away, the others are delayed while a worker thread asks
+    /// the endpoint. The username isn't checked, the token decides who the client is.
+    pub fn username_password(
+        &self,
+        client: &dyn MosquittoClientContext,
+        _username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let token = password.map(str::trim).unwrap_or_default();
+        let token = token.strip_prefix("Bearer ").unwrap_or(token).trim();
+        if token.is_empty() {
+            return AuthDecision::deny("no token given as password");
+        }
+        let client_id = client.get_id();
+        if let Some(info) = self.introspector.cached(token) {
+            return admit(&self.sessions, client_id, Ok(info));
+        }
+        let introspector = Arc::clone(&self.introspector);
+        let sessions = Arc::clone(&self.sessions);
+        let token = token.to_string();
+        self.pool.submit_auth(client, move || {
+            admit(&sessions, client_id, introspector.introspect(&token))
+        })
+    }
+
+    /// Decides an ACL check with the scopes of the client's token. Clients that didn't log in
+    /// with a token are deferred.
+    pub fn acl_check(
+        &self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let client_id = client.get_id();
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let info = match sessions.get(&client_id) {
+            Some(info) => info,
+            None => return AuthDecision::Defer,
+        };
+        if info.is_expired() {
+            return AuthDecision::deny("token expired");
+        }
+        let patterns: Vec<AclPattern> = info
+            .scopes
+            .iter()
+            .filter_map(|scope| self.scopes.get(scope))
+            .flatten()
+            .cloned()
+            .collect();
+        let username = client.get_username();
+        match acl::check(&patterns, &client_id, username.as_deref(), level, topic) {
+            Ok(_) => AuthDecision::Allow,
+            Err(_) => AuthDecision::deny("not allowed by the scopes of the token"),
+        }
+    }
+
+    /// The answer about the token the client logged in with
+    pub fn token_info(&self, client_id: &str) -> Option<TokenInfo> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.get(client_id).cloned()
+    }
+
+    /// The scopes of the token the client logged in with, empty for other clients
+    pub fn scopes(&self, client_id: &str) -> Vec<String> {
+        self.token_info(client_id)
+            .map(|info| info.scopes)
+            .unwrap_or_default()
+    }
+}
+
+// The decision about a login, remembering the token of allowed clients for their ACL checks
+fn admit(
+    sessions: &Mutex<HashMap<String, TokenInfo>>,
+    client_id: String,
+    answer: Result<TokenInfo, IntrospectionError>,
+) -> AuthDecision {
+    match answer {
+        Ok(info) if !info.active => AuthDecision::deny("token not active"),
+        Ok(info) if info.is_expired() => AuthDecision::deny("token expired"),
+        Ok(info) => {
+            let mut sessions = sessions.lock().unwrap_or_else(|e| e.into_inner());
+            sessions.insert(client_id, info);
+            AuthDecision::Allow
+        }
+        Err(_) => AuthDecision::Error(Error::Unknown),
+    }
+}
+
+/// Forgets the token of a client when it disconnects
+impl ClientLifecycle for IntrospectionAuth {
+    fn authenticated(&mut self, _client: &dyn MosquittoClientContext) {}
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, _reason: DisconnectReason) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.remove(&client.get_id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::client;
+    use crate::AclCheckAccessLevel::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const ACTIVE: &str = r#"{"active": true, "scope": "telemetry profile", "sub": "sensor-1",
+        "client_id": "fleet", "exp": 4102444800, "aud": "mqtt"}"#;
+
+    fn fake(answer: &'static str) -> (Introspector, Arc<AtomicUsize>) {
+        let posts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&posts);
+        let mut introspector = Introspector::new("https://auth.example.com/introspect");
+        introspector.transport = Transport::Fake(Box::new(move |token| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            match token {
+                "unreachable" => Err(IntrospectionError::Http("connection refused".to_string())),
+                "revoked" => Ok(r#"{"active": false}"#.to_string()),
+                _ => Ok(answer.to_string()),
+            }
+        }));
+        (introspector, posts)
+    }
+
+    #[test]
+    fn answers_are_parsed() {
+        let info = TokenInfo::parse(ACTIVE).unwrap();
+        assert!(info.active);
+        assert_eq!(info.scopes, vec!["telemetry", "profile"]);
+        assert!(info.has_scope("profile") && !info.has_scope("admin"));
+        assert_eq!(info.subject.as_deref(), Some("sensor-1"));
+        assert_eq!(info.client_id.as_deref(), Some("fleet"));
+        assert_eq!(info.get("aud").and_then(Value::as_str), Some("mqtt"));
+        assert!(!info.is_expired());
+
+        let inactive = TokenInfo::parse(r#"{"active": false}"#).unwrap();
+        assert!(!inactive.active && inactive.scopes.is_empty());
+        for invalid in [
+            "",
+            "[]",
+            r#"{"scope": "telemetry"}"#,
+            r#"{"active": "yes"}"#,
+        ] {
+            assert!(TokenInfo::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(form_encode("a b:c%"), "a+b%3Ac%25");
+    }
+
+    #[test]
+    fn answers_are_cached() {
+        let (introspector, posts) = fake(ACTIVE);
+        let introspector = introspector.with_cache(Duration::from_secs(60), 2);
+        assert!(introspector.introspect("t1").unwrap().active);
+        assert!(introspector.introspect("t1").unwrap().active);
+        assert!(!introspector.introspect("revoked").unwrap().active);
+        assert!(!introspector.introspect("revoked").unwrap().active);
+        assert_eq!(posts.load(Ordering::Relaxed), 2);
+
+        assert!(introspector.introspect("unreachable").is_err());
+        assert!(introspector.introspect("unreachable").is_err());
+        assert_eq!(posts.load(Ordering::Relaxed), 4);
+
+        // full, t1 expires first and makes room
+        introspector.introspect("t2").unwrap();
+        assert_eq!(introspector.cached("t1"), None);
+        assert!(introspector.cached("t2").is_some());
+
+        let (uncached, posts) = fake(ACTIVE);
+        let uncached = uncached.with_cache(Duration::ZERO, 10);
+        uncached.introspect("t1").unwrap();
+        uncached.introspect("t1").unwrap();
+        assert_eq!(posts.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn scopes_decide_acl_checks() {
+        let (introspector, _) = fake(ACTIVE);
+        let mut auth = IntrospectionAuth::new(introspector, 1)
+            .with_scope("telemetry", "write devices/%c/#; read commands/%c/#")
+            .unwrap()
+            .with_scope("admin", "readwrite #")
+            .unwrap();
+        let client = client();
+        assert_eq!(
+            auth.acl_check(&client, Write, "devices/stub-client/temp"),
+            AuthDecision::Defer
+        );
+
+        let answer = auth.introspector().introspect("t1");
+        assert_eq!(
+            admit(&auth.sessions, "stub-client".to_string(), answer),
+            AuthDecision::Allow
+        );
+        assert_eq!(auth.scopes("stub-client"), vec!["telemetry", "profile"]);
+        assert_eq!(
+            auth.acl_check(&client, Write, "devices/stub-client/temp"),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.acl_check(&client, Read, "commands/stub-client/reboot"),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.acl_check(&client, Write, "commands/stub-client/reboot"),
+            AuthDecision::deny("not allowed by the scopes of the token")
+        );
+        // cached now, decided without a worker thread
+        assert_eq!(
+            auth.username_password(&client, None, Some("Bearer t1")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.username_password(&client, None, Some("")),
+            AuthDecision::deny("no token given as password")
+        );
+
+        auth.disconnected(&client, DisconnectReason::Normal);
+        assert_eq!(auth.token_info("stub-client"), None);
+
+        let sessions = Mutex::new(HashMap::new());
+        let revoked = auth.introspector().introspect("revoked");
+        assert_eq!(
+            admit(&sessions, "c".to_string(), revoked),
+            AuthDecision::deny("token not active")
+        );
+        let unreachable = auth.introspector().introspect("unreachable");
+        assert_eq!(
+            admit(&sessions, "c".to_string(), unreachable),
+            AuthDecision::Error(Error::Unknown)
+        );
+        assert!(sessions.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,577 @@
+// JSON Web Tokens as MQTT passwords, enabled with the "jwt" feature:
+//
+//     fn username_password(&mut self, client: &dyn MosquittoClientContext, _username: Option<&str>, password: Option<&str>) -> AuthDecision {
+//         match self.validator.check_password(password) {
+//             Ok(claims) => {
+//                 self.claims.insert(client.get_id(), claims);   // for acl_check
+//                 AuthDecision::Allow
+//             }
+//             Err(e) => e.into(),
+//         }
+//     }
+//
+// Tokens are signed with HS256 and a shared secret, or with RS256 or ES256 and a public key from
+// a PEM file, a JWKS file or a JWKS URL. A key only accepts its own algorithm, so a public key
+// can't be used as an HS256 secret. The expiry is always checked, the issuer and audience when
+// they are configured.
+//
+// A JWKS URL is fetched when a token is first checked and again once the keys are older than the
+// refresh interval, or when a token names a key that isn't in the set, at most every 10 seconds.
+// While the URL can't be reached the keys fetched last stay in use. Fetching blocks the broker
+// thread, see async_auth for checking logins off it.
+//
+// Options, for JwtValidator::from_opts: `jwt_secret`, `jwt_public_key_file`, `jwks_file` or
+// `jwks_url` for the keys, `jwks_refresh` (default 5m), `jwt_issuer`, `jwt_audience` and
+// `jwt_leeway`, how long a token is still accepted after it expired (default 60s). With
+// opts::OptResolver the secret can be read from `jwt_secret_file`.
+use crate::opts::{opt_or, OptError};
+use crate::{AuthDecision, Error, MosquittoOpt};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_REFRESH: &str = "5m";
+const DEFAULT_LEEWAY: &str = "60s";
+// Tokens naming unknown keys don't make the keys be fetched more often than this
+const MIN_REFETCH: Duration = Duration::from_secs(10);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a token was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JwtError {
+    /// The client sent no password
+    MissingToken,
+    /// Not a JWT, or its claims aren't what the plugin expects
+    Malformed(String),
+    /// Signed with an algorithm the key isn't used with
+    UnsupportedAlgorithm,
+    /// Names a key that isn't known, or the keys have more than one and the token names none
+    UnknownKey,
+    InvalidSignature,
+    Expired,
+    /// The `nbf` claim lies in the future
+    NotYetValid,
+    InvalidIssuer,
+    InvalidAudience,
+    /// The keys couldn't be loaded or fetched, which isn't the client's fault
+    Keys(String),
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JwtError::MissingToken => write!(f, "no token given as password"),
+            JwtError::Malformed(reason) => write!(f, "malformed token: {}", reason),
+            JwtError::UnsupportedAlgorithm => {
+                write!(f, "token signed with an unsupported algorithm")
+            }
+            JwtError::UnknownKey => write!(f, "token signed with an unknown key"),
+            JwtError::InvalidSignature => write!(f, "invalid token signature"),
+            JwtError::Expired => write!(f, "token expired"),
+            JwtError::NotYetValid => write!(f, "token not valid yet"),
+            JwtError::InvalidIssuer => write!(f, "token from the wrong issuer"),
+            JwtError::InvalidAudience => write!(f, "token for the wrong audience"),
+            JwtError::Keys(reason) => write!(f, "token keys unavailable: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for JwtError {}
+
+/// Refused tokens deny the client with the error as the reason, unavailable keys are an error
+impl From<JwtError> for AuthDecision {
+    fn from(error: JwtError) -> AuthDecision {
+        match error {
+            JwtError::Keys(_) => AuthDecision::Error(Error::Unknown),
+            error => AuthDecision::deny(error.to_string()),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for JwtError {
+    fn from(error: jsonwebtoken::errors::Error) -> JwtError {
+        use jsonwebtoken::errors::ErrorKind;
+        match error.kind() {
+            ErrorKind::InvalidSignature => JwtError::InvalidSignature,
+            ErrorKind::ExpiredSignature => JwtError::Expired,
+            ErrorKind::ImmatureSignature => JwtError::NotYetValid,
+            ErrorKind::InvalidIssuer => JwtError::InvalidIssuer,
+            ErrorKind::InvalidAudience => JwtError::InvalidAudience,
+            ErrorKind::InvalidAlgorithm | ErrorKind::MissingAlgorithm => {
+                JwtError::UnsupportedAlgorithm
+            }
+            _ => JwtError::Malformed(error.to_string()),
+        }
+    }
+}
+
+/// The claims of a valid token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims(Map<String, Value>);
+
+impl Claims {
+    /// The `sub` claim
+    pub fn subject(&self) -> Option<&str> {
+        self.str("sub")
+    }
+
+    /// The `exp` claim, in seconds since the epoch
+    pub fn expires_at(&self) -> Option<u64> {
+        self.0.get("exp").and_then(Value::as_u64)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// A claim holding a string
+    pub fn str(&self, name: &str) -> Option<&str> {
+        self.0.get(name).and_then(Value::as_str)
+    }
+
+    /// A claim holding a list of strings, like topic filters. A single string is a list of one,
+    /// anything else isn't a list of strings and is left out.
+    pub fn strings(&self, name: &str) -> Vec<&str> {
+        match self.0.get(name) {
+            Some(Value::String(s)) => vec![s.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn into_map(self) -> Map<String, Value> {
+        self.0
+    }
+}
+
+type Fetch = dyn Fn(&str) -> Result<String, String> + Send + Sync;
+
+struct RemoteJwks {
+    url: String,
+    refresh: Duration,
+    fetch: Box<Fetch>,
+    cache: Mutex<RemoteCache>,
+}
+
+#[derive(Default)]
+struct RemoteCache {
+    keys: Option<(Arc<JwkSet>, Instant)>,
+    attempted: Option<Instant>,
+}
+
+impl RemoteJwks {
+    // The keys, fetched again when they are old or don't include kid
+    fn keys(&self, kid: Option<&str>) -> Result<Arc<JwkSet>, JwtError> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if let Some((keys, fetched)) = &cache.keys {
+            let fresh = now.duration_since(*fetched) < self.refresh;
+            let known = kid.is_none_or(|kid| keys.find(kid).is_some());
+            if fresh && known {
+                return Ok(Arc::clone(keys));
+            }
+        }
+        let may_fetch = cache
+            .attempted
+            .is_none_or(|attempted| now.duration_since(attempted) >= MIN_REFETCH);
+        if may_fetch {
+            cache.attempted = Some(now);
+            let fetched = (self.fetch)(&self.url)
+                .and_then(|json| serde_json::from_str::<JwkSet>(&json).map_err(|e| e.to_string()));
+            match fetched {
+                Ok(keys) => cache.keys = Some((Arc::new(keys), now)),
+                Err(reason) if cache.keys.is_none() => {
+                    return Err(JwtError::Keys(format!("fetching {}: {}", self.url, reason)))
+                }
+                // the keys fetched last are used until the URL works again
+                Err(_) => {}
+            }
+        }
+        match &cache.keys {
+            Some((keys, _)) => Ok(Arc::clone(keys)),
+            None => Err(JwtError::Keys(format!("{} not fetched yet", self.url))),
+        }
+    }
+}
+
+fn fetch_url(url: &str) -> Result<String, String> {
+    ureq::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_string()
+        .map_err(|e| e.to_string())
+}
+
+enum Keys {
+    Key(DecodingKey, Algorithm),
+    Jwks(JwkSet),
+    Remote(RemoteJwks),
+}
+
+/// Checks tokens against keys, issuer and audience
+pub struct JwtValidator {
+    keys: Keys,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: Duration,
+}
+
+impl fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys = match &self.keys {
+            Keys::Key(_, algorithm) => format!("{:?} key", algorithm),
+            Keys::Jwks(keys) => format!("{} keys", keys.keys.len()),
+            Keys::Remote(remote) => remote.url.clone(),
+        };
+        f.debug_struct("JwtValidator")
+            .field("keys", &keys)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+impl JwtValidator {
+    fn new(keys: Keys) -> JwtValidator {
+        JwtValidator {
+            keys,
+            issuer: None,
+            audience: None,
+            leeway: Duration::from_secs(60),
+        }
+    }
+
+    /// HS256 tokens signed with the shared secret
+    pub fn hs256(secret: &[u8]) -> JwtValidator {
+        JwtValidator::new(Keys::Key(
+            DecodingKey::from_secret(secret),
+            Algorithm::HS256,
+        ))
+    }
+
+    /// RS256 tokens, verified with an RSA public key in PEM
+    pub fn rs256_pem(pem: &[u8]) -> Result<JwtValidator, JwtError> {
+        let key = DecodingKey::from_rsa_pem(pem).map_err(|e| JwtError::Keys(e.to_string()))?;
+        Ok(JwtValidator::new(Keys::Key(key, Algorithm::RS256)))
+    }
+
+    /// ES256 tokens, verified with a P-256 public key in PEM
+    pub fn es256_pem(pem: &[u8]) -> Result<JwtValidator, JwtError> {
+        let key = DecodingKey::from_ec_pem(pem).map_err(|e| JwtError::Keys(e.to_string()))?;
+        Ok(JwtValidator::new(Keys::Key(key, Algorithm::ES256)))
+    }
+
+    /// RS256 and ES256 tokens, verified with the RSA and P-256 keys of the set
+    pub fn jwks(keys: JwkSet) -> JwtValidator {
+        JwtValidator::new(Keys::Jwks(keys))
+    }
+
+    /// Like jwks, with the set fetched from url and fetched again after refresh
+    pub fn jwks_url(url: &str, refresh: Duration) -> JwtValidator {
+        JwtValidator::jwks_with_fetch(url, refresh, Box::new(fetch_url))
+    }
+
+    fn jwks_with_fetch(url: &str, refresh: Duration, fetch: Box<Fetch>) -> JwtValidator {
+        JwtValidator::new(Keys::Remote(RemoteJwks {
+            url: url.to_string(),
+            refresh,
+            fetch,
+            cache: Mutex::new(RemoteCache::default()),
+        }))
+    }
+
+    /// Only accept tokens whose `iss` claim is issuer
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Only accept tokens whose `aud` claim is or includes audience
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// How long tokens are accepted after they expired, for clocks that are a little off. 60
+    /// seconds unless set.
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Reads the keys, issuer, audience and leeway from the options, see the top of the module
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<JwtValidator, OptError> {
+        let read = |key: &str, path: &str| {
+            fs::read(path).map_err(|error| OptError::File {
+                key: key.to_string(),
+                path: path.to_string(),
+                error,
+            })
+        };
+        let invalid = |key: &str, path: &str, expected| OptError::Invalid {
+            key: key.to_string(),
+            value: path.to_string(),
+            expected,
+        };
+        let mut validator = if let Some(secret) = opts.get("jwt_secret") {
+            JwtValidator::hs256(secret.as_bytes())
+        } else if let Some(path) = opts.get("jwt_public_key_file") {
+            let pem = read("jwt_public_key_file", path)?;
+            JwtValidator::rs256_pem(&pem)
+                .or_else(|_| JwtValidator::es256_pem(&pem))
+                .map_err(|_| {
+                    invalid(
+                        "jwt_public_key_file",
+                        path,
+                        "an RSA or P-256 public key in PEM",
+                    )
+                })?
+        } else if let Some(path) = opts.get("jwks_file") {
+            let keys = serde_json::from_slice(&read("jwks_file", path)?)
+                .map_err(|_| invalid("jwks_file", path, "a JSON Web Key Set"))?;
+            JwtValidator::jwks(keys)
+        } else if let Some(url) = opts.get("jwks_url") {
+            let refresh = opt_or(opts, "jwks_refresh", DEFAULT_REFRESH)?;
+            JwtValidator::jwks_url(url, refresh)
+        } else {
+            return Err(OptError::Missing {
+                key: "jwt_secret".to_string(),
+            });
+        };
+        if let Some(issuer) = opts.get("jwt_issuer") {
+            validator = validator.with_issuer(issuer);
+        }
+        if let Some(audience) = opts.get("jwt_audience") {
+            validator = validator.with_audience(audience);
+        }
+        Ok(validator.with_leeway(opt_or(opts, "jwt_leeway", DEFAULT_LEEWAY)?))
+    }
+
+    /// Checks the token and returns its claims
+    pub fn validate(&self, token: &str) -> Result<Claims, JwtError> {
+        self.validate_as(token).map(Claims)
+    }
+
+    /// Like validate, with the claims deserialized into a type of the plugin
+    pub fn validate_as<C: DeserializeOwned>(&self, token: &str) -> Result<C, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let kid = header.kid.as_deref();
+        let (key, algorithm) = match &self.keys {
+            Keys::Key(key, algorithm) => (key.clone(), *algorithm),
+            Keys::Jwks(keys) => key_from_set(keys, kid)?,
+            Keys::Remote(remote) => {
+                let keys = remote.keys(kid)?;
+                key_from_set(&keys, kid)?
+            }
+        };
+        if header.alg != algorithm {
+            return Err(JwtError::UnsupportedAlgorithm);
+        }
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.set_required_spec_claims(&["exp"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(jsonwebtoken::decode::<C>(token, &key, &validation)?.claims)
+    }
+
+    /// Treats the MQTT password as a bearer token, with or without a `Bearer ` in front
+    pub fn check_password(&self, password: Option<&str>) -> Result<Claims, JwtError> {
+        let token = password.map(str::trim).unwrap_or_default();
+        let token = token.strip_prefix("Bearer ").unwrap_or(token).trim();
+        if token.is_empty() {
+            return Err(JwtError::MissingToken);
+        }
+        self.validate(token)
+    }
+}
+
+// The key named kid, or the only key when the token names none, with its algorithm
+fn key_from_set(keys: &JwkSet, kid: Option<&str>) -> Result<(DecodingKey, Algorithm), JwtError> {
+    let jwk = match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+    .ok_or(JwtError::UnknownKey)?;
+    let algorithm = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Algorithm::RS256,
+        AlgorithmParameters::EllipticCurve(ec) if ec.curve == EllipticCurve::P256 => {
+            Algorithm::ES256
+        }
+        _ => return Err(JwtError::UnsupportedAlgorithm),
+    };
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| JwtError::Keys(e.to_string()))?;
+    Ok((key, algorithm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // valid.jwt and wrong_audience.jwt are signed with the key in jwks.json, valid.jwt expires
+    // in 2100, both name the key test-key and are issued by https://auth.example.com
+    const JWKS: &str = include_str!("../fixtures/jwks.json");
+    const VALID: &str = include_str!("../fixtures/valid.jwt");
+    const WRONG_AUDIENCE: &str = include_str!("../fixtures/wrong_audience.jwt");
+
+    fn hs256(claims: Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    fn jwks() -> JwtValidator {
+        JwtValidator::jwks(serde_json::from_str(JWKS).unwrap())
+            .with_issuer("https://auth.example.com")
+            .with_audience("mqtt")
+    }
+
+    #[test]
+    fn shared_secret_tokens() {
+        let validator = JwtValidator::hs256(b"secret").with_audience("mqtt");
+        let token = hs256(
+            json!({"sub": "sensor-1", "exp": 4102444800u64, "aud": "mqtt", "write": ["alerts/#"]}),
+            b"secret",
+        );
+        let claims = validator
+            .check_password(Some(&format!("Bearer {}", token)))
+            .unwrap();
+        assert_eq!(claims.subject(), Some("sensor-1"));
+        assert_eq!(claims.expires_at(), Some(4102444800));
+        assert_eq!(claims.strings("write"), vec!["alerts/#"]);
+        assert!(claims.strings("read").is_empty());
+
+        let forged = hs256(json!({"exp": 4102444800u64, "aud": "mqtt"}), b"guess");
+        assert_eq!(validator.validate(&forged), Err(JwtError::InvalidSignature));
+        let expired = hs256(json!({"exp": 1600000000, "aud": "mqtt"}), b"secret");
+        assert_eq!(validator.validate(&expired), Err(JwtError::Expired));
+        let other = hs256(json!({"exp": 4102444800u64, "aud": "web"}), b"secret");
+        assert_eq!(validator.validate(&other), Err(JwtError::InvalidAudience));
+        assert_eq!(validator.check_password(None), Err(JwtError::MissingToken));
+        assert!(matches!(
+            validator.check_password(Some("password")),
+            Err(JwtError::Malformed(_))
+        ));
+        assert_eq!(
+            AuthDecision::from(JwtError::Expired),
+            AuthDecision::deny("token expired")
+        );
+    }
+
+    #[test]
+    fn key_set_tokens() {
+        let claims = jwks().validate(VALID.trim()).unwrap();
+        assert_eq!(claims.strings("read"), vec!["public/#"]);
+        assert_eq!(
+            jwks().validate(WRONG_AUDIENCE.trim()),
+            Err(JwtError::InvalidAudience)
+        );
+        let issuer = jwks().with_issuer("https://other.example.com");
+        assert_eq!(issuer.validate(VALID.trim()), Err(JwtError::InvalidIssuer));
+
+        // the public key can't be used as a shared secret
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("test-key".to_string());
+        let confused = jsonwebtoken::encode(
+            &header,
+            &json!({"exp": 4102444800u64}),
+            &EncodingKey::from_secret(JWKS.as_bytes()),
+        )
+        .unwrap();
+        assert_eq!(
+            jwks().validate(&confused),
+            Err(JwtError::UnsupportedAlgorithm)
+        );
+        header.kid = Some("other-key".to_string());
+        let unknown = jsonwebtoken::encode(
+            &header,
+            &json!({"exp": 4102444800u64}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert_eq!(jwks().validate(&unknown), Err(JwtError::UnknownKey));
+    }
+
+    #[test]
+    fn fetched_keys_are_cached() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fetches);
+        let validator = JwtValidator::jwks_with_fetch(
+            "https://auth.example.com/jwks.json",
+            Duration::from_secs(300),
+            Box::new(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Ok(JWKS.to_string())
+            }),
+        )
+        .with_audience("mqtt");
+        assert!(validator.validate(VALID.trim()).is_ok());
+        assert!(validator.validate(VALID.trim()).is_ok());
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        // an unknown key doesn't fetch again right away
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rotated-key".to_string());
+        let token = jsonwebtoken::encode(
+            &header,
+            &json!({"exp": 4102444800u64}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert_eq!(validator.validate(&token), Err(JwtError::UnknownKey));
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        let down = JwtValidator::jwks_with_fetch(
+            "https://auth.example.com/jwks.json",
+            Duration::from_secs(300),
+            Box::new(|_| Err("connection refused".to_string())),
+        );
+        assert!(matches!(
+            down.validate(VALID.trim()),
+            Err(JwtError::Keys(_))
+        ));
+        assert_eq!(
+            AuthDecision::from(JwtError::Keys(String::new())),
+            AuthDecision::Error(Error::Unknown)
+        );
+    }
+
+    #[test]
+    fn options_choose_the_keys() {
+        let mut opts = HashMap::new();
+        assert!(matches!(
+            JwtValidator::from_opts(&opts),
+            Err(OptError::Missing { .. })
+        ));
+        opts.insert("jwt_secret", "secret");
+        opts.insert("jwt_issuer", "https://auth.example.com");
+        opts.insert("jwt_leeway", "0");
+        let validator = JwtValidator::from_opts(&opts).unwrap();
+        let token = hs256(
+            json!({"exp": 4102444800u64, "iss": "https://auth.example.com"}),
+            b"secret",
+        );
+        assert!(validator.validate(&token).is_ok());
+        opts.insert("jwt_leeway", "a minute");
+        assert!(JwtValidator::from_opts(&opts).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+// How long the plugin callbacks take, to tell whether a plugin is behind latency spikes of the
+// broker. Returned from MosquittoPlugin::callback_timings, the generated callbacks record the
+// time each of them took into a histogram per callback, and log a warning for calls slower than
+// the threshold. publish_to copies the histograms into a stats::Stats:
+//
+//     latency/acl_check/count        calls
+//     latency/acl_check/sum_us       total time in microseconds
+//     latency/acl_check/slow         calls over the threshold
+//     latency/acl_check/le_10ms      calls that took at most 10 ms, one per bucket
+//
+// Options: `latency_buckets`, bucket bounds in milliseconds separated by commas, and
+// `latency_warn_ms`, the threshold.
+use crate::opts::OptError;
+use crate::stats::Stats;
+use crate::MosquittoOpt;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Bucket bounds in milliseconds used when none are configured
+pub const DEFAULT_BUCKETS_MS: &[f64] = &[0.1, 1.0, 10.0, 100.0, 1000.0];
+
+/// The calls of one callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    bounds: Vec<Duration>,
+    // Per bound and one more for the calls slower than every bound
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+    max: Duration,
+    slow: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[Duration]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            count: 0,
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+            slow: 0,
+        }
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        let bucket = self.bounds.partition_point(|&bound| bound < elapsed);
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Calls over the slow threshold
+    pub fn slow(&self) -> u64 {
+        self.slow
+    }
+
+    /// Each bound with the number of calls that took at most that long, like Prometheus
+    /// histogram buckets. The calls slower than every bound are count minus the last.
+    pub fn cumulative(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.bounds
+            .iter()
+            .zip(&self.counts)
+            .scan(0, |total, (bound, count)| {
+                *total += count;
+                Some((*bound, *total))
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CallbackTimings {
+    bounds: Vec<Duration>,
+    slow_threshold: Option<Duration>,
+    histograms: BTreeMap<&'static str, Histogram>,
+}
+
+impl Default for CallbackTimings {
+    fn default() -> Self {
+        CallbackTimings::new(DEFAULT_BUCKETS_MS.iter().map(|&ms| millis(ms)).collect())
+    }
+}
+
+impl CallbackTimings {
+    /// With the given bucket bounds, in any order
+    pub fn new(mut bounds: Vec<Duration>) -> CallbackTimings {
+        bounds.sort();
+        bounds.dedup();
+        CallbackTimings {
+            bounds,
+            slow_threshold: None,
+            histograms: BTreeMap::new(),
+        }
+    }
+
+    /// Calls taking longer are logged as warnings and counted as slow
+    pub fn with_slow_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Reads the `latency_buckets` and `latency_warn_ms` options
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<CallbackTimings, OptError> {
+        let mut timings = match opts.get("latency_buckets") {
+            Some(buckets) => {
+                let bounds = buckets
+                    .split(',')
+                    .map(|ms| parse_millis("latency_buckets", ms.trim()))
+                    .collect::<Result<_, _>>()?;
+                CallbackTimings::new(bounds)
+            }
+            None => CallbackTimings::default(),
+        };
+        if let Some(threshold) = opts.get("latency_warn_ms") {
+            timings = timings.with_slow_threshold(parse_millis("latency_warn_ms", threshold)?);
+        }
+        Ok(timings)
+    }
+
+    /// Adds a call of callback, returns whether it was over the slow threshold
+    pub fn record(&mut self, callback: &'static str, elapsed: Duration) -> bool {
+        let bounds = &self.bounds;
+        let histogram = self
+            .histograms
+            .entry(callback)
+            .or_insert_with(|| Histogram::new(bounds));
+        histogram.record(elapsed);
+        let slow = self.slow_threshold.is_some_and(|t| elapsed > t);
+        if slow {
+            histogram.slow += 1;
+        }
+        slow
+    }
+
+    pub fn get(&self, callback: &str) -> Option<&Histogram> {
+        self.histograms.get(callback)
+    }
+
+    /// The callbacks that were called, by name
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Histogram)> {
+        self.histograms.iter().map(|(name, h)| (*name, h))
+    }
+
+    /// Sets the `latency/...` counters of stats to the current histograms
+    pub fn publish_to(&self, stats: &Stats) {
+        for (callback, histogram) in self.iter() {
+            let name = |suffix: &str| format!("latency/{}/{}", callback, suffix);
+            stats.set(&name("count"), histogram.count);
+            stats.set(&name("sum_us"), histogram.sum.as_micros() as u64);
+            stats.set(&name("slow"), histogram.slow);
+            for (bound, calls) in histogram.cumulative() {
+                let ms = bound.as_micros() as f64 / 1000.0;
+                stats.set(&name(&format!("le_{}ms", ms)), calls);
+            }
+        }
+    }
+}
+
+fn millis(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms / 1000.0)
+}
+
+fn parse_millis(key: &str, value: &str) -> Result<Duration, OptError> {
+    match value.parse::<f64>() {
+        Ok(ms) if ms.is_finite() && ms >= 0.0 => Ok(millis(ms)),
+        _ => Err(OptError::Invalid {
+            key: key.to_string(),
+            value: value.to_string(),
+            expected: "milliseconds",
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn calls_are_sorted_into_buckets() {
+        let ms = Duration::from_millis;
+        let mut timings =
+            CallbackTimings::new(vec![ms(10), ms(1), ms(10)]).with_slow_threshold(ms(50));
+        for elapsed in [ms(0), ms(1), ms(5), ms(60)] {
+            timings.record("acl_check", elapsed);
+        }
+        assert!(!timings.record("on_message", ms(50)));
+        assert!(timings.record("on_message", ms(51)));
+
+        let acl = timings.get("acl_check").unwrap();
+        assert_eq!(
+            (acl.count(), acl.sum(), acl.max(), acl.slow()),
+            (4, ms(66), ms(60), 1)
+        );
+        assert_eq!(
+            acl.cumulative().collect::<Vec<_>>(),
+            vec![(ms(1), 2), (ms(10), 3)]
+        );
+        assert_eq!(
+            timings.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec!["acl_check", "on_message"]
+        );
+
+        let stats = Stats::new("$SYS/plugin/test", Duration::from_secs(10));
+        timings.publish_to(&stats);
+        assert_eq!(stats.get("latency/acl_check/count"), 4);
+        assert_eq!(stats.get("latency/acl_check/sum_us"), 66_000);
+        assert_eq!(stats.get("latency/acl_check/le_1ms"), 2);
+        assert_eq!(stats.get("latency/on_message/slow"), 1);
+    }
+
+    #[test]
+    fn options_set_buckets_and_threshold() {
+        let mut opts = HashMap::new();
+        let timings = CallbackTimings::from_opts(&opts).unwrap();
+        assert_eq!(timings.bounds.len(), DEFAULT_BUCKETS_MS.len());
+        assert_eq!(timings.slow_threshold, None);
+
+        opts.insert("latency_buckets", "5, 0.5");
+        opts.insert("latency_warn_ms", "20");
+        let timings = CallbackTimings::from_opts(&opts).unwrap();
+        assert_eq!(
+            timings.bounds,
+            vec![Duration::from_micros(500), Duration::from_millis(5)]
+        );
+        assert_eq!(timings.slow_threshold, Some(Duration::from_millis(20)));
+
+        opts.insert("latency_warn_ms", "soon");
+        assert!(CallbackTimings::from_opts(&opts).is_err());
+    }
+}
@@ -0,0 +1,720 @@
+// Logins checked against an LDAP directory or Active Directory, enabled with the "ldap" feature.
+// The password is verified by binding as the user, whose DN is either built from a template
+// (bind as user):
+//
+//     plugin_opt_ldap_url ldaps://ldap.example.com
+//     plugin_opt_ldap_user_dn uid=%u,ou=people,dc=example,dc=com
+//
+// or found by a search with a service account first (search and bind), needed when the DN
+// isn't known from the username, like sAMAccountName in Active Directory:
+//
+//     plugin_opt_ldap_bind_dn cn=mqtt,ou=services,dc=example,dc=com
+//     plugin_opt_ldap_bind_password ...
+//     plugin_opt_ldap_base_dn dc=example,dc=com
+//     plugin_opt_ldap_user_filter (sAMAccountName=%u)
+//
+// The groups of the user decide the ACL checks, each group grants the acl_file patterns
+// configured for it (see acl). Groups are searched below ldap_group_base_dn with
+// ldap_group_filter, where %d is the DN of the user and %u the username, and named by
+// ldap_group_attribute:
+//
+//     plugin_opt_ldap_group_base_dn ou=groups,dc=example,dc=com
+//     plugin_opt_ldap_group_filter (member=%d)                     # the default
+//     plugin_opt_ldap_group_attribute cn                           # the default
+//     plugin_opt_ldap_group_sensors write sensors/%u/#; read commands/%u/#
+//     plugin_opt_ldap_group_operators read sensors/#
+//
+// Usernames are escaped before they are put into DNs and filters. Empty passwords are refused,
+// as many servers treat a bind without a password as an anonymous bind that always succeeds.
+//
+// Connections are kept open for the next logins, at most ldap_pool_size (default 4) of them, in
+// search and bind mode they stay bound as the service account. Further options: ldap_timeout
+// (5s), ldap_starttls (false) and ldap_threads (mosquitto 2.1 only, 0 asks on the broker thread).
+// Clients logged in by other plugins are deferred in acl_check, the groups of a client are
+// dropped when it disconnects if the LdapAuth is returned from MosquittoPlugin::client_registry.
+use crate::acl::{self, AclPattern};
+use crate::clients::ClientLifecycle;
+use crate::opts::{opt, opt_or, OptError};
+use crate::{
+    AclCheckAccessLevel, AuthDecision, DisconnectReason, Error, MosquittoClientContext,
+    MosquittoOpt,
+};
+use ldap3::{dn_escape, ldap_escape, LdapConn, LdapConnSettings, Scope, SearchEntry};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const GROUP_OPT_PREFIX: &str = "ldap_group_";
+// Options starting with GROUP_OPT_PREFIX that aren't groups
+const GROUP_OPTS: &[&str] = &[
+    "ldap_group_base_dn",
+    "ldap_group_filter",
+    "ldap_group_attribute",
+];
+// The LDAP result code for a wrong password or unknown DN
+const INVALID_CREDENTIALS: u32 = 49;
+
+// What the login needs from a connection, a trait so tests don't need a server
+trait Directory: Send {
+    // Whether the directory accepted the password, Err when it couldn't be asked
+    fn bind(&mut self, dn: &str, password: &str) -> Result<bool, String>;
+    // The DNs found and the values of attribute for each of them
+    fn search(
+        &mut self,
+        base: &str,
+        filter: &str,
+        attribute: &str,
+    ) -> Result<Vec<(String, Vec<String>)>, String>;
+}
+
+struct Ldap {
+    conn: LdapConn,
+    timeout: Duration,
+}
+
+impl Directory for Ldap {
+    fn bind(&mut self, dn: &str, password: &str) -> Result<bool, String> {
+        let result = self
+            .conn
+            .with_timeout(self.timeout)
+            .simple_bind(dn, password)
+            .map_err(|e| e.to_string())?;
+        match result.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            rc => Err(format!("bind failed with result code {}", rc)),
+        }
+    }
+
+    fn search(
+        &mut self,
+        base: &str,
+        filter: &str,
+        attribute: &str,
+    ) -> Result<Vec<(String, Vec<String>)>, String> {
+        let (entries, _) = self
+            .conn
+            .with_timeout(self.timeout)
+            .search(base, Scope::Subtree, filter, vec![attribute])
+            .and_then(|result| result.success())
+            .map_err(|e| e.to_string())?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let mut entry = SearchEntry::construct(entry);
+                let values = entry.attrs.remove(attribute).unwrap_or_default();
+                (entry.dn, values)
+            })
+            .collect())
+    }
+}
+
+type Connect = dyn Fn() -> Result<Box<dyn Directory>, String> + Send + Sync;
+
+/// How the DN of a user is found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindMode {
+    /// The DN is the template with %u replaced by the username
+    User { dn_template: String },
+    /// The DN is searched for as the service account, with %u in the filter replaced by the
+    /// username. Exactly one entry has to be found.
+    Search {
+        bind_dn: String,
+        bind_password: String,
+        base_dn: String,
+        filter: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GroupSearch {
+    base_dn: String,
+    filter: String,
+    attribute: String,
+}
+
+// A pooled connection, and whether it is bound as the service account
+struct Conn {
+    directory: Box<dyn Directory>,
+    service: bool,
+}
+
+/// Where and how to check logins
+pub struct LdapConfig {
+    url: String,
+    mode: BindMode,
+    groups: Option<GroupSearch>,
+    timeout: Duration,
+    starttls: bool,
+    pool_size: usize,
+    connect: Option<Box<Connect>>,
+}
+
+impl fmt::Debug for LdapConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LdapConfig")
+            .field("url", &self.url)
+            .field("groups", &self.groups)
+            .finish()
+    }
+}
+
+impl LdapConfig {
+    /// Binds as the DN made from dn_template, `uid=%u,ou=people,dc=example,dc=com`
+    pub fn bind_as_user(url: &str, dn_template: &str) -> LdapConfig {
+        LdapConfig::new(
+            url,
+            BindMode::User {
+                dn_template: dn_template.to_string(),
+            },
+        )
+    }
+
+    /// Searches the user below base_dn with `(uid=%u)` as the service account, then binds as the
+    /// DN found
+    pub fn search_and_bind(
+        url: &str,
+        bind_dn: &str,
+        bind_password: &str,
+        base_dn: &str,
+    ) -> LdapConfig {
+        LdapConfig::new(
+            url,
+            BindMode::Search {
+                bind_dn: bind_dn.to_string(),
+                bind_password: bind_password.to_string(),
+                base_dn: base_dn.to_string(),
+                filter: "(uid=%u)".to_string(),
+            },
+        )
+    }
+
+    fn new(url: &str, mode: BindMode) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            mode,
+            groups: None,
+            timeout: Duration::from_secs(5),
+            starttls: false,
+            pool_size: 4,
+            connect: None,
+        }
+    }
+
+    /// The filter finding users in search and bind mode, like `(sAMAccountName=%u)`
+    pub fn with_user_filter(mut self, filter: &str) -> Self {
+        if let BindMode::Search { filter: f, .. } = &mut self.mode {
+            *f = filter.to_string();
+        }
+        self
+    }
+
+    /// Looks up the groups of users below base_dn with filter, %d is replaced by the DN of the
+    /// user and %u by the username, and names them by attribute
+    pub fn with_groups(mut self, base_dn: &str, filter: &str, attribute: &str) -> Self {
+        self.groups = Some(GroupSearch {
+            base_dn: base_dn.to_string(),
+            filter: filter.to_string(),
+            attribute: attribute.to_string(),
+        });
+        self
+    }
+
+    /// For connecting and for each operation
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Upgrades ldap:// connections to TLS before binding
+    pub fn with_starttls(mut self) -> Self {
+        self.starttls = true;
+        self
+    }
+
+    /// Connections kept open for later logins, 0 connects for every login
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    pub fn mode(&self) -> &BindMode {
+        &self.mode
+    }
+
+    /// Reads the server, bind mode and group search from the options, see the top of the module
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<LdapConfig, OptError> {
+        let url: String = opt(opts, "ldap_url")?;
+        let dn_template: Option<String> = opt(opts, "ldap_user_dn")?;
+        let mut config = match dn_template {
+            Some(template) => LdapConfig::bind_as_user(&url, &template),
+            None => {
+                let bind_dn: String = opt(opts, "ldap_bind_dn")?;
+                let bind_password: String = opt(opts, "ldap_bind_password")?;
+                let base_dn: String = opt(opts, "ldap_base_dn")?;
+                LdapConfig::search_and_bind(&url, &bind_dn, &bind_password, &base_dn)
+                    .with_user_filter(&opt_or::<String>(opts, "ldap_user_filter", "(uid=%u)")?)
+            }
+        };
+        let group_base: Option<String> = opt(opts, "ldap_group_base_dn")?;
+        if let Some(base_dn) = group_base {
+            let filter: String = opt_or(opts, "ldap_group_filter", "(member=%d)")?;
+            let attribute: String = opt_or(opts, "ldap_group_attribute", "cn")?;
+            config = config.with_groups(&base_dn, &filter, &attribute);
+        }
+        if opt_or(opts, "ldap_starttls", "false")? {
+            config = config.with_starttls();
+        }
+        Ok(config
+            .with_timeout(opt_or(opts, "ldap_timeout", "5s")?)
+            .with_pool_size(opt_or(opts, "ldap_pool_size", "4")?))
+    }
+
+    fn connect(&self) -> Result<Box<dyn Directory>, String> {
+        if let Some(connect) = &self.connect {
+            return connect();
+        }
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls);
+        let conn = LdapConn::with_settings(settings, &self.url).map_err(|e| e.to_string())?;
+        Ok(Box::new(Ldap {
+            conn,
+            timeout: self.timeout,
+        }))
+    }
+}
+
+// The configuration with the idle connections, shared with the worker threads
+struct Directories {
+    config: LdapConfig,
+    idle: Mutex<Vec<Conn>>,
+}
+
+impl Directories {
+    // The groups of the user when the password is right, None when it isn't
+    fn authenticate(&self, username: &str, password: &str) -> Result<Option<Vec<String>>, String> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => Conn {
+                directory: self.config.connect()?,
+                service: false,
+            },
+        };
+        // A connection failing half way is dropped, the next login connects again
+        let groups = self.authenticate_on(&mut conn, username, password)?;
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.config.pool_size {
+            idle.push(conn);
+        }
+        Ok(groups)
+    }
+
+    fn authenticate_on(
+        &self,
+        conn: &mut Conn,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Vec<String>>, String> {
+        let dn = match &self.config.mode {
+            BindMode::User { dn_template } => dn_template.replace("%u", &dn_escape(username)),
+            BindMode::Search {
+                base_dn, filter, ..
+            } => {
+                self.bind_service(conn)?;
+                let filter = filter.replace("%u", &ldap_escape(username));
+                let mut found = conn.directory.search(base_dn, &filter, "1.1")?;
+                if found.len() != 1 {
+                    return Ok(None);
+                }
+                found.remove(0).0
+            }
+        };
+        conn.service = false;
+        if !conn.directory.bind(&dn, password)? {
+            return Ok(None);
+        }
+        let search = match &self.config.groups {
+            Some(search) => search,
+            None => return Ok(Some(Vec::new())),
+        };
+        if matches!(self.config.mode, BindMode::Search { .. }) {
+            self.bind_service(conn)?;
+        }
+        let filter = search
+            .filter
+            .replace("%d", &ldap_escape(&dn))
+            .replace("%u", &ldap_escape(username));
+        let groups = conn
+            .directory
+            .search(&search.base_dn, &filter, &search.attribute)?
+            .into_iter()
+            .flat_map(|(_, names)| names)
+            .collect();
+        Ok(Some(groups))
+    }
+
+    fn bind_service(&self, conn: &mut Conn) -> Result<(), String> {
+        if let BindMode::Search {
+            bind_dn,
+            bind_password,
+            ..
+        } = &self.config.mode
+        {
+            if !conn.service {
+                if !conn.directory.bind(bind_dn, bind_password)? {
+                    return Err(format!("the directory refused the password of {}", bind_dn));
+                }
+                conn.service = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+/// Logins checked against the directory, and ACL checks decided by the groups of the user
+pub struct LdapAuth {
+    directories: Arc<Directories>,
+    // The groups of the logged in clients, by client id
+    sessions: Sessions,
+    groups: HashMap<String, Vec<AclPattern>>,
+    #[cfg(mosquitto_2_1)]
+    pool: Option<crate::worker_pool::WorkerPool>,
+}
+
+impl fmt::Debug for LdapAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LdapAuth")
+            .field("config", &self.directories.config)
+            .field("groups", &self.groups.keys())
+            .finish()
+    }
+}
+
+impl LdapAuth {
+    pub fn new(config: LdapConfig) -> LdapAuth {
+        LdapAuth {
+            directories: Arc::new(Directories {
+                config,
+                idle: Mutex::new(Vec::new()),
+            }),
+            sessions: Arc::default(),
+            groups: HashMap::new(),
+            #[cfg(mosquitto_2_1)]
+            pool: None,
+        }
+    }
+
+    /// Lets members of the group do what the patterns allow, a `;` separated list like
+    /// `write sensors/%u/#; read commands/%u/#`
+    pub fn with_group(mut self, group: &str, patterns: &str) -> Result<Self, Error> {
+        self.groups
+            .insert(group.to_string(), AclPattern::parse_list(patterns)?);
+        Ok(self)
+    }
+
+    /// Checks logins on threads worker threads, so the broker doesn't wait for the directory
+    #[cfg(mosquitto_2_1)]
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.pool = Some(crate::worker_pool::WorkerPool::new(threads, 256));
+        self
+    }
+
+    /// The configuration, groups and threads from the options, see the top of the module
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<LdapAuth, OptError> {
+        let mut auth = LdapAuth::new(LdapConfig::from_opts(opts)?);
+        for (key, patterns) in opts {
+            let group = match key.strip_prefix(GROUP_OPT_PREFIX) {
+                Some(group) if !GROUP_OPTS.contains(key) => group,
+                _ => continue,
+            };
+            auth = auth
+                .with_group(group, patterns)
+                .map_err(|_| OptError::Invalid {
+                    key: key.to_string(),
+                    value: patterns.to_string(),
+                    expected: "acl_file patterns separated by ;",
+                })?;
+        }
+        #[cfg(mosquitto_2_1)]
+        {
+            let threads: usize = opt_or(opts, "ldap_threads", "0")?;
+            if threads > 0 {
+                auth = auth.with_threads(threads);
+            }
+        }
+        Ok(auth)
+    }
+
+    /// Checks the username and password against the directory. Clients without either are
+    /// denied.
+    pub fn username_password(
+        &self,
+        client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let (username, password) = match (username, password) {
+            (Some(username), Some(password)) if !username.is_empty() && !password.is_empty() => {
+                (username.to_string(), password.to_string())
+            }
+            _ => return AuthDecision::deny("username and password required"),
+        };
+        let directories = Arc::clone(&self.directories);
+        let sessions = Arc::clone(&self.sessions);
+        let client_id = client.get_id();
+        let check = move || match directories.authenticate(&username, &password) {
+            Ok(Some(groups)) => {
+                let mut sessions = sessions.lock().unwrap_or_else(|e| e.into_inner());
+                sessions.insert(client_id, groups);
+                AuthDecision::Allow
+            }
+            Ok(None) => AuthDecision::deny("wrong username or password"),
+            Err(_) => AuthDecision::Error(Error::Unknown),
+        };
+        #[cfg(mosquitto_2_1)]
+        if let Some(pool) = &self.pool {
+            return pool.submit_auth(client, check);
+        }
+        check()
+    }
+
+    /// Decides an ACL check with the groups of the user. Clients that didn't log in through
+    /// LDAP are deferred.
+    pub fn acl_check(
+        &self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let client_id = client.get_id();
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let groups = match sessions.get(&client_id) {
+            Some(groups) => groups,
+            None => return AuthDecision::Defer,
+        };
+        let patterns: Vec<AclPattern> = groups
+            .iter()
+            .filter_map(|group| self.groups.get(group))
+            .flatten()
+            .cloned()
+            .collect();
+        let username = client.get_username();
+        match acl::check(&patterns, &client_id, username.as_deref(), level, topic) {
+            Ok(_) => AuthDecision::Allow,
+            Err(_) => AuthDecision::deny("not allowed by the groups of the user"),
+        }
+    }
+
+    /// The groups of the user the client logged in as, None for clients not logged in through
+    /// LDAP
+    pub fn groups(&self, client_id: &str) -> Option<Vec<String>> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.get(client_id).cloned()
+    }
+}
+
+/// Forgets the groups of a client when it disconnects
+impl ClientLifecycle for LdapAuth {
+    fn authenticated(&mut self, _client: &dyn MosquittoClientContext) {}
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, _reason: DisconnectReason) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.remove(&client.get_id());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::client;
+    use crate::AclCheckAccessLevel::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // alice and bob in ou=people, alice in the sensors group, and the service account
+    struct Fake {
+        binds: Arc<Mutex<Vec<String>>>,
+        bound: Option<String>,
+    }
+
+    impl Directory for Fake {
+        fn bind(&mut self, dn: &str, password: &str) -> Result<bool, String> {
+            self.binds.lock().unwrap().push(dn.to_string());
+            let ok = matches!(
+                (dn, password),
+                ("uid=alice,ou=people,dc=example,dc=com", "secret")
+                    | ("uid=bob,ou=people,dc=example,dc=com", "hunter2")
+                    | ("cn=mqtt,dc=example,dc=com", "service")
+            );
+            self.bound = if ok { Some(dn.to_string()) } else { None };
+            Ok(ok)
+        }
+
+        fn search(
+            &mut self,
+            base: &str,
+            filter: &str,
+            _attribute: &str,
+        ) -> Result<Vec<(String, Vec<String>)>, String> {
+            if self.bound.is_none() {
+                return Err("anonymous search".to_string());
+            }
+            Ok(match (base, filter) {
+                ("dc=example,dc=com", "(uid=alice)") => {
+                    vec![("uid=alice,ou=people,dc=example,dc=com".to_string(), vec![])]
+                }
+                (
+                    "ou=groups,dc=example,dc=com",
+                    "(member=uid=alice,ou=people,dc=example,dc=com)",
+                ) => {
+                    vec![("cn=sensors".to_string(), vec!["sensors".to_string()])]
+                }
+                _ => vec![],
+            })
+        }
+    }
+
+    fn with_fake(config: LdapConfig) -> (LdapConfig, Arc<AtomicUsize>, Arc<Mutex<Vec<String>>>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let binds = Arc::new(Mutex::new(Vec::new()));
+        let (c, b) = (Arc::clone(&connects), Arc::clone(&binds));
+        let config = LdapConfig {
+            connect: Some(Box::new(move || {
+                c.fetch_add(1, Ordering::Relaxed);
+                Ok(Box::new(Fake {
+                    binds: Arc::clone(&b),
+                    bound: None,
+                }) as Box<dyn Directory>)
+            })),
+            ..config
+        };
+        (config, connects, binds)
+    }
+
+    #[test]
+    fn bind_as_user() {
+        let config = LdapConfig::bind_as_user(
+            "ldap://ldap.example.com",
+            "uid=%u,ou=people,dc=example,dc=com",
+        );
+        let (config, connects, binds) = with_fake(config);
+        let auth = LdapAuth::new(config);
+        let client = client();
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(auth.groups("stub-client"), Some(vec![]));
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("guess")),
+            AuthDecision::deny("wrong username or password")
+        );
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("")),
+            AuthDecision::deny("username and password required")
+        );
+        // escaped, so it can't add to the DN
+        auth.username_password(&client, Some("bob,ou=admins"), Some("hunter2"));
+        let bind = binds.lock().unwrap().last().cloned().unwrap();
+        assert!(bind.starts_with("uid=bob\\"), "{}", bind);
+        assert!(
+            !bind.contains(",ou=admins") && bind.ends_with(",ou=people,dc=example,dc=com"),
+            "{}",
+            bind
+        );
+        // the connection is reused
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn search_and_bind_with_groups() {
+        let config = LdapConfig::search_and_bind(
+            "ldap://ldap.example.com",
+            "cn=mqtt,dc=example,dc=com",
+            "service",
+            "dc=example,dc=com",
+        )
+        .with_groups("ou=groups,dc=example,dc=com", "(member=%d)", "cn");
+        let (config, _, binds) = with_fake(config);
+        let mut auth = LdapAuth::new(config)
+            .with_group("sensors", "write sensors/%c/#; read commands/#")
+            .unwrap();
+        let client = client();
+        assert_eq!(
+            auth.acl_check(&client, Read, "commands/reboot"),
+            AuthDecision::Defer
+        );
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            *binds.lock().unwrap(),
+            vec![
+                "cn=mqtt,dc=example,dc=com",
+                "uid=alice,ou=people,dc=example,dc=com",
+                "cn=mqtt,dc=example,dc=com",
+            ]
+        );
+        assert_eq!(
+            auth.groups("stub-client"),
+            Some(vec!["sensors".to_string()])
+        );
+        assert_eq!(
+            auth.acl_check(&client, Write, "sensors/stub-client/temp"),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.acl_check(&client, Write, "commands/reboot"),
+            AuthDecision::deny("not allowed by the groups of the user")
+        );
+        // bob isn't found by the filter
+        assert_eq!(
+            auth.username_password(&client, Some("bob"), Some("hunter2")),
+            AuthDecision::deny("wrong username or password")
+        );
+
+        auth.disconnected(&client, DisconnectReason::Normal);
+        assert_eq!(auth.groups("stub-client"), None);
+    }
+
+    #[test]
+    fn options_choose_the_mode() {
+        let mut opts = HashMap::new();
+        opts.insert("ldap_url", "ldap://ldap.example.com");
+        assert!(matches!(
+            LdapConfig::from_opts(&opts),
+            Err(OptError::Missing { .. })
+        ));
+        opts.insert("ldap_bind_dn", "cn=mqtt,dc=example,dc=com");
+        opts.insert("ldap_bind_password", "service");
+        opts.insert("ldap_base_dn", "dc=example,dc=com");
+        opts.insert("ldap_user_filter", "(sAMAccountName=%u)");
+        opts.insert("ldap_group_base_dn", "ou=groups,dc=example,dc=com");
+        opts.insert("ldap_group_operators", "read #");
+        let auth = LdapAuth::from_opts(&opts).unwrap();
+        let config = &auth.directories.config;
+        assert!(matches!(
+            config.mode(),
+            BindMode::Search { filter, .. } if filter == "(sAMAccountName=%u)"
+        ));
+        assert_eq!(
+            config
+                .groups
+                .as_ref()
+                .map(|g| (g.filter.as_str(), g.attribute.as_str())),
+            Some(("(member=%d)", "cn"))
+        );
+        assert_eq!(auth.groups.keys().collect::<Vec<_>>(), vec!["operators"]);
+
+        opts.insert("ldap_user_dn", "uid=%u,dc=example,dc=com");
+        assert!(matches!(
+            LdapConfig::from_opts(&opts).unwrap().mode(),
+            BindMode::User { .. }
+        ));
+    }
+}
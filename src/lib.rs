@@ -4,15 +4,81 @@ pub use mosquitto_dev::*;
 
 use std::collections::HashMap;
 use std::convert::From;
+use std::convert::TryFrom;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::fmt;
 
+pub mod acl;
+pub mod acl_cache;
+pub mod acl_provider;
+#[cfg(feature = "async")]
+pub mod async_auth;
+pub mod audit;
+#[cfg(feature = "auth-plugin-v4")]
+pub mod auth_plugin_v4;
+pub mod broker_handle;
+pub mod certificate;
+pub mod clients;
+#[cfg(mosquitto_2_1)]
+pub mod delayed_auth;
 pub mod dynlib;
+#[cfg(feature = "dynsec")]
+pub mod dynsec;
+#[cfg(feature = "introspection")]
+pub mod introspection;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod latency;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+#[cfg(feature = "log")]
+pub mod logger;
+pub mod mosquitto_calls;
+pub mod opts;
+#[cfg(feature = "passwd")]
+pub mod passwd;
+#[cfg(feature = "password")]
+pub mod password;
+#[cfg(mosquitto_2_1)]
+pub mod persist;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod properties;
+pub mod ratelimit;
+pub mod raw;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod retained;
+pub mod scheduler;
+pub mod simple;
+#[cfg(feature = "sql")]
+pub mod sql;
+#[cfg(feature = "state")]
+pub mod state;
+pub mod stats;
+#[cfg(feature = "testing")]
+pub mod test_broker;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod topic;
+#[cfg(feature = "tracing")]
+pub mod trace;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+pub mod worker_pool;
 
+pub use clients::ClientLifecycle;
 pub use dynlib::*;
 pub use libc;
-use libc::c_void;
+#[cfg(feature = "log")]
+pub use log;
+pub use mosquitto_plugin_macros::mosquitto_plugin;
+pub use simple::SimpleAuthPlugin;
+#[cfg(feature = "async")]
+pub use tokio;
+#[cfg(feature = "tracing")]
+pub use tracing;
 use std::net::IpAddr;
 use std::str::FromStr;
 
@@ -26,29 +92,63 @@ pub fn __own_string(ch: *mut std::os::raw::c_char) -> String {
 
 pub type MosquittoOpt<'a> = HashMap<&'a str, &'a str>;
 
+// Depending on the broker version and the config syntax used (auth_opt_<key> with
+// auth_plugin, plugin_opt_<key> with plugin) the keys arrive with or without a prefix.
+const OPT_PREFIXES: [&str; 2] = ["plugin_opt_", "auth_opt_"];
+
+fn strip_opt_prefix(key: &str) -> &str {
+    OPT_PREFIXES
+        .iter()
+        .find_map(|prefix| key.strip_prefix(prefix))
+        .unwrap_or(key)
+}
+
+// The options as a string for logging, sorted by key, with the values of anything looking like
+// a credential replaced.
+pub(crate) fn describe_opts(opts: &MosquittoOpt) -> String {
+    let mut keys: Vec<&&str> = opts.keys().collect();
+    keys.sort();
+    keys.iter()
+        .map(|key| {
+            let lower = key.to_lowercase();
+            if lower.contains("password") || lower.contains("secret") {
+                format!("{}=<redacted>", key)
+            } else {
+                format!("{}={}", key, opts[**key])
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 // parses the pointers given by mosquitto into a rust native structure
+// Keys are handed to the plugin without their auth_opt_ or plugin_opt_ prefix. Options with a
+// null or non UTF-8 key or value are left out, and a null array is no options at all.
 pub fn __from_ptr_and_size<'a>(opts: *mut mosquitto_opt, count: usize) -> MosquittoOpt<'a> {
     let mut map = HashMap::new();
+    if opts.is_null() {
+        return map;
+    }
     // Yep, raw pointer values
     let optsval = opts as usize;
     for i in 0..count {
         // manually increment the pointers according to the coun value
         let opt = unsafe {
-            let opt = optsval + i as usize * std::mem::size_of::<mosquitto_opt>();
-            (opt as *mut mosquitto_opt).as_ref().unwrap()
-        };
-
-        // get a reference, and then use this to parse the values into owned strings
-        //let key = __own_string(opt.key);
-        let key: &str = unsafe {
-            let c_str = std::ffi::CStr::from_ptr(opt.key);
-            c_str.to_str().unwrap()
+            let opt = optsval + i * std::mem::size_of::<mosquitto_opt>();
+            &*(opt as *const mosquitto_opt)
         };
-        let value: &str = unsafe {
-            let c_str = std::ffi::CStr::from_ptr(opt.value);
-            c_str.to_str().unwrap()
+        if opt.key.is_null() || opt.value.is_null() {
+            continue;
+        }
+        let (key, value) = unsafe {
+            (
+                std::ffi::CStr::from_ptr(opt.key).to_str(),
+                std::ffi::CStr::from_ptr(opt.value).to_str(),
+            )
         };
-        map.insert(key, value);
+        if let (Ok(key), Ok(value)) = (key, value) {
+            map.insert(strip_opt_prefix(key), value);
+        }
     }
 
     map
@@ -85,11 +185,16 @@ impl std::fmt::Display for AccessLevel {
 }
 
 #[repr(C)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
 pub enum AclCheckAccessLevel {
+    /// A message is delivered to the client
     Read = 1,
+    /// The client publishes a message
     Write = 2,
+    /// The client subscribes, the topic is the filter it sent and may contain wildcards
     Subscribe = 4,
+    /// The client unsubscribes, the topic is the filter it sent
+    Unsubscribe = 8,
 }
 
 impl std::fmt::Display for AclCheckAccessLevel {
@@ -104,14 +209,60 @@ impl Into<Option<AclCheckAccessLevel>> for AccessLevel {
             AccessLevel::Read => Some(AclCheckAccessLevel::Read),
             AccessLevel::Write => Some(AclCheckAccessLevel::Write),
             AccessLevel::Subscribe => Some(AclCheckAccessLevel::Subscribe),
+            AccessLevel::Unsubscribe => Some(AclCheckAccessLevel::Unsubscribe),
             _ => None,
         }
     }
 }
 
+/// Why a client went away, from the reason code of the disconnect event. mosquitto reports a
+/// DISCONNECT asking for the will to be published (MQTT v5 reason 0x04) like any other, so it
+/// is Normal as well.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client sent DISCONNECT
+    Normal,
+    /// The connection broke or was closed without DISCONNECT
+    ConnectionLost,
+    /// Nothing was received from the client within its keepalive
+    KeepaliveTimeout,
+    /// The client sent something invalid, or used a QoS, retain or topic alias the broker
+    /// doesn't allow it
+    ProtocolError,
+    /// The client sent a packet larger than message_size_limit or max_packet_size
+    PacketTooLarge,
+    /// TLS failed on the connection
+    TlsError,
+    /// The client was kicked, e.g. through the dynamic security plugin
+    AdministrativeAction,
+    /// A new connection with the same client id took over the session. The broker reports it
+    /// as Normal, the generated callbacks tell it apart, see MosquittoPlugin::on_session_takeover.
+    SessionTakenOver,
+    /// A code mosquitto doesn't use for disconnects, or a newer one
+    Unknown(i32),
+}
+
+impl From<i32> for DisconnectReason {
+    fn from(reason: i32) -> Self {
+        match reason {
+            0 => DisconnectReason::Normal, // MOSQ_ERR_SUCCESS
+            7 => DisconnectReason::ConnectionLost, // MOSQ_ERR_CONN_LOST
+            19 => DisconnectReason::KeepaliveTimeout, // MOSQ_ERR_KEEPALIVE
+            2 | 18 | 21 | 22 => DisconnectReason::ProtocolError, // MOSQ_ERR_PROTOCOL, MOSQ_ERR_MALFORMED_UTF8, MOSQ_ERR_MALFORMED_PACKET, MOSQ_ERR_DUPLICATE_PROPERTY
+            24 | 28 | 29 => DisconnectReason::ProtocolError, // MOSQ_ERR_QOS_NOT_SUPPORTED, MOSQ_ERR_RETAIN_NOT_SUPPORTED, MOSQ_ERR_TOPIC_ALIAS_INVALID
+            9 | 25 => DisconnectReason::PacketTooLarge, // MOSQ_ERR_PAYLOAD_SIZE, MOSQ_ERR_OVERSIZE_PACKET
+            8 | 23 => DisconnectReason::TlsError, // MOSQ_ERR_TLS, MOSQ_ERR_TLS_HANDSHAKE
+            30 => DisconnectReason::AdministrativeAction, // MOSQ_ERR_ADMINISTRATIVE_ACTION
+            other => DisconnectReason::Unknown(other),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Error {
+    /// mosquitto 2.1: username_password decides later, see delayed_auth
+    AuthDelayed = -5,
     AuthContinue = -4,
     NoSubscriber = -3,
     SubExists = -2,
@@ -142,11 +293,140 @@ pub enum Error {
     QosNotSupported = 24,
     OversizePacket = 25,
     OCSP = 26,
+    Timeout = 27,
+    RetainNotSupported = 28,
+    TopicAliasInvalid = 29,
+    AdministrativeAction = 30,
+    AlreadyExists = 31,
+    /// Not a mosquitto error code of its own, the topic of a message published by the plugin
+    /// contains `+` or `#`. Converts back into Inval, like the following two.
+    TopicWildcard = 256,
+    /// The topic of a published message is longer than 65535 bytes
+    TopicTooLong = 257,
+    /// The topic of a published message has more levels than the broker accepts
+    TopicTooDeep = 258,
 }
 
+#[cfg(mosquitto_2_1)]
+const _: () = assert!(Error::AuthDelayed as i32 == mosq_err_t_MOSQ_ERR_AUTH_DELAYED as i32);
+// Returned as is by the generated callbacks for chaining plugins
+const _: () = assert!(Error::PluginDefer as mosq_err_t == mosq_err_t_MOSQ_ERR_PLUGIN_DEFER);
+
 impl Into<i32> for Error {
     fn into(self) -> i32 {
-        self as i32
+        match self {
+            Error::TopicWildcard | Error::TopicTooLong | Error::TopicTooDeep => Error::Inval as i32,
+            e => e as i32,
+        }
+    }
+}
+
+impl Error {
+    /// The variant for a mosq_err_t returned by the broker, None for MOSQ_ERR_SUCCESS and for
+    /// codes added to mosquitto after this crate
+    pub fn from_code(code: i32) -> Option<Error> {
+        let error = match code {
+            -5 => Error::AuthDelayed,
+            -4 => Error::AuthContinue,
+            -3 => Error::NoSubscriber,
+            -2 => Error::SubExists,
+            -1 => Error::ConnPending,
+            1 => Error::NoMem,
+            2 => Error::Protocol,
+            3 => Error::Inval,
+            4 => Error::NoConn,
+            5 => Error::ConnRefused,
+            6 => Error::NotFound,
+            7 => Error::ConnLost,
+            8 => Error::Tls,
+            9 => Error::PayloadSize,
+            10 => Error::NotSupported,
+            11 => Error::Auth,
+            12 => Error::AclDenied,
+            13 => Error::Unknown,
+            14 => Error::Errno,
+            15 => Error::Eai,
+            16 => Error::Proxy,
+            17 => Error::PluginDefer,
+            18 => Error::MalformedUtf8,
+            19 => Error::Keepalive,
+            20 => Error::Lookup,
+            21 => Error::MalformedPacket,
+            22 => Error::DuplicateProperty,
+            23 => Error::TlsHandshake,
+            24 => Error::QosNotSupported,
+            25 => Error::OversizePacket,
+            26 => Error::OCSP,
+            27 => Error::Timeout,
+            28 => Error::RetainNotSupported,
+            29 => Error::TopicAliasInvalid,
+            30 => Error::AdministrativeAction,
+            31 => Error::AlreadyExists,
+            _ => return None,
+        };
+        Some(error)
+    }
+
+    /// What went wrong, the text mosquitto_strerror has where it has one
+    pub fn description(&self) -> &'static str {
+        match self {
+            Error::AuthDelayed => "Authentication delayed.",
+            Error::AuthContinue => "Continue with authentication.",
+            Error::NoSubscriber => "No subscribers.",
+            Error::SubExists => "Subscription already exists.",
+            Error::ConnPending => "Connection pending.",
+            Error::NoMem => "Out of memory.",
+            Error::Protocol => "A network protocol error occurred when communicating with the broker.",
+            Error::Inval => "Invalid function arguments provided.",
+            Error::NoConn => "The client is not currently connected.",
+            Error::ConnRefused => "The connection was refused.",
+            Error::NotFound => "Message not found (internal error).",
+            Error::ConnLost => "The connection was lost.",
+            Error::Tls => "A TLS error occurred.",
+            Error::PayloadSize => "Payload too large.",
+            Error::NotSupported => "This feature is not supported.",
+            Error::Auth => "Authorisation failed.",
+            Error::AclDenied => "Access denied by ACL.",
+            Error::Unknown => "Unknown error.",
+            Error::Errno => "Error defined by errno.",
+            Error::Eai => "Lookup error.",
+            Error::Proxy => "Proxy error.",
+            Error::PluginDefer => "Plugin deferred.",
+            Error::MalformedUtf8 => "Malformed UTF-8",
+            Error::Keepalive => "Keepalive exceeded",
+            Error::Lookup => "DNS Lookup failed",
+            Error::MalformedPacket => "Malformed packet",
+            Error::DuplicateProperty => "Duplicate property in property list",
+            Error::TlsHandshake => "TLS handshake failed.",
+            Error::QosNotSupported => "Requested QoS not supported on server.",
+            Error::OversizePacket => "Packet larger than supported by the server.",
+            Error::OCSP => "OCSP error.",
+            Error::Timeout => "Timeout",
+            Error::RetainNotSupported => "Retain not supported",
+            Error::TopicAliasInvalid => "Topic alias invalid",
+            Error::AdministrativeAction => "Administrative action",
+            Error::AlreadyExists => "Already exists",
+            Error::TopicWildcard => "Wildcards can't be published to.",
+            Error::TopicTooLong => "Topic longer than 65535 bytes.",
+            Error::TopicTooDeep => "Topic nested deeper than the broker allows.",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code: i32 = (*self).into();
+        write!(f, "{} (mosquitto error {})", self.description(), code)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// What a broker function returning a mosq_err_t did, codes this crate doesn't know are Unknown
+pub(crate) fn to_result(code: i32) -> Result<Success, Error> {
+    match code {
+        0 => Ok(Success),
+        code => Err(Error::from_code(code).unwrap_or(Error::Unknown)),
     }
 }
 
@@ -159,6 +439,55 @@ impl Into<i32> for Success {
     }
 }
 
+/// The outcome of username_password and the ACL checks. Deny is a decision about the client,
+/// Error means the plugin couldn't decide, e.g. because its backend is down, and is logged as
+/// such.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Let the client in, or allow the access
+    Allow,
+    /// Refuse the client or the access, the broker sees MOSQ_ERR_AUTH or MOSQ_ERR_ACL_DENIED.
+    /// A non-empty reason is written to the broker log at debug level.
+    Deny { reason: String },
+    /// Leave the decision to the other plugins and the broker configuration
+    Defer,
+    /// Checking failed, logged as an error and returned to the broker as is. Error::AuthDelayed
+    /// (mosquitto 2.1) isn't logged, see delayed_auth.
+    Error(Error),
+}
+
+impl AuthDecision {
+    /// Deny with a reason for the broker log
+    pub fn deny(reason: impl Into<String>) -> AuthDecision {
+        AuthDecision::Deny {
+            reason: reason.into(),
+        }
+    }
+}
+
+/// For plugins and helpers like acl::check written against Result: Err(Auth) and
+/// Err(AclDenied) deny without a reason, Err(PluginDefer) defers and other errors are Error.
+impl From<Result<Success, Error>> for AuthDecision {
+    fn from(result: Result<Success, Error>) -> AuthDecision {
+        match result {
+            Ok(Success) => AuthDecision::Allow,
+            Err(error) => error.into(),
+        }
+    }
+}
+
+impl From<Error> for AuthDecision {
+    fn from(error: Error) -> AuthDecision {
+        match error {
+            Error::Auth | Error::AclDenied => AuthDecision::Deny {
+                reason: String::new(),
+            },
+            Error::PluginDefer => AuthDecision::Defer,
+            error => AuthDecision::Error(error),
+        }
+    }
+}
+
 // #[repr(C)]
 // #[derive(Debug)]
 // pub enum QoS {
@@ -177,22 +506,354 @@ impl Into<i32> for Success {
 //     }
 // }
 
+/// A message as acl_check, on_message and on_control_command see it. The broker events carry
+/// neither the packet id nor the dup flag of the PUBLISH, so plugins can't get at them.
 #[derive(Debug)]
 pub struct MosquittoMessage<'a> {
     pub topic: &'a str,
     pub payload: &'a [u8],
-    pub qos: i32,
+    pub qos: QOS,
+    pub retain: bool,
+    /// The MQTT v5 properties the client sent with the message
+    pub properties: properties::MessageProperties<'a>,
+}
+
+/// A MosquittoMessage that owns its topic, payload and properties, so it can be kept past the
+/// callback, e.g. in a queue or on a worker thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MosquittoMessageOwned {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QOS,
     pub retain: bool,
+    pub properties: properties::Properties,
+}
+
+impl From<MosquittoMessage<'_>> for MosquittoMessageOwned {
+    fn from(message: MosquittoMessage<'_>) -> MosquittoMessageOwned {
+        MosquittoMessageOwned {
+            topic: message.topic.to_string(),
+            payload: message.payload.to_vec(),
+            qos: message.qos,
+            retain: message.retain,
+            properties: message.properties.to_properties(),
+        }
+    }
+}
+
+/// Why on_message_check rejected a message.
+///
+/// Error::AclDenied drops the message, a QoS 1 or 2 publisher gets a PUBACK/PUBREC with reason
+/// code "not authorized". Any other error is passed on to the broker as is, which makes it
+/// disconnect the publishing client. The reason string is sent along for MQTT v5 clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageVeto {
+    pub error: Error,
+    pub reason_string: Option<String>,
+}
+
+impl MessageVeto {
+    pub fn new(error: Error) -> MessageVeto {
+        MessageVeto {
+            error,
+            reason_string: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> MessageVeto {
+        self.reason_string = Some(reason.to_string());
+        self
+    }
+}
+
+impl From<Error> for MessageVeto {
+    fn from(error: Error) -> MessageVeto {
+        MessageVeto::new(error)
+    }
+}
+
+/// What on_control_command sends back to the client that sent the command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlResponse {
+    None,
+    /// Published to `<topic>/response` with QoS 0, e.g. a JSON document
+    Reply(Vec<u8>),
+}
+
+impl ControlResponse {
+    pub fn reply(payload: impl Into<Vec<u8>>) -> ControlResponse {
+        ControlResponse::Reply(payload.into())
+    }
+}
+
+/// What on_connect knows about the CONNECT of a client. The broker doesn't hand plugins the will
+/// or the CONNECT properties of MQTT v5, neither with 2.0 nor with 2.1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectInfo {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub address: Option<IpAddr>,
+    pub protocol: MosquittoClientProtocol,
+    pub protocol_version: MosquittoClientProtocolVersion,
+    /// In seconds, 0 when the client doesn't want keepalive checks
+    pub keepalive: u16,
+    /// Clean session for MQTT 3.1 and 3.1.1, clean start for v5
+    pub clean_start: bool,
+    /// The session expiry interval, None before mosquitto 2.1
+    pub session_expiry: Option<std::time::Duration>,
+}
+
+impl ConnectInfo {
+    pub fn of(client: &dyn MosquittoClientContext) -> ConnectInfo {
+        ConnectInfo {
+            client_id: client.get_id(),
+            username: client.get_username(),
+            address: client.get_address(),
+            protocol: client.get_protocol(),
+            protocol_version: client.get_protocol_version(),
+            keepalive: u16::try_from(client.get_keepalive()).unwrap_or(u16::MAX),
+            clean_start: client.is_clean_session(),
+            session_expiry: client.session_expiry(),
+        }
+    }
+}
+
+/// What ext_auth_start and ext_auth_continue decided
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// The client is authenticated, the data, if any, is sent along with the CONNACK
+    Accept(Option<Vec<u8>>),
+    /// Send the data to the client in an AUTH packet, its answer goes to ext_auth_continue
+    Continue(Vec<u8>),
+    /// The client is refused
+    Reject,
+    /// Not a method this plugin handles, other plugins are asked
+    Defer,
+}
+
+/// How on_message_mut changes a message before the broker routes it, None leaves that part as
+/// it was. The new values are copied into buffers allocated with mosquitto_malloc, the broker
+/// frees the ones they replace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageRewrite {
+    pub topic: Option<String>,
+    pub payload: Option<Vec<u8>>,
+    pub retain: Option<bool>,
+    /// Replaces all properties of the message, Some(Properties::new()) removes them
+    pub properties: Option<properties::Properties>,
+}
+
+impl MessageRewrite {
+    /// Leaves the message as it is
+    pub fn unchanged() -> MessageRewrite {
+        MessageRewrite::default()
+    }
+
+    /// The topic has to be a valid topic to publish to, without wildcards
+    pub fn with_topic(mut self, topic: &str) -> MessageRewrite {
+        self.topic = Some(topic.to_string());
+        self
+    }
+
+    pub fn with_payload(mut self, payload: impl Into<Vec<u8>>) -> MessageRewrite {
+        self.payload = Some(payload.into());
+        self
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> MessageRewrite {
+        self.retain = Some(retain);
+        self
+    }
+
+    pub fn with_properties(mut self, properties: properties::Properties) -> MessageRewrite {
+        self.properties = Some(properties);
+        self
+    }
+
+    pub fn is_unchanged(&self) -> bool {
+        *self == MessageRewrite::default()
+    }
+}
+
+/// A mosquitto version. Brokers don't tell plugins their own version, see
+/// PluginContext::broker_version for what is known at init.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BrokerVersion {
+    pub major: i32,
+    pub minor: i32,
+    pub revision: i32,
+}
+
+impl BrokerVersion {
+    /// The broker that calls mosquitto_auth_plugin_init, only 1.6 uses that interface
+    pub const AUTH_PLUGIN_V4: BrokerVersion = BrokerVersion {
+        major: 1,
+        minor: 6,
+        revision: 0,
+    };
+
+    /// The LIBMOSQUITTO_MAJOR/MINOR/REVISION of the mosquitto headers the plugin was built with,
+    /// see mosquitto_dev::HEADERS_VERSION
+    pub fn headers() -> BrokerVersion {
+        let mut parts = mosquitto_dev::HEADERS_VERSION
+            .split('.')
+            .map(|p| p.parse().unwrap_or(0));
+        BrokerVersion {
+            major: parts.next().unwrap_or(0),
+            minor: parts.next().unwrap_or(0),
+            revision: parts.next().unwrap_or(0),
+        }
+    }
+
+    /// True for major.minor and any later version
+    pub fn at_least(&self, major: i32, minor: i32) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+impl fmt::Display for BrokerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.revision)
+    }
+}
+
+/// What the broker tells a plugin when loading it, besides the options
+#[derive(Debug, Copy, Clone)]
+pub struct PluginContext {
+    /// The version of the headers the plugin was built with when mosquitto_plugin_init loaded
+    /// it, BrokerVersion::AUTH_PLUGIN_V4 when mosquitto_auth_plugin_init did. Loaded through the
+    /// first, the broker has the plugin interface of these headers and is at least as new.
+    pub broker_version: BrokerVersion,
+    /// Plugin identifier, for raw::register_raw_callback
+    pub handle: raw::PluginHandle,
+}
+
+/// What happens to an event whose callback panicked, returned from on_panic
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Refuse the client, subscription or message, notifications are ignored
+    Deny,
+    /// Return PluginDefer, leaving checks to the other plugins and the broker configuration
+    Defer,
+    /// Abort the broker, like the panic would have without the plugin catching it
+    Abort,
+}
+
+/// Why a plugin can't start, returned from try_init. It is written to the broker log and the
+/// broker refuses to start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitError {
+    message: String,
+}
+
+impl InitError {
+    pub fn new(message: impl Into<String>) -> InitError {
+        InitError {
+            message: message.into(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for InitError {}
+
+impl From<opts::OptError> for InitError {
+    fn from(error: opts::OptError) -> InitError {
+        InitError::new(error.to_string())
+    }
+}
+
+impl From<String> for InitError {
+    fn from(message: String) -> InitError {
+        InitError::new(message)
+    }
 }
 
+impl From<&str> for InitError {
+    fn from(message: &str) -> InitError {
+        InitError::new(message)
+    }
+}
+
+/// Options the broker passes along with a subscription ACL check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SubscriptionOptions {
+    /// The qos requested for the subscription
+    pub qos: i32,
+}
+
+/// A subscription a client asked for, in on_subscribe. Changes made by the plugin are what the
+/// broker subscribes the client with.
+#[cfg(mosquitto_2_1)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub topic_filter: String,
+    pub qos: i32,
+    /// MQTT v5: don't send the client its own messages
+    pub no_local: bool,
+    /// MQTT v5: keep the retain flag of forwarded messages
+    pub retain_as_published: bool,
+    /// MQTT v5: 0 sends retained messages on subscribe, 1 only for new subscriptions, 2 never
+    pub retain_handling: u8,
+    /// MQTT v5 subscription identifier, 0 when there is none
+    pub identifier: u32,
+}
+
+#[cfg(mosquitto_2_1)]
+impl Subscription {
+    // The subscription options byte of the SUBSCRIBE packet
+    pub(crate) fn from_options(topic_filter: String, options: u8, identifier: u32) -> Subscription {
+        Subscription {
+            topic_filter,
+            qos: (options & 0x03) as i32,
+            no_local: options & 0x04 != 0,
+            retain_as_published: options & 0x08 != 0,
+            retain_handling: (options >> 4) & 0x03,
+            identifier,
+        }
+    }
+
+    pub(crate) fn options(&self) -> u8 {
+        (self.qos as u8 & 0x03)
+            | if self.no_local { 0x04 } else { 0 }
+            | if self.retain_as_published { 0x08 } else { 0 }
+            | (self.retain_handling & 0x03) << 4
+    }
+}
+
+/// The quality of service of a message, ordered from AtMostOnce to ExactlyOnce
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum QOS {
     AtMostOnce,
     AtLeastOnce,
     ExactlyOnce,
 }
 
+/// The QoS level as it appears on the wire, Inval for anything but 0, 1 and 2
+impl TryFrom<i32> for QOS {
+    type Error = Error;
+
+    fn try_from(qos: i32) -> Result<QOS, Error> {
+        match qos {
+            0 => Ok(QOS::AtMostOnce),
+            1 => Ok(QOS::AtLeastOnce),
+            2 => Ok(QOS::ExactlyOnce),
+            _ => Err(Error::Inval),
+        }
+    }
+}
+
 impl QOS {
-    fn to_i32(&self) -> i32 {
+    pub fn to_i32(&self) -> i32 {
         match self {
             QOS::AtMostOnce => 0,
             QOS::AtLeastOnce => 1,
@@ -201,41 +862,115 @@ impl QOS {
     }
 }
 
+/// How a client is connected, see MosquittoClientContext::get_protocol
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MosquittoClientProtocol {
     Mqtt,
     MqttSn,
+    /// MQTT over websockets
     Websockets,
+    /// A transport added to mosquitto after this crate
+    Unknown(u32),
 }
 
+impl From<u32> for MosquittoClientProtocol {
+    fn from(protocol: u32) -> MosquittoClientProtocol {
+        match protocol {
+            p if p == mosquitto_protocol_mp_mqtt => MosquittoClientProtocol::Mqtt,
+            p if p == mosquitto_protocol_mp_mqttsn => MosquittoClientProtocol::MqttSn,
+            p if p == mosquitto_protocol_mp_websockets => MosquittoClientProtocol::Websockets,
+            p => MosquittoClientProtocol::Unknown(p),
+        }
+    }
+}
+
+/// The MQTT version a client connected with, see MosquittoClientContext::get_protocol_version
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MosquittoClientProtocolVersion {
-    V3,
-    V4,
-    V5,
+    /// MQTT 3.1, protocol level 3
+    Mqtt31,
+    /// MQTT 3.1.1, protocol level 4
+    Mqtt311,
+    /// MQTT 5, protocol level 5, the only one with properties and reason codes
+    Mqtt5,
+    /// A protocol level this crate doesn't know
+    Unknown(i32),
+}
+
+impl MosquittoClientProtocolVersion {
+    /// The protocol level of the CONNECT packet
+    pub fn level(&self) -> i32 {
+        match self {
+            MosquittoClientProtocolVersion::Mqtt31 => 3,
+            MosquittoClientProtocolVersion::Mqtt311 => 4,
+            MosquittoClientProtocolVersion::Mqtt5 => 5,
+            MosquittoClientProtocolVersion::Unknown(level) => *level,
+        }
+    }
+}
+
+impl From<i32> for MosquittoClientProtocolVersion {
+    fn from(level: i32) -> MosquittoClientProtocolVersion {
+        match level {
+            3 => MosquittoClientProtocolVersion::Mqtt31,
+            4 => MosquittoClientProtocolVersion::Mqtt311,
+            5 => MosquittoClientProtocolVersion::Mqtt5,
+            level => MosquittoClientProtocolVersion::Unknown(level),
+        }
+    }
 }
 
 pub trait MosquittoClientContext {
-    /// Binding to mosquitto_client_address
-    fn get_address(&self) -> std::net::IpAddr;
-    /// Binding to mosquitto_client_clean_session
+    /// Binding to mosquitto_client_address. IPv4 clients on dual stack listeners are reported as
+    /// their IPv4 address rather than ::ffff:a.b.c.d. None when the broker has no IP address for
+    /// the client, e.g. for clients on a unix socket. The broker doesn't tell the port.
+    fn get_address(&self) -> Option<std::net::IpAddr>;
+    /// Binding to mosquitto_client_clean_session. For MQTT v5 clients this is the clean start
+    /// flag, whether the session outlives the connection is told by session_expiry.
     fn is_clean_session(&self) -> bool;
-    /// Binding to mosquitto_client_id
+    /// How long the broker keeps the session after the client disconnected: zero for clean
+    /// sessions, u32::MAX seconds for sessions that never expire, like MQTT 3 sessions without
+    /// clean session. None when unknown, before mosquitto 2.1 the broker doesn't tell.
+    fn session_expiry(&self) -> Option<std::time::Duration> {
+        None
+    }
+    /// Binding to mosquitto_client_id, empty when the broker has none
     fn get_id(&self) -> String;
     /// Binding to mosquitto_client_keepalive
     fn get_keepalive(&self) -> i32;
-    /// Binding to mosquitto_client_certificate
-    fn get_certificate(&self) -> Option<&[u8]>;
-    // TODO replace with a reasonable return type from another lib. openssl or x509_parser maybe?
+    /// Binding to mosquitto_client_certificate, the certificate the client presented on a
+    /// listener with require_certificate. Always None without the "tls" feature.
+    fn get_certificate(&self) -> Option<certificate::ClientCertificate>;
     /// Binding to mosquitto_client_protocol
     fn get_protocol(&self) -> MosquittoClientProtocol;
-    /// Binding to mosquitto_client_protocol_version
+    /// Binding to mosquitto_client_protocol_version, e.g. to refuse MQTT 3.1 clients in
+    /// username_password
     fn get_protocol_version(&self) -> MosquittoClientProtocolVersion;
     /// Binding to mosquitto_client_sub_count
     fn get_sub_count(&self) -> i32;
-    /// Binding to mosquitto_client_username
-    fn get_username(&self) -> String;
-    /// Binding to mosquitto_set_username
-    /// Error is either NoMem or Inval
-    fn set_username(&self, username: String) -> Result<Success, Error>;
+    /// Binding to mosquitto_client_username, None when the client connected without a username.
+    /// Unlike the client id it is checked by username_password, so it is what per user rules
+    /// should be keyed on.
+    fn get_username(&self) -> Option<String>;
+    /// Binding to mosquitto_set_username. Called from username_password it maps an external
+    /// identity, like the subject of a token or the CN of a certificate, onto the username the
+    /// broker uses for ACL checks, logging and the plugins called after this one.
+    /// Error is either NoMem or Inval, Inval also for usernames containing NUL.
+    fn set_username(&self, username: &str) -> Result<Success, Error>;
+    /// Tells apart two connections using the same client id, e.g. while one takes over the
+    /// session of the other. Implementations not backed by a broker connection can keep the default.
+    fn connection_id(&self) -> usize {
+        0
+    }
+}
+
+// The address as mosquitto prints it, an IPv6 address can carry the zone of a link local address
+fn parse_address(address: &str) -> Option<IpAddr> {
+    let address = address.split('%').next().unwrap_or_default();
+    match IpAddr::from_str(address).ok()? {
+        IpAddr::V6(v6) => Some(v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4)),
+        v4 => Some(v4),
+    }
 }
 
 pub struct MosquittoClient {
@@ -243,12 +978,17 @@ pub struct MosquittoClient {
 }
 
 impl MosquittoClientContext for MosquittoClient {
-    fn get_address(&self) -> IpAddr {
+    fn connection_id(&self) -> usize {
+        self.client as usize
+    }
+
+    fn get_address(&self) -> Option<IpAddr> {
         unsafe {
             let address = mosquitto_client_address(self.client);
-            let c_str = std::ffi::CStr::from_ptr(address);
-            let str = c_str.to_str().expect("Couldn't convert CStr to &str"); // TODO should we avoid expect here and instead return Option<String>?
-            IpAddr::from_str(str).expect("Couldn't parse ip")
+            if address.is_null() {
+                return None;
+            }
+            parse_address(std::ffi::CStr::from_ptr(address).to_str().ok()?)
         }
     }
 
@@ -258,11 +998,20 @@ impl MosquittoClientContext for MosquittoClient {
         }
     }
 
+    #[cfg(mosquitto_2_1)]
+    fn session_expiry(&self) -> Option<std::time::Duration> {
+        let seconds = unsafe { mosquitto_client_session_expiry_interval(self.client) };
+        Some(std::time::Duration::from_secs(seconds.into()))
+    }
+
     fn get_id(&self) -> String {
         unsafe {
             let client_id = mosquitto_client_id(self.client);
-            let c_str = std::ffi::CStr::from_ptr(client_id);
-            c_str.to_str().expect("Couldn't convert CStr to &str").to_string() // TODO should we avoid expect here and instead return Option<String>?
+            if client_id.is_null() {
+                return String::new();
+            }
+            // The broker refuses client ids that aren't UTF-8, this only guards against panicking
+            std::ffi::CStr::from_ptr(client_id).to_string_lossy().into_owned()
         }
     }
 
@@ -272,38 +1021,23 @@ impl MosquittoClientContext for MosquittoClient {
         }
     }
 
-    fn get_certificate(&self) -> Option<&[u8]> {
-        unimplemented!()
+    fn get_certificate(&self) -> Option<certificate::ClientCertificate> {
+        #[cfg(feature = "tls")]
+        let certificate = unsafe { certificate::of_client(self.client) };
+        #[cfg(not(feature = "tls"))]
+        let certificate = None;
+        certificate
     }
 
     fn get_protocol(&self) -> MosquittoClientProtocol {
         unsafe {
-            let protocol = mosquitto_client_protocol(self.client) as u32;
-            if protocol == mosquitto_protocol_mp_mqtt {
-                MosquittoClientProtocol::Mqtt
-            } else if protocol == mosquitto_protocol_mp_mqttsn {
-                MosquittoClientProtocol::MqttSn
-            } else if protocol == mosquitto_protocol_mp_websockets {
-                MosquittoClientProtocol::Websockets
-            } else {
-                // TODO either we panic here. Or we need to return a result/option
-                // The benefit of returning the result/option would be to let library-user-space
-                // gracefully shutdown. Which would be preferable.
-
-                panic!("mosquitto_client_protocol returned invalid protocol {}", protocol);
-            }
+            (mosquitto_client_protocol(self.client) as u32).into()
         }
     }
 
     fn get_protocol_version(&self) -> MosquittoClientProtocolVersion {
         unsafe {
-            let protocol_version = mosquitto_client_protocol_version(self.client);
-            match protocol_version {
-                3 => MosquittoClientProtocolVersion::V3,
-                4 => MosquittoClientProtocolVersion::V4,
-                5 => MosquittoClientProtocolVersion::V5,
-                _ => panic!("invalid mosquitto client protocol version returned from mosquitto_client_protocol_version. {}", protocol_version)
-            }
+            mosquitto_client_protocol_version(self.client).into()
         }
     }
 
@@ -313,25 +1047,20 @@ impl MosquittoClientContext for MosquittoClient {
         }
     }
 
-    fn get_username(&self) -> String {
+    fn get_username(&self) -> Option<String> {
         unsafe {
             let username = mosquitto_client_username(self.client);
-            let c_str = std::ffi::CStr::from_ptr(username);
-            c_str.to_str().expect("Couldn't convert CStr to &str").to_string() // TODO should we avoid expect here and instead return Option<String>?
+            if username.is_null() {
+                return None;
+            }
+            // The broker only accepts UTF-8 usernames
+            std::ffi::CStr::from_ptr(username).to_str().ok().map(str::to_string)
         }
     }
 
-    fn set_username(&self, username: String) -> Result<Success, Error> {
-        unsafe {
-            let c_string = &CString::new(username).expect("no cstring for u");
-            let res = mosquitto_set_username(self.client, c_string.as_c_str().as_ptr());
-            match res {
-                0 => Ok(Success),
-                1 => Err(Error::NoMem),
-                3 => Err(Error::Inval),
-                _ => Err(Error::Unknown), // Any other number returned from set_username is undefined behaviour
-            }
-        }
+    fn set_username(&self, username: &str) -> Result<Success, Error> {
+        let username = CString::new(username).map_err(|_| Error::Inval)?;
+        to_result(unsafe { mosquitto_set_username(self.client, username.as_ptr()) })
     }
 }
 
@@ -368,6 +1097,11 @@ impl From<MosquittoPluginEvent> for i32 {
     }
 }
 
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is not a mosquitto plugin",
+    label = "used as a plugin here",
+    note = "create_dynamic_library! needs an `impl MosquittoPlugin for {Self}`, with at least `fn init(opts: MosquittoOpt) -> Self`"
+)]
 pub trait MosquittoPlugin {
     /// This will be run once on every startup, or load, and will allocate the structure, to be
     /// reconstructed in other calls to the plugin.
@@ -375,35 +1109,148 @@ pub trait MosquittoPlugin {
     /// This requires unsafe usage due to nature of C calls
     fn init(opts: MosquittoOpt) -> Self;
 
-    /// Called when SIGHUP is sent to the broker PID
+    /// Called by the generated mosquitto_plugin_init instead of init, for plugins that need to
+    /// know the broker version or their plugin handle while initializing.
+    /// Default implementation calls init.
+    #[allow(unused)]
+    fn init_with_context(opts: MosquittoOpt, context: &PluginContext) -> Self
+    where
+        Self: Sized,
+    {
+        Self::init(opts)
+    }
+
+    /// Called by the generated mosquitto_plugin_init instead of init_with_context, for plugins
+    /// that can fail to start, e.g. on options rejected by `Config::from_opts(&opts)?`.
+    /// An error is logged and no callbacks are registered, mosquitto refuses to start. init is
+    /// never called when this is implemented. Default implementation calls init_with_context.
+    #[allow(unused)]
+    fn try_init(opts: MosquittoOpt, context: &PluginContext) -> Result<Self, InitError>
+    where
+        Self: Sized,
+    {
+        Ok(Self::init_with_context(opts, context))
+    }
+
+    /// Called when SIGHUP is sent to the broker PID, with the options from the reloaded config,
+    /// without their auth_opt_ or plugin_opt_ prefix like at init. Options that were removed
+    /// from the config are missing, the plugin has to fall back to its defaults for them.
     #[allow(unused)]
     fn on_reload(&mut self, opts: MosquittoOpt) {}
 
-    /// Access level checks, default implementation always returns success
-    /// If all acl checks from all plugins returns defer the action should be allowed.
-    /// However that doesn't happen right now, if this returns Err(PluginDefer) for a write the message is not let through.
+    /// Access level checks, default implementation always allows.
+    /// AuthDecision::Defer leaves the decision to the other plugins and the acl_file of the
+    /// broker, so a plugin can decide for the topics it knows about and defer the rest. When
+    /// every check defers mosquitto denies, a deferred write is not let through.
+    /// Helpers returning Result, like acl::check_client, convert with `.into()`.
     #[allow(unused)]
     fn acl_check(
         &mut self,
         client: &dyn MosquittoClientContext,
         acl: AclCheckAccessLevel,
         msg: MosquittoMessage,
-    ) -> Result<Success, Error> {
-        Ok(Success)
+    ) -> AuthDecision {
+        AuthDecision::Allow
+    }
+
+    /// Access level checks for subscriptions, the pattern is the subscription filter as sent by
+    /// the client and may contain wildcards. See topic::pattern_is_subset_of for checking it
+    /// against the patterns a client is allowed.
+    /// Default implementation forwards to acl_check with AclCheckAccessLevel::Subscribe, so
+    /// plugins that only implement acl_check see subscriptions there like before.
+    fn acl_check_subscribe(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        pattern: &str,
+        opts: SubscriptionOptions,
+    ) -> AuthDecision {
+        let msg = MosquittoMessage {
+            topic: pattern,
+            payload: &[],
+            // The broker only asks about QoS 0 to 2
+            qos: QOS::try_from(opts.qos).unwrap_or(QOS::AtMostOnce),
+            retain: false,
+            properties: properties::MessageProperties::none(),
+        };
+        self.acl_check(client, AclCheckAccessLevel::Subscribe, msg)
+    }
+
+    /// Access level checks for unsubscribing, the pattern is the filter as sent by the client.
+    /// Default implementation forwards to acl_check with AclCheckAccessLevel::Unsubscribe, like
+    /// acl_check_subscribe.
+    fn acl_check_unsubscribe(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        pattern: &str,
+    ) -> AuthDecision {
+        let msg = MosquittoMessage {
+            topic: pattern,
+            payload: &[],
+            qos: QOS::AtMostOnce,
+            retain: false,
+            properties: properties::MessageProperties::none(),
+        };
+        self.acl_check(client, AclCheckAccessLevel::Unsubscribe, msg)
     }
+
+    /// Connection policies checked when a client connects, before username_password and
+    /// ext_auth_start, e.g. refusing a keepalive of more than an hour or persistent sessions
+    /// for anonymous clients. Allow passes the client on to them, any other decision is final
+    /// and the client never reaches username_password. A plugin with on_connect but without
+    /// username_password leaves the login itself to the other plugins and the broker.
+    #[allow(unused)]
+    fn on_connect(&mut self, client: &dyn MosquittoClientContext, connect: &ConnectInfo) -> AuthDecision {
+        AuthDecision::Allow
+    }
+
     #[allow(unused)]
-    /// Username and password checks, default implementation always returns success
+    /// Username and password checks, default implementation always allows.
+    /// With mosquitto 2.1 AuthDecision::Error(Error::AuthDelayed) keeps the client waiting for a
+    /// decision made later, see delayed_auth::AuthCompletion.
+    /// AuthDecision::Defer passes the client on to the next plugin, or to the password_file of
+    /// the broker, so a plugin can handle its own users next to another auth backend.
     fn username_password(
         &mut self,
         client: &dyn MosquittoClientContext,
         username: Option<&str>,
         password: Option<&str>,
-    ) -> Result<Success, Error> {
-        Ok(Success)
+    ) -> AuthDecision {
+        AuthDecision::Allow
     }
 
-    /// Tested unsuccessfully. Haven't gotten this to work yet.
-    /// Suspect it has something to do with how the mosquitto_callback_register is called with the event_data parameter
+    /// MQTT v5 enhanced authentication: the client connected with an authentication method, like
+    /// SCRAM-SHA-256, and data for it. Called instead of username_password for such clients.
+    /// Default implementation defers, leaving the method to other plugins, the broker refuses
+    /// methods no plugin accepts.
+    #[allow(unused)]
+    fn ext_auth_start(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        method: &str,
+        data: &[u8],
+    ) -> AuthStep {
+        AuthStep::Defer
+    }
+
+    /// The answer of the client to the data of AuthStep::Continue, with the method of the start.
+    #[allow(unused)]
+    fn ext_auth_continue(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        method: &str,
+        data: &[u8],
+    ) -> AuthStep {
+        AuthStep::Defer
+    }
+
+    /// The $CONTROL topics the plugin takes commands on, like `$CONTROL/my-plugin/v1`. Asked once
+    /// after init, the callbacks are registered for each of them. Messages published to these
+    /// topics go to on_control_command instead of being routed to subscribers.
+    fn control_topics(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// A message published to one of the control_topics
     #[allow(unused)]
     fn on_control(
         &mut self,
@@ -411,6 +1258,19 @@ pub trait MosquittoPlugin {
         message: MosquittoMessage,
     ) {}
 
+    /// Called instead of on_control when implemented, and can answer the command. A reply is
+    /// published to `<topic>/response`, for the client that sent the command only, like the
+    /// dynamic security plugin does.
+    /// Default implementation calls on_control and doesn't reply.
+    fn on_control_command(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        message: MosquittoMessage,
+    ) -> ControlResponse {
+        self.on_control(client, message);
+        ControlResponse::None
+    }
+
     /// Called when a message is sent on the broker.
     /// The message has to pass the ACL check otherwise this callback will not be called.
     #[allow(unused)]
@@ -420,7 +1280,48 @@ pub trait MosquittoPlugin {
         message: MosquittoMessage,
     ) {}
 
-    /// Untested
+    /// Called instead of on_message when implemented, and can reject the message, e.g. when the
+    /// payload doesn't validate. See MessageVeto for what the publisher sees.
+    /// Default implementation calls on_message and lets every message through.
+    fn on_message_check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        message: MosquittoMessage,
+    ) -> Result<Success, MessageVeto> {
+        self.on_message(client, message);
+        Ok(Success)
+    }
+
+    /// Called instead of on_message_check when implemented, and can change the topic, payload,
+    /// retain flag and properties of the message before the broker routes it. A rewrite the
+    /// broker can't use, like a topic with wildcards, is logged and drops the message.
+    /// Default implementation calls on_message_check and leaves the message unchanged.
+    fn on_message_mut(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        message: MosquittoMessage,
+    ) -> Result<MessageRewrite, MessageVeto> {
+        self.on_message_check(client, message)
+            .map(|Success| MessageRewrite::unchanged())
+    }
+
+    /// The pre-shared key for a client connecting to a TLS-PSK listener, as a hex string like in
+    /// a psk_file. The hint is the psk_hint of the listener, empty if it has none. None leaves the
+    /// lookup to on_psk, keys that aren't hex or don't fit into the buffer of the broker refuse
+    /// the client.
+    #[allow(unused)]
+    fn psk_key(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        identity: &str,
+        hint: &str,
+    ) -> Option<String> {
+        None
+    }
+
+    /// Called when psk_key returns None, with the key buffer of the broker. Untested
+    /// Default implementation returns MOSQ_ERR_PLUGIN_DEFER, so the psk_file of the broker or
+    /// other plugins are asked.
     #[allow(unused)]
     fn on_psk(
         &mut self,
@@ -430,113 +1331,200 @@ pub trait MosquittoPlugin {
         key: &str,
         max_key_len: i32,
     ) -> i32 {
-        0
+        Error::PluginDefer.into()
     }
 
     /// Called every 100 ms
     /// All now_ns, next_ns, now_s, next_s parameters are always zero right now.
     /// I'm not sure if it's a bug on this library's part or of mosquitto.
-    /// If you want to keep time you'll have to measure it yourself right now.
+    /// If you want to keep time use tick, which is called right after with the current time.
     #[allow(unused)]
     fn on_tick(&mut self, now_ns: i64, next_ns: i64, now_s: i32, next_s: i32) {}
 
+    /// Called on every tick of the broker main loop, after on_tick, with the current time. The
+    /// place for periodic housekeeping like expiring caches, refreshing tokens or Stats::tick_at.
+    /// It runs on the broker thread, so it should be quick, and it isn't called at a fixed rate:
+    /// compare now with the time of the last run to do something every so often.
+    #[allow(unused)]
+    fn tick(&mut self, now: std::time::Instant) {}
+
+    /// Called when a client subscribes, after the ACL check allowed it. Changing the subscription
+    /// changes what the client is subscribed to, e.g. to move it below a per client prefix, an
+    /// error refuses the subscription. Needs mosquitto 2.1.
+    #[cfg(mosquitto_2_1)]
+    #[allow(unused)]
+    fn on_subscribe(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        subscription: &mut Subscription,
+    ) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    /// Called when a client unsubscribes. A filter changed in on_subscribe has to be changed the
+    /// same way here, or the client can't unsubscribe. Needs mosquitto 2.1.
+    #[cfg(mosquitto_2_1)]
+    #[allow(unused)]
+    fn on_unsubscribe(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        topic_filter: &mut String,
+    ) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    /// The client went away, reason being the code of the disconnect event or SessionTakenOver
+    #[allow(unused)]
+    fn on_disconnect(&mut self, client: &dyn MosquittoClientContext, reason: DisconnectReason) {}
+
+    /// A new connection with the same client id took over the session of client, e.g. a second
+    /// device sharing credentials or an id being hijacked. Called for the old connection right
+    /// before its on_disconnect, takeover describes the new one. Only connections the plugin saw
+    /// connect count: with mosquitto 2.1 every client, with 2.0 the clients logging in with a
+    /// username and password. Those are deferred to the other plugins and the broker when the
+    /// plugin doesn't implement username_password, and otherwise only the logins it allowed count.
+    #[allow(unused)]
+    fn on_session_takeover(&mut self, client: &dyn MosquittoClientContext, takeover: &clients::SessionTakeover) {}
+
+    /// The registry kept up to date by the generated callbacks: told about clients after
+    /// username_password succeeds and after on_disconnect, see clients::ClientRegistry and
+    /// clients::ClientStore
+    fn client_registry(&mut self) -> Option<&mut dyn ClientLifecycle> {
+        None
+    }
+
+    /// The list of connected clients kept up to date by the generated callbacks, see
+    /// clients::ConnectedClients. With mosquitto 2.0 this registers for the login and the ACL
+    /// checks, which are deferred unless username_password and acl_check are implemented.
+    fn connected_clients(&mut self) -> Option<&mut clients::ConnectedClients> {
+        None
+    }
+
+    /// The scheduler whose due jobs are run on every tick, after tick, see scheduler::Scheduler
+    fn scheduler(&mut self) -> Option<&mut scheduler::Scheduler> {
+        None
+    }
+
+    /// The statistics published on the tick once their interval has passed, see stats::Stats
+    fn stats(&mut self) -> Option<&mut stats::Stats> {
+        None
+    }
+
+    /// The topics with a retained message, kept up to date by the generated message callback,
+    /// see retained::RetainedTopics
+    fn retained_topics(&mut self) -> Option<&mut retained::RetainedTopics> {
+        None
+    }
+
+    /// Where the generated callbacks record how long each call took, see latency::CallbackTimings
+    fn callback_timings(&mut self) -> Option<&mut latency::CallbackTimings> {
+        None
+    }
+
+    /// Where the generated username_password and acl_check callbacks record the decisions of the
+    /// plugin, see audit::AuditLog
+    fn audit_log(&mut self) -> Option<&mut audit::AuditLog> {
+        None
+    }
+
+    /// Called after a callback panicked, with the name of the callback and the panic message.
+    /// The panic is already logged, this is for reporting it elsewhere and for choosing what
+    /// happens to the event. The plugin may be left in the state the panic interrupted.
+    /// Default implementation denies the event.
     #[allow(unused)]
-    fn on_disconnect(&mut self, client: &dyn MosquittoClientContext, reason: i32) {}
+    fn on_panic(&mut self, callback: &str, message: &str) -> PanicPolicy {
+        PanicPolicy::Deny
+    }
+
+    /// Called when the broker unloads the plugin on shutdown, before the structure is dropped and
+    /// after the last callback. The place to save state that should survive a restart, see
+    /// state::StateStore, to flush logs, close connections and join worker threads. A SIGHUP
+    /// doesn't unload the plugin, it calls on_reload. Not called when try_init failed.
+    fn on_cleanup(&mut self) {}
 
     #[allow(unused)]
     /// Broadcast a message from the broker
     /// If called in a username and password check the connecting client will not get the message
     /// Use the broker_publish_to_client combined with this if you want to send to all clients including the one that is connecting
+    /// Only callable from the callbacks, other threads publish through broker_handle::BrokerHandle
     fn broker_broadcast_publish(
         &mut self,
         topic: &str,
-        payload: &[u8],
+        payload: impl AsRef<[u8]>,
         qos: QOS,
         retain: bool,
     ) -> Result<Success, Error> {
-        let cstr = &CString::new(topic).expect("no cstring for u");
-        let bytes = cstr.as_bytes_with_nul();
-        let topic = bytes.as_ptr();
-
-        let mut nullptr: *const c_void = std::ptr::null();
-        let properties: *mut mosquitto_property = std::ptr::null_mut();
-
-        // let payload: *mut c_void = std::ptr::null_mut(); // payload bytes, non-null if payload length > 0, must be heap allocated
-        let payload_len = payload.len();
-        let payload: *const c_void = Box::new(payload).as_ptr() as *const c_void; // payload bytes, non-null if payload length > 0, must be heap allocated
-
-        unsafe {
-            let c_payload: *mut c_void =
-                libc::malloc(std::mem::size_of::<u8>() * payload_len) as *mut c_void;
-            payload.copy_to(c_payload, payload_len);
-            /**
-             * https://mosquitto.org/api2/files/mosquitto_broker-h.html#mosquitto_broker_publish
-             * maybe want to switch to mosquitto_broker_publish to maintain ownership over
-             * payload memory.
-             * "payload	payload bytes.  If payloadlen > 0 this must not be NULL.  Must be allocated on the heap.  Will be freed by mosquitto after use if the function returns success."
-             * What happens if it is not successfull? Do i need to free the memory myself? This is a leak if if i front free memory  in all cases except 0 (Success) below?
-             */
-            let res = mosquitto_broker_publish(
-                nullptr as *const i8, // client id to send to, null = all clients
-                topic as *const i8,
-                payload_len as i32, // payload length in bytes, 0 for empty payload
-                c_payload, // payload bytes, non-null if payload length > 0, must be heap allocated
-                qos.to_i32(), // qos
-                retain,    // retain
-                properties, //mqtt5 properties
-            );
-            match res {
-                0 => Ok(Success),
-                1 => Err(Error::NoMem),
-                3 => Err(Error::Inval),
-                default => Err(Error::Unknown),
-            }
-        }
+        mosquitto_calls::publish_broadcast(topic, payload, qos, retain)
     }
     #[allow(unused)]
     /// To be called from implementations of the plugin when
-    /// a plugin wants to publish to a specific client.
+    /// a plugin wants to publish to a specific client, see mosquitto_calls::publish_to_client.
     fn broker_publish_to_client(
         &mut self,
         client_id: &str,
         topic: &str,
-        payload: &[u8],
+        payload: impl AsRef<[u8]>,
         qos: QOS,
         retain: bool,
     ) -> Result<Success, Error> {
-        let cstr = &CString::new(client_id).expect("no cstring for u");
-        let bytes = cstr.as_bytes_with_nul();
-        let client_id = bytes.as_ptr();
-
-        let cstr = &CString::new(topic).expect("no cstring for u");
-        let bytes = cstr.as_bytes_with_nul();
-        let topic = bytes.as_ptr();
-
-        let payload_len = payload.len();
-        let payload: *const c_void = Box::new(payload).as_ptr() as *const c_void;
-
-        unsafe {
-            let c_payload: *mut c_void =
-                libc::malloc(std::mem::size_of::<u8>() * payload_len) as *mut c_void;
-            payload.copy_to(c_payload, payload_len);
-
-            let res = mosquitto_broker_publish(
-                client_id as *const i8, // client id to send to, null = all clients
-                topic as *const i8,     // topic to publish on
-                payload_len as i32,     // payload length in bytes, 0 for empty payload
-                c_payload, // payload bytes, non-null if payload length > 0, must be heap allocated
-                qos.to_i32(), // qos
-                retain,    // retain
-                std::ptr::null_mut(), //mqtt5 properties
-            );
-            match res {
-                0 => Ok(Success),
-                1 => Err(Error::NoMem),
-                3 => Err(Error::Inval),
-                default => Err(Error::Unknown),
-            }
+        mosquitto_calls::publish_to_client(client_id, topic, payload, qos, retain)
+    }
+    #[allow(unused)]
+    /// Publish the same message to a group of clients, see mosquitto_calls::publish_to_clients.
+    /// Returns the result of each publish together with the client id it was meant for.
+    fn broker_publish_to_clients(
+        &mut self,
+        client_ids: &[&str],
+        topic: &str,
+        payload: impl AsRef<[u8]>,
+        qos: QOS,
+        retain: bool,
+    ) -> Vec<(String, Result<Success, Error>)> {
+        mosquitto_calls::publish_to_clients(client_ids, topic, payload, qos, retain)
+    }
+    #[allow(unused)]
+    /// Broadcast a batch of (topic, payload, qos, retain) messages, see
+    /// mosquitto_calls::publish_batch. Returns the result of each, in order.
+    fn broker_publish_batch<P: AsRef<[u8]>>(&mut self, messages: &[(&str, P, QOS, bool)]) -> Vec<Result<Success, Error>> {
+        mosquitto_calls::publish_batch(messages)
+    }
+    #[allow(unused)]
+    /// Delete the retained message of a topic, see mosquitto_calls::clear_retained
+    fn broker_clear_retained(&mut self, topic: &str) -> Result<Success, Error> {
+        mosquitto_calls::clear_retained(topic)
+    }
+    #[allow(unused)]
+    /// Replace the retained message of a topic, keeping retained_topics up to date
+    fn broker_set_retained(&mut self, topic: &str, payload: impl AsRef<[u8]>, qos: QOS) -> Result<Success, Error> {
+        match self.retained_topics() {
+            Some(retained) => retained.set(topic, payload, qos),
+            None => mosquitto_calls::publish_broadcast(topic, payload, qos, true),
         }
     }
+    #[allow(unused)]
+    /// Delete the retained message of a topic, or of every topic in retained_topics a pattern
+    /// matches, and return how many were deleted, see retained::RetainedTopics::delete. Patterns
+    /// are Err(NotSupported) for plugins without retained_topics.
+    fn broker_delete_retained(&mut self, topic_or_pattern: &str) -> Result<usize, Error> {
+        match self.retained_topics() {
+            Some(retained) => retained.delete(topic_or_pattern),
+            None if topic::is_valid_publish_topic(topic_or_pattern) => mosquitto_calls::clear_retained(topic_or_pattern).map(|_| 1),
+            None => Err(Error::NotSupported),
+        }
+    }
+    #[allow(unused)]
+    /// Disconnect the client with this id, e.g. from on_control_command or when it exceeds a
+    /// rate limit. with_will makes the broker send its will, see
+    /// mosquitto_calls::kick_client_by_clientid. on_disconnect is called once it is gone.
+    fn broker_kick_client(&mut self, client_id: &str, with_will: bool) -> Result<Success, Error> {
+        mosquitto_calls::kick_client_by_clientid(client_id, with_will)
+    }
+    #[allow(unused)]
+    /// Disconnect every client logged in with this username, see
+    /// mosquitto_calls::kick_client_by_username
+    fn broker_kick_by_username(&mut self, username: &str, with_will: bool) -> Result<Success, Error> {
+        mosquitto_calls::kick_client_by_username(username, with_will)
+    }
 }
 
 // #[derive(Debug)]
@@ -602,10 +1590,209 @@ pub trait MosquittoPlugin {
 //     0
 // }
 
+#[cfg(any(test, feature = "testing"))]
+mod stub_ffi;
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn parse_opts(opts: &[(&str, &str)]) -> HashMap<String, String> {
+        let strings: Vec<(CString, CString)> = opts
+            .iter()
+            .map(|(k, v)| (CString::new(*k).unwrap(), CString::new(*v).unwrap()))
+            .collect();
+        let mut raw: Vec<mosquitto_opt> = strings
+            .iter()
+            .map(|(k, v)| mosquitto_opt {
+                key: k.as_ptr() as *mut _,
+                value: v.as_ptr() as *mut _,
+            })
+            .collect();
+        __from_ptr_and_size(raw.as_mut_ptr(), raw.len())
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn error_codes() {
+        for code in -5..=31 {
+            match Error::from_code(code) {
+                Some(error) => assert_eq!(Into::<i32>::into(error), code),
+                None => assert_eq!(code, 0),
+            }
+        }
+        assert_eq!(Error::from_code(99), None);
+        assert_eq!(to_result(0), Ok(Success));
+        assert_eq!(to_result(12), Err(Error::AclDenied));
+        assert_eq!(to_result(99), Err(Error::Unknown));
+        assert_eq!(
+            Error::NoMem.to_string(),
+            "Out of memory. (mosquitto error 1)"
+        );
+        assert_eq!(
+            Error::TopicWildcard.to_string(),
+            "Wildcards can't be published to. (mosquitto error 3)"
+        );
+        let boxed: Box<dyn std::error::Error> = Box::new(Error::Auth);
+        assert_eq!(boxed.to_string(), "Authorisation failed. (mosquitto error 11)");
+    }
+
+    #[test]
+    fn results_convert_into_decisions() {
+        assert_eq!(AuthDecision::from(Ok(Success)), AuthDecision::Allow);
+        assert_eq!(AuthDecision::from(Err(Error::AclDenied)), AuthDecision::deny(""));
+        assert_eq!(AuthDecision::from(Err(Error::Auth)), AuthDecision::deny(""));
+        assert_eq!(AuthDecision::from(Err(Error::PluginDefer)), AuthDecision::Defer);
+        assert_eq!(AuthDecision::from(Error::NoMem), AuthDecision::Error(Error::NoMem));
+    }
+
+    #[test]
+    fn option_prefixes_are_stripped() {
+        let expected: HashMap<String, String> = [("acl_file", "/etc/acl"), ("timeout", "5")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        // mosquitto 1.6 style
+        assert_eq!(parse_opts(&[("auth_opt_acl_file", "/etc/acl"), ("auth_opt_timeout", "5")]), expected);
+        // mosquitto 2.x plugin_opt_, with and without the prefix stripped by the broker
+        assert_eq!(parse_opts(&[("plugin_opt_acl_file", "/etc/acl"), ("plugin_opt_timeout", "5")]), expected);
+        assert_eq!(parse_opts(&[("acl_file", "/etc/acl"), ("timeout", "5")]), expected);
+        // mixed
+        assert_eq!(parse_opts(&[("auth_opt_acl_file", "/etc/acl"), ("plugin_opt_timeout", "5")]), expected);
+    }
+
+    #[test]
+    fn described_options_hide_credentials() {
+        let mut opts = MosquittoOpt::new();
+        opts.insert("db_password", "hunter2");
+        opts.insert("jwt_Secret", "s3cr3t");
+        opts.insert("host", "localhost");
+        assert_eq!(
+            describe_opts(&opts),
+            "db_password=<redacted>, host=localhost, jwt_Secret=<redacted>"
+        );
+    }
+
+    #[test]
+    fn client_addresses() {
+        let v4 = |s: &str| Some(IpAddr::V4(s.parse().unwrap()));
+        assert_eq!(parse_address("192.168.1.7"), v4("192.168.1.7"));
+        assert_eq!(parse_address("::ffff:192.168.1.7"), v4("192.168.1.7"));
+        assert_eq!(parse_address("::1"), Some(IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)));
+        assert_eq!(parse_address("fe80::1%eth0"), Some(IpAddr::V6("fe80::1".parse().unwrap())));
+        assert_eq!(parse_address("/run/mosquitto.sock"), None);
+        assert_eq!(parse_address(""), None);
+    }
+
+    #[test]
+    fn protocols() {
+        use MosquittoClientProtocolVersion::*;
+        for (level, version) in [(3, Mqtt31), (4, Mqtt311), (5, Mqtt5), (6, Unknown(6))].iter() {
+            assert_eq!(MosquittoClientProtocolVersion::from(*level), *version);
+            assert_eq!(version.level(), *level);
+        }
+        assert_eq!(MosquittoClientProtocol::from(mosquitto_protocol_mp_websockets), MosquittoClientProtocol::Websockets);
+        assert_eq!(MosquittoClientProtocol::from(42), MosquittoClientProtocol::Unknown(42));
+    }
+
+    #[cfg(mosquitto_2_1)]
+    #[test]
+    fn session_expiry_comes_from_the_broker() {
+        let client = stub_ffi::client();
+        assert_eq!(client.session_expiry(), Some(std::time::Duration::from_secs(3600)));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn certificates_come_from_the_broker() {
+        stub_ffi::reset();
+        let client = stub_ffi::client();
+        assert_eq!(client.get_certificate(), None);
+        stub_ffi::set_certificate(Some(include_bytes!("../fixtures/client.der").to_vec()));
+        let certificate = client.get_certificate().unwrap();
+        assert_eq!(certificate.common_name(), Some("sensor-1"));
+        stub_ffi::set_certificate(Some(b"not a certificate".to_vec()));
+        assert_eq!(client.get_certificate(), None);
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn usernames_are_optional() {
+        stub_ffi::reset();
+        let client = stub_ffi::client();
+        assert_eq!(client.get_username(), None);
+        client.set_username("alice").unwrap();
+        assert_eq!(client.get_username().as_deref(), Some("alice"));
+        assert_eq!(client.set_username("mallory\0admin"), Err(Error::Inval));
+        assert_eq!(client.get_username().as_deref(), Some("alice"));
+        client.set_username("").unwrap();
+        assert_eq!(client.get_username().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn owned_messages_outlive_the_event() {
+        stub_ffi::reset();
+        let list = properties::Properties::new()
+            .user_property("tenant", "a")
+            .content_type("application/json")
+            .message_expiry(std::time::Duration::from_secs(60))
+            .to_list()
+            .unwrap();
+        let payload = b"{\"t\": 21}".to_vec();
+        let message = MosquittoMessage {
+            topic: "sensors/1",
+            payload: &payload,
+            qos: QOS::AtLeastOnce,
+            retain: true,
+            properties: unsafe { properties::MessageProperties::from_ptr(list.ptr) },
+        };
+        let owned = MosquittoMessageOwned::from(message);
+        drop(list);
+        drop(payload);
+
+        let owned = std::thread::spawn(move || owned).join().unwrap();
+        assert_eq!(owned.topic, "sensors/1");
+        assert_eq!(owned.payload, b"{\"t\": 21}");
+        assert_eq!((owned.qos, owned.retain), (QOS::AtLeastOnce, true));
+        assert_eq!(
+            owned.properties,
+            properties::Properties::new()
+                .user_property("tenant", "a")
+                .content_type("application/json")
+                .message_expiry(std::time::Duration::from_secs(60))
+        );
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn client_ids_never_panic() {
+        stub_ffi::reset();
+        let client = stub_ffi::client();
+        assert_eq!(client.get_id(), "stub-client");
+        stub_ffi::set_client_id(Some(b"sensor-\xff"));
+        assert_eq!(client.get_id(), "sensor-\u{fffd}");
+        stub_ffi::set_client_id(None);
+        assert_eq!(client.get_id(), "");
+        stub_ffi::reset();
+    }
+
+    #[cfg(mosquitto_2_1)]
+    #[test]
+    fn subscription_options_round_trip() {
+        // qos 2, no local, retain as published, retain handling 1
+        let subscription = Subscription::from_options("a/#".to_string(), 0x1e, 7);
+        assert_eq!(subscription.qos, 2);
+        assert!(subscription.no_local);
+        assert!(subscription.retain_as_published);
+        assert_eq!(subscription.retain_handling, 1);
+        assert_eq!(subscription.options(), 0x1e);
+        assert_eq!(Subscription::from_options("a".to_string(), 0x21, 0).options(), 0x21);
+    }
 }
@@ -0,0 +1,80 @@
+// log crate integration, enabled with the "log" feature.
+//
+// MosquittoLogger writes the records of log::error!, log::info! etc. to the broker log through
+// mosquitto_log_printf, so plugins and the libraries they use end up next to the broker's own
+// messages. Which levels are shown is decided by log_type in mosquitto.conf.
+use crate::mosquitto_calls::log_printf;
+use crate::mosquitto_dev::*;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+static LOGGER: MosquittoLogger = MosquittoLogger;
+
+/// Installs the MosquittoLogger as the global logger and lets every level through, the broker
+/// filters them again. Called by the generated mosquitto_plugin_init, only the first call has any
+/// effect so reloading the plugin, or a plugin that installed its own logger, is left alone. Use
+/// log::set_max_level to skip formatting records that would be dropped anyway.
+pub fn init() {
+    INIT.call_once(|| {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LevelFilter::Trace);
+        }
+    });
+}
+
+/// Maps log levels onto the broker log levels
+pub fn mosquitto_log_level(level: Level) -> u32 {
+    match level {
+        Level::Error => MOSQ_LOG_ERR,
+        Level::Warn => MOSQ_LOG_WARNING,
+        Level::Info => MOSQ_LOG_INFO,
+        Level::Debug | Level::Trace => MOSQ_LOG_DEBUG,
+    }
+}
+
+/// log::Log writing records to the broker log, prefixed with their target
+#[derive(Debug, Default)]
+pub struct MosquittoLogger;
+
+impl Log for MosquittoLogger {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        log_printf(
+            mosquitto_log_level(record.level()),
+            &format!("{}: {}", record.target(), record.args()),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    #[test]
+    fn records_go_to_the_broker_log() {
+        let logger = MosquittoLogger;
+        for (level, message) in [(Level::Error, "denied"), (Level::Trace, "details")] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target("my_plugin")
+                    .args(format_args!("{} {}", message, 1))
+                    .build(),
+            );
+        }
+        assert_eq!(
+            stub_ffi::logged(),
+            vec![
+                (MOSQ_LOG_ERR as i32, "my_plugin: denied 1".to_string()),
+                (MOSQ_LOG_DEBUG as i32, "my_plugin: details 1".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,603 @@
+// Safe(r) wrappers around the functions the broker exposes to plugins. The MosquittoPlugin
+// trait helpers forward here, so these can also be called from code that doesn't have access to
+// the plugin instance. Topics and client ids containing NUL can't be handed to the broker, they
+// are Err(Error::Inval). The topics of published messages are checked before they reach the
+// broker, a bad one is Err(Error::TopicWildcard), TopicTooLong, TopicTooDeep or MalformedUtf8.
+use crate::mosquitto_dev::*;
+use crate::properties::{Properties, PropertyList};
+use crate::topic::{self, TopicError};
+use crate::{to_result, Error, Success, QOS};
+use std::os::raw::c_void;
+use std::ffi::{CStr, CString};
+
+/// Write a message to the broker log. Binding to mosquitto_log_printf, level is one of the
+/// MOSQ_LOG_* constants. Messages containing NUL bytes are cut at the first NUL.
+pub fn log_printf(level: u32, message: &str) {
+    let message = match CString::new(message) {
+        Ok(message) => message,
+        Err(e) => {
+            let nul = e.nul_position();
+            CString::new(&message[..nul]).unwrap_or_default()
+        }
+    };
+    unsafe {
+        mosquitto_log_printf(
+            level as i32,
+            b"%s\0".as_ptr() as *const std::os::raw::c_char,
+            message.as_ptr(),
+        );
+    }
+}
+
+/// Broadcast a message from the broker to every client subscribed to the topic.
+/// Binding to mosquitto_broker_publish with a null client id.
+///
+/// The payload can be anything that derefs to bytes: a slice, an owned Vec<u8>, a Cow<[u8]>, a
+/// String or a bytes::Bytes. The broker needs a buffer allocated with mosquitto_malloc that it
+/// frees itself, so the payload is copied into one exactly once. Use publish_broadcast_with to
+/// serialize straight into that buffer instead.
+pub fn publish_broadcast(
+    topic: &str,
+    payload: impl AsRef<[u8]>,
+    qos: QOS,
+    retain: bool,
+) -> Result<Success, Error> {
+    let topic = &publish_topic(topic)?;
+    broker_publish(None, topic, BrokerPayload::copy_from(payload.as_ref()), qos.to_i32(), retain, PropertyList::empty())
+}
+
+/// Like publish_broadcast, with the payload written by `write` directly into the len bytes long,
+/// zeroed, broker owned buffer.
+pub fn publish_broadcast_with(
+    topic: &str,
+    len: usize,
+    qos: QOS,
+    retain: bool,
+    write: impl FnOnce(&mut [u8]),
+) -> Result<Success, Error> {
+    let topic = &publish_topic(topic)?;
+    broker_publish(None, topic, BrokerPayload::with_writer(len, write), qos.to_i32(), retain, PropertyList::empty())
+}
+
+/// Like publish_broadcast, with MQTT v5 properties attached to the message. Properties that can't
+/// be represented (strings containing NUL, correlation data over 65535 bytes) are
+/// Err(Error::Inval) and nothing is published.
+pub fn publish_broadcast_with_properties(
+    topic: &str,
+    payload: impl AsRef<[u8]>,
+    qos: QOS,
+    retain: bool,
+    properties: &Properties,
+) -> Result<Success, Error> {
+    let topic = &publish_topic(topic)?;
+    let properties = properties.to_list()?;
+    broker_publish(None, topic, BrokerPayload::copy_from(payload.as_ref()), qos.to_i32(), retain, properties)
+}
+
+/// Delete the retained message of a topic, by publishing an empty retained message to it like
+/// `mosquitto_pub -r -n` does. Subscribers of the topic receive the empty message. The topic
+/// can't contain wildcards, retained messages are cleared one topic at a time.
+pub fn clear_retained(topic: &str) -> Result<Success, Error> {
+    publish_broadcast(topic, &b""[..], QOS::AtMostOnce, true)
+}
+
+/// Publish a message from the broker to a single client.
+/// Binding to mosquitto_broker_publish.
+///
+/// Err(Error::Inval) is returned for invalid arguments (empty topic, bad qos or payload). The
+/// broker queues plugin publishes and resolves the client id on the next loop iteration, a
+/// message for a client that isn't connected is dropped without an error.
+pub fn publish_to_client(
+    client_id: &str,
+    topic: &str,
+    payload: impl AsRef<[u8]>,
+    qos: QOS,
+    retain: bool,
+) -> Result<Success, Error> {
+    let client_id = &CString::new(client_id).map_err(|_| Error::Inval)?;
+    let topic = &publish_topic(topic)?;
+    broker_publish(Some(client_id), topic, BrokerPayload::copy_from(payload.as_ref()), qos.to_i32(), retain, PropertyList::empty())
+}
+
+/// Like publish_to_client, with the payload written by `write` directly into the len bytes long,
+/// zeroed, broker owned buffer.
+pub fn publish_to_client_with(
+    client_id: &str,
+    topic: &str,
+    len: usize,
+    qos: QOS,
+    retain: bool,
+    write: impl FnOnce(&mut [u8]),
+) -> Result<Success, Error> {
+    let client_id = &CString::new(client_id).map_err(|_| Error::Inval)?;
+    let topic = &publish_topic(topic)?;
+    broker_publish(Some(client_id), topic, BrokerPayload::with_writer(len, write), qos.to_i32(), retain, PropertyList::empty())
+}
+
+/// Like publish_to_client, with MQTT v5 properties attached to the message, see
+/// publish_broadcast_with_properties.
+pub fn publish_to_client_with_properties(
+    client_id: &str,
+    topic: &str,
+    payload: impl AsRef<[u8]>,
+    qos: QOS,
+    retain: bool,
+    properties: &Properties,
+) -> Result<Success, Error> {
+    let client_id = &CString::new(client_id).map_err(|_| Error::Inval)?;
+    let topic = &publish_topic(topic)?;
+    let properties = properties.to_list()?;
+    broker_publish(Some(client_id), topic, BrokerPayload::copy_from(payload.as_ref()), qos.to_i32(), retain, properties)
+}
+
+/// Publish the same message to each of the given clients.
+/// The topic is converted once and every recipient gets its own copy of the payload, the result
+/// for each client id is reported separately. Like publish_to_client, clients that aren't
+/// connected don't make their publish fail.
+pub fn publish_to_clients(
+    client_ids: &[&str],
+    topic: &str,
+    payload: impl AsRef<[u8]>,
+    qos: QOS,
+    retain: bool,
+) -> Vec<(String, Result<Success, Error>)> {
+    let topic = publish_topic(topic);
+    let payload = payload.as_ref();
+    let qos = qos.to_i32();
+    client_ids
+        .iter()
+        .map(|client_id| {
+            // A bad topic fails for every client
+            let res = match (&topic, CString::new(*client_id)) {
+                (Ok(topic), Ok(cstr)) => broker_publish(Some(&cstr), topic, BrokerPayload::copy_from(payload), qos, retain, PropertyList::empty()),
+                (Err(e), _) => Err(*e),
+                (_, Err(_)) => Err(Error::Inval),
+            };
+            (client_id.to_string(), res)
+        })
+        .collect()
+}
+
+/// Broadcast a batch of messages, given as (topic, payload, qos, retain), e.g. telemetry fanned
+/// out to many topics. The broker copies the topic, so one buffer holds the C string of every
+/// topic in turn and the only allocation per message is the payload the broker takes over. The
+/// results are in the order of the messages, one that fails doesn't stop the ones after it.
+pub fn publish_batch<P: AsRef<[u8]>>(messages: &[(&str, P, QOS, bool)]) -> Vec<Result<Success, Error>> {
+    let mut c_topic = Vec::with_capacity(messages.iter().map(|m| m.0.len() + 1).max().unwrap_or(0));
+    messages
+        .iter()
+        .map(|(topic, payload, qos, retain)| {
+            c_topic.clear();
+            c_topic.extend_from_slice(topic.as_bytes());
+            c_topic.push(0);
+            let c_topic = CStr::from_bytes_with_nul(&c_topic).map_err(|_| Error::Inval)?;
+            check_topic(c_topic, topic)?;
+            broker_publish(None, c_topic, BrokerPayload::copy_from(payload.as_ref()), qos.to_i32(), *retain, PropertyList::empty())
+        })
+        .collect()
+}
+
+/// Disconnect the client with this id. Binding to mosquitto_kick_client_by_clientid, with_will
+/// makes the broker send the will of the client as if the connection had been lost.
+pub fn kick_client_by_clientid(client_id: &str, with_will: bool) -> Result<Success, Error> {
+    let client_id = CString::new(client_id).map_err(|_| Error::Inval)?;
+    to_result(unsafe { mosquitto_kick_client_by_clientid(client_id.as_ptr(), with_will) })
+}
+
+/// Disconnect every client logged in with this username. Binding to
+/// mosquitto_kick_client_by_username.
+pub fn kick_client_by_username(username: &str, with_will: bool) -> Result<Success, Error> {
+    let username = CString::new(username).map_err(|_| Error::Inval)?;
+    to_result(unsafe { mosquitto_kick_client_by_username(username.as_ptr(), with_will) })
+}
+
+/// Whether the topic is matched by the subscription pattern. Binding to
+/// mosquitto_topic_matches_sub, so `+`, `#` and `$` topics are handled exactly like the broker does
+/// when delivering messages. Err(Error::Inval) when the pattern isn't a valid subscription or the
+/// topic contains wildcards. For many patterns topic::TopicMatcher avoids matching each one.
+pub fn topic_matches(pattern: &str, topic: &str) -> Result<bool, Error> {
+    let pattern = CString::new(pattern).map_err(|_| Error::Inval)?;
+    let topic = CString::new(topic).map_err(|_| Error::Inval)?;
+    let mut result = false;
+    to_result(unsafe { mosquitto_topic_matches_sub(pattern.as_ptr(), topic.as_ptr(), &mut result) })?;
+    Ok(result)
+}
+
+/// Split a topic or subscription filter into its levels. Binding to mosquitto_sub_topic_tokenise,
+/// the levels borrow from topic, empty levels (`a//b`, `/a`) are empty strings.
+pub fn tokenise_topic(topic: &str) -> Result<Vec<&str>, Error> {
+    let c_topic = CString::new(topic).map_err(|_| Error::Inval)?;
+    let mut tokens: *mut *mut std::os::raw::c_char = std::ptr::null_mut();
+    let mut count = 0;
+    to_result(unsafe { mosquitto_sub_topic_tokenise(c_topic.as_ptr(), &mut tokens, &mut count) })?;
+
+    // The broker copies every level, only their lengths are needed to slice topic
+    let mut levels = Vec::with_capacity(count.max(0) as usize);
+    let mut start = 0;
+    for i in 0..count.max(0) as usize {
+        let token = unsafe { *tokens.add(i) };
+        let len = if token.is_null() {
+            0
+        } else {
+            unsafe { CStr::from_ptr(token) }.to_bytes().len()
+        };
+        match topic.get(start..start + len) {
+            Some(level) => levels.push(level),
+            None => break,
+        }
+        start += len + 1;
+    }
+    unsafe { mosquitto_sub_topic_tokens_free(&mut tokens, count) };
+    if levels.len() == count.max(0) as usize {
+        Ok(levels)
+    } else {
+        Err(Error::Unknown)
+    }
+}
+
+/// Whether messages can be published to the topic. Binding to mosquitto_pub_topic_check, the
+/// error names the rule that was broken.
+pub fn check_publish_topic(topic: &str) -> Result<(), TopicError> {
+    let c_topic = CString::new(topic).map_err(|_| TopicError::Nul)?;
+    match unsafe { mosquitto_pub_topic_check(c_topic.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(topic_error(topic, false)),
+    }
+}
+
+/// Whether clients can subscribe to the filter. Binding to mosquitto_sub_topic_check, the error
+/// names the rule that was broken.
+pub fn check_subscribe_filter(filter: &str) -> Result<(), TopicError> {
+    let c_filter = CString::new(filter).map_err(|_| TopicError::Nul)?;
+    match unsafe { mosquitto_sub_topic_check(c_filter.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(topic_error(filter, true)),
+    }
+}
+
+// The topic of a message the plugin publishes, checked the way mosquitto checks the topic of a
+// PUBLISH packet. mosquitto_broker_publish itself only refuses empty topics.
+fn publish_topic(topic: &str) -> Result<CString, Error> {
+    let c_topic = CString::new(topic).map_err(|_| Error::Inval)?;
+    check_topic(&c_topic, topic)?;
+    Ok(c_topic)
+}
+
+fn check_topic(c_topic: &CStr, topic: &str) -> Result<(), Error> {
+    if unsafe { mosquitto_pub_topic_check(c_topic.as_ptr()) } != 0 {
+        return Err(topic_error(topic, false).into());
+    }
+    // Valid Rust strings can still hold control characters and non-characters
+    if unsafe { mosquitto_validate_utf8(c_topic.as_ptr(), topic.len() as i32) } != 0 {
+        return Err(Error::MalformedUtf8);
+    }
+    Ok(())
+}
+
+// Names the rule a topic the broker refused broke, the decision itself is left to the broker
+pub(crate) fn topic_error(topic: &str, filter: bool) -> TopicError {
+    let too_long = topic.len() > u16::MAX as usize;
+    if !filter && topic.contains(&['+', '#'][..]) {
+        TopicError::Wildcard
+    } else if filter && !topic.is_empty() && !too_long && !topic::is_valid_subscribe_filter(topic) {
+        TopicError::MisplacedWildcard
+    } else if too_long {
+        TopicError::TooLong
+    } else if topic.matches('/').count() > 200 {
+        TopicError::TooManyLevels
+    } else {
+        TopicError::Invalid
+    }
+}
+
+// A payload buffer allocated with mosquitto_malloc. mosquitto_broker_publish takes ownership of
+// it on success only, so it is freed on drop unless it was handed over.
+pub(crate) struct BrokerPayload {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl BrokerPayload {
+    pub(crate) fn copy_from(payload: &[u8]) -> BrokerPayload {
+        if payload.is_empty() {
+            return BrokerPayload { ptr: std::ptr::null_mut(), len: 0 };
+        }
+        unsafe {
+            let ptr = mosquitto_malloc(payload.len());
+            if !ptr.is_null() {
+                std::ptr::copy_nonoverlapping(payload.as_ptr(), ptr as *mut u8, payload.len());
+            }
+            BrokerPayload { ptr, len: payload.len() }
+        }
+    }
+
+    fn with_writer(len: usize, write: impl FnOnce(&mut [u8])) -> BrokerPayload {
+        if len == 0 {
+            write(&mut []);
+            return BrokerPayload { ptr: std::ptr::null_mut(), len: 0 };
+        }
+        unsafe {
+            let ptr = mosquitto_calloc(len, 1);
+            if !ptr.is_null() {
+                write(std::slice::from_raw_parts_mut(ptr as *mut u8, len));
+            }
+            BrokerPayload { ptr, len }
+        }
+    }
+
+    /// False when mosquitto_malloc failed
+    pub(crate) fn is_allocated(&self) -> bool {
+        self.len == 0 || !self.ptr.is_null()
+    }
+
+    /// The buffer, still owned by self
+    #[cfg(mosquitto_2_1)]
+    pub(crate) fn as_ptr(&self) -> *mut c_void {
+        self.ptr
+    }
+
+    /// The buffer and its length, for handing it over to the broker
+    pub(crate) fn into_raw(mut self) -> (*mut c_void, usize) {
+        let ptr = std::mem::replace(&mut self.ptr, std::ptr::null_mut());
+        (ptr, self.len)
+    }
+}
+
+impl Drop for BrokerPayload {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { mosquitto_free(self.ptr) };
+        }
+    }
+}
+
+fn broker_publish(
+    client_id: Option<&CStr>,
+    topic: &CStr,
+    mut payload: BrokerPayload,
+    qos: i32,
+    retain: bool,
+    mut properties: PropertyList,
+) -> Result<Success, Error> {
+    if !payload.is_allocated() {
+        return Err(Error::NoMem);
+    }
+    if payload.len > i32::MAX as usize {
+        return Err(Error::PayloadSize);
+    }
+    let res = unsafe {
+        mosquitto_broker_publish(
+            client_id.map_or(std::ptr::null(), |c| c.as_ptr()), // client id to send to, null = all clients
+            topic.as_ptr(),       // topic to publish on
+            payload.len as i32,   // payload length in bytes, 0 for empty payload
+            payload.ptr,          // payload bytes, non-null if payload length > 0, must be heap allocated
+            qos,                  // qos
+            retain,               // retain
+            properties.ptr,       //mqtt5 properties, null for none, freed by mosquitto like the payload
+        )
+    };
+    let success = to_result(res)?;
+    // mosquitto frees the payload and the properties after use
+    payload.ptr = std::ptr::null_mut();
+    properties.ptr = std::ptr::null_mut();
+    Ok(success)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    #[test]
+    fn publish_to_connected_client_succeeds() {
+        stub_ffi::reset();
+        let res = publish_to_client("client-1", "greeting", b"hello", QOS::AtLeastOnce, false);
+        assert_eq!(res, Ok(Success));
+        let published = stub_ffi::published();
+        assert_eq!(published[0].client_id.as_deref(), Some("client-1"));
+        assert_eq!(published[0].payload, b"hello");
+    }
+
+    #[test]
+    fn invalid_arguments_stay_inval() {
+        stub_ffi::reset();
+        let res = publish_to_client("client-1", "", b"hello", QOS::AtMostOnce, false);
+        assert_eq!(res, Err(Error::Inval));
+    }
+
+    #[test]
+    fn publish_to_clients_converts_topic_once_and_reports_each_client() {
+        stub_ffi::reset();
+        let clients = ["client-1", "client\0", "client-2"];
+        let results = publish_to_clients(&clients, "group/alert", b"fire", QOS::AtLeastOnce, false);
+
+        assert_eq!(
+            results,
+            vec![
+                ("client-1".to_string(), Ok(Success)),
+                ("client\0".to_string(), Err(Error::Inval)),
+                ("client-2".to_string(), Ok(Success)),
+            ]
+        );
+        // The client id with a nul never reached the broker
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 2);
+        // Every broker call got the same converted topic
+        assert!(published.iter().all(|p| p.topic_ptr == published[0].topic_ptr));
+        assert!(published.iter().all(|p| p.payload == b"fire"));
+    }
+
+    #[test]
+    fn publish_batch_reports_each_message() {
+        stub_ffi::reset();
+        let messages: [(&str, &[u8], QOS, bool); 4] = [
+            ("sensors/1/temperature", b"21.5", QOS::AtLeastOnce, true),
+            ("sensors/+/temperature", b"x", QOS::AtMostOnce, false),
+            ("sensors/2\0", b"x", QOS::AtMostOnce, false),
+            ("sensors/2/humidity", b"", QOS::AtMostOnce, false),
+        ];
+        let results = publish_batch(&messages);
+        assert_eq!(results, vec![Ok(Success), Err(Error::TopicWildcard), Err(Error::Inval), Ok(Success)]);
+
+        let published = stub_ffi::published();
+        let topics: Vec<_> = published.iter().map(|p| (p.topic.as_str(), p.qos, p.retain)).collect();
+        assert_eq!(topics, vec![("sensors/1/temperature", 1, true), ("sensors/2/humidity", 0, false)]);
+        // One buffer held every topic
+        assert_eq!(published[0].topic_ptr, published[1].topic_ptr);
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn owned_borrowed_and_written_payloads_publish_the_same_bytes() {
+        stub_ffi::reset();
+        let owned: Vec<u8> = b"reading".to_vec();
+        publish_broadcast("a", &owned, QOS::AtMostOnce, false).unwrap();
+        publish_broadcast("a", owned, QOS::AtMostOnce, false).unwrap();
+        publish_broadcast("a", std::borrow::Cow::Borrowed(&b"reading"[..]), QOS::AtMostOnce, false).unwrap();
+        publish_broadcast_with("a", 7, QOS::AtMostOnce, false, |buf| buf.copy_from_slice(b"reading")).unwrap();
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 4);
+        assert!(published.iter().all(|p| p.payload == b"reading"));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn nul_bytes_are_inval() {
+        stub_ffi::reset();
+        assert_eq!(publish_broadcast("a\0b", b"x", QOS::AtMostOnce, false), Err(Error::Inval));
+        assert_eq!(publish_broadcast_with("a\0b", 1, QOS::AtMostOnce, false, |_| {}), Err(Error::Inval));
+        assert_eq!(publish_to_client("client\0", "a", b"x", QOS::AtMostOnce, false), Err(Error::Inval));
+        assert_eq!(publish_to_client("client-1", "a\0b", b"x", QOS::AtMostOnce, false), Err(Error::Inval));
+        assert_eq!(publish_to_client_with("client-1", "a\0b", 1, QOS::AtMostOnce, false, |_| {}), Err(Error::Inval));
+        assert_eq!(
+            publish_to_clients(&["client-1", "client\0"], "a", b"x", QOS::AtMostOnce, false),
+            vec![("client-1".to_string(), Ok(Success)), ("client\0".to_string(), Err(Error::Inval))]
+        );
+        assert_eq!(
+            publish_to_clients(&["client-1"], "a\0b", b"x", QOS::AtMostOnce, false),
+            vec![("client-1".to_string(), Err(Error::Inval))]
+        );
+        assert_eq!(clear_retained("a\0b"), Err(Error::Inval));
+        assert_eq!(kick_client_by_clientid("client\0", false), Err(Error::Inval));
+        assert_eq!(stub_ffi::published().len(), 1);
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn topics_are_matched_by_the_broker() {
+        assert_eq!(topic_matches("sensors/+/temperature", "sensors/1/temperature"), Ok(true));
+        assert_eq!(topic_matches("sensors/#", "sensors"), Ok(true));
+        assert_eq!(topic_matches("sensors/+", "sensors/1/temperature"), Ok(false));
+        assert_eq!(topic_matches("#", "$SYS/broker/uptime"), Ok(false));
+        assert_eq!(topic_matches("sensors/#/temperature", "sensors/1/temperature"), Err(Error::Inval));
+        assert_eq!(topic_matches("sensors/#", "sensors/+"), Err(Error::Inval));
+        assert_eq!(topic_matches("sensors/\0", "sensors/a"), Err(Error::Inval));
+    }
+
+    #[test]
+    fn topics_are_tokenised_by_the_broker() {
+        stub_ffi::reset();
+        assert_eq!(tokenise_topic("sensors/+/temperature"), Ok(vec!["sensors", "+", "temperature"]));
+        assert_eq!(tokenise_topic("/a//b/"), Ok(vec!["", "a", "", "b", ""]));
+        assert_eq!(tokenise_topic("$SYS/#"), Ok(vec!["$SYS", "#"]));
+        assert_eq!(tokenise_topic("a\0b"), Err(Error::Inval));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn topic_checks_name_the_broken_rule() {
+        assert_eq!(check_publish_topic("sensors/1/temperature"), Ok(()));
+        assert_eq!(check_publish_topic("sensors/+/temperature"), Err(TopicError::Wildcard));
+        assert_eq!(check_publish_topic("sensors\0"), Err(TopicError::Nul));
+        assert_eq!(check_publish_topic(&"a".repeat(65536)), Err(TopicError::TooLong));
+        assert_eq!(check_publish_topic(&"a/".repeat(201)), Err(TopicError::TooManyLevels));
+        assert_eq!(check_subscribe_filter("sensors/+/#"), Ok(()));
+        assert_eq!(check_subscribe_filter("sensors/#/temperature"), Err(TopicError::MisplacedWildcard));
+        assert_eq!(check_subscribe_filter("sensors/temp+"), Err(TopicError::MisplacedWildcard));
+        assert_eq!(check_subscribe_filter(&"+/".repeat(201)), Err(TopicError::TooManyLevels));
+    }
+
+    #[test]
+    fn bad_topics_say_what_is_wrong() {
+        stub_ffi::reset();
+        assert_eq!(publish_broadcast("sensors/+", b"x", QOS::AtMostOnce, false), Err(Error::TopicWildcard));
+        assert_eq!(clear_retained("sensors/#"), Err(Error::TopicWildcard));
+        assert_eq!(publish_to_client("client-1", &"a".repeat(65536), b"x", QOS::AtMostOnce, false), Err(Error::TopicTooLong));
+        assert_eq!(publish_broadcast_with(&"a/".repeat(201), 1, QOS::AtMostOnce, false, |_| {}), Err(Error::TopicTooDeep));
+        assert_eq!(publish_broadcast("sensors/\u{1}", b"x", QOS::AtMostOnce, false), Err(Error::MalformedUtf8));
+        assert_eq!(
+            publish_to_clients(&["client-1"], "sensors/#", b"x", QOS::AtMostOnce, false),
+            vec![("client-1".to_string(), Err(Error::TopicWildcard))]
+        );
+        assert!(stub_ffi::published().is_empty());
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        assert_eq!(Into::<i32>::into(Error::TopicWildcard), Error::Inval as i32);
+    }
+
+    #[test]
+    fn empty_payloads_are_published_without_a_buffer() {
+        stub_ffi::reset();
+        // The stub refuses a zero length with a buffer, like a length without one
+        publish_broadcast("a", b"", QOS::AtMostOnce, false).unwrap();
+        publish_to_client_with("client-1", "a", 0, QOS::AtMostOnce, false, |buf| assert!(buf.is_empty())).unwrap();
+        clear_retained("sensors/1/temperature").unwrap();
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 3);
+        assert!(published.iter().all(|p| p.payload.is_empty()));
+        assert!(published[2].retain);
+        assert_eq!(published[2].qos, 0);
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn payload_is_freed_when_the_broker_rejects_it() {
+        stub_ffi::reset();
+        let _ = publish_to_client_with("client-1", "", 5, QOS::AtMostOnce, false, |_| {});
+        stub_ffi::fail_publishes(Error::NoMem.into());
+        let res = publish_to_client("client-1", "a", b"hello", QOS::AtMostOnce, false);
+        assert_eq!(res, Err(Error::NoMem));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+    }
+
+    #[test]
+    fn kicks_are_passed_to_the_broker() {
+        stub_ffi::reset();
+        assert_eq!(kick_client_by_clientid("client-1", true), Ok(Success));
+        assert_eq!(kick_client_by_username("alice", false), Ok(Success));
+        assert_eq!(kick_client_by_clientid("client\0", false), Err(Error::Inval));
+        assert_eq!(
+            stub_ffi::kicked(),
+            vec![("client-1".to_string(), true), ("alice".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn properties_are_handed_to_the_broker() {
+        stub_ffi::reset();
+        let props = Properties::new()
+            .response_topic("replies/client-1")
+            .correlation_data(b"req-7")
+            .user_property("origin", "plugin");
+        publish_to_client_with_properties("client-1", "requests", b"ping", QOS::AtLeastOnce, false, &props).unwrap();
+        publish_broadcast_with_properties("requests", b"ping", QOS::AtMostOnce, false, &props).unwrap();
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 2);
+        assert!(published.iter().all(|p| p.properties
+            == vec![
+                stub_ffi::StubProperty::String(8, "replies/client-1".to_string()),
+                stub_ffi::StubProperty::Binary(9, b"req-7".to_vec()),
+                stub_ffi::StubProperty::StringPair(38, "origin".to_string(), "plugin".to_string()),
+            ]));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+
+        // freed by the plugin when the broker doesn't take them
+        let res = publish_broadcast_with_properties("a", b"x", QOS::AtMostOnce, false, &props.clone().content_type("nul\0"));
+        assert_eq!(res, Err(Error::Inval));
+        stub_ffi::fail_publishes(Error::NoMem.into());
+        let res = publish_to_client_with_properties("client-1", "a", b"x", QOS::AtMostOnce, false, &props);
+        assert_eq!(res, Err(Error::NoMem));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        // the invalid properties never reached the broker
+        assert_eq!(stub_ffi::published().len(), 3);
+    }
+}
@@ -3,5 +3,29 @@
 #![allow(non_snake_case)]
 #![allow(unused)]
 
+/// The version of the mosquitto headers the bindings were generated from, e.g. "2.0.18". The
+/// events and functions of mosquitto 2.1 are only bound with 2.1 headers, see the README.
+pub const HEADERS_VERSION: &str = env!("MOSQUITTO_HEADERS_VERSION");
+
 // All the bindings provided by mosquitto_plugin.h
+#[cfg(not(all(feature = "auth-plugin-v4", not(any(test, feature = "testing")))))]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+// With the auth plugin interface of mosquitto 1.6 the functions it lacks are looked up at runtime,
+// see auth_plugin_v4::broker_2_0. Named imports win over the glob.
+#[cfg(all(feature = "auth-plugin-v4", not(any(test, feature = "testing"))))]
+pub mod bindings {
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+#[cfg(all(feature = "auth-plugin-v4", not(any(test, feature = "testing"))))]
+pub use bindings::*;
+#[cfg(all(feature = "auth-plugin-v4", not(any(test, feature = "testing"))))]
+pub use crate::auth_plugin_v4::broker_2_0::{
+    mosquitto_broker_publish, mosquitto_callback_register, mosquitto_callback_unregister,
+    mosquitto_client_protocol_version, mosquitto_kick_client_by_clientid,
+    mosquitto_kick_client_by_username, mosquitto_property_add_binary,
+    mosquitto_property_add_int32, mosquitto_property_add_string, mosquitto_property_add_string_pair,
+    mosquitto_property_free_all, mosquitto_property_read_binary, mosquitto_property_read_int32,
+    mosquitto_property_read_string, mosquitto_property_read_string_pair, mosquitto_set_username,
+    mosquitto_validate_utf8,
+};
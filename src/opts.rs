@@ -0,0 +1,574 @@
+// Indirection for option values, so secrets don't have to be written into mosquitto.conf:
+//
+//     plugin_opt_db_password_file /run/secrets/db_password
+//     plugin_opt_db_host ${DB_HOST}:5432
+//
+// With files enabled `db_password_file` is read and handed to the plugin as `db_password`. With
+// env enabled `${NAME}` is replaced with the environment variable, `$$` is a literal `$`. Values
+// are interpolated before files are read, so paths can use variables, the contents of the files
+// are used as they are. Nothing is resolved unless the plugin asks for it in init:
+//
+//     let opts = OptResolver::new().files().env().resolve(&opts)?;
+//
+// Typed options are read with #[derive(PluginOpts)], each field is the option of the same name:
+//
+//     #[derive(PluginOpts)]
+//     struct Config {
+//         db_host: String,                // required
+//         #[opt(default = "30s")]
+//         timeout: Duration,
+//         #[opt(name = "max_clients", default)]
+//         limit: u32,                     // Default::default() when not given
+//         cache_dir: Option<PathBuf>,     // None when not given
+//     }
+//
+//     let config = Config::from_opts(&opts)?;
+use crate::MosquittoOpt;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub use mosquitto_plugin_macros::PluginOpts;
+
+const FILE_SUFFIX: &str = "_file";
+
+#[derive(Debug)]
+pub enum OptError {
+    /// The file named by `key` couldn't be read
+    File {
+        key: String,
+        path: String,
+        error: io::Error,
+    },
+    /// `key` refers to a variable that isn't set
+    UnsetVariable { key: String, variable: String },
+    /// `key` has a `${` without a matching `}` or with an invalid name in between
+    InvalidReference { key: String, reference: String },
+    /// Both `key` and `key_file` are given
+    Conflict { key: String },
+    /// The required option `key` isn't given
+    Missing { key: String },
+    /// `value` of `key` can't be parsed, expected describes what would have been accepted
+    Invalid {
+        key: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for OptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptError::File { key, path, error } => {
+                write!(f, "option {}: can't read {}: {}", key, path, error)
+            }
+            OptError::UnsetVariable { key, variable } => {
+                write!(
+                    f,
+                    "option {}: environment variable {} is not set",
+                    key, variable
+                )
+            }
+            OptError::InvalidReference { key, reference } => {
+                write!(
+                    f,
+                    "option {}: invalid variable reference {:?}",
+                    key, reference
+                )
+            }
+            OptError::Conflict { key } => write!(
+                f,
+                "option {} is given both directly and as {}{}",
+                key, key, FILE_SUFFIX
+            ),
+            OptError::Missing { key } => write!(f, "option {} is required", key),
+            OptError::Invalid {
+                key,
+                value,
+                expected,
+            } => write!(f, "option {}: expected {}, got {:?}", key, expected, value),
+        }
+    }
+}
+
+impl std::error::Error for OptError {}
+
+/// Options with the indirections resolved, owning their values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolvedOpts(HashMap<String, String>);
+
+impl ResolvedOpts {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|v| v.as_str())
+    }
+
+    /// Borrowed as MosquittoOpt, for helpers taking the options of init like Stats::from_opts
+    pub fn as_opt(&self) -> MosquittoOpt<'_> {
+        self.0
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptResolver {
+    files: bool,
+    env: bool,
+}
+
+impl OptResolver {
+    /// Resolves nothing until files or env are enabled
+    pub fn new() -> OptResolver {
+        OptResolver::default()
+    }
+
+    /// Read `<key>_file` options and expose their trimmed contents as `<key>`
+    pub fn files(mut self) -> OptResolver {
+        self.files = true;
+        self
+    }
+
+    /// Replace `${NAME}` in values with environment variables
+    pub fn env(mut self) -> OptResolver {
+        self.env = true;
+        self
+    }
+
+    pub fn resolve(&self, opts: &MosquittoOpt) -> Result<ResolvedOpts, OptError> {
+        self.resolve_with(opts, |name| std::env::var(name).ok())
+    }
+
+    fn resolve_with(
+        &self,
+        opts: &MosquittoOpt,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<ResolvedOpts, OptError> {
+        let mut resolved = HashMap::new();
+        for (key, value) in opts {
+            let value = if self.env {
+                interpolate(key, value, &env)?
+            } else {
+                value.to_string()
+            };
+            resolved.insert(key.to_string(), value);
+        }
+        if self.files {
+            let file_keys: Vec<String> = resolved
+                .keys()
+                .filter(|k| k.ends_with(FILE_SUFFIX))
+                .cloned()
+                .collect();
+            for file_key in file_keys {
+                let key = &file_key[..file_key.len() - FILE_SUFFIX.len()];
+                if key.is_empty() {
+                    continue;
+                }
+                if resolved.contains_key(key) {
+                    return Err(OptError::Conflict {
+                        key: key.to_string(),
+                    });
+                }
+                let path = &resolved[&file_key];
+                let contents = fs::read_to_string(path).map_err(|error| OptError::File {
+                    key: file_key.clone(),
+                    path: path.clone(),
+                    error,
+                })?;
+                resolved.insert(key.to_string(), contents.trim().to_string());
+            }
+        }
+        Ok(ResolvedOpts(resolved))
+    }
+}
+
+/// Options read into a struct, usually derived with #[derive(PluginOpts)]
+pub trait PluginOpts: Sized {
+    fn from_opts(opts: &MosquittoOpt) -> Result<Self, OptError>;
+}
+
+/// A type an option value can be parsed into
+pub trait OptValue: Sized {
+    /// What is accepted, for the error message, like "an integer"
+    const EXPECTED: &'static str;
+
+    fn parse_opt(value: &str) -> Option<Self>;
+
+    /// The value when the option isn't given, None makes the option required
+    fn absent() -> Option<Self> {
+        None
+    }
+}
+
+/// The option `key` parsed as T, an error when it isn't given unless T is an Option
+pub fn opt<T: OptValue>(opts: &MosquittoOpt, key: &str) -> Result<T, OptError> {
+    match opts.get(key) {
+        Some(value) => parse(key, value),
+        None => T::absent().ok_or_else(|| OptError::Missing {
+            key: key.to_string(),
+        }),
+    }
+}
+
+/// The option `key` parsed as T, or default parsed the same way when it isn't given
+pub fn opt_or<T: OptValue>(opts: &MosquittoOpt, key: &str, default: &str) -> Result<T, OptError> {
+    parse(key, opts.get(key).copied().unwrap_or(default))
+}
+
+fn parse<T: OptValue>(key: &str, value: &str) -> Result<T, OptError> {
+    T::parse_opt(value).ok_or_else(|| OptError::Invalid {
+        key: key.to_string(),
+        value: value.to_string(),
+        expected: T::EXPECTED,
+    })
+}
+
+impl<T: OptValue> OptValue for Option<T> {
+    const EXPECTED: &'static str = T::EXPECTED;
+
+    fn parse_opt(value: &str) -> Option<Self> {
+        T::parse_opt(value).map(Some)
+    }
+
+    fn absent() -> Option<Self> {
+        Some(None)
+    }
+}
+
+impl OptValue for String {
+    const EXPECTED: &'static str = "a string";
+
+    fn parse_opt(value: &str) -> Option<Self> {
+        Some(value.to_string())
+    }
+}
+
+impl OptValue for PathBuf {
+    const EXPECTED: &'static str = "a path";
+
+    fn parse_opt(value: &str) -> Option<Self> {
+        Some(PathBuf::from(value))
+    }
+}
+
+// The spellings mosquitto.conf accepts
+impl OptValue for bool {
+    const EXPECTED: &'static str = "true or false";
+
+    fn parse_opt(value: &str) -> Option<Self> {
+        match value.trim() {
+            "true" | "yes" | "on" | "1" => Some(true),
+            "false" | "no" | "off" | "0" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! opt_value_from_str {
+    ($expected:expr, $($t:ty),*) => {
+        $(
+            impl OptValue for $t {
+                const EXPECTED: &'static str = $expected;
+
+                fn parse_opt(value: &str) -> Option<Self> {
+                    value.trim().parse().ok()
+                }
+            }
+        )*
+    };
+}
+
+opt_value_from_str!("an integer", i8, i16, i32, i64, isize);
+opt_value_from_str!("a non-negative integer", u8, u16, u32, u64, usize);
+opt_value_from_str!("a number", f32, f64);
+
+// Plain numbers are seconds, like the intervals in mosquitto.conf
+impl OptValue for Duration {
+    const EXPECTED: &'static str = "a duration like 30s, 500ms, 5m or 2h";
+
+    fn parse_opt(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: u64 = number.parse().ok()?;
+        match unit {
+            "ms" => Some(Duration::from_millis(number)),
+            "" | "s" => Some(Duration::from_secs(number)),
+            "m" => number.checked_mul(60).map(Duration::from_secs),
+            "h" => number.checked_mul(60 * 60).map(Duration::from_secs),
+            _ => None,
+        }
+    }
+}
+
+fn interpolate(
+    key: &str,
+    value: &str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<String, OptError> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let invalid = || OptError::InvalidReference {
+                key: key.to_string(),
+                reference: rest.to_string(),
+            };
+            let end = after.find('}').ok_or_else(invalid)?;
+            let name = &after[..end];
+            // Only plain names, so "${A_${B}}" is an error rather than silently half expanded
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(OptError::InvalidReference {
+                    key: key.to_string(),
+                    reference: rest[..end + 3].to_string(),
+                });
+            }
+            let variable = env(name).ok_or_else(|| OptError::UnsetVariable {
+                key: key.to_string(),
+                variable: name.to_string(),
+            })?;
+            // The value of the variable is not interpolated again
+            out.push_str(&variable);
+            rest = &after[end + 1..];
+        } else {
+            // A lone $ is kept, e.g. in passwd style hashes
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "HOST" => Some("db.local".to_string()),
+            "B" => Some("X".to_string()),
+            "TRICKY" => Some("${HOST}".to_string()),
+            _ => None,
+        }
+    }
+
+    fn resolve(resolver: OptResolver, opts: &[(&str, &str)]) -> Result<ResolvedOpts, OptError> {
+        resolver.resolve_with(&opts.iter().copied().collect(), env)
+    }
+
+    // A file in a fresh directory, removed with it when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let dir = std::env::temp_dir().join(format!(
+                "mosquitto-plugin-opts-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    #[test]
+    fn raw_values_by_default() {
+        let opts = resolve(
+            OptResolver::new(),
+            &[("host", "${HOST}"), ("password_file", "/nonexistent")],
+        )
+        .unwrap();
+        assert_eq!(opts.get("host"), Some("${HOST}"));
+        assert_eq!(opts.get("password"), None);
+    }
+
+    #[test]
+    fn interpolation() {
+        let cases = [
+            ("${HOST}:5432", Ok("db.local:5432")),
+            ("plain", Ok("plain")),
+            ("$$HOST", Ok("$HOST")),
+            ("$${HOST}", Ok("${HOST}")),
+            ("$$${HOST}", Ok("$db.local")),
+            ("$6$salt$hash", Ok("$6$salt$hash")),
+            ("trailing $", Ok("trailing $")),
+            ("${TRICKY}", Ok("${HOST}")),
+            ("${B}${B}", Ok("XX")),
+            ("${A_${B}}", Err("invalid")),
+            ("${HOST", Err("invalid")),
+            ("${}", Err("invalid")),
+            ("${UNSET}", Err("unset")),
+        ];
+        for (value, expected) in cases.iter() {
+            let res = resolve(OptResolver::new().env(), &[("key", value)]);
+            match (res, expected) {
+                (Ok(opts), Ok(expected)) => {
+                    assert_eq!(opts.get("key"), Some(*expected), "{}", value)
+                }
+                (Err(OptError::InvalidReference { .. }), Err("invalid")) => {}
+                (Err(OptError::UnsetVariable { variable, .. }), Err("unset")) => {
+                    assert_eq!(variable, "UNSET")
+                }
+                (res, expected) => panic!("{}: got {:?}, expected {:?}", value, res, expected),
+            }
+        }
+        let err = resolve(OptResolver::new().env(), &[("db_host", "${UNSET}")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "option db_host: environment variable UNSET is not set"
+        );
+    }
+
+    #[test]
+    fn files_are_read_and_trimmed() {
+        let secret = TempFile::new("secret", "hunter2\n");
+        let opts = resolve(
+            OptResolver::new().files(),
+            &[("db_password_file", secret.path()), ("user", "mqtt")],
+        )
+        .unwrap();
+        assert_eq!(opts.get("db_password"), Some("hunter2"));
+        assert_eq!(opts.as_opt().get("user"), Some(&"mqtt"));
+
+        // the path itself can come from the environment
+        let dir = secret.0.parent().unwrap().to_str().unwrap().to_string();
+        let opts = OptResolver::new()
+            .files()
+            .env()
+            .resolve_with(
+                &[("db_password_file", "${DIR}/secret")]
+                    .iter()
+                    .copied()
+                    .collect(),
+                |name| {
+                    if name == "DIR" {
+                        Some(dir.clone())
+                    } else {
+                        None
+                    }
+                },
+            )
+            .unwrap();
+        assert_eq!(opts.get("db_password"), Some("hunter2"));
+
+        let err = resolve(
+            OptResolver::new().files(),
+            &[("db_password_file", secret.path()), ("db_password", "x")],
+        )
+        .unwrap_err();
+        assert!(matches!(err, OptError::Conflict { .. }));
+    }
+
+    #[test]
+    fn typed_values() {
+        assert_eq!(bool::parse_opt("yes"), Some(true));
+        assert_eq!(bool::parse_opt("off"), Some(false));
+        assert_eq!(bool::parse_opt("maybe"), None);
+        assert_eq!(u16::parse_opt(" 1883 "), Some(1883));
+        assert_eq!(u16::parse_opt("-1"), None);
+        assert_eq!(i32::parse_opt("-1"), Some(-1));
+        assert_eq!(f64::parse_opt("0.5"), Some(0.5));
+        assert_eq!(Duration::parse_opt("90"), Some(Duration::from_secs(90)));
+        assert_eq!(Duration::parse_opt("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(
+            Duration::parse_opt("500ms"),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(Duration::parse_opt("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(Duration::parse_opt("2h"), Some(Duration::from_secs(7200)));
+        for invalid in ["", "s", "1.5s", "-1s", "10 days"].iter() {
+            assert_eq!(Duration::parse_opt(invalid), None, "{}", invalid);
+        }
+        assert_eq!(Duration::parse_opt(&format!("{}h", u64::MAX)), None);
+    }
+
+    #[test]
+    fn typed_options() {
+        let opts: MosquittoOpt = [("port", "1883"), ("timeout", "soon")]
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(opt::<u16>(&opts, "port").unwrap(), 1883);
+        assert_eq!(opt::<Option<u16>>(&opts, "port").unwrap(), Some(1883));
+        assert_eq!(opt::<Option<String>>(&opts, "host").unwrap(), None);
+        assert_eq!(opt_or::<u32>(&opts, "retries", "3").unwrap(), 3);
+        assert_eq!(opt_or::<u16>(&opts, "port", "8883").unwrap(), 1883);
+
+        let err = opt::<String>(&opts, "host").unwrap_err();
+        assert!(matches!(err, OptError::Missing { ref key } if key == "host"));
+        assert_eq!(err.to_string(), "option host is required");
+
+        let err = opt::<Duration>(&opts, "timeout").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "option timeout: expected a duration like 30s, 500ms, 5m or 2h, got \"soon\""
+        );
+        let err = opt::<Option<u8>>(&opts, "port").unwrap_err();
+        assert!(matches!(err, OptError::Invalid { ref key, .. } if key == "port"));
+    }
+
+    #[test]
+    fn unreadable_files_are_errors() {
+        let err = resolve(
+            OptResolver::new().files(),
+            &[("key_file", "/nonexistent/key.pem")],
+        )
+        .unwrap_err();
+        match &err {
+            OptError::File { key, error, .. } => {
+                assert_eq!(key, "key_file");
+                assert_eq!(error.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("expected a file error, got {:?}", other),
+        }
+        assert!(err
+            .to_string()
+            .starts_with("option key_file: can't read /nonexistent/key.pem: "));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let secret = TempFile::new("unreadable", "hunter2");
+            fs::set_permissions(&secret.0, fs::Permissions::from_mode(0o000)).unwrap();
+            // root reads the file regardless of its mode
+            if fs::read(&secret.0).is_err() {
+                let err = resolve(
+                    OptResolver::new().files(),
+                    &[("db_password_file", secret.path())],
+                )
+                .unwrap_err();
+                assert!(
+                    matches!(err, OptError::File { ref error, .. } if error.kind() == io::ErrorKind::PermissionDenied)
+                );
+            }
+        }
+    }
+}
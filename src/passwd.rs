@@ -0,0 +1,314 @@
+// Password hashes in the format written by mosquitto_passwd, enabled with the "passwd" feature.
+//
+// mosquitto_passwd writes lines of `username:hash` where the hash is one of
+//     $7$<iterations>$<base64 salt>$<base64 hash>   PBKDF2-HMAC-SHA512, mosquitto 2.0 and later
+//     $6$<base64 salt>$<base64 hash>                SHA512 of the password followed by the salt
+// Salt and hash are standard base64 with padding, see password_mosq.c in the mosquitto sources.
+// PasswordFile reads a whole file, so a plugin can take over the password_file of the broker:
+//
+//     let passwords = PasswordFile::load("/etc/mosquitto/passwd")?;
+//     if passwords.verify(username, password) { ... }
+use crate::Error;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha512};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Iterations mosquitto_passwd uses for new $7$ hashes
+pub const DEFAULT_ITERATIONS: u32 = 101;
+
+const SALT_LEN: usize = 12;
+const HASH_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordHash {
+    /// `$6$`, a single round of SHA512
+    Sha512 { salt: Vec<u8>, hash: Vec<u8> },
+    /// `$7$`, PBKDF2-HMAC-SHA512
+    Pbkdf2Sha512 {
+        iterations: u32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+/// Parses the hash part of a password file line. Returns Err(Error::Inval) for anything that
+/// isn't a $6$ or $7$ hash.
+pub fn parse_hash(s: &str) -> Result<PasswordHash, Error> {
+    let parts: Vec<&str> = s.split('$').collect();
+    match parts.as_slice() {
+        ["", "6", salt, hash] => Ok(PasswordHash::Sha512 {
+            salt: decode(salt)?,
+            hash: decode(hash)?,
+        }),
+        ["", "7", iterations, salt, hash] => {
+            let iterations = iterations.parse().map_err(|_| Error::Inval)?;
+            if iterations == 0 {
+                return Err(Error::Inval);
+            }
+            Ok(PasswordHash::Pbkdf2Sha512 {
+                iterations,
+                salt: decode(salt)?,
+                hash: decode(hash)?,
+            })
+        }
+        _ => Err(Error::Inval),
+    }
+}
+
+/// Checks the password against the hash. The hashes are compared in constant time.
+pub fn verify(password: &str, hash: &PasswordHash) -> bool {
+    match hash {
+        PasswordHash::Sha512 { salt, hash } => {
+            let computed = sha512(password.as_bytes(), salt);
+            constant_time_eq(&computed, hash)
+        }
+        PasswordHash::Pbkdf2Sha512 {
+            iterations,
+            salt,
+            hash,
+        } => {
+            let computed = pbkdf2_sha512(password.as_bytes(), salt, *iterations);
+            constant_time_eq(&computed, hash)
+        }
+    }
+}
+
+/// Hashes the password with a random salt, in the $7$ format mosquitto_passwd writes
+pub fn hash_password(password: &str, iterations: u32) -> Result<String, Error> {
+    let mut salt = [0u8; SALT_LEN];
+    getrandom::getrandom(&mut salt).map_err(|_| Error::Unknown)?;
+    Ok(hash_password_with_salt(password, iterations, &salt))
+}
+
+/// Same as hash_password with the salt supplied by the caller
+pub fn hash_password_with_salt(password: &str, iterations: u32, salt: &[u8]) -> String {
+    let iterations = iterations.max(1);
+    let hash = pbkdf2_sha512(password.as_bytes(), salt, iterations);
+    format!("$7${}${}${}", iterations, STANDARD.encode(salt), STANDARD.encode(hash))
+}
+
+impl std::fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PasswordHash::Sha512 { salt, hash } => {
+                write!(f, "$6${}${}", STANDARD.encode(salt), STANDARD.encode(hash))
+            }
+            PasswordHash::Pbkdf2Sha512 {
+                iterations,
+                salt,
+                hash,
+            } => write!(
+                f,
+                "$7${}${}${}",
+                iterations,
+                STANDARD.encode(salt),
+                STANDARD.encode(hash)
+            ),
+        }
+    }
+}
+
+/// Why a password file couldn't be read
+#[derive(Debug)]
+pub enum PasswordFileError {
+    Io(io::Error),
+    /// Line number `line`, counted from 1, isn't `username:hash` with a $6$ or $7$ hash. The line
+    /// itself isn't kept, it may contain a hash.
+    Invalid {
+        line: usize,
+    },
+}
+
+impl fmt::Display for PasswordFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PasswordFileError::Io(e) => write!(f, "can't read the password file: {}", e),
+            PasswordFileError::Invalid { line } => {
+                write!(f, "invalid line {} in the password file", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PasswordFileError {}
+
+/// The users of a password file written by mosquitto_passwd
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PasswordFile {
+    users: HashMap<String, PasswordHash>,
+}
+
+impl PasswordFile {
+    /// Parses lines of `username:hash`, empty lines and lines starting with # are skipped
+    pub fn parse(contents: &str) -> Result<PasswordFile, PasswordFileError> {
+        let mut users = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || PasswordFileError::Invalid { line: i + 1 };
+            let (username, hash) = line.split_once(':').ok_or_else(invalid)?;
+            if username.is_empty() {
+                return Err(invalid());
+            }
+            users.insert(
+                username.to_string(),
+                parse_hash(hash).map_err(|_| invalid())?,
+            );
+        }
+        Ok(PasswordFile { users })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<PasswordFile, PasswordFileError> {
+        let contents = std::fs::read_to_string(path).map_err(PasswordFileError::Io)?;
+        PasswordFile::parse(&contents)
+    }
+
+    /// Whether the user is in the file and the password matches. A password is hashed for
+    /// unknown users too, so the time taken doesn't tell which users exist.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => verify(password, hash),
+            None => {
+                let _ = pbkdf2_sha512(password.as_bytes(), &[0; SALT_LEN], DEFAULT_ITERATIONS);
+                false
+            }
+        }
+    }
+
+    pub fn get(&self, username: &str) -> Option<&PasswordHash> {
+        self.users.get(username)
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    /// The usernames, in no particular order
+    pub fn usernames(&self) -> impl Iterator<Item = &str> {
+        self.users.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+    match STANDARD.decode(s) {
+        Ok(bytes) if !bytes.is_empty() => Ok(bytes),
+        _ => Err(Error::Inval),
+    }
+}
+
+fn sha512(password: &[u8], salt: &[u8]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha512::new();
+    hasher.update(password);
+    hasher.update(salt);
+    let mut hash = [0u8; HASH_LEN];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+fn pbkdf2_sha512(password: &[u8], salt: &[u8], iterations: u32) -> [u8; HASH_LEN] {
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, &mut hash);
+    hash
+}
+
+// Looks at every byte regardless of where the first difference is, so the time taken doesn't
+// tell an attacker how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Salt 01..0c and aa * 12, hashes computed with Python's hashlib (pbkdf2_hmac and sha512)
+    // following password_mosq.c, independently of this implementation.
+    const PBKDF2_101: &str = "$7$101$AQIDBAUGBwgJCgsM$vi3gtTBBsyA0ndLJ0V/YApuRViDA9ghC166cp1AjnIEUVevnLN/JRt4mV1VV33uV0F7F5qO0R1SqlyHqKHWWdg==";
+    const PBKDF2_1000: &str = "$7$1000$AQIDBAUGBwgJCgsM$TZESqSk2LL+Fbyr2JWKvbD4a3ErLZv+11yiTRqY370NIsau62tlKfrRNpWlKXBmoX2qeBimY6rHirGo9HREfDg==";
+    const SHA512: &str = "$6$qqqqqqqqqqqqqqqq$AX/R8g61YZ3ybX5jPWA+yN2TOzhVuNLW5CY7hPTcfVLLSeQ1bSNnDWcEzW9DqD7l+4GgAijYY1vRUaMjeufmAA==";
+
+    #[test]
+    fn verifies_known_hashes() {
+        assert!(verify("password", &parse_hash(PBKDF2_101).unwrap()));
+        assert!(!verify("Password", &parse_hash(PBKDF2_101).unwrap()));
+        assert!(verify("mosquitto", &parse_hash(PBKDF2_1000).unwrap()));
+        assert!(verify("password", &parse_hash(SHA512).unwrap()));
+        assert!(!verify("", &parse_hash(SHA512).unwrap()));
+    }
+
+    #[test]
+    fn hashes_in_the_mosquitto_passwd_format() {
+        let salt: Vec<u8> = (1..=12).collect();
+        assert_eq!(hash_password_with_salt("password", 101, &salt), PBKDF2_101);
+
+        let hash = hash_password("secret", DEFAULT_ITERATIONS).unwrap();
+        assert!(hash.starts_with("$7$101$"));
+        let parsed = parse_hash(&hash).unwrap();
+        assert!(verify("secret", &parsed));
+        assert_eq!(parsed.to_string(), hash);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        for hash in &[
+            "",
+            "password",
+            "$5$c2FsdA==$aGFzaA==",
+            "$6$c2FsdA==",
+            "$6$$aGFzaA==",
+            "$6$not base64$aGFzaA==",
+            "$7$0$c2FsdA==$aGFzaA==",
+            "$7$many$c2FsdA==$aGFzaA==",
+            "$7$101$c2FsdA==$aGFzaA==$",
+        ] {
+            assert_eq!(parse_hash(hash), Err(Error::Inval), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn reads_password_files() {
+        let contents = format!(
+            "# migrated from the broker\nalice:{}\r\n\nbob:{}\n",
+            PBKDF2_101, SHA512
+        );
+        let passwords = PasswordFile::parse(&contents).unwrap();
+        assert_eq!(passwords.len(), 2);
+        assert!(passwords.verify("alice", "password"));
+        assert!(passwords.verify("bob", "password"));
+        assert!(!passwords.verify("alice", "mosquitto"));
+        assert!(!passwords.verify("carol", "password"));
+        assert!(passwords.contains("bob") && !passwords.contains("carol"));
+        assert_eq!(
+            passwords.get("alice"),
+            Some(&parse_hash(PBKDF2_101).unwrap())
+        );
+
+        let error = PasswordFile::parse(&format!("alice:{}\nbob:$5$x\n", SHA512)).unwrap_err();
+        assert_eq!(error.to_string(), "invalid line 2 in the password file");
+        for line in &["alice", ":$6$qqqq$AX==", "alice:"] {
+            assert!(PasswordFile::parse(line).is_err(), "{}", line);
+        }
+        assert!(matches!(
+            PasswordFile::load("/nonexistent/passwd"),
+            Err(PasswordFileError::Io(_))
+        ));
+    }
+}
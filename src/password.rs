@@ -0,0 +1,272 @@
+// Hashing and verifying passwords with argon2id or bcrypt, enabled with the "password" feature,
+// for plugins keeping their own users rather than a mosquitto password file (see passwd):
+//
+//     let stored = Hasher::argon2id().hash("secret")?;       // when creating the user
+//     password::verify("secret", &stored)                     // when they log in
+//
+// Hashes are the usual strings, `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>` in the PHC format
+// and `$2b$12$<salt and hash>` for bcrypt, so they can be shared with other software. The salt
+// and the parameters are part of the hash, verify reads them from there. The defaults follow the
+// OWASP recommendations. bcrypt only looks at the first 72 bytes of a password.
+//
+// A PasswordStore looks up the hash of a user, check then answers username_password:
+//
+//     fn username_password(&mut self, _client: &dyn MosquittoClientContext, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+//         self.users.check(username, password)
+//     }
+use crate::{AuthDecision, Error};
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// bcrypt cost used unless another is set
+pub const DEFAULT_BCRYPT_COST: u32 = 12;
+
+const SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+/// Creates hashes with one algorithm and its parameters
+#[derive(Debug, Clone)]
+pub struct Hasher {
+    algorithm: Algorithm,
+    argon2: argon2::Params,
+    bcrypt_cost: u32,
+}
+
+impl Hasher {
+    /// argon2id with 19 MiB of memory, 2 iterations and 1 lane
+    pub fn argon2id() -> Hasher {
+        Hasher {
+            algorithm: Algorithm::Argon2id,
+            argon2: argon2::Params::default(),
+            bcrypt_cost: DEFAULT_BCRYPT_COST,
+        }
+    }
+
+    /// bcrypt with cost 12
+    pub fn bcrypt() -> Hasher {
+        Hasher {
+            algorithm: Algorithm::Bcrypt,
+            ..Hasher::argon2id()
+        }
+    }
+
+    /// Sets the argon2id memory in KiB, iterations and lanes. Err(Error::Inval) for values argon2
+    /// doesn't accept, like less than 8 KiB of memory per lane.
+    pub fn with_argon2_params(
+        mut self,
+        memory_kib: u32,
+        iterations: u32,
+        lanes: u32,
+    ) -> Result<Self, Error> {
+        self.argon2 =
+            argon2::Params::new(memory_kib, iterations, lanes, None).map_err(|_| Error::Inval)?;
+        Ok(self)
+    }
+
+    /// Sets the bcrypt cost, each step doubles the work. Err(Error::Inval) outside of 4 to 31.
+    pub fn with_bcrypt_cost(mut self, cost: u32) -> Result<Self, Error> {
+        if !(4..=31).contains(&cost) {
+            return Err(Error::Inval);
+        }
+        self.bcrypt_cost = cost;
+        Ok(self)
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Hashes the password with a random salt
+    pub fn hash(&self, password: &str) -> Result<String, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|_| Error::Unknown)?;
+        self.hash_with_salt(password, &salt)
+    }
+
+    /// Same as hash with the salt supplied by the caller
+    pub fn hash_with_salt(&self, password: &str, salt: &[u8; SALT_LEN]) -> Result<String, Error> {
+        match self.algorithm {
+            Algorithm::Argon2id => {
+                let salt = SaltString::encode_b64(salt).map_err(|_| Error::Inval)?;
+                let argon2 = Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    self.argon2.clone(),
+                );
+                let hash = argon2
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|_| Error::Unknown)?;
+                Ok(hash.to_string())
+            }
+            Algorithm::Bcrypt => {
+                let hash = bcrypt::hash_with_salt(password, self.bcrypt_cost, *salt)
+                    .map_err(|_| Error::Inval)?;
+                Ok(hash.format_for_version(bcrypt::Version::TwoB))
+            }
+        }
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Hasher::argon2id()
+    }
+}
+
+/// The algorithm of a hash made by Hasher or other software using the same formats
+pub fn algorithm(hash: &str) -> Option<Algorithm> {
+    if hash.starts_with("$argon2id$") {
+        Some(Algorithm::Argon2id)
+    } else if ["$2a$", "$2b$", "$2y$"].iter().any(|v| hash.starts_with(v)) {
+        Some(Algorithm::Bcrypt)
+    } else {
+        None
+    }
+}
+
+/// Checks the password against an argon2id or bcrypt hash, false for anything else. The hashes
+/// are compared in constant time.
+pub fn verify(password: &str, hash: &str) -> bool {
+    match algorithm(hash) {
+        Some(Algorithm::Argon2id) => match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        },
+        Some(Algorithm::Bcrypt) => bcrypt::verify(password, hash).unwrap_or(false),
+        None => false,
+    }
+}
+
+// Verified against for unknown users, so they take as long as known ones
+fn unknown_user_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        Hasher::argon2id()
+            .hash_with_salt("", &[0; SALT_LEN])
+            .unwrap_or_default()
+    })
+}
+
+/// Where the password hashes of the users are kept
+pub trait PasswordStore {
+    /// The hash of the user's password, None for unknown users. Err when the store couldn't be
+    /// asked, e.g. because a database is down.
+    fn password_hash(&self, username: &str) -> Result<Option<String>, Error>;
+
+    /// Decides a login: allowed when the user is known and the password matches. Clients
+    /// without a username or password are denied. Unknown users take as long as wrong passwords,
+    /// so the time taken doesn't tell which users exist.
+    fn check(&self, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+        let (username, password) = match (username, password) {
+            (Some(username), Some(password)) => (username, password),
+            _ => return AuthDecision::deny("username and password required"),
+        };
+        match self.password_hash(username) {
+            Ok(Some(hash)) if verify(password, &hash) => AuthDecision::Allow,
+            Ok(Some(_)) => AuthDecision::deny("wrong password"),
+            Ok(None) => {
+                verify(password, unknown_user_hash());
+                AuthDecision::deny("unknown user")
+            }
+            Err(e) => AuthDecision::Error(e),
+        }
+    }
+}
+
+/// Usernames and their hashes, e.g. read from the plugin options
+impl PasswordStore for HashMap<String, String> {
+    fn password_hash(&self, username: &str) -> Result<Option<String>, Error> {
+        Ok(self.get(username).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters, the tests are about the format and not the strength
+    fn argon2id() -> Hasher {
+        Hasher::argon2id().with_argon2_params(8, 1, 1).unwrap()
+    }
+
+    fn bcrypt() -> Hasher {
+        Hasher::bcrypt().with_bcrypt_cost(4).unwrap()
+    }
+
+    #[test]
+    fn hashes_verify() {
+        for hasher in [argon2id(), bcrypt()] {
+            let hash = hasher.hash("secret").unwrap();
+            assert_eq!(algorithm(&hash), Some(hasher.algorithm()));
+            assert!(verify("secret", &hash), "{}", hash);
+            assert!(!verify("Secret", &hash), "{}", hash);
+            // a new salt every time
+            assert_ne!(hasher.hash("secret").unwrap(), hash);
+        }
+        let salt = [7; SALT_LEN];
+        let hash = argon2id().hash_with_salt("secret", &salt).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=8,t=1,p=1$BwcHBwcHBwcHBwcHBwcHBw$"));
+        assert_eq!(argon2id().hash_with_salt("secret", &salt).unwrap(), hash);
+        assert!(bcrypt()
+            .hash_with_salt("secret", &salt)
+            .unwrap()
+            .starts_with("$2b$04$"));
+    }
+
+    #[test]
+    fn other_hashes_dont_verify() {
+        for hash in [
+            "",
+            "secret",
+            "$6$c2FsdA==$aGFzaA==",
+            "$argon2id$v=19$",
+            "$2b$04$short",
+        ] {
+            assert!(!verify("secret", hash), "{}", hash);
+        }
+        assert!(Hasher::bcrypt().with_bcrypt_cost(3).is_err());
+        assert!(Hasher::argon2id().with_argon2_params(1, 1, 1).is_err());
+    }
+
+    #[test]
+    fn stores_decide_logins() {
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), bcrypt().hash("secret").unwrap());
+        assert_eq!(
+            users.check(Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            users.check(Some("alice"), Some("guess")),
+            AuthDecision::deny("wrong password")
+        );
+        assert_eq!(
+            users.check(Some("bob"), Some("secret")),
+            AuthDecision::deny("unknown user")
+        );
+        assert_eq!(
+            users.check(Some("alice"), None),
+            AuthDecision::deny("username and password required")
+        );
+
+        struct Down;
+        impl PasswordStore for Down {
+            fn password_hash(&self, _username: &str) -> Result<Option<String>, Error> {
+                Err(Error::Unknown)
+            }
+        }
+        assert_eq!(
+            Down.check(Some("alice"), Some("secret")),
+            AuthDecision::Error(Error::Unknown)
+        );
+    }
+}
@@ -0,0 +1,624 @@
+// Keeping the broker state in a database of the plugin, like RocksDB or SQLite, instead of
+// mosquitto.db, with the persistence events of mosquitto 2.1:
+//
+//     impl PersistencePlugin for Store {
+//         fn restore(&mut self, restore: &mut Restore) -> Result<Success, Error> {
+//             for client in self.db.clients()? {
+//                 restore.client(&client)?;
+//             }
+//             ...
+//         }
+//         fn client_add(&mut self, client: &PersistedClient) -> Result<Success, Error> {
+//             self.db.put_client(client)
+//         }
+//     }
+//     create_dynamic_library!(Store, persistence);
+//
+// The broker needs `persistence true` in its config. At startup it asks the plugin to restore
+// what it stored, the records are handed back through Restore. Afterwards every change to
+// clients, subscriptions, queued messages and retained messages is reported. A message is stored
+// once as a BaseMessage, the ClientMessages queued for clients and retained topics refer to it
+// by store id, so base messages have to be restored before the records referring to them.
+use crate::dynlib::{event, guarded, optional_str, payload, required_str, InternalUserData, PluginInfo};
+use crate::mosquitto_calls::{log_printf, BrokerPayload};
+use crate::mosquitto_dev::*;
+use crate::properties::{MessageProperties, Properties};
+use crate::{dynlib, to_result, Error, MosquittoPlugin, Subscription, Success};
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::marker::PhantomData;
+use std::os::raw::{c_char, c_int, c_void};
+
+/// A client session as the broker stores it. The will of the client isn't persisted, a client
+/// restored from the database has none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistedClient {
+    pub client_id: String,
+    pub username: Option<String>,
+    /// The MQTT v5 extended authentication method the client connected with
+    pub auth_method: Option<String>,
+    /// When the session expires, in seconds since the epoch, 0 while the client is connected
+    pub session_expiry_time: i64,
+    pub session_expiry_interval: u32,
+    pub will_delay_interval: u32,
+    pub max_packet_size: u32,
+    /// The port of the listener the client connected to
+    pub listener_port: u16,
+    pub max_qos: u8,
+    pub retain_available: bool,
+}
+
+/// A message kept by the broker, referred to by store_id from the client messages and retained
+/// topics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaseMessage {
+    pub store_id: u64,
+    /// When the message expires, in seconds since the epoch, 0 for never
+    pub expiry_time: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// The client that published the message, None for messages from the broker or a plugin
+    pub source_id: Option<String>,
+    pub source_username: Option<String>,
+    pub source_mid: u16,
+    pub source_port: u16,
+    pub qos: u8,
+    pub retain: bool,
+    pub properties: Properties,
+}
+
+/// A message queued for a client, in either direction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMessage {
+    pub client_id: String,
+    /// Orders the messages of a client
+    pub cmsg_id: u64,
+    /// The BaseMessage this is a copy of
+    pub store_id: u64,
+    pub subscription_identifier: u32,
+    pub mid: u16,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    /// 0 for messages to the client, 1 for messages from it
+    pub direction: u8,
+    /// Where the message is in the QoS flow, the mosquitto mosquitto_msg_state
+    pub state: u8,
+}
+
+/// A subscription of a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistedSubscription {
+    pub client_id: String,
+    pub subscription: Subscription,
+}
+
+/// Hands the stored records back to the broker, only available in PersistencePlugin::restore.
+/// Errors are what the broker returned, a restore that fails stops the broker from starting.
+pub struct Restore {
+    // The broker functions may only be called from the broker thread
+    _broker_thread: PhantomData<*const ()>,
+}
+
+impl Restore {
+    pub fn client(&mut self, client: &PersistedClient) -> Result<Success, Error> {
+        let client_id = cstring(&client.client_id)?;
+        let username = client.username.as_deref().map(cstring).transpose()?;
+        let auth_method = client.auth_method.as_deref().map(cstring).transpose()?;
+        let mut raw = mosquitto_client {
+            clientid: client_id.as_ptr() as *mut c_char,
+            username: username.as_ref().map_or(std::ptr::null_mut(), |u| u.as_ptr() as *mut c_char),
+            auth_method: auth_method.as_ref().map_or(std::ptr::null_mut(), |m| m.as_ptr() as *mut c_char),
+            will: std::ptr::null_mut(),
+            will_delay_time: 0,
+            session_expiry_time: client.session_expiry_time as time_t,
+            will_delay_interval: client.will_delay_interval,
+            session_expiry_interval: client.session_expiry_interval,
+            max_packet_size: client.max_packet_size,
+            listener_port: client.listener_port,
+            max_qos: client.max_qos,
+            retain_available: client.retain_available,
+            padding: [0; 6],
+            future2: [std::ptr::null_mut(); 8],
+        };
+        to_result(unsafe { mosquitto_persist_client_add(&mut raw) })
+    }
+
+    /// The topic, payload, source and properties are copied into buffers the broker takes over
+    pub fn base_msg(&mut self, msg: &BaseMessage) -> Result<Success, Error> {
+        let payloadlen = u32::try_from(msg.payload.len()).map_err(|_| Error::PayloadSize)?;
+        let topic = broker_string(&msg.topic)?;
+        let payload = BrokerPayload::copy_from(&msg.payload);
+        if !payload.is_allocated() {
+            return Err(Error::NoMem);
+        }
+        let source_id = msg.source_id.as_deref().map(broker_string).transpose()?;
+        let source_username = msg.source_username.as_deref().map(broker_string).transpose()?;
+        let mut properties = msg.properties.to_list()?;
+        let mut raw = mosquitto_base_msg {
+            store_id: msg.store_id,
+            expiry_time: msg.expiry_time,
+            topic: topic.as_ptr() as *mut c_char,
+            payload: payload.as_ptr(),
+            source_id: source_id.as_ref().map_or(std::ptr::null_mut(), |s| s.as_ptr() as *mut c_char),
+            source_username: source_username.as_ref().map_or(std::ptr::null_mut(), |s| s.as_ptr() as *mut c_char),
+            properties: properties.ptr,
+            payloadlen,
+            source_mid: msg.source_mid,
+            source_port: msg.source_port,
+            qos: msg.qos,
+            retain: msg.retain,
+            padding: [0; 6],
+            future2: [std::ptr::null_mut(); 8],
+        };
+        let success = to_result(unsafe { mosquitto_persist_base_msg_add(&mut raw) })?;
+        // The broker frees them along with the message
+        topic.into_raw();
+        payload.into_raw();
+        for buffer in source_id.into_iter().chain(source_username) {
+            buffer.into_raw();
+        }
+        properties.ptr = std::ptr::null_mut();
+        Ok(success)
+    }
+
+    /// The topic has to be restored after the base message it refers to
+    pub fn retain(&mut self, topic: &str, store_id: u64) -> Result<Success, Error> {
+        let topic = cstring(topic)?;
+        to_result(unsafe { mosquitto_persist_retain_msg_set(topic.as_ptr(), store_id) })
+    }
+
+    /// The client has to be restored first
+    pub fn subscription(&mut self, subscription: &PersistedSubscription) -> Result<Success, Error> {
+        let client_id = cstring(&subscription.client_id)?;
+        let topic_filter = cstring(&subscription.subscription.topic_filter)?;
+        let raw = mosquitto_subscription {
+            clientid: client_id.as_ptr() as *mut c_char,
+            topic_filter: topic_filter.as_ptr() as *mut c_char,
+            properties: std::ptr::null_mut(),
+            identifier: subscription.subscription.identifier,
+            options: subscription.subscription.options(),
+            padding: [0; 3],
+            future2: [std::ptr::null_mut(); 8],
+        };
+        to_result(unsafe { mosquitto_subscription_add(&raw) })
+    }
+
+    /// The client and the base message have to be restored first
+    pub fn client_msg(&mut self, msg: &ClientMessage) -> Result<Success, Error> {
+        let client_id = cstring(&msg.client_id)?;
+        let mut raw = mosquitto_client_msg {
+            clientid: client_id.as_ptr(),
+            cmsg_id: msg.cmsg_id,
+            store_id: msg.store_id,
+            subscription_identifier: msg.subscription_identifier,
+            mid: msg.mid,
+            qos: msg.qos,
+            retain: msg.retain,
+            dup: msg.dup as u8,
+            direction: msg.direction,
+            state: msg.state,
+            padding: [0; 5],
+            future2: [std::ptr::null_mut(); 8],
+        };
+        to_result(unsafe { mosquitto_persist_client_msg_add(&mut raw) })
+    }
+}
+
+fn cstring(s: &str) -> Result<CString, Error> {
+    CString::new(s).map_err(|_| Error::Inval)
+}
+
+// A NUL terminated copy allocated with mosquitto_malloc, for strings the broker takes over
+fn broker_string(s: &str) -> Result<BrokerPayload, Error> {
+    let s = cstring(s)?;
+    let copy = BrokerPayload::copy_from(s.as_bytes_with_nul());
+    if copy.is_allocated() {
+        Ok(copy)
+    } else {
+        Err(Error::NoMem)
+    }
+}
+
+/// The storage side of a plugin registered with `create_dynamic_library!(Type, persistence)` or
+/// `#[mosquitto_plugin(persistence)]`. Every method defaults to doing nothing, errors are logged
+/// and returned to the broker. Needs mosquitto 2.1.
+#[allow(unused)]
+pub trait PersistencePlugin: MosquittoPlugin {
+    /// Called once at startup, before clients connect
+    fn restore(&mut self, restore: &mut Restore) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn base_msg_add(&mut self, msg: &BaseMessage) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn base_msg_delete(&mut self, store_id: u64) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn retain_msg_set(&mut self, topic: &str, store_id: u64) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn retain_msg_delete(&mut self, topic: &str) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn client_add(&mut self, client: &PersistedClient) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn client_update(&mut self, client: &PersistedClient) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    /// The client session ended, its subscriptions and messages are deleted separately
+    fn client_delete(&mut self, client_id: &str) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn subscription_add(&mut self, subscription: &PersistedSubscription) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn subscription_delete(&mut self, client_id: &str, topic_filter: &str) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn client_msg_add(&mut self, msg: &ClientMessage) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    fn client_msg_delete(&mut self, msg: &ClientMessage) -> Result<Success, Error> {
+        Ok(Success)
+    }
+
+    /// The state or mid of a queued message changed
+    fn client_msg_update(&mut self, msg: &ClientMessage) -> Result<Success, Error> {
+        Ok(Success)
+    }
+}
+
+const FAILED: c_int = Error::Unknown as c_int;
+
+// The extern "C" function registered for a persistence event. $body gets the plugin and the
+// event, None from it means the event data was malformed.
+macro_rules! persist_event {
+    ($name:ident, $callback:literal, $event:ty, |$plugin:ident, $data:ident| $body:expr) => {
+        extern "C" fn $name<T: PersistencePlugin>(_event: c_int, event_data: *mut c_void, user_data: *mut c_void) -> c_int {
+            guarded::<T>($callback, user_data, FAILED, || {
+                let $data: &mut $event = match unsafe { event($callback, event_data) } {
+                    Some(event_data) => event_data,
+                    None => return FAILED,
+                };
+                let $plugin: &mut T = unsafe { &mut (*(user_data as *mut InternalUserData<T>)).external_user_data };
+                match $body {
+                    Some(Ok(_)) => 0,
+                    Some(Err(e)) => {
+                        log_printf(MOSQ_LOG_ERR, &format!("{}: failed: {}", $callback, e));
+                        e.into()
+                    }
+                    None => FAILED,
+                }
+            })
+        }
+    };
+}
+
+persist_event!(on_restore, "persist_restore", mosquitto_evt_persist_restore, |plugin, _data| Some(
+    plugin.restore(&mut Restore { _broker_thread: PhantomData })
+));
+persist_event!(on_base_msg_add, "persist_base_msg_add", mosquitto_evt_persist_base_msg, |plugin, data| unsafe {
+    base_message("persist_base_msg_add", &data.data)
+}
+.map(|msg| plugin.base_msg_add(&msg)));
+persist_event!(on_base_msg_delete, "persist_base_msg_delete", mosquitto_evt_persist_base_msg, |plugin, data| Some(
+    plugin.base_msg_delete(data.data.store_id)
+));
+persist_event!(on_retain_msg_set, "persist_retain_msg_set", mosquitto_evt_persist_retain_msg, |plugin, data| unsafe {
+    required_str("persist_retain_msg_set", "topic", data.topic)
+}
+.map(|topic| plugin.retain_msg_set(topic, data.store_id)));
+persist_event!(on_retain_msg_delete, "persist_retain_msg_delete", mosquitto_evt_persist_retain_msg, |plugin, data| unsafe {
+    required_str("persist_retain_msg_delete", "topic", data.topic)
+}
+.map(|topic| plugin.retain_msg_delete(topic)));
+persist_event!(on_client_add, "persist_client_add", mosquitto_evt_persist_client, |plugin, data| unsafe {
+    persisted_client("persist_client_add", &data.data)
+}
+.map(|client| plugin.client_add(&client)));
+persist_event!(on_client_update, "persist_client_update", mosquitto_evt_persist_client, |plugin, data| unsafe {
+    persisted_client("persist_client_update", &data.data)
+}
+.map(|client| plugin.client_update(&client)));
+persist_event!(on_client_delete, "persist_client_delete", mosquitto_evt_persist_client, |plugin, data| unsafe {
+    required_str("persist_client_delete", "client id", data.data.clientid)
+}
+.map(|client_id| plugin.client_delete(client_id)));
+persist_event!(on_subscription_add, "persist_subscription_add", mosquitto_evt_persist_subscription, |plugin, data| unsafe {
+    persisted_subscription("persist_subscription_add", &data.data)
+}
+.map(|subscription| plugin.subscription_add(&subscription)));
+persist_event!(on_subscription_delete, "persist_subscription_delete", mosquitto_evt_persist_subscription, |plugin, data| unsafe {
+    persisted_subscription("persist_subscription_delete", &data.data)
+}
+.map(|s| plugin.subscription_delete(&s.client_id, &s.subscription.topic_filter)));
+persist_event!(on_client_msg_add, "persist_client_msg_add", mosquitto_evt_persist_client_msg, |plugin, data| unsafe {
+    client_message("persist_client_msg_add", &data.data)
+}
+.map(|msg| plugin.client_msg_add(&msg)));
+persist_event!(on_client_msg_delete, "persist_client_msg_delete", mosquitto_evt_persist_client_msg, |plugin, data| unsafe {
+    client_message("persist_client_msg_delete", &data.data)
+}
+.map(|msg| plugin.client_msg_delete(&msg)));
+persist_event!(on_client_msg_update, "persist_client_msg_update", mosquitto_evt_persist_client_msg, |plugin, data| unsafe {
+    client_message("persist_client_msg_update", &data.data)
+}
+.map(|msg| plugin.client_msg_update(&msg)));
+
+// time_t is 32 bit on some targets
+#[allow(clippy::unnecessary_cast)]
+unsafe fn persisted_client(callback: &str, raw: &mosquitto_client) -> Option<PersistedClient> {
+    Some(PersistedClient {
+        client_id: unsafe { required_str(callback, "client id", raw.clientid) }?.to_string(),
+        username: unsafe { optional_str(callback, "username", raw.username) }.ok()?.map(str::to_string),
+        auth_method: unsafe { optional_str(callback, "auth method", raw.auth_method) }.ok()?.map(str::to_string),
+        session_expiry_time: raw.session_expiry_time as i64,
+        session_expiry_interval: raw.session_expiry_interval,
+        will_delay_interval: raw.will_delay_interval,
+        max_packet_size: raw.max_packet_size,
+        listener_port: raw.listener_port,
+        max_qos: raw.max_qos,
+        retain_available: raw.retain_available,
+    })
+}
+
+unsafe fn base_message(callback: &str, raw: &mosquitto_base_msg) -> Option<BaseMessage> {
+    Some(BaseMessage {
+        store_id: raw.store_id,
+        expiry_time: raw.expiry_time,
+        topic: unsafe { required_str(callback, "topic", raw.topic) }?.to_string(),
+        payload: unsafe { payload(callback, raw.payload, raw.payloadlen as usize) }?.to_vec(),
+        source_id: unsafe { optional_str(callback, "source id", raw.source_id) }.ok()?.map(str::to_string),
+        source_username: unsafe { optional_str(callback, "source username", raw.source_username) }.ok()?.map(str::to_string),
+        source_mid: raw.source_mid,
+        source_port: raw.source_port,
+        qos: raw.qos,
+        retain: raw.retain,
+        properties: unsafe { MessageProperties::from_ptr(raw.properties) }.to_properties(),
+    })
+}
+
+unsafe fn client_message(callback: &str, raw: &mosquitto_client_msg) -> Option<ClientMessage> {
+    Some(ClientMessage {
+        client_id: unsafe { required_str(callback, "client id", raw.clientid) }?.to_string(),
+        cmsg_id: raw.cmsg_id,
+        store_id: raw.store_id,
+        subscription_identifier: raw.subscription_identifier,
+        mid: raw.mid,
+        qos: raw.qos,
+        retain: raw.retain,
+        dup: raw.dup != 0,
+        direction: raw.direction,
+        state: raw.state,
+    })
+}
+
+unsafe fn persisted_subscription(callback: &str, raw: &mosquitto_subscription) -> Option<PersistedSubscription> {
+    let client_id = unsafe { required_str(callback, "client id", raw.clientid) }?;
+    let topic_filter = unsafe { required_str(callback, "topic filter", raw.topic_filter) }?;
+    Some(PersistedSubscription {
+        client_id: client_id.to_string(),
+        subscription: Subscription::from_options(topic_filter.to_string(), raw.options, raw.identifier),
+    })
+}
+
+type Trampoline = unsafe extern "C" fn(c_int, *mut c_void, *mut c_void) -> c_int;
+
+fn events<T: PersistencePlugin>() -> [(mosquitto_plugin_event, Trampoline); 13] {
+    [
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_RESTORE, on_restore::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_BASE_MSG_ADD, on_base_msg_add::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_BASE_MSG_DELETE, on_base_msg_delete::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_RETAIN_MSG_SET, on_retain_msg_set::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_RETAIN_MSG_DELETE, on_retain_msg_delete::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_ADD, on_client_add::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_DELETE, on_client_delete::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_UPDATE, on_client_update::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_SUBSCRIPTION_ADD, on_subscription_add::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_SUBSCRIPTION_DELETE, on_subscription_delete::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_MSG_ADD, on_client_msg_add::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_MSG_DELETE, on_client_msg_delete::<T>),
+        (mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_MSG_UPDATE, on_client_msg_update::<T>),
+    ]
+}
+
+/// Called from the mosquitto_plugin_init generated by `create_dynamic_library!(Type, persistence)`
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_init<T: PersistencePlugin>(
+    identifier: *mut c_void,
+    user_data: *mut *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+) -> c_int {
+    unsafe { plugin_init_with::<T>(identifier, user_data, opts, opt_count, &dynlib::DEFAULT_PLUGIN_INFO) }
+}
+
+/// Same as dynlib::plugin_init_with, registering the persistence events as well. A broker that
+/// refuses them would lose the state on restart, so the plugin refuses to start.
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_init_with<T: PersistencePlugin>(
+    identifier: *mut c_void,
+    user_data: *mut *mut c_void,
+    opts: *mut mosquitto_opt,
+    opt_count: c_int,
+    info: &PluginInfo,
+) -> c_int {
+    let rc = unsafe { dynlib::plugin_init_with::<T>(identifier, user_data, opts, opt_count, info) };
+    if rc != 0 {
+        return rc;
+    }
+    for (event, trampoline) in events::<T>() {
+        let rc = unsafe { mosquitto_callback_register(identifier as _, event as _, Some(trampoline), std::ptr::null(), *user_data) };
+        if rc != 0 {
+            log_printf(MOSQ_LOG_ERR, &format!("Can't register the persistence event {}, it needs mosquitto 2.1: error {}", event, rc));
+            return rc;
+        }
+    }
+    Success.into()
+}
+
+/// Called from the mosquitto_plugin_cleanup generated by `create_dynamic_library!(Type, persistence)`
+///
+/// # Safety
+/// The arguments have to be the ones mosquitto passed to mosquitto_plugin_cleanup, user_data
+/// being the pointer set by plugin_init.
+#[doc(hidden)]
+pub unsafe fn plugin_cleanup<T: PersistencePlugin>(user_data: *mut c_void, opts: *mut mosquitto_opt, opt_count: c_int) -> c_int {
+    if !user_data.is_null() {
+        let identifier = unsafe { (*(user_data as *mut InternalUserData<T>)).identifier };
+        for (event, trampoline) in events::<T>() {
+            unsafe { mosquitto_callback_unregister(identifier as _, event as _, Some(trampoline), std::ptr::null()) };
+        }
+    }
+    unsafe { dynlib::plugin_cleanup::<T>(user_data, opts, opt_count) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+    use crate::MosquittoOpt;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static STORED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn stored() -> Vec<String> {
+        STORED.with(|s| s.borrow().clone())
+    }
+
+    struct Store;
+
+    impl MosquittoPlugin for Store {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Store
+        }
+    }
+
+    impl PersistencePlugin for Store {
+        fn restore(&mut self, restore: &mut Restore) -> Result<Success, Error> {
+            restore.client(&PersistedClient {
+                client_id: "c1".to_string(),
+                username: Some("alice".to_string()),
+                auth_method: None,
+                session_expiry_time: 0,
+                session_expiry_interval: 3600,
+                will_delay_interval: 0,
+                max_packet_size: 0,
+                listener_port: 1883,
+                max_qos: 2,
+                retain_available: true,
+            })?;
+            restore.base_msg(&BaseMessage {
+                store_id: 7,
+                expiry_time: 0,
+                topic: "a/b".to_string(),
+                payload: b"hello".to_vec(),
+                source_id: Some("c2".to_string()),
+                source_username: None,
+                source_mid: 1,
+                source_port: 1883,
+                qos: 1,
+                retain: true,
+                properties: Properties::new().content_type("text/plain"),
+            })?;
+            restore.retain("a/b", 7)?;
+            restore.subscription(&PersistedSubscription {
+                client_id: "c1".to_string(),
+                subscription: Subscription::from_options("a/#".to_string(), 1, 0),
+            })
+        }
+
+        fn base_msg_add(&mut self, msg: &BaseMessage) -> Result<Success, Error> {
+            STORED.with(|s| s.borrow_mut().push(format!("{} {} {:?}", msg.store_id, msg.topic, msg.payload)));
+            Ok(Success)
+        }
+
+        fn client_delete(&mut self, client_id: &str) -> Result<Success, Error> {
+            STORED.with(|s| s.borrow_mut().push(format!("deleted {}", client_id)));
+            Err(Error::NotFound)
+        }
+    }
+
+    fn with_store(test: impl FnOnce()) {
+        stub_ffi::reset();
+        let mut identifier = 0u8;
+        let mut user_data = std::ptr::null_mut();
+        let identifier = &mut identifier as *mut u8 as *mut c_void;
+        assert_eq!(unsafe { plugin_init::<Store>(identifier, &mut user_data, std::ptr::null_mut(), 0) }, 0);
+        test();
+        assert_eq!(unsafe { plugin_cleanup::<Store>(user_data, std::ptr::null_mut(), 0) }, 0);
+        assert!(!stub_ffi::registered_events().contains(&(mosquitto_plugin_event_MOSQ_EVT_PERSIST_RESTORE as c_int)));
+    }
+
+    fn fire<E>(event: mosquitto_plugin_event, event_data: &mut E) -> c_int {
+        unsafe { stub_ffi::fire_event(event as c_int, event_data as *mut E as *mut c_void) }
+    }
+
+    #[test]
+    fn persistence_events_are_registered() {
+        with_store(|| {
+            let registered = stub_ffi::registered_events();
+            for (event, _) in events::<Store>() {
+                assert!(registered.contains(&(event as c_int)), "{} isn't registered", event);
+            }
+        });
+    }
+
+    #[test]
+    fn events_reach_the_plugin() {
+        with_store(|| {
+            let topic = CString::new("a/b").unwrap();
+            let mut msg: mosquitto_evt_persist_base_msg = unsafe { std::mem::zeroed() };
+            msg.data.store_id = 3;
+            msg.data.topic = topic.as_ptr() as *mut c_char;
+            msg.data.payload = b"hi".as_ptr() as *mut c_void;
+            msg.data.payloadlen = 2;
+            assert_eq!(fire(mosquitto_plugin_event_MOSQ_EVT_PERSIST_BASE_MSG_ADD, &mut msg), 0);
+
+            let client_id = CString::new("c1").unwrap();
+            let mut client: mosquitto_evt_persist_client = unsafe { std::mem::zeroed() };
+            client.data.clientid = client_id.as_ptr() as *mut c_char;
+            assert_eq!(fire(mosquitto_plugin_event_MOSQ_EVT_PERSIST_CLIENT_DELETE, &mut client), Error::NotFound as c_int);
+
+            // Without a topic the event is refused before it reaches the plugin
+            msg.data.topic = std::ptr::null_mut();
+            assert_eq!(fire(mosquitto_plugin_event_MOSQ_EVT_PERSIST_BASE_MSG_ADD, &mut msg), FAILED);
+            assert_eq!(stored(), vec!["3 a/b [104, 105]".to_string(), "deleted c1".to_string()]);
+        });
+    }
+
+    #[test]
+    fn restore_hands_the_records_to_the_broker() {
+        with_store(|| {
+            let mut restore: mosquitto_evt_persist_restore = unsafe { std::mem::zeroed() };
+            assert_eq!(fire(mosquitto_plugin_event_MOSQ_EVT_PERSIST_RESTORE, &mut restore), 0);
+            assert_eq!(
+                stub_ffi::persisted(),
+                vec!["client_add c1", "base_msg_add 7 a/b", "retain_msg_set a/b 7", "subscription_add c1 a/#"]
+            );
+            // The broker took over the copies of the base message
+            assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        });
+    }
+}
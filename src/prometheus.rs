@@ -0,0 +1,203 @@
+// Serving stats::Stats to Prometheus, enabled with the "prometheus" feature.
+//
+// The exporter answers GET /metrics on the address of the `prometheus_bind` option, e.g.
+// `plugin_opt_prometheus_bind 127.0.0.1:9234`, from a thread of its own. Counters are named
+// `<plugin name>_<counter name>` with everything Prometheus doesn't allow in names replaced by
+// `_`, so `clients/connected` of the plugin `jwt-auth` becomes `jwt_auth_clients_connected`.
+// Counters that were set with Stats::set are exported as gauges.
+use crate::stats::{self, Counters, Stats};
+use crate::MosquittoOpt;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// A scrape that doesn't send its request in this time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The HTTP endpoint, stopped when dropped
+#[derive(Debug)]
+pub struct PrometheusExporter {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PrometheusExporter {
+    /// Serves the counters of stats on address, port 0 picks a free port
+    pub fn serve(
+        name: &str,
+        stats: &Stats,
+        address: impl std::net::ToSocketAddrs,
+    ) -> io::Result<PrometheusExporter> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let namespace = metric_name(name);
+        let counters = stats.counters();
+        let thread = {
+            let stop = Arc::clone(&stop);
+            std::thread::Builder::new()
+                .name("prometheus-exporter".to_string())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        // A scraper that misbehaves only loses its own answer
+                        if let Ok(stream) = stream {
+                            let _ = answer(stream, &namespace, &counters);
+                        }
+                    }
+                })?
+        };
+        Ok(PrometheusExporter {
+            address,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serves on the address of the `prometheus_bind` option, None without it
+    pub fn from_opts(
+        name: &str,
+        stats: &Stats,
+        opts: &MosquittoOpt,
+    ) -> io::Result<Option<PrometheusExporter>> {
+        match opts.get("prometheus_bind") {
+            Some(address) => PrometheusExporter::serve(name, stats, *address).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for PrometheusExporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wakes the thread up from accept
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn answer(stream: TcpStream, namespace: &str, counters: &Counters) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers aren't needed, but are read so closing doesn't reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(namespace, counters)),
+        _ => (
+            "404 Not Found",
+            "Not found, metrics are at /metrics\n".to_string(),
+        ),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// The text exposition format
+fn render(namespace: &str, counters: &Counters) -> String {
+    let mut body = String::new();
+    for (name, value, gauge) in stats::read(counters) {
+        let name = metric_name(&format!("{}_{}", namespace, name));
+        let kind = if gauge { "gauge" } else { "counter" };
+        let _ = writeln!(body, "# TYPE {} {}\n{} {}", name, kind, name, value);
+    }
+    body
+}
+
+// [a-zA-Z_:][a-zA-Z0-9_:]*
+fn metric_name(name: &str) -> String {
+    let mut metric: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if metric.starts_with(|c: char| c.is_ascii_digit()) {
+        metric.insert(0, '_');
+    }
+    metric
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_are_served_over_http() {
+        let stats = Stats::new("$SYS/plugin/jwt-auth", Duration::from_secs(10));
+        stats.incr("auth/ok");
+        stats.set("clients/connected", 3);
+        let mut opts = MosquittoOpt::new();
+        opts.insert("prometheus_bind", "127.0.0.1:0");
+        let exporter = PrometheusExporter::from_opts("jwt-auth", &stats, &opts)
+            .unwrap()
+            .unwrap();
+
+        // counted after the exporter started
+        stats.incr("auth/ok");
+        let response = get(exporter.address(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(
+            "\r\n\r\n# TYPE jwt_auth_auth_ok counter\njwt_auth_auth_ok 2\n\
+             # TYPE jwt_auth_clients_connected gauge\njwt_auth_clients_connected 3\n"
+        ));
+        assert!(get(exporter.address(), "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let address = exporter.address();
+        drop(exporter);
+        assert!(TcpStream::connect(address).is_err());
+        assert!(
+            PrometheusExporter::from_opts("jwt-auth", &stats, &MosquittoOpt::new())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn names_are_made_valid() {
+        assert_eq!(
+            metric_name("jwt-auth_clients/connected"),
+            "jwt_auth_clients_connected"
+        );
+        assert_eq!(metric_name("2fa:ok"), "_2fa:ok");
+    }
+}
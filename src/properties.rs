@@ -0,0 +1,406 @@
+// MQTT v5 properties for messages published by the plugin, see
+// mosquitto_calls::publish_broadcast_with_properties and publish_to_client_with_properties.
+//
+// Properties keeps the values on the Rust side, the mosquitto_property list is only built when a
+// message is published, so the same Properties can be used for any number of messages.
+//
+// MessageProperties reads the properties of incoming messages in acl_check and on_message. The
+// mosquitto_property_read_* functions hand out copies, each getter returns owned values and frees
+// the copies right away.
+use crate::mosquitto_dev::*;
+use crate::Error;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::raw::{c_char, c_int, c_void};
+use std::time::Duration;
+
+// Property identifiers from the MQTT 5 specification
+const MESSAGE_EXPIRY_INTERVAL: c_int = 2;
+const CONTENT_TYPE: c_int = 3;
+const RESPONSE_TOPIC: c_int = 8;
+const CORRELATION_DATA: c_int = 9;
+const USER_PROPERTY: c_int = 38;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Property {
+    Int32(c_int, u32),
+    String(c_int, String),
+    Binary(c_int, Vec<u8>),
+    StringPair(c_int, String, String),
+}
+
+/// The properties of a published message. Clients connected with MQTT 3.1 or 3.1.1 receive the
+/// message without them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Properties(Vec<Property>);
+
+impl Properties {
+    pub fn new() -> Properties {
+        Properties::default()
+    }
+
+    /// Adds a user property, names may repeat and the order is kept
+    pub fn user_property(mut self, name: &str, value: &str) -> Properties {
+        self.0.push(Property::StringPair(
+            USER_PROPERTY,
+            name.to_string(),
+            value.to_string(),
+        ));
+        self
+    }
+
+    /// The MIME type of the payload, e.g. `application/json`
+    pub fn content_type(self, content_type: &str) -> Properties {
+        self.set(Property::String(CONTENT_TYPE, content_type.to_string()))
+    }
+
+    /// The topic a receiver should publish its response to
+    pub fn response_topic(self, topic: &str) -> Properties {
+        self.set(Property::String(RESPONSE_TOPIC, topic.to_string()))
+    }
+
+    /// Data the receiver sends back with its response, at most 65535 bytes
+    pub fn correlation_data(self, data: impl AsRef<[u8]>) -> Properties {
+        self.set(Property::Binary(CORRELATION_DATA, data.as_ref().to_vec()))
+    }
+
+    /// How long the broker keeps the message for offline subscribers, in whole seconds
+    pub fn message_expiry(self, expiry: Duration) -> Properties {
+        let secs = u32::try_from(expiry.as_secs()).unwrap_or(u32::MAX);
+        self.set(Property::Int32(MESSAGE_EXPIRY_INTERVAL, secs))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Every property but the user properties may only appear once, setting it again replaces it
+    fn set(mut self, property: Property) -> Properties {
+        let id = property.id();
+        self.0.retain(|p| p.id() != id);
+        self.0.push(property);
+        self
+    }
+
+    // Builds the list handed to mosquitto_broker_publish. Strings containing NUL and correlation
+    // data longer than 65535 bytes are Err(Error::Inval).
+    pub(crate) fn to_list(&self) -> Result<PropertyList, Error> {
+        let mut list = PropertyList::empty();
+        for property in &self.0 {
+            let res = unsafe {
+                match property {
+                    Property::Int32(id, value) => {
+                        mosquitto_property_add_int32(&mut list.ptr, *id, *value)
+                    }
+                    Property::String(id, value) => {
+                        let value = CString::new(value.as_str()).map_err(|_| Error::Inval)?;
+                        mosquitto_property_add_string(&mut list.ptr, *id, value.as_ptr())
+                    }
+                    Property::Binary(id, value) => {
+                        let len = u16::try_from(value.len()).map_err(|_| Error::Inval)?;
+                        mosquitto_property_add_binary(
+                            &mut list.ptr,
+                            *id,
+                            value.as_ptr() as *const c_void,
+                            len,
+                        )
+                    }
+                    Property::StringPair(id, name, value) => {
+                        let name = CString::new(name.as_str()).map_err(|_| Error::Inval)?;
+                        let value = CString::new(value.as_str()).map_err(|_| Error::Inval)?;
+                        mosquitto_property_add_string_pair(
+                            &mut list.ptr,
+                            *id,
+                            name.as_ptr(),
+                            value.as_ptr(),
+                        )
+                    }
+                }
+            };
+            match res {
+                0 => {}
+                1 => return Err(Error::NoMem),
+                // MOSQ_ERR_INVAL, and MOSQ_ERR_MALFORMED_UTF8 for strings mosquitto rejects
+                _ => return Err(Error::Inval),
+            }
+        }
+        Ok(list)
+    }
+}
+
+impl Property {
+    fn id(&self) -> c_int {
+        match self {
+            Property::Int32(id, _)
+            | Property::String(id, _)
+            | Property::Binary(id, _)
+            | Property::StringPair(id, _, _) => *id,
+        }
+    }
+}
+
+// A mosquitto_property list allocated by mosquitto. Like the payload, mosquitto_broker_publish
+// only takes ownership of it on success, so it is freed on drop unless it was handed over.
+pub(crate) struct PropertyList {
+    pub(crate) ptr: *mut mosquitto_property,
+}
+
+impl PropertyList {
+    pub(crate) fn empty() -> PropertyList {
+        PropertyList {
+            ptr: std::ptr::null_mut(),
+        }
+    }
+}
+
+impl Drop for PropertyList {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { mosquitto_property_free_all(&mut self.ptr) };
+        }
+    }
+}
+
+/// The MQTT v5 properties of an incoming message, borrowed from the broker for the duration of the
+/// callback. Messages from MQTT 3.1 and 3.1.1 clients, and subscriptions, have none.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageProperties<'a> {
+    list: *const mosquitto_property,
+    _event: PhantomData<&'a mosquitto_property>,
+}
+
+impl<'a> MessageProperties<'a> {
+    pub fn none() -> MessageProperties<'a> {
+        MessageProperties {
+            list: std::ptr::null(),
+            _event: PhantomData,
+        }
+    }
+
+    /// # Safety
+    /// list has to be null or a property list that outlives 'a, like the one in an event
+    pub unsafe fn from_ptr(list: *const mosquitto_property) -> MessageProperties<'a> {
+        MessageProperties {
+            list,
+            _event: PhantomData,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_null()
+    }
+
+    /// All user properties in the order the client sent them, names may repeat
+    pub fn user_properties(&self) -> Vec<(String, String)> {
+        let mut properties = Vec::new();
+        let mut skip_first = false;
+        let mut from = self.list;
+        while !from.is_null() {
+            let (mut name, mut value) = (std::ptr::null_mut(), std::ptr::null_mut());
+            from = unsafe {
+                mosquitto_property_read_string_pair(
+                    from,
+                    USER_PROPERTY,
+                    &mut name,
+                    &mut value,
+                    skip_first,
+                )
+            };
+            let (name, value) = unsafe { (take_string(name), take_string(value)) };
+            if let (false, Some(name), Some(value)) = (from.is_null(), name, value) {
+                properties.push((name, value));
+            }
+            skip_first = true;
+        }
+        properties
+    }
+
+    /// The value of the first user property with this name
+    pub fn user_property(&self, name: &str) -> Option<String> {
+        self.user_properties()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn content_type(&self) -> Option<String> {
+        self.read_string(CONTENT_TYPE)
+    }
+
+    pub fn response_topic(&self) -> Option<String> {
+        self.read_string(RESPONSE_TOPIC)
+    }
+
+    pub fn correlation_data(&self) -> Option<Vec<u8>> {
+        if self.list.is_null() {
+            return None;
+        }
+        let mut value = std::ptr::null_mut();
+        let mut len = 0u16;
+        let found = unsafe {
+            mosquitto_property_read_binary(self.list, CORRELATION_DATA, &mut value, &mut len, false)
+        };
+        if found.is_null() {
+            return None;
+        }
+        if value.is_null() {
+            return Some(Vec::new());
+        }
+        unsafe {
+            let data = std::slice::from_raw_parts(value as *const u8, len as usize).to_vec();
+            mosquitto_free(value);
+            Some(data)
+        }
+    }
+
+    pub fn message_expiry(&self) -> Option<Duration> {
+        if self.list.is_null() {
+            return None;
+        }
+        let mut secs = 0u32;
+        let found = unsafe {
+            mosquitto_property_read_int32(self.list, MESSAGE_EXPIRY_INTERVAL, &mut secs, false)
+        };
+        if found.is_null() {
+            None
+        } else {
+            Some(Duration::from_secs(secs.into()))
+        }
+    }
+
+    /// Copies the properties this module knows into a Properties, to keep them past the callback
+    /// or to publish a message with the same properties
+    pub fn to_properties(&self) -> Properties {
+        let mut properties = Properties::new();
+        for (name, value) in self.user_properties() {
+            properties = properties.user_property(&name, &value);
+        }
+        if let Some(content_type) = self.content_type() {
+            properties = properties.content_type(&content_type);
+        }
+        if let Some(topic) = self.response_topic() {
+            properties = properties.response_topic(&topic);
+        }
+        if let Some(data) = self.correlation_data() {
+            properties = properties.correlation_data(data);
+        }
+        if let Some(expiry) = self.message_expiry() {
+            properties = properties.message_expiry(expiry);
+        }
+        properties
+    }
+
+    fn read_string(&self, id: c_int) -> Option<String> {
+        if self.list.is_null() {
+            return None;
+        }
+        let mut value = std::ptr::null_mut();
+        let found = unsafe { mosquitto_property_read_string(self.list, id, &mut value, false) };
+        let value = unsafe { take_string(value) };
+        if found.is_null() {
+            None
+        } else {
+            value
+        }
+    }
+}
+
+// Copies a string allocated by mosquitto and frees it. mosquitto checks string properties for
+// valid UTF-8 when it receives them, the lossy conversion is only a fallback.
+unsafe fn take_string(ptr: *mut c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    let value = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+    mosquitto_free(ptr as *mut c_void);
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::{self, StubProperty};
+
+    #[test]
+    fn builds_the_list_in_order() {
+        stub_ffi::reset();
+        let props = Properties::new()
+            .user_property("a", "1")
+            .content_type("text/plain")
+            .user_property("a", "2")
+            .content_type("application/json")
+            .message_expiry(Duration::from_secs(90));
+        let list = props.to_list().unwrap();
+        assert_eq!(
+            unsafe { stub_ffi::properties_of(list.ptr) },
+            vec![
+                StubProperty::StringPair(38, "a".to_string(), "1".to_string()),
+                StubProperty::StringPair(38, "a".to_string(), "2".to_string()),
+                StubProperty::String(3, "application/json".to_string()),
+                StubProperty::Int32(2, 90),
+            ]
+        );
+        drop(list);
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+        assert!(Properties::new().to_list().unwrap().ptr.is_null());
+    }
+
+    #[test]
+    fn invalid_values_free_what_was_built() {
+        stub_ffi::reset();
+        let props = Properties::new()
+            .response_topic("replies/1")
+            .user_property("key", "nul\0");
+        assert_eq!(props.to_list().err(), Some(Error::Inval));
+        let props = Properties::new()
+            .response_topic("replies/1")
+            .correlation_data(vec![0; 65536]);
+        assert_eq!(props.to_list().err(), Some(Error::Inval));
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+
+        let expiry = Properties::new().message_expiry(Duration::from_secs(u64::MAX));
+        let list = expiry.to_list().unwrap();
+        assert_eq!(
+            unsafe { stub_ffi::properties_of(list.ptr) },
+            vec![StubProperty::Int32(2, u32::MAX)]
+        );
+    }
+
+    #[test]
+    fn message_properties_are_read_and_freed() {
+        stub_ffi::reset();
+        let list = Properties::new()
+            .user_property("tenant", "a")
+            .content_type("application/json")
+            .user_property("trace", "1")
+            .user_property("tenant", "b")
+            .correlation_data(b"req-7")
+            .to_list()
+            .unwrap();
+        let props = unsafe { MessageProperties::from_ptr(list.ptr) };
+        assert!(!props.is_empty());
+        assert_eq!(
+            props.user_properties(),
+            vec![
+                ("tenant".to_string(), "a".to_string()),
+                ("trace".to_string(), "1".to_string()),
+                ("tenant".to_string(), "b".to_string()),
+            ]
+        );
+        assert_eq!(props.user_property("tenant").as_deref(), Some("a"));
+        assert_eq!(props.user_property("missing"), None);
+        assert_eq!(props.content_type().as_deref(), Some("application/json"));
+        assert_eq!(props.correlation_data().as_deref(), Some(&b"req-7"[..]));
+        assert_eq!(props.response_topic(), None);
+        assert_eq!(props.message_expiry(), None);
+        drop(list);
+        assert_eq!(stub_ffi::outstanding_allocations(), 0);
+
+        let none = MessageProperties::none();
+        assert!(none.is_empty());
+        assert!(none.user_properties().is_empty());
+        assert_eq!(none.content_type(), None);
+        assert_eq!(none.correlation_data(), None);
+    }
+}
@@ -0,0 +1,255 @@
+// Token bucket rate limiting per client, meant to be enforced from acl_check:
+//
+//     if !self.limiter.check(&client.get_id()) {
+//         return AuthDecision::deny("rate limit exceeded");
+//     }
+//
+// and released from on_disconnect with self.limiter.remove(&client.get_id()).
+//
+// Buckets that have been idle long enough to be full again are dropped, so millions of short
+// lived client ids don't keep memory around after they're gone.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Source of the current time for the limiter, replaceable in tests
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The default Clock, std::time::Instant is monotonic
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+pub struct RateLimiter<C: Clock = MonotonicClock> {
+    per_second: f64,
+    burst: f64,
+    topic_levels: usize,
+    idle_timeout: Duration,
+    last_sweep: Instant,
+    // client id -> topic prefix -> bucket, the prefix is empty unless topic_levels is set
+    buckets: HashMap<String, HashMap<String, Bucket>>,
+    clock: C,
+}
+
+impl RateLimiter<MonotonicClock> {
+    /// Allow per_second messages on average, and bursts of up to burst messages at once
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        RateLimiter::with_clock(per_second, burst, MonotonicClock)
+    }
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn with_clock(per_second: f64, burst: u32, clock: C) -> Self {
+        let burst = f64::from(burst.max(1));
+        // An idle bucket has refilled completely after burst / per_second, dropping it after that
+        // doesn't change any decision.
+        let refill = Duration::from_secs_f64(burst / per_second.max(f64::MIN_POSITIVE));
+        let now = clock.now();
+        RateLimiter {
+            per_second,
+            burst,
+            topic_levels: 0,
+            idle_timeout: refill.max(Duration::from_secs(60)),
+            last_sweep: now,
+            buckets: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Keep a separate bucket for each prefix of this many topic levels, used by check_topic.
+    /// With 1, a client publishing to both "sensors/..." and "logs/..." is limited separately
+    /// for each.
+    pub fn with_topic_levels(mut self, levels: usize) -> Self {
+        self.topic_levels = levels;
+        self
+    }
+
+    /// How long a bucket may stay unused before it is dropped. Values shorter than the time it
+    /// takes a bucket to refill completely are raised to that.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        let refill = Duration::from_secs_f64(self.burst / self.per_second.max(f64::MIN_POSITIVE));
+        self.idle_timeout = timeout.max(refill);
+        self
+    }
+
+    /// Takes a token from the client's bucket, returns false when the client is over its limit
+    pub fn check(&mut self, client_id: &str) -> bool {
+        self.take(client_id, "")
+    }
+
+    /// Like check, with a bucket per client and topic prefix (see with_topic_levels)
+    pub fn check_topic(&mut self, client_id: &str, topic: &str) -> bool {
+        let prefix = if self.topic_levels == 0 {
+            ""
+        } else {
+            match topic.match_indices('/').nth(self.topic_levels - 1) {
+                Some((i, _)) => &topic[..i],
+                None => topic,
+            }
+        };
+        self.take(client_id, prefix)
+    }
+
+    /// Forget all buckets of a client, to be called from on_disconnect
+    pub fn remove(&mut self, client_id: &str) {
+        self.buckets.remove(client_id);
+    }
+
+    /// Number of clients currently tracked
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    fn take(&mut self, client_id: &str, prefix: &str) -> bool {
+        let now = self.clock.now();
+        if now.duration_since(self.last_sweep) >= self.idle_timeout {
+            self.sweep(now);
+        }
+
+        let burst = self.burst;
+        let per_second = self.per_second;
+        let bucket = self
+            .buckets
+            .entry(client_id.to_string())
+            .or_default()
+            .entry(prefix.to_string())
+            .or_insert(Bucket {
+                tokens: burst,
+                last: now,
+            });
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let idle_timeout = self.idle_timeout;
+        self.buckets.retain(|_, prefixes| {
+            prefixes.retain(|_, bucket| now.duration_since(bucket.last) < idle_timeout);
+            !prefixes.is_empty()
+        });
+        self.last_sweep = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::ManualClock;
+
+    // xorshift, good enough to generate test sequences without pulling in a crate
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let clock = ManualClock::new();
+        let mut limiter = RateLimiter::with_clock(2.0, 3, clock.clone());
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        // other clients have their own bucket
+        assert!(limiter.check("b"));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+    }
+
+    #[test]
+    fn topic_prefixes_get_separate_buckets() {
+        let clock = ManualClock::new();
+        let mut limiter = RateLimiter::with_clock(1.0, 1, clock).with_topic_levels(1);
+        assert!(limiter.check_topic("a", "sensors/1/temp"));
+        assert!(!limiter.check_topic("a", "sensors/2/temp"));
+        assert!(limiter.check_topic("a", "logs/1"));
+        assert!(limiter.check_topic("a", "single"));
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn remove_and_idle_eviction() {
+        let clock = ManualClock::new();
+        let mut limiter = RateLimiter::with_clock(10.0, 10, clock.clone())
+            .with_idle_timeout(Duration::from_secs(5));
+        limiter.check("a");
+        limiter.check("b");
+        limiter.remove("a");
+        assert_eq!(limiter.len(), 1);
+
+        clock.advance(Duration::from_secs(6));
+        limiter.check("c");
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn never_allows_more_than_burst_plus_refill() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let per_second = (rng.next() % 50 + 1) as f64;
+            let burst = (rng.next() % 20 + 1) as u32;
+            let clock = ManualClock::new();
+            let mut limiter = RateLimiter::with_clock(per_second, burst, clock.clone());
+
+            let mut elapsed = Duration::from_secs(0);
+            let mut allowed = 0u64;
+            for _ in 0..500 {
+                let step = Duration::from_millis(rng.next() % 100);
+                clock.advance(step);
+                elapsed += step;
+                if limiter.check("client") {
+                    allowed += 1;
+                }
+                let limit = f64::from(burst) + elapsed.as_secs_f64() * per_second;
+                assert!(allowed as f64 <= limit + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn steady_rate_is_always_allowed() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..100 {
+            let per_second = (rng.next() % 100 + 1) as f64;
+            let clock = ManualClock::new();
+            let mut limiter = RateLimiter::with_clock(per_second, 1, clock.clone());
+            for _ in 0..100 {
+                assert!(limiter.check("client"));
+                // rounded up, Duration has nanosecond resolution
+                clock.advance(Duration::from_nanos((1e9 / per_second).ceil() as u64));
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// Escape hatch for broker events the MosquittoPlugin trait doesn't cover yet.
+//
+// Newer mosquitto releases add events faster than this crate wraps them. These functions give
+// direct access to mosquitto_callback_register with the identifier the broker passed to
+// mosquitto_plugin_init, so a plugin can handle such an event itself while still using the trait
+// for everything else.
+use crate::mosquitto_dev::*;
+use crate::{to_result, Error, Success};
+use std::cell::Cell;
+use std::os::raw::{c_int, c_void};
+
+/// The signature mosquitto calls every event callback with: the event number, a pointer to the
+/// event specific mosquitto_evt_* structure and the user data given at registration.
+pub type RawCallback = unsafe extern "C" fn(event: c_int, event_data: *mut c_void, userdata: *mut c_void) -> c_int;
+
+/// Opaque handle to the plugin identifier (mosquitto_plugin_id_t) the broker passed to
+/// mosquitto_plugin_init. It stays valid until mosquitto_plugin_cleanup returns.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PluginHandle(*mut mosquitto_plugin_id_t);
+
+thread_local! {
+    static CURRENT: Cell<Option<PluginHandle>> = const { Cell::new(None) };
+}
+
+impl PluginHandle {
+    /// The handle of this plugin. Set before MosquittoPlugin::init is called and cleared after
+    /// cleanup, so it is available from init and from every callback, which the broker runs on
+    /// its main thread. Returns None on any other thread.
+    pub fn current() -> Option<PluginHandle> {
+        CURRENT.with(|c| c.get())
+    }
+
+    /// The raw identifier, for calling broker functions not wrapped by this crate
+    pub fn as_ptr(&self) -> *mut mosquitto_plugin_id_t {
+        self.0
+    }
+
+    pub(crate) fn set_current(handle: Option<PluginHandle>) {
+        CURRENT.with(|c| c.set(handle));
+    }
+
+    pub(crate) fn from_identifier(identifier: *mut c_void) -> PluginHandle {
+        PluginHandle(identifier as *mut mosquitto_plugin_id_t)
+    }
+}
+
+/// Register a callback for a broker event, binding to mosquitto_callback_register.
+///
+/// # Safety
+/// - handle must be the handle of this plugin, and the plugin must not have been cleaned up.
+/// - cb is called with the event_data structure of the event number it's registered for, it has
+///   to cast it to the right mosquitto_evt_* type for the broker version in use. The meaning of
+///   the return value is event specific as well.
+/// - event_data is read by the broker for some events, e.g. the topic for MOSQ_EVT_CONTROL, and
+///   has to point to what that event expects.
+/// - userdata is handed back to cb untouched and has to stay valid until the callback is
+///   unregistered or the plugin is cleaned up.
+/// - cb must not unwind across the FFI boundary.
+/// - Registering the same cb twice for an event fails with Error::AlreadyExists, the callbacks
+///   registered by create_dynamic_library! are private so they can't collide.
+pub unsafe fn register_raw_callback(
+    handle: PluginHandle,
+    event: i32,
+    cb: RawCallback,
+    event_data: *const c_void,
+    userdata: *mut c_void,
+) -> Result<Success, Error> {
+    to_result(mosquitto_callback_register(handle.0, event, Some(cb), event_data, userdata))
+}
+
+/// Unregister a callback registered with register_raw_callback, binding to
+/// mosquitto_callback_unregister. Fails with Error::NotFound if it wasn't registered.
+///
+/// # Safety
+/// handle must be the handle of this plugin, and event_data the same as passed to
+/// register_raw_callback.
+pub unsafe fn unregister_raw_callback(
+    handle: PluginHandle,
+    event: i32,
+    cb: RawCallback,
+    event_data: *const c_void,
+) -> Result<Success, Error> {
+    to_result(mosquitto_callback_unregister(handle.0, event, Some(cb), event_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    unsafe extern "C" fn on_event(_event: c_int, _event_data: *mut c_void, userdata: *mut c_void) -> c_int {
+        *(userdata as *mut u32) += 1;
+        0
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let mut id = 0u8;
+        let handle = PluginHandle::from_identifier(&mut id as *mut u8 as *mut c_void);
+        let mut calls = 0u32;
+        let userdata = &mut calls as *mut u32 as *mut c_void;
+        unsafe {
+            register_raw_callback(handle, 42, on_event, std::ptr::null(), userdata).unwrap();
+            assert_eq!(
+                register_raw_callback(handle, 42, on_event, std::ptr::null(), userdata),
+                Err(Error::AlreadyExists)
+            );
+            stub_ffi::fire_event(42, std::ptr::null_mut());
+            unregister_raw_callback(handle, 42, on_event, std::ptr::null()).unwrap();
+            assert_eq!(
+                unregister_raw_callback(handle, 42, on_event, std::ptr::null()),
+                Err(Error::NotFound)
+            );
+            stub_ffi::fire_event(42, std::ptr::null_mut());
+        }
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn current_handle_is_per_thread() {
+        let mut id = 0u8;
+        let handle = PluginHandle::from_identifier(&mut id as *mut u8 as *mut c_void);
+        PluginHandle::set_current(Some(handle));
+        assert_eq!(PluginHandle::current(), Some(handle));
+        assert!(std::thread::spawn(|| PluginHandle::current().is_none()).join().unwrap());
+        PluginHandle::set_current(None);
+        assert_eq!(PluginHandle::current(), None);
+    }
+}
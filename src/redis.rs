@@ -0,0 +1,760 @@
+// Users and ACLs kept in Redis, so all brokers of a deployment share them, enabled with the
+// "redis" feature. With the default prefix the keys are:
+//
+//     SET mqtt:user:alice '$argon2id$v=19$...'                 # hash of the password, see password
+//     SADD mqtt:acl:alice 'write sensors/%u/#' 'read commands/%c'   # acl_file patterns
+//     SADD mqtt:revoked alice                                  # refused until removed again
+//
+// What is read about a user is cached for redis_cache_ttl, so a broker asks Redis about a user
+// once per ttl and not for every message. Changes are announced on the invalidation channel, every
+// broker subscribed to it drops what it cached about the user right away:
+//
+//     PUBLISH mqtt:invalidate alice     # after changing the password or the patterns of alice
+//     PUBLISH mqtt:invalidate *         # drops everything
+//
+// RedisAuth::revoke adds the user to the revoked set and announces it, the clients of the user
+// are then denied in their next ACL checks on every broker. While the subscription is lost the
+// cache is cleared and not used, so no invalidation is missed.
+//
+//     plugin_opt_redis_url redis://redis.example.com:6379/0
+//     plugin_opt_redis_prefix mqtt:            # the default
+//     plugin_opt_redis_cache_ttl 30s           # 0 asks Redis every time
+//     plugin_opt_redis_cache_size 10000        # users cached at most
+//     plugin_opt_redis_timeout 5s              # for connecting and each command
+//     plugin_opt_redis_pool_size 4             # connections kept open
+//     plugin_opt_redis_invalidation true       # subscribe to the invalidation channel
+//
+// and redis_threads (mosquitto 2.1 only, 0 asks on the broker thread).
+use crate::acl::{self, AclPattern};
+use crate::opts::{opt, opt_or, OptError};
+use crate::password::PasswordStore;
+use crate::{AclCheckAccessLevel, AuthDecision, Error, MosquittoClientContext, MosquittoOpt};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+// Published on the invalidation channel to drop every cached user
+const ALL_USERS: &str = "*";
+
+// What is stored about a user
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct UserRecord {
+    hash: Option<String>,
+    patterns: Vec<String>,
+    revoked: bool,
+}
+
+// What the checks need from a connection, a trait so tests don't need a server
+trait Store: Send {
+    fn user(&mut self, keys: &Keys, username: &str) -> Result<UserRecord, String>;
+    // Adds the user to or removes it from the revoked set, and announces the change
+    fn set_revoked(&mut self, keys: &Keys, username: &str, revoked: bool) -> Result<(), String>;
+    fn publish(&mut self, keys: &Keys, username: &str) -> Result<(), String>;
+}
+
+impl Store for ::redis::Connection {
+    fn user(&mut self, keys: &Keys, username: &str) -> Result<UserRecord, String> {
+        let (hash, patterns, revoked) = ::redis::pipe()
+            .cmd("GET")
+            .arg(keys.user(username))
+            .cmd("SMEMBERS")
+            .arg(keys.acl(username))
+            .cmd("SISMEMBER")
+            .arg(keys.revoked())
+            .arg(username)
+            .query(self)
+            .map_err(|e| e.to_string())?;
+        Ok(UserRecord {
+            hash,
+            patterns,
+            revoked,
+        })
+    }
+
+    fn set_revoked(&mut self, keys: &Keys, username: &str, revoked: bool) -> Result<(), String> {
+        ::redis::pipe()
+            .atomic()
+            .cmd(if revoked { "SADD" } else { "SREM" })
+            .arg(keys.revoked())
+            .arg(username)
+            .ignore()
+            .cmd("PUBLISH")
+            .arg(keys.channel())
+            .arg(username)
+            .ignore()
+            .query(self)
+            .map_err(|e| e.to_string())
+    }
+
+    fn publish(&mut self, keys: &Keys, username: &str) -> Result<(), String> {
+        ::redis::cmd("PUBLISH")
+            .arg(keys.channel())
+            .arg(username)
+            .query(self)
+            .map_err(|e| e.to_string())
+    }
+}
+
+type Connect = dyn Fn() -> Result<Box<dyn Store>, String> + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Keys {
+    prefix: String,
+}
+
+impl Keys {
+    fn user(&self, username: &str) -> String {
+        format!("{}user:{}", self.prefix, username)
+    }
+
+    fn acl(&self, username: &str) -> String {
+        format!("{}acl:{}", self.prefix, username)
+    }
+
+    fn revoked(&self) -> String {
+        format!("{}revoked", self.prefix)
+    }
+
+    fn channel(&self) -> String {
+        format!("{}invalidate", self.prefix)
+    }
+}
+
+/// Where the users are kept and how long they are cached
+pub struct RedisConfig {
+    url: String,
+    keys: Keys,
+    cache_ttl: Duration,
+    cache_size: usize,
+    timeout: Duration,
+    pool_size: usize,
+    connect: Option<Box<Connect>>,
+}
+
+impl fmt::Debug for RedisConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedisConfig")
+            .field("url", &self.url)
+            .field("prefix", &self.keys.prefix)
+            .field("cache_ttl", &self.cache_ttl)
+            .finish()
+    }
+}
+
+impl RedisConfig {
+    /// The server at url, `redis://host:port/db` or `rediss://` for TLS, with the keys
+    /// starting with `mqtt:` and users cached for 30 seconds
+    pub fn new(url: &str) -> RedisConfig {
+        RedisConfig {
+            url: url.to_string(),
+            keys: Keys {
+                prefix: "mqtt:".to_string(),
+            },
+            cache_ttl: Duration::from_secs(30),
+            cache_size: 10_000,
+            timeout: Duration::from_secs(5),
+            pool_size: 4,
+            connect: None,
+        }
+    }
+
+    /// Put in front of every key and of the invalidation channel
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.keys.prefix = prefix.to_string();
+        self
+    }
+
+    /// Caches what is read about a user for ttl, at most size users. A ttl of 0 asks Redis for
+    /// every check.
+    pub fn with_cache(mut self, ttl: Duration, size: usize) -> Self {
+        self.cache_ttl = ttl;
+        self.cache_size = size;
+        self
+    }
+
+    /// For connecting and for each command
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Connections kept open for later checks, 0 connects for every check
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// The key holding the password hash of the user
+    pub fn user_key(&self, username: &str) -> String {
+        self.keys.user(username)
+    }
+
+    /// The set holding the acl_file patterns of the user
+    pub fn acl_key(&self, username: &str) -> String {
+        self.keys.acl(username)
+    }
+
+    /// The set of revoked usernames
+    pub fn revoked_key(&self) -> String {
+        self.keys.revoked()
+    }
+
+    /// The channel changed usernames are published on
+    pub fn invalidation_channel(&self) -> String {
+        self.keys.channel()
+    }
+
+    /// Reads the server, prefix, cache and connection options, see the top of the module
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<RedisConfig, OptError> {
+        let url: String = opt(opts, "redis_url")?;
+        Ok(RedisConfig::new(&url)
+            .with_prefix(&opt_or::<String>(opts, "redis_prefix", "mqtt:")?)
+            .with_cache(
+                opt_or(opts, "redis_cache_ttl", "30s")?,
+                opt_or(opts, "redis_cache_size", "10000")?,
+            )
+            .with_timeout(opt_or(opts, "redis_timeout", "5s")?)
+            .with_pool_size(opt_or(opts, "redis_pool_size", "4")?))
+    }
+
+    fn connect(&self) -> Result<Box<dyn Store>, String> {
+        if let Some(connect) = &self.connect {
+            return connect();
+        }
+        let conn = ::redis::Client::open(self.url.as_str())
+            .and_then(|client| client.get_connection_with_timeout(self.timeout))
+            .map_err(|e| e.to_string())?;
+        conn.set_read_timeout(Some(self.timeout))
+            .and_then(|_| conn.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| e.to_string())?;
+        Ok(Box::new(conn))
+    }
+}
+
+// The configuration with the idle connections and the cached users, shared with the worker
+// threads and the invalidation thread
+struct Users {
+    config: RedisConfig,
+    idle: Mutex<Vec<Box<dyn Store>>>,
+    cache: Mutex<HashMap<String, (Arc<UserRecord>, Instant)>>,
+    // False while the invalidation thread isn't subscribed, the cache isn't used then
+    cache_valid: Mutex<bool>,
+}
+
+impl Users {
+    fn with_store<T>(
+        &self,
+        f: impl FnOnce(&mut dyn Store, &Keys) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let mut store = match idle {
+            Some(store) => store,
+            None => self.config.connect()?,
+        };
+        // A connection failing is dropped, the next check connects again
+        let result = f(store.as_mut(), &self.config.keys)?;
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.config.pool_size {
+            idle.push(store);
+        }
+        Ok(result)
+    }
+
+    fn user(&self, username: &str) -> Result<Arc<UserRecord>, String> {
+        let caching = !self.config.cache_ttl.is_zero()
+            && *self.cache_valid.lock().unwrap_or_else(|e| e.into_inner());
+        if caching {
+            let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((user, expires)) = cache.get(username) {
+                if *expires > Instant::now() {
+                    return Ok(Arc::clone(user));
+                }
+            }
+        }
+        let user = Arc::new(self.with_store(|store, keys| store.user(keys, username))?);
+        if caching {
+            let now = Instant::now();
+            let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+            if cache.len() >= self.config.cache_size {
+                cache.retain(|_, (_, expires)| *expires > now);
+            }
+            if cache.len() < self.config.cache_size {
+                cache.insert(
+                    username.to_string(),
+                    (Arc::clone(&user), now + self.config.cache_ttl),
+                );
+            }
+        }
+        Ok(user)
+    }
+
+    fn invalidate(&self, username: &str) {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if username == ALL_USERS {
+            cache.clear();
+        } else {
+            cache.remove(username);
+        }
+    }
+
+    fn set_cache_valid(&self, valid: bool) {
+        *self.cache_valid.lock().unwrap_or_else(|e| e.into_inner()) = valid;
+        if !valid {
+            self.invalidate(ALL_USERS);
+        }
+    }
+}
+
+/// Logins checked against the password hashes in Redis
+impl PasswordStore for Users {
+    fn password_hash(&self, username: &str) -> Result<Option<String>, Error> {
+        let user = self.user(username).map_err(|_| Error::Unknown)?;
+        Ok(user.hash.clone())
+    }
+}
+
+// Subscribes to the invalidation channel until the users are dropped, connecting again after
+// errors
+fn listen(users: Weak<Users>) {
+    const RETRY: Duration = Duration::from_secs(1);
+    while let Some(strong) = users.upgrade() {
+        let client = match ::redis::Client::open(strong.config.url.as_str()) {
+            Ok(client) => client,
+            Err(_) => return,
+        };
+        let channel = strong.config.keys.channel();
+        let timeout = strong.config.timeout;
+        drop(strong);
+        let mut conn = match client.get_connection_with_timeout(timeout) {
+            Ok(conn) => conn,
+            Err(_) => {
+                std::thread::sleep(RETRY);
+                continue;
+            }
+        };
+        let mut pubsub = conn.as_pubsub();
+        if pubsub.subscribe(&channel).is_err() || pubsub.set_read_timeout(Some(RETRY)).is_err() {
+            std::thread::sleep(RETRY);
+            continue;
+        }
+        match users.upgrade() {
+            Some(users) => users.set_cache_valid(true),
+            None => return,
+        }
+        loop {
+            let message = pubsub.get_message();
+            let users = match users.upgrade() {
+                Some(users) => users,
+                None => return,
+            };
+            match message {
+                Ok(message) => {
+                    if let Ok(username) = message.get_payload::<String>() {
+                        users.invalidate(&username);
+                    }
+                }
+                // Checks every RETRY whether the users are still around
+                Err(e) if e.is_timeout() => {}
+                Err(_) => {
+                    users.set_cache_valid(false);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Logins and ACL checks answered with the users in Redis
+pub struct RedisAuth {
+    users: Arc<Users>,
+    #[cfg(mosquitto_2_1)]
+    pool: Option<crate::worker_pool::WorkerPool>,
+}
+
+impl fmt::Debug for RedisAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RedisAuth")
+            .field("config", &self.users.config)
+            .finish()
+    }
+}
+
+impl RedisAuth {
+    /// Connects when the first check is made. Users are cached only after
+    /// subscribe_invalidations, as changes wouldn't be noticed before their ttl ran out.
+    pub fn new(config: RedisConfig) -> RedisAuth {
+        RedisAuth {
+            users: Arc::new(Users {
+                config,
+                idle: Mutex::new(Vec::new()),
+                cache: Mutex::new(HashMap::new()),
+                cache_valid: Mutex::new(false),
+            }),
+            #[cfg(mosquitto_2_1)]
+            pool: None,
+        }
+    }
+
+    /// Subscribes to the invalidation channel on a thread of its own, which stops when the
+    /// RedisAuth is dropped
+    pub fn subscribe_invalidations(&self) -> std::io::Result<()> {
+        let users = Arc::downgrade(&self.users);
+        std::thread::Builder::new()
+            .name("redis-invalidation".to_string())
+            .spawn(move || listen(users))?;
+        Ok(())
+    }
+
+    /// Caches users for the cache ttl even without subscribing to the invalidation channel,
+    /// changes then take up to the ttl to be noticed
+    pub fn with_cache_without_invalidation(self) -> Self {
+        self.users.set_cache_valid(true);
+        self
+    }
+
+    /// Checks on threads worker threads, so the broker doesn't wait for Redis
+    #[cfg(mosquitto_2_1)]
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.pool = Some(crate::worker_pool::WorkerPool::new(threads, 256));
+        self
+    }
+
+    /// The configuration and threads from the options, subscribing to the invalidation channel
+    /// unless redis_invalidation is false
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<RedisAuth, OptError> {
+        let mut auth = RedisAuth::new(RedisConfig::from_opts(opts)?);
+        if opt_or(opts, "redis_invalidation", "true")? {
+            auth.subscribe_invalidations()
+                .map_err(|_| OptError::Invalid {
+                    key: "redis_invalidation".to_string(),
+                    value: "true".to_string(),
+                    expected: "false, no thread could be started",
+                })?;
+        } else {
+            auth = auth.with_cache_without_invalidation();
+        }
+        #[cfg(mosquitto_2_1)]
+        {
+            let threads: usize = opt_or(opts, "redis_threads", "0")?;
+            if threads > 0 {
+                auth = auth.with_threads(threads);
+            }
+        }
+        Ok(auth)
+    }
+
+    pub fn config(&self) -> &RedisConfig {
+        &self.users.config
+    }
+
+    /// Allowed when the user isn't revoked and the password matches the hash. Clients without
+    /// a username or password are denied.
+    pub fn username_password(
+        &self,
+        client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let users = Arc::clone(&self.users);
+        let username = username.map(str::to_string);
+        let password = password.map(str::to_string);
+        let check = move || {
+            if let Some(username) = &username {
+                match users.user(username) {
+                    Ok(user) if user.revoked => return AuthDecision::deny("revoked"),
+                    Ok(_) => {}
+                    Err(_) => return AuthDecision::Error(Error::Unknown),
+                }
+            }
+            users.check(username.as_deref(), password.as_deref())
+        };
+        #[cfg(mosquitto_2_1)]
+        if let Some(pool) = &self.pool {
+            return pool.submit_auth(client, check);
+        }
+        #[cfg(not(mosquitto_2_1))]
+        let _ = client;
+        check()
+    }
+
+    /// Decides an ACL check with the patterns of the user, revoked users are denied. Clients
+    /// without a username are deferred.
+    pub fn acl_check(
+        &self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let username = match client.get_username() {
+            Some(username) => username,
+            None => return AuthDecision::Defer,
+        };
+        let user = match self.users.user(&username) {
+            Ok(user) => user,
+            Err(_) => return AuthDecision::Error(Error::Unknown),
+        };
+        if user.revoked {
+            return AuthDecision::deny("revoked");
+        }
+        // Patterns that don't parse are ignored, like the lines of an acl_file without access
+        let patterns: Vec<AclPattern> = user
+            .patterns
+            .iter()
+            .filter_map(|pattern| AclPattern::parse(pattern).ok())
+            .collect();
+        match acl::check(&patterns, &client.get_id(), Some(&username), level, topic) {
+            Ok(_) => AuthDecision::Allow,
+            Err(_) => AuthDecision::deny("not allowed by the patterns of the user"),
+        }
+    }
+
+    /// Revokes the user on every broker sharing the Redis server: logins are refused and the
+    /// ACL checks of connected clients denied until restore
+    pub fn revoke(&self, username: &str) -> Result<(), Error> {
+        self.set_revoked(username, true)
+    }
+
+    /// Lifts a revoke
+    pub fn restore(&self, username: &str) -> Result<(), Error> {
+        self.set_revoked(username, false)
+    }
+
+    fn set_revoked(&self, username: &str, revoked: bool) -> Result<(), Error> {
+        self.users.invalidate(username);
+        self.users
+            .with_store(|store, keys| store.set_revoked(keys, username, revoked))
+            .map_err(|_| Error::Unknown)
+    }
+
+    /// Announces that the user changed, every broker drops what it cached about them. `*`
+    /// drops all users.
+    pub fn invalidate(&self, username: &str) -> Result<(), Error> {
+        self.users.invalidate(username);
+        self.users
+            .with_store(|store, keys| store.publish(keys, username))
+            .map_err(|_| Error::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::Hasher;
+    use crate::stub_ffi::client;
+    use crate::AclCheckAccessLevel::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // The keys of a Redis server, shared by the connections
+    #[derive(Default)]
+    struct Server {
+        strings: HashMap<String, String>,
+        sets: HashMap<String, Vec<String>>,
+        published: Vec<(String, String)>,
+        lookups: usize,
+    }
+
+    struct Fake(Arc<Mutex<Server>>);
+
+    impl Store for Fake {
+        fn user(&mut self, keys: &Keys, username: &str) -> Result<UserRecord, String> {
+            let mut server = self.0.lock().unwrap();
+            server.lookups += 1;
+            let revoked = server
+                .sets
+                .get(&keys.revoked())
+                .cloned()
+                .unwrap_or_default();
+            Ok(UserRecord {
+                hash: server.strings.get(&keys.user(username)).cloned(),
+                patterns: server
+                    .sets
+                    .get(&keys.acl(username))
+                    .cloned()
+                    .unwrap_or_default(),
+                revoked: revoked.iter().any(|u| u == username),
+            })
+        }
+
+        fn set_revoked(
+            &mut self,
+            keys: &Keys,
+            username: &str,
+            revoked: bool,
+        ) -> Result<(), String> {
+            let mut server = self.0.lock().unwrap();
+            let set = server.sets.entry(keys.revoked()).or_default();
+            set.retain(|u| u != username);
+            if revoked {
+                set.push(username.to_string());
+            }
+            server
+                .published
+                .push((keys.channel(), username.to_string()));
+            Ok(())
+        }
+
+        fn publish(&mut self, keys: &Keys, username: &str) -> Result<(), String> {
+            let mut server = self.0.lock().unwrap();
+            server
+                .published
+                .push((keys.channel(), username.to_string()));
+            Ok(())
+        }
+    }
+
+    fn fake(server: &Arc<Mutex<Server>>, connects: &Arc<AtomicUsize>) -> RedisConfig {
+        let server = Arc::clone(server);
+        let connects = Arc::clone(connects);
+        let mut config = RedisConfig::new("redis://localhost").with_prefix("test:");
+        config.connect = Some(Box::new(move || {
+            connects.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(Fake(Arc::clone(&server))) as Box<dyn Store>)
+        }));
+        config
+    }
+
+    fn alice() -> Arc<Mutex<Server>> {
+        let mut server = Server::default();
+        let hash = Hasher::bcrypt()
+            .with_bcrypt_cost(4)
+            .unwrap()
+            .hash("secret")
+            .unwrap();
+        server.strings.insert("test:user:alice".to_string(), hash);
+        server.sets.insert(
+            "test:acl:alice".to_string(),
+            vec![
+                "write sensors/%u/#".to_string(),
+                "read commands/%c".to_string(),
+            ],
+        );
+        Arc::new(Mutex::new(server))
+    }
+
+    #[test]
+    fn users_decide_logins_and_acl_checks() {
+        let server = alice();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let auth = RedisAuth::new(fake(&server, &connects));
+        let client = client();
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("guess")),
+            AuthDecision::deny("wrong password")
+        );
+        assert_eq!(
+            auth.username_password(&client, Some("bob"), Some("secret")),
+            AuthDecision::deny("unknown user")
+        );
+        assert_eq!(
+            auth.username_password(&client, None, Some("secret")),
+            AuthDecision::deny("username and password required")
+        );
+        // the connection is reused
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        // the stub client is stub-client, logged in as alice
+        assert_eq!(
+            auth.acl_check(&client, Read, "commands/alice"),
+            AuthDecision::Defer
+        );
+        client.set_username("alice").unwrap();
+        for (level, topic, allowed) in [
+            (Write, "sensors/alice/temp", true),
+            (Read, "sensors/alice/temp", false),
+            (Read, "commands/stub-client", true),
+            (Write, "commands/stub-client", false),
+        ] {
+            let expected = if allowed {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::deny("not allowed by the patterns of the user")
+            };
+            assert_eq!(auth.acl_check(&client, level, topic), expected, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn the_cache_is_dropped_by_invalidations() {
+        let server = alice();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let auth = RedisAuth::new(fake(&server, &connects).with_cache(Duration::from_secs(60), 10));
+        let users = &auth.users;
+        // without the subscription nothing is cached
+        users.user("alice").unwrap();
+        users.user("alice").unwrap();
+        assert_eq!(server.lock().unwrap().lookups, 2);
+
+        users.set_cache_valid(true);
+        users.user("alice").unwrap();
+        users.user("alice").unwrap();
+        assert_eq!(server.lock().unwrap().lookups, 3);
+
+        // a message on the channel
+        users.invalidate("alice");
+        users.user("alice").unwrap();
+        assert_eq!(server.lock().unwrap().lookups, 4);
+
+        auth.invalidate(ALL_USERS).unwrap();
+        assert_eq!(
+            server.lock().unwrap().published,
+            vec![("test:invalidate".to_string(), "*".to_string())]
+        );
+        users.user("alice").unwrap();
+        assert_eq!(server.lock().unwrap().lookups, 5);
+
+        // losing the subscription clears the cache
+        users.set_cache_valid(false);
+        assert!(users.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn revoked_users_are_refused() {
+        let server = alice();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let auth = RedisAuth::new(fake(&server, &connects)).with_cache_without_invalidation();
+        let client = client();
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        auth.revoke("alice").unwrap();
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::deny("revoked")
+        );
+        auth.restore("alice").unwrap();
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(server.lock().unwrap().published.len(), 2);
+    }
+
+    #[test]
+    fn options() {
+        let mut opts = HashMap::new();
+        assert!(matches!(
+            RedisConfig::from_opts(&opts),
+            Err(OptError::Missing { .. })
+        ));
+        opts.insert("redis_url", "redis://localhost");
+        opts.insert("redis_prefix", "broker:");
+        opts.insert("redis_cache_ttl", "0s");
+        let config = RedisConfig::from_opts(&opts).unwrap();
+        assert_eq!(config.user_key("alice"), "broker:user:alice");
+        assert_eq!(config.acl_key("alice"), "broker:acl:alice");
+        assert_eq!(config.revoked_key(), "broker:revoked");
+        assert_eq!(config.invalidation_channel(), "broker:invalidate");
+        assert!(config.cache_ttl.is_zero());
+        opts.insert("redis_pool_size", "-1");
+        assert!(matches!(
+            RedisConfig::from_opts(&opts),
+            Err(OptError::Invalid { .. })
+        ));
+    }
+}
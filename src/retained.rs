@@ -0,0 +1,122 @@
+// Retained messages by topic. The plugin interface can't list what the broker retains, so
+// RetainedTopics remembers the topics it saw a retained message published to: return it from
+// MosquittoPlugin::retained_topics and the generated message callback tells it about every
+// retained message that passed on_message_mut, the empty ones removing the topic again.
+//
+// With that a control command can prune the retained values of sensors that went away by
+// pattern, e.g. `sensors/42/#`. Only topics retained since the plugin was loaded are known,
+// those restored from mosquitto.db at startup have to be deleted by exact topic.
+use crate::mosquitto_calls;
+use crate::topic::{is_valid_publish_topic, is_valid_subscribe_filter, pattern_is_subset_of};
+use crate::{Error, Success, QOS};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Default)]
+pub struct RetainedTopics {
+    topics: BTreeSet<String>,
+}
+
+impl RetainedTopics {
+    pub fn new() -> RetainedTopics {
+        RetainedTopics::default()
+    }
+
+    /// The topics with a retained message, in order
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.iter().map(String::as_str)
+    }
+
+    /// The topics with a retained message the pattern matches
+    pub fn matching<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.topics().filter(move |topic| pattern_is_subset_of(pattern, topic))
+    }
+
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    /// Publishes payload as the retained message of topic, replacing the one the broker has
+    pub fn set(&mut self, topic: &str, payload: impl AsRef<[u8]>, qos: QOS) -> Result<Success, Error> {
+        let payload = payload.as_ref();
+        let success = mosquitto_calls::publish_broadcast(topic, payload, qos, true)?;
+        self.observed(topic, !payload.is_empty());
+        Ok(success)
+    }
+
+    /// Deletes the retained message of a topic, or of every known topic a pattern with `+` or
+    /// `#` matches, and returns how many were deleted. A topic is deleted whether it is known or
+    /// not. Stops at the first topic the broker refuses, the ones before it stay deleted.
+    pub fn delete(&mut self, topic_or_pattern: &str) -> Result<usize, Error> {
+        if is_valid_publish_topic(topic_or_pattern) {
+            mosquitto_calls::clear_retained(topic_or_pattern)?;
+            self.topics.remove(topic_or_pattern);
+            return Ok(1);
+        }
+        if !is_valid_subscribe_filter(topic_or_pattern) {
+            return Err(Error::Inval);
+        }
+        let matching: Vec<String> = self.matching(topic_or_pattern).map(str::to_string).collect();
+        for topic in &matching {
+            mosquitto_calls::clear_retained(topic)?;
+            self.topics.remove(topic);
+        }
+        Ok(matching.len())
+    }
+
+    // A retained message was published to topic, an empty one deletes the retained message
+    pub(crate) fn observed(&mut self, topic: &str, has_payload: bool) {
+        if has_payload {
+            self.topics.insert(topic.to_string());
+        } else {
+            self.topics.remove(topic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+
+    #[test]
+    fn set_and_empty_messages_track_the_topics() {
+        stub_ffi::reset();
+        let mut retained = RetainedTopics::new();
+        retained.set("sensors/1/temperature", "21.5", QOS::AtLeastOnce).unwrap();
+        retained.observed("sensors/2/temperature", true);
+        retained.observed("sensors/1/temperature", false);
+        assert_eq!(retained.topics().collect::<Vec<_>>(), vec!["sensors/2/temperature"]);
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 1);
+        assert!(published[0].retain);
+        assert_eq!(published[0].payload, b"21.5");
+    }
+
+    #[test]
+    fn patterns_delete_every_known_topic_they_match() {
+        stub_ffi::reset();
+        let mut retained = RetainedTopics::new();
+        for topic in &["sensors/1/temperature", "sensors/1/humidity", "sensors/2/temperature"] {
+            retained.observed(topic, true);
+        }
+        assert_eq!(retained.delete("sensors/1/#"), Ok(2));
+        assert_eq!(retained.delete("sensors/+/pressure"), Ok(0));
+        let cleared: Vec<_> = stub_ffi::published().into_iter().map(|p| (p.topic, p.payload.len(), p.retain)).collect();
+        assert_eq!(cleared, vec![("sensors/1/humidity".to_string(), 0, true), ("sensors/1/temperature".to_string(), 0, true)]);
+        assert_eq!(retained.topics().collect::<Vec<_>>(), vec!["sensors/2/temperature"]);
+    }
+
+    #[test]
+    fn unknown_topics_are_deleted_and_bad_patterns_refused() {
+        stub_ffi::reset();
+        let mut retained = RetainedTopics::new();
+        assert_eq!(retained.delete("restored/topic"), Ok(1));
+        assert_eq!(stub_ffi::published()[0].topic, "restored/topic");
+        assert_eq!(retained.delete("sensors/#/x"), Err(Error::Inval));
+        assert_eq!(retained.delete(""), Err(Error::Inval));
+    }
+}
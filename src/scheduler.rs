@@ -0,0 +1,166 @@
+// One-shot and repeating jobs run from the tick event, so plugins don't have to compare times in
+// MosquittoPlugin::tick themselves:
+//
+//     scheduler.every(Duration::from_secs(60), move || cache.lock().unwrap().evict_expired());
+//     scheduler.after(token.expires_in(), move || refresh.store(true, Ordering::Relaxed));
+//
+// Returned from MosquittoPlugin::scheduler, the generated tick callback runs the jobs that are due
+// after tick. The broker ticks about every 100 ms, which is how precise the jobs are. A repeating
+// job that fell behind, e.g. because the broker was busy, runs once and not for every missed
+// interval. Jobs run on the broker thread and can call mosquitto functions.
+use std::time::{Duration, Instant};
+
+/// Identifies a scheduled job for Scheduler::cancel
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct JobId(u64);
+
+struct Job {
+    id: JobId,
+    next: Instant,
+    // None for jobs that run once
+    every: Option<Duration>,
+    run: Box<dyn FnMut()>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    next_id: u64,
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("jobs", &self.jobs.len())
+            .finish()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// Runs job every interval, the first time one interval from now
+    pub fn every(&mut self, interval: Duration, job: impl FnMut() + 'static) -> JobId {
+        self.every_from(Instant::now(), interval, job)
+    }
+
+    /// Like every, counting the first interval from start
+    pub fn every_from(
+        &mut self,
+        start: Instant,
+        interval: Duration,
+        job: impl FnMut() + 'static,
+    ) -> JobId {
+        self.add(start + interval, Some(interval), Box::new(job))
+    }
+
+    /// Runs job once, on the first tick at or after when
+    pub fn at(&mut self, when: Instant, job: impl FnOnce() + 'static) -> JobId {
+        let mut job = Some(job);
+        let run = move || {
+            if let Some(job) = job.take() {
+                job()
+            }
+        };
+        self.add(when, None, Box::new(run))
+    }
+
+    /// Runs job once, delay from now
+    pub fn after(&mut self, delay: Duration, job: impl FnOnce() + 'static) -> JobId {
+        self.at(Instant::now() + delay, job)
+    }
+
+    /// Removes the job, false if it already ran or was cancelled
+    pub fn cancel(&mut self, id: JobId) -> bool {
+        let before = self.jobs.len();
+        self.jobs.retain(|job| job.id != id);
+        self.jobs.len() != before
+    }
+
+    /// When the next job is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.jobs.iter().map(|job| job.next).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Runs the jobs due at now, earliest first, and returns how many ran. Called by the
+    /// generated tick callback, or by the plugin when it drives the scheduler itself.
+    pub fn run_due(&mut self, now: Instant) -> usize {
+        let mut due: Vec<usize> = (0..self.jobs.len())
+            .filter(|&i| self.jobs[i].next <= now)
+            .collect();
+        due.sort_by_key(|&i| self.jobs[i].next);
+        for &i in &due {
+            let job = &mut self.jobs[i];
+            (job.run)();
+            if let Some(every) = job.every {
+                // Skips the intervals missed while the broker didn't tick
+                while job.next <= now {
+                    job.next += every;
+                }
+            }
+        }
+        self.jobs
+            .retain(|job| job.every.is_some() || job.next > now);
+        due.len()
+    }
+
+    fn add(&mut self, next: Instant, every: Option<Duration>, run: Box<dyn FnMut()>) -> JobId {
+        let id = JobId(self.next_id);
+        self.next_id += 1;
+        // A zero interval would be due on every tick forever
+        let every = every.map(|every| every.max(Duration::from_millis(1)));
+        self.jobs.push(Job {
+            id,
+            next,
+            every,
+            run,
+        });
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn jobs_run_when_due() {
+        let runs = Rc::new(RefCell::new(Vec::new()));
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut scheduler = Scheduler::new();
+        let record = |name: &'static str| {
+            let runs = Rc::clone(&runs);
+            move || runs.borrow_mut().push(name)
+        };
+        scheduler.every_from(start, secs(10), record("evict"));
+        scheduler.at(start + secs(5), record("refresh"));
+        let cancelled = scheduler.at(start + secs(5), record("cancelled"));
+        assert!(scheduler.cancel(cancelled));
+        assert_eq!(scheduler.next_due(), Some(start + secs(5)));
+
+        assert_eq!(scheduler.run_due(start + secs(4)), 0);
+        assert_eq!(scheduler.run_due(start + secs(10)), 2);
+        assert_eq!(*runs.borrow(), vec!["refresh", "evict"]);
+        assert_eq!(scheduler.len(), 1);
+
+        // a late tick runs the repeating job once and keeps its rhythm
+        assert_eq!(scheduler.run_due(start + secs(35)), 1);
+        assert_eq!(scheduler.next_due(), Some(start + secs(40)));
+        assert_eq!(scheduler.run_due(start + secs(39)), 0);
+        assert_eq!(runs.borrow().len(), 3);
+        assert!(!scheduler.cancel(cancelled));
+    }
+}
@@ -0,0 +1,263 @@
+// The common case of an auth plugin in two functions, the crate writes the MosquittoPlugin:
+//
+//     use mosquitto_plugin::*;
+//
+//     struct Users;
+//
+//     impl SimpleAuthPlugin for Users {
+//         fn init(_opts: MosquittoOpt) -> Self {
+//             Users
+//         }
+//
+//         fn verify(&self, username: &str, password: &str) -> bool {
+//             password == "secret"
+//         }
+//
+//         fn allowed(&self, username: &str, topic: &str, access: AclCheckAccessLevel) -> bool {
+//             topic.starts_with(&format!("users/{}/", username))
+//         }
+//     }
+//
+//     create_simple_plugin!(Users);
+//
+// Clients without a username or password are denied, and so are the ACL checks of clients without
+// a username. Subscriptions are checked with the filter as sent by the client, wildcards included,
+// with AclCheckAccessLevel::Subscribe. The answers of allowed are cached per client, topic and
+// access level (see acl_cache), and dropped when the client logs in again or disconnects, so
+// allowed should only depend on its arguments.
+//
+// Options, besides the ones of the plugin:
+//
+//     plugin_opt_simple_acl_cache_ttl 60s
+//     plugin_opt_simple_acl_cache_size 10000
+//
+// When more than this is needed, the SimpleAuth can be replaced with an own MosquittoPlugin
+// calling the same verify and allowed.
+use crate::acl_cache::AclCache;
+use crate::clients::ClientLifecycle;
+use crate::opts::{opt_or, OptError};
+use crate::{
+    AclCheckAccessLevel, AuthDecision, InitError, MosquittoClientContext, MosquittoMessage,
+    MosquittoOpt, MosquittoPlugin, PluginContext,
+};
+
+/// Logins and ACL checks as plain yes or no answers, turned into a plugin by
+/// create_simple_plugin!
+pub trait SimpleAuthPlugin {
+    /// Creates the plugin from the options in mosquitto.conf, without their prefix
+    fn init(opts: MosquittoOpt) -> Self;
+
+    /// Whether the password is the one of the user
+    fn verify(&self, username: &str, password: &str) -> bool;
+
+    /// Whether the user may read, write, subscribe to or unsubscribe from the topic
+    fn allowed(&self, username: &str, topic: &str, access: AclCheckAccessLevel) -> bool;
+}
+
+/// The MosquittoPlugin of a SimpleAuthPlugin
+pub struct SimpleAuth<P> {
+    plugin: P,
+    cache: AclCache,
+}
+
+impl<P: SimpleAuthPlugin> SimpleAuth<P> {
+    /// Reads the cache options, see the top of the module, and creates the plugin with the rest
+    pub fn from_opts(opts: MosquittoOpt) -> Result<SimpleAuth<P>, OptError> {
+        let cache = AclCache::new(
+            opt_or(&opts, "simple_acl_cache_ttl", "60s")?,
+            opt_or(&opts, "simple_acl_cache_size", "10000")?,
+        );
+        Ok(SimpleAuth {
+            plugin: P::init(opts),
+            cache,
+        })
+    }
+
+    pub fn plugin(&self) -> &P {
+        &self.plugin
+    }
+
+    /// The plugin, for changes after which cache().clear() might be needed
+    pub fn plugin_mut(&mut self) -> &mut P {
+        &mut self.plugin
+    }
+
+    pub fn cache(&mut self) -> &mut AclCache {
+        &mut self.cache
+    }
+}
+
+impl<P: SimpleAuthPlugin> MosquittoPlugin for SimpleAuth<P> {
+    fn init(opts: MosquittoOpt) -> Self {
+        SimpleAuth::from_opts(opts).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_init(opts: MosquittoOpt, _context: &PluginContext) -> Result<Self, InitError> {
+        Ok(SimpleAuth::from_opts(opts)?)
+    }
+
+    fn username_password(
+        &mut self,
+        _client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        match (username, password) {
+            (Some(username), Some(password)) if self.plugin.verify(username, password) => {
+                AuthDecision::Allow
+            }
+            (Some(_), Some(_)) => AuthDecision::deny("wrong username or password"),
+            _ => AuthDecision::deny("username and password required"),
+        }
+    }
+
+    fn acl_check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        msg: MosquittoMessage,
+    ) -> AuthDecision {
+        let username = match client.get_username() {
+            Some(username) => username,
+            None => return AuthDecision::deny("client without username"),
+        };
+        let plugin = &self.plugin;
+        self.cache
+            .get_or_insert_with(&client.get_id(), msg.topic, level, || {
+                if plugin.allowed(&username, msg.topic, level) {
+                    AuthDecision::Allow
+                } else {
+                    AuthDecision::deny("not allowed")
+                }
+            })
+    }
+
+    fn client_registry(&mut self) -> Option<&mut dyn ClientLifecycle> {
+        Some(&mut self.cache)
+    }
+}
+
+// This generates the dynamic c bindings functions of a SimpleAuthPlugin, like
+// create_dynamic_library! does for a MosquittoPlugin. It has to be invoked once, at the root of
+// the plugin crate.
+#[macro_export]
+macro_rules! create_simple_plugin {
+    ($t:ty) => {
+        $crate::create_dynamic_library!($crate::simple::SimpleAuth<$t>);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::properties::MessageProperties;
+    use crate::stub_ffi::{self, client};
+    use crate::AclCheckAccessLevel::{Read, Write};
+    use crate::QOS;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Users {
+        asked: Cell<usize>,
+    }
+
+    impl SimpleAuthPlugin for Users {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Users::default()
+        }
+
+        fn verify(&self, username: &str, password: &str) -> bool {
+            username == "alice" && password == "secret"
+        }
+
+        fn allowed(&self, username: &str, topic: &str, access: AclCheckAccessLevel) -> bool {
+            self.asked.set(self.asked.get() + 1);
+            access != Write && topic.starts_with(&format!("users/{}/", username))
+        }
+    }
+
+    fn message(topic: &str) -> MosquittoMessage<'_> {
+        MosquittoMessage {
+            topic,
+            payload: &[],
+            qos: QOS::AtMostOnce,
+            retain: false,
+            properties: MessageProperties::none(),
+        }
+    }
+
+    #[test]
+    fn logins_are_verified() {
+        let mut auth = SimpleAuth::<Users>::from_opts(HashMap::new()).unwrap();
+        let client = client();
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), Some("guess")),
+            AuthDecision::deny("wrong username or password")
+        );
+        assert_eq!(
+            auth.username_password(&client, Some("alice"), None),
+            AuthDecision::deny("username and password required")
+        );
+    }
+
+    #[test]
+    fn acl_checks_are_cached() {
+        stub_ffi::reset();
+        let mut auth = SimpleAuth::<Users>::from_opts(HashMap::new()).unwrap();
+        let client = client();
+        assert_eq!(
+            auth.acl_check(&client, Read, message("users/alice/inbox")),
+            AuthDecision::deny("client without username")
+        );
+
+        client.set_username("alice").unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                auth.acl_check(&client, Read, message("users/alice/inbox")),
+                AuthDecision::Allow
+            );
+        }
+        assert_eq!(
+            auth.acl_check(&client, Write, message("users/alice/inbox")),
+            AuthDecision::deny("not allowed")
+        );
+        assert_eq!(auth.plugin().asked.get(), 2);
+
+        // logging in again drops what is known about the client
+        auth.client_registry().unwrap().authenticated(&client);
+        auth.acl_check(&client, Read, message("users/alice/inbox"));
+        assert_eq!(auth.plugin().asked.get(), 3);
+    }
+
+    #[test]
+    fn subscriptions_are_checked_with_the_filter() {
+        stub_ffi::reset();
+        let mut auth = SimpleAuth::<Users>::from_opts(HashMap::new()).unwrap();
+        let client = client();
+        client.set_username("alice").unwrap();
+        let opts = crate::SubscriptionOptions { qos: 0 };
+        assert_eq!(
+            auth.acl_check_subscribe(&client, "users/alice/#", opts),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            auth.acl_check_subscribe(&client, "users/#", opts),
+            AuthDecision::deny("not allowed")
+        );
+    }
+
+    #[test]
+    fn cache_options_are_checked() {
+        let mut opts = HashMap::new();
+        opts.insert("simple_acl_cache_ttl", "soon");
+        assert!(matches!(
+            SimpleAuth::<Users>::from_opts(opts),
+            Err(OptError::Invalid { .. })
+        ));
+    }
+}
@@ -0,0 +1,341 @@
+// Plugin state that should survive broker restarts, like dynamic ACL grants, bans or device
+// registrations.
+//
+// The state is written as JSON to <state_dir>/<name>.json, first into <name>.json.tmp which is
+// then renamed over the old file, so a crash while writing leaves the previous state in place.
+// Next to the state the file holds a format version:
+//
+//     {"version": 2, "state": {...}}
+//
+// States written with an older version are passed through the upgrade function given to
+// with_version before being deserialized.
+use crate::MosquittoOpt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The option naming the directory, `auth_opt_state_dir` or `plugin_opt_state_dir` in mosquitto.conf
+pub const STATE_DIR_OPT: &str = "state_dir";
+
+/// Turns a state written with `from_version` into one of the current version
+pub type Upgrade = fn(from_version: u32, state: Value) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    /// The file couldn't be read as a state, it was moved to bad_path so the next save doesn't
+    /// overwrite it
+    Corrupt {
+        bad_path: PathBuf,
+        reason: String,
+    },
+    /// The file was written by a newer version of the plugin, it is left as it is
+    UnsupportedVersion {
+        found: u32,
+        supported: u32,
+    },
+    /// The upgrade function failed, the file is left as it is
+    Upgrade {
+        from_version: u32,
+        reason: String,
+    },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "state file: {}", e),
+            StateError::Corrupt { bad_path, reason } => {
+                write!(
+                    f,
+                    "corrupt state file ({}), moved to {}",
+                    reason,
+                    bad_path.display()
+                )
+            }
+            StateError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "state file has version {}, this plugin supports up to {}",
+                found, supported
+            ),
+            StateError::Upgrade {
+                from_version,
+                reason,
+            } => {
+                write!(
+                    f,
+                    "upgrading state from version {} failed: {}",
+                    from_version, reason
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+fn no_upgrade(_from_version: u32, state: Value) -> Result<Value, String> {
+    Ok(state)
+}
+
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: PathBuf,
+    version: u32,
+    upgrade: Upgrade,
+}
+
+impl StateStore {
+    /// Stores the state in `dir/name.json`, dir is created on the first save
+    pub fn new(dir: impl AsRef<Path>, name: &str) -> StateStore {
+        StateStore {
+            path: dir.as_ref().join(format!("{}.json", name)),
+            version: 1,
+            upgrade: no_upgrade,
+        }
+    }
+
+    /// A store in the directory given by the state_dir option, None when it isn't set
+    pub fn from_opts(opts: &MosquittoOpt, name: &str) -> Option<StateStore> {
+        opts.get(STATE_DIR_OPT)
+            .map(|dir| StateStore::new(dir, name))
+    }
+
+    /// Sets the version written with the state, 1 by default. Older states are passed to upgrade
+    /// when loading.
+    pub fn with_version(mut self, version: u32, upgrade: Upgrade) -> StateStore {
+        self.version = version;
+        self.upgrade = upgrade;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn path_with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    /// Loads the saved state, or the default state if nothing was saved yet
+    pub fn load<T: DeserializeOwned + Default>(&self) -> Result<T, StateError> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let (version, state) = match parse_file(&bytes) {
+            Ok(parsed) => parsed,
+            Err(reason) => return Err(self.preserve_corrupt(reason)),
+        };
+        if version > self.version {
+            return Err(StateError::UnsupportedVersion {
+                found: version,
+                supported: self.version,
+            });
+        }
+        let state = if version < self.version {
+            (self.upgrade)(version, state).map_err(|reason| StateError::Upgrade {
+                from_version: version,
+                reason,
+            })?
+        } else {
+            state
+        };
+        serde_json::from_value(state).map_err(|e| self.preserve_corrupt(e.to_string()))
+    }
+
+    fn preserve_corrupt(&self, reason: String) -> StateError {
+        let bad_path = self.path_with_suffix(".bad");
+        match fs::rename(&self.path, &bad_path) {
+            Ok(()) => StateError::Corrupt { bad_path, reason },
+            Err(e) => StateError::Io(e),
+        }
+    }
+
+    /// Writes the state, replacing the saved one only once it is completely written
+    pub fn save<T: Serialize>(&self, state: &T) -> Result<(), StateError> {
+        let state = serde_json::to_value(state)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut file = Map::new();
+        file.insert("version".to_string(), Value::from(self.version));
+        file.insert("state".to_string(), state);
+        let bytes = serde_json::to_vec_pretty(&Value::Object(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // A temp file left by a crash is truncated here
+        let temp_path = self.path_with_suffix(".tmp");
+        let mut temp = fs::File::create(&temp_path)?;
+        temp.write_all(&bytes)?;
+        temp.sync_all()?;
+        drop(temp);
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn parse_file(bytes: &[u8]) -> Result<(u32, Value), String> {
+    let mut file = match serde_json::from_slice::<Value>(bytes).map_err(|e| e.to_string())? {
+        Value::Object(file) => file,
+        _ => return Err("expected an object".to_string()),
+    };
+    let version = file
+        .get("version")
+        .and_then(Value::as_u64)
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or_else(|| "missing version".to_string())?;
+    let state = file
+        .remove("state")
+        .ok_or_else(|| "missing state".to_string())?;
+    Ok((version, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    type Bans = HashMap<String, u64>;
+
+    // A fresh directory per test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "mosquitto-plugin-state-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn bans() -> Bans {
+        bans_of(&[("c1", 60), ("c2", 3600)])
+    }
+
+    fn bans_of(bans: &[(&str, u64)]) -> Bans {
+        bans.iter()
+            .map(|(id, secs)| (id.to_string(), *secs))
+            .collect()
+    }
+
+    #[test]
+    fn missing_file_loads_default() {
+        let dir = TempDir::new("missing");
+        let store = StateStore::new(&dir.0, "bans");
+        assert_eq!(store.load::<Bans>().unwrap(), Bans::new());
+        assert!(!dir.0.exists());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = TempDir::new("roundtrip");
+        let mut opts = MosquittoOpt::new();
+        opts.insert(STATE_DIR_OPT, dir.0.to_str().unwrap());
+        let store = StateStore::from_opts(&opts, "bans").unwrap();
+        store.save(&bans()).unwrap();
+        assert_eq!(store.load::<Bans>().unwrap(), bans());
+        assert!(StateStore::from_opts(&MosquittoOpt::new(), "bans").is_none());
+    }
+
+    #[test]
+    fn stale_temp_file_is_ignored_and_replaced() {
+        let dir = TempDir::new("crash");
+        let store = StateStore::new(&dir.0, "bans");
+        store.save(&bans()).unwrap();
+        // the broker died halfway through the next save
+        fs::write(dir.0.join("bans.json.tmp"), b"{\"version\": 1, \"sta").unwrap();
+        assert_eq!(store.load::<Bans>().unwrap(), bans());
+
+        let mut more = bans();
+        more.insert("c3".to_string(), 1);
+        store.save(&more).unwrap();
+        assert_eq!(store.load::<Bans>().unwrap(), more);
+        assert!(!dir.0.join("bans.json.tmp").exists());
+    }
+
+    #[test]
+    fn corrupt_file_is_moved_aside() {
+        let dir = TempDir::new("corrupt");
+        let store = StateStore::new(&dir.0, "bans");
+        fs::create_dir_all(&dir.0).unwrap();
+        fs::write(store.path(), b"not json").unwrap();
+        match store.load::<Bans>() {
+            Err(StateError::Corrupt { bad_path, .. }) => {
+                assert_eq!(bad_path, dir.0.join("bans.json.bad"));
+                assert_eq!(fs::read(&bad_path).unwrap(), b"not json");
+            }
+            other => panic!("expected corrupt, got {:?}", other),
+        }
+        // the next start begins from scratch without losing the bad file
+        assert_eq!(store.load::<Bans>().unwrap(), Bans::new());
+        assert!(dir.0.join("bans.json.bad").exists());
+
+        // valid json of the wrong shape is corrupt as well
+        fs::write(store.path(), b"{\"version\": 1, \"state\": [1, 2]}").unwrap();
+        assert!(matches!(
+            store.load::<Bans>(),
+            Err(StateError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn older_versions_are_upgraded() {
+        // version 1 was a list of banned client ids, version 2 adds the ban duration
+        fn upgrade(from_version: u32, state: Value) -> Result<Value, String> {
+            match (from_version, state) {
+                (1, Value::Array(ids)) => Ok(Value::Object(
+                    ids.into_iter()
+                        .filter_map(|id| Some((id.as_str()?.to_string(), Value::from(60u64))))
+                        .collect(),
+                )),
+                (version, _) => Err(format!("can't upgrade from {}", version)),
+            }
+        }
+
+        let dir = TempDir::new("upgrade");
+        StateStore::new(&dir.0, "bans")
+            .save(&vec!["c1", "c2"])
+            .unwrap();
+        let store = StateStore::new(&dir.0, "bans").with_version(2, upgrade);
+        let expected = bans_of(&[("c1", 60), ("c2", 60)]);
+        assert_eq!(store.load::<Bans>().unwrap(), expected);
+
+        store.save(&expected).unwrap();
+        match StateStore::new(&dir.0, "bans").load::<Bans>() {
+            Err(StateError::UnsupportedVersion {
+                found: 2,
+                supported: 1,
+            }) => {}
+            other => panic!("expected unsupported version, got {:?}", other),
+        }
+        // a newer file is not treated as corrupt
+        assert!(store.path().exists());
+    }
+}
@@ -0,0 +1,298 @@
+// Plugin statistics, published the same way mosquitto publishes its own $SYS/broker/... topics.
+//
+// Counters are plain atomics so incrementing them from the callbacks is cheap. The publishing is
+// driven by the tick event: return the Stats from MosquittoPlugin::stats, or call Stats::tick_at
+// from MosquittoPlugin::tick, and every counter is published as a retained message once the flush
+// interval has passed.
+use crate::mosquitto_calls::{self, log_printf};
+use crate::mosquitto_dev::MOSQ_LOG_ERR;
+use crate::{Error, MosquittoOpt, Success, QOS};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Default number of seconds between two publishes of the counters
+pub const DEFAULT_STATS_INTERVAL: u64 = 10;
+
+#[derive(Default)]
+pub(crate) struct Counter {
+    value: AtomicU64,
+    // Set through Stats::set, the value can go down
+    gauge: AtomicBool,
+}
+
+// Shared with the prometheus exporter thread
+pub(crate) type Counters = Arc<RwLock<BTreeMap<String, Counter>>>;
+
+pub struct Stats {
+    prefix: String,
+    interval: Duration,
+    last_flush: Option<Instant>,
+    counters: Counters,
+}
+
+impl Stats {
+    /// Counters are published under `<prefix>/<counter name>`
+    pub fn new(prefix: &str, interval: Duration) -> Stats {
+        Stats {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            interval,
+            last_flush: None,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Reads the `stats_prefix` and `stats_interval` (seconds) options, defaulting to
+    /// `$SYS/broker/plugin/<name>` and DEFAULT_STATS_INTERVAL.
+    pub fn from_opts(name: &str, opts: &MosquittoOpt) -> Stats {
+        let prefix = match opts.get("stats_prefix") {
+            Some(prefix) => prefix.to_string(),
+            None => format!("$SYS/broker/plugin/{}", name),
+        };
+        let interval = opts
+            .get("stats_interval")
+            .and_then(|i| i.parse().ok())
+            .unwrap_or(DEFAULT_STATS_INTERVAL);
+        Stats::new(&prefix, Duration::from_secs(interval))
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Creates the named counter at zero, so it is published before it is first incremented
+    pub fn declare(&self, name: &str) {
+        self.add(name, 0)
+    }
+
+    /// Increment the named counter by one, creating it on first use
+    pub fn incr(&self, name: &str) {
+        self.add(name, 1)
+    }
+
+    /// Increment the named counter by n, creating it on first use
+    pub fn add(&self, name: &str, n: u64) {
+        {
+            let counters = self.counters.read().unwrap();
+            if let Some(counter) = counters.get(name) {
+                counter.value.fetch_add(n, Ordering::Relaxed);
+                return;
+            }
+        }
+        let mut counters = self.counters.write().unwrap();
+        counters
+            .entry(name.to_string())
+            .or_default()
+            .value
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Set the named counter, for values that go down as well like the number of clients
+    pub fn set(&self, name: &str, value: u64) {
+        {
+            let counters = self.counters.read().unwrap();
+            if let Some(counter) = counters.get(name) {
+                counter.value.store(value, Ordering::Relaxed);
+                counter.gauge.store(true, Ordering::Relaxed);
+                return;
+            }
+        }
+        let mut counters = self.counters.write().unwrap();
+        let counter = counters.entry(name.to_string()).or_default();
+        counter.value.store(value, Ordering::Relaxed);
+        counter.gauge.store(true, Ordering::Relaxed);
+    }
+
+    /// Current value of the named counter, zero if it has never been incremented
+    pub fn get(&self, name: &str) -> u64 {
+        let counters = self.counters.read().unwrap();
+        counters
+            .get(name)
+            .map(|c| c.value.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// All counters and their current values, sorted by name
+    pub fn snapshot(&self) -> Vec<(String, u64)> {
+        read(&self.counters)
+            .into_iter()
+            .map(|(name, value, _)| (name, value))
+            .collect()
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn counters(&self) -> Counters {
+        Arc::clone(&self.counters)
+    }
+
+    /// To be called from on_tick. Publishes the counters when the flush interval has passed.
+    pub fn tick(&mut self) -> Result<Success, Error> {
+        self.tick_at(Instant::now())
+    }
+
+    /// Same as tick, with the current time supplied by the caller
+    pub fn tick_at(&mut self, now: Instant) -> Result<Success, Error> {
+        match self.last_flush {
+            Some(last) if now.duration_since(last) < self.interval => Ok(Success),
+            _ => {
+                self.last_flush = Some(now);
+                self.flush()
+            }
+        }
+    }
+
+    /// Publish every counter as a retained message right away. A failed publish is logged and
+    /// doesn't keep the other counters from being published, the first error is returned.
+    pub fn flush(&self) -> Result<Success, Error> {
+        let mut result = Ok(Success);
+        for (name, value) in self.snapshot() {
+            let topic = format!("{}/{}", self.prefix, name);
+            if let Err(e) = mosquitto_calls::publish_broadcast(
+                &topic,
+                value.to_string().as_bytes(),
+                QOS::AtMostOnce,
+                true,
+            ) {
+                log_printf(
+                    MOSQ_LOG_ERR,
+                    &format!("Stats: publish to {} failed: {}", topic, e),
+                );
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+// Name, value and whether it is a gauge of every counter, sorted by name
+pub(crate) fn read(counters: &Counters) -> Vec<(String, u64, bool)> {
+    let counters = counters.read().unwrap();
+    counters
+        .iter()
+        .map(|(name, c)| {
+            let gauge = c.gauge.load(Ordering::Relaxed);
+            (name.clone(), c.value.load(Ordering::Relaxed), gauge)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+    use std::collections::HashMap;
+
+    #[test]
+    fn set_overwrites() {
+        let stats = Stats::new("$SYS/broker/plugin/test", Duration::from_secs(10));
+        stats.set("clients", 3);
+        stats.set("clients", 1);
+        assert_eq!(stats.get("clients"), 1);
+    }
+
+    #[test]
+    fn counters_start_at_zero_and_increment() {
+        let stats = Stats::new("$SYS/broker/plugin/test", Duration::from_secs(10));
+        assert_eq!(stats.get("auth_fail"), 0);
+        stats.incr("auth_fail");
+        stats.incr("auth_fail");
+        stats.add("acl_denied", 5);
+        assert_eq!(stats.get("auth_fail"), 2);
+        assert_eq!(
+            stats.snapshot(),
+            vec![("acl_denied".to_string(), 5), ("auth_fail".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn declared_counters_are_published_at_zero() {
+        stub_ffi::reset();
+        let stats = Stats::new("$SYS/plugin/test", Duration::from_secs(10));
+        stats.declare("auth_ok");
+        stats.incr("auth_fail");
+        stats.declare("auth_fail");
+        stats.flush().unwrap();
+        let published: Vec<_> = stub_ffi::published()
+            .into_iter()
+            .map(|p| (p.topic, p.payload))
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                ("$SYS/plugin/test/auth_fail".to_string(), b"1".to_vec()),
+                ("$SYS/plugin/test/auth_ok".to_string(), b"0".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_publishes_dont_stop_the_flush() {
+        stub_ffi::reset();
+        let stats = Stats::new("$SYS/plugin/test", Duration::from_secs(10));
+        stats.incr("auth_fail");
+        stats.incr("auth_ok");
+        stub_ffi::fail_publishes(Error::NoMem.into());
+        assert_eq!(stats.flush(), Err(Error::NoMem));
+        let topics: Vec<_> = stub_ffi::published().into_iter().map(|p| p.topic).collect();
+        assert_eq!(
+            topics,
+            ["$SYS/plugin/test/auth_fail", "$SYS/plugin/test/auth_ok"]
+        );
+        assert_eq!(
+            stub_ffi::logged(),
+            [
+                (
+                    MOSQ_LOG_ERR as i32,
+                    format!(
+                        "Stats: publish to $SYS/plugin/test/auth_fail failed: {}",
+                        Error::NoMem
+                    )
+                ),
+                (
+                    MOSQ_LOG_ERR as i32,
+                    format!(
+                        "Stats: publish to $SYS/plugin/test/auth_ok failed: {}",
+                        Error::NoMem
+                    )
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn options_configure_prefix_and_interval() {
+        let mut opts = HashMap::new();
+        let stats = Stats::from_opts("test", &opts);
+        assert_eq!(stats.prefix(), "$SYS/broker/plugin/test");
+        assert_eq!(stats.interval, Duration::from_secs(DEFAULT_STATS_INTERVAL));
+
+        opts.insert("stats_prefix", "metrics/auth/");
+        opts.insert("stats_interval", "60");
+        let stats = Stats::from_opts("test", &opts);
+        assert_eq!(stats.prefix(), "metrics/auth");
+        assert_eq!(stats.interval, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn tick_publishes_retained_counters_once_per_interval() {
+        stub_ffi::reset();
+        let mut stats = Stats::new("$SYS/broker/plugin/test", Duration::from_secs(10));
+        stats.incr("auth_fail");
+
+        let start = Instant::now();
+        stats.tick_at(start).unwrap();
+        stats.tick_at(start + Duration::from_secs(5)).unwrap();
+        let published = stub_ffi::published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].topic, "$SYS/broker/plugin/test/auth_fail");
+        assert_eq!(published[0].payload, b"1");
+        assert!(published[0].retain);
+        assert_eq!(published[0].client_id, None);
+
+        stats.tick_at(start + Duration::from_secs(10)).unwrap();
+        assert_eq!(stub_ffi::published().len(), 2);
+    }
+}
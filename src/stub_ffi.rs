@@ -0,0 +1,938 @@
+// Stand-ins for the broker functions, linked into the test binary only, and with the "testing"
+// feature into the test binaries of plugins, see testing. The real symbols live in the mosquitto
+// executable and are resolved when the plugin is loaded, so without these the functions in
+// mosquitto_calls couldn't be exercised by unit tests.
+//
+// Every stub records what it was called with in thread local storage, tests run on their own
+// threads so they don't see each other's calls.
+#![allow(clippy::missing_safety_doc)]
+// testing only uses some of the helpers
+#![cfg_attr(not(test), allow(dead_code))]
+
+use crate::mosquitto_dev::*;
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub client_id: Option<String>,
+    pub topic: String,
+    /// Address of the topic string, to tell whether callers reused a conversion
+    pub topic_ptr: usize,
+    pub payload: Vec<u8>,
+    pub qos: i32,
+    pub retain: bool,
+    pub properties: Vec<StubProperty>,
+}
+
+thread_local! {
+    static PUBLISHED: RefCell<Vec<Published>> = const { RefCell::new(Vec::new()) };
+    static ALLOCATIONS: Cell<isize> = const { Cell::new(0) };
+    static LOGGED: RefCell<Vec<(c_int, String)>> = const { RefCell::new(Vec::new()) };
+    static KICKED: RefCell<Vec<(String, bool)>> = const { RefCell::new(Vec::new()) };
+    static COMPLETED: RefCell<Vec<(String, c_int)>> = const { RefCell::new(Vec::new()) };
+    static PLUGIN_INFO: RefCell<Option<(String, Option<String>)>> = const { RefCell::new(None) };
+    static PERSISTED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    // Set with mosquitto_set_username, every client shares it
+    static USERNAME: RefCell<Option<std::ffi::CString>> = const { RefCell::new(None) };
+    // Set by set_client_id, stub-client until then
+    static CLIENT_ID: RefCell<Option<Option<std::ffi::CString>>> = const { RefCell::new(None) };
+    // DER of the certificate every client presents
+    static CERTIFICATE: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+    // What every client sent in its CONNECT
+    static KEEPALIVE: Cell<c_int> = const { Cell::new(60) };
+    static CLEAN_SESSION: Cell<bool> = const { Cell::new(true) };
+    // What mosquitto_broker_publish answers after recording a valid publish
+    static PUBLISH_RESULT: Cell<c_int> = const { Cell::new(0) };
+}
+
+/// Lines written with mosquitto_log_printf since the last reset
+pub fn logged() -> Vec<(c_int, String)> {
+    LOGGED.with(|l| l.borrow().clone())
+}
+
+pub fn reset() {
+    PUBLISHED.with(|p| p.borrow_mut().clear());
+    ALLOCATIONS.with(|a| a.set(0));
+    LOGGED.with(|l| l.borrow_mut().clear());
+    KICKED.with(|k| k.borrow_mut().clear());
+    COMPLETED.with(|c| c.borrow_mut().clear());
+    PLUGIN_INFO.with(|i| i.borrow_mut().take());
+    PERSISTED.with(|p| p.borrow_mut().clear());
+    USERNAME.with(|u| u.borrow_mut().take());
+    CERTIFICATE.with(|c| c.borrow_mut().take());
+    CLIENT_ID.with(|c| c.borrow_mut().take());
+    KEEPALIVE.with(|k| k.set(60));
+    CLEAN_SESSION.with(|c| c.set(true));
+    REGISTERED.with(|r| r.borrow_mut().clear());
+    PUBLISH_RESULT.with(|r| r.set(0));
+}
+
+/// Buffers allocated with mosquitto_malloc/calloc that haven't been freed yet
+pub fn outstanding_allocations() -> isize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+/// Client ids and usernames passed to the kick functions, with their with_will flag
+pub fn kicked() -> Vec<(String, bool)> {
+    KICKED.with(|k| k.borrow().clone())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_kick_client_by_clientid(clientid: *const c_char, with_will: bool) -> c_int {
+    KICKED.with(|k| k.borrow_mut().push((opt_string(clientid).unwrap_or_default(), with_will)));
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_kick_client_by_username(username: *const c_char, with_will: bool) -> c_int {
+    KICKED.with(|k| k.borrow_mut().push((opt_string(username).unwrap_or_default(), with_will)));
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+/// Client ids and results passed to mosquitto_complete_basic_auth
+#[allow(dead_code)]
+pub fn completed() -> Vec<(String, c_int)> {
+    COMPLETED.with(|c| c.borrow().clone())
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_complete_basic_auth(clientid: *const c_char, result: c_int) {
+    COMPLETED.with(|c| c.borrow_mut().push((opt_string(clientid).unwrap_or_default(), result)));
+}
+
+/// Name and version passed to mosquitto_plugin_set_info
+#[allow(dead_code)]
+pub fn plugin_info() -> Option<(String, Option<String>)> {
+    PLUGIN_INFO.with(|i| i.borrow().clone())
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_plugin_set_info(
+    _identifier: *mut mosquitto_plugin_id_t,
+    plugin_name: *const c_char,
+    plugin_version: *const c_char,
+) -> c_int {
+    PLUGIN_INFO.with(|i| *i.borrow_mut() = Some((opt_string(plugin_name).unwrap_or_default(), opt_string(plugin_version))));
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+/// The records handed to the mosquitto_persist_* and mosquitto_subscription_* functions, like
+/// "client_add c1" or "retain_msg_set a/b 7"
+#[allow(dead_code)]
+pub fn persisted() -> Vec<String> {
+    PERSISTED.with(|p| p.borrow().clone())
+}
+
+#[cfg(mosquitto_2_1)]
+fn persist(record: String) -> c_int {
+    PERSISTED.with(|p| p.borrow_mut().push(record));
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_client_add(client: *mut mosquitto_client) -> c_int {
+    match client.as_ref().and_then(|c| opt_string(c.clientid)) {
+        Some(client_id) => persist(format!("client_add {}", client_id)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_client_update(client: *mut mosquitto_client) -> c_int {
+    match client.as_ref().and_then(|c| opt_string(c.clientid)) {
+        Some(client_id) => persist(format!("client_update {}", client_id)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_client_delete(clientid: *const c_char) -> c_int {
+    match opt_string(clientid) {
+        Some(client_id) => persist(format!("client_delete {}", client_id)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+unsafe fn persist_client_msg(what: &str, client_msg: *mut mosquitto_client_msg) -> c_int {
+    match client_msg.as_ref().and_then(|m| opt_string(m.clientid).map(|id| (id, m.store_id))) {
+        Some((client_id, store_id)) => persist(format!("{} {} {}", what, client_id, store_id)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_client_msg_add(client_msg: *mut mosquitto_client_msg) -> c_int {
+    persist_client_msg("client_msg_add", client_msg)
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_client_msg_delete(client_msg: *mut mosquitto_client_msg) -> c_int {
+    persist_client_msg("client_msg_delete", client_msg)
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_client_msg_update(client_msg: *mut mosquitto_client_msg) -> c_int {
+    persist_client_msg("client_msg_update", client_msg)
+}
+
+// Like the broker, takes ownership of the strings, payload and properties on success
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_base_msg_add(msg: *mut mosquitto_base_msg) -> c_int {
+    let msg = match msg.as_mut() {
+        Some(msg) => msg,
+        None => return mosq_err_t_MOSQ_ERR_INVAL,
+    };
+    let topic = match opt_string(msg.topic) {
+        Some(topic) if (msg.payloadlen > 0) != msg.payload.is_null() => topic,
+        _ => return mosq_err_t_MOSQ_ERR_INVAL,
+    };
+    for ptr in [msg.topic as *mut c_void, msg.payload, msg.source_id as *mut c_void, msg.source_username as *mut c_void] {
+        if !ptr.is_null() {
+            mosquitto_free(ptr);
+        }
+    }
+    mosquitto_property_free_all(&mut msg.properties);
+    persist(format!("base_msg_add {} {}", msg.store_id, topic))
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_base_msg_delete(store_id: u64) -> c_int {
+    persist(format!("base_msg_delete {}", store_id))
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_retain_msg_set(topic: *const c_char, store_id: u64) -> c_int {
+    match opt_string(topic) {
+        Some(topic) => persist(format!("retain_msg_set {} {}", topic, store_id)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_persist_retain_msg_delete(topic: *const c_char) -> c_int {
+    match opt_string(topic) {
+        Some(topic) => persist(format!("retain_msg_delete {}", topic)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_subscription_add(sub: *const mosquitto_subscription) -> c_int {
+    match sub.as_ref().map(|s| (opt_string(s.clientid), opt_string(s.topic_filter))) {
+        Some((Some(client_id), Some(topic_filter))) => persist(format!("subscription_add {} {}", client_id, topic_filter)),
+        _ => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_subscription_delete(clientid: *const c_char, topic_filter: *const c_char) -> c_int {
+    match (opt_string(clientid), opt_string(topic_filter)) {
+        (Some(client_id), Some(topic_filter)) => persist(format!("subscription_delete {} {}", client_id, topic_filter)),
+        _ => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+pub fn published() -> Vec<Published> {
+    PUBLISHED.with(|p| p.borrow().clone())
+}
+
+unsafe fn opt_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
+
+// mosquitto_log_printf is variadic, which can't be defined in stable Rust. mosquitto_calls always
+// calls it with a "%s" format and one string argument, which this fixed signature receives the
+// same way on the platforms the tests run on.
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_log_printf(level: c_int, _fmt: *const c_char, message: *const c_char) {
+    let message = opt_string(message).unwrap_or_default();
+    LOGGED.with(|l| l.borrow_mut().push((level, message)));
+}
+
+// mosquitto_topic_matches_sub2 of lib/util_topic.c in mosquitto 2.0, step by step so the tests of
+// the topic module compare against the broker and not against themselves. s and t are the
+// positions in sub and topic, reading past the end gives the terminating nul like in C.
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_topic_matches_sub(sub: *const c_char, topic: *const c_char, result: *mut bool) -> c_int {
+    const INVAL: c_int = mosq_err_t_MOSQ_ERR_INVAL;
+    const SUCCESS: c_int = mosq_err_t_MOSQ_ERR_SUCCESS;
+    if result.is_null() {
+        return INVAL;
+    }
+    *result = false;
+    if sub.is_null() || topic.is_null() {
+        return INVAL;
+    }
+    let (sub, topic) = (CStr::from_ptr(sub).to_bytes(), CStr::from_ptr(topic).to_bytes());
+    let at = |bytes: &[u8], i: usize| bytes.get(i).copied().unwrap_or(0);
+    if sub.is_empty() || topic.is_empty() {
+        return INVAL;
+    }
+    if (sub[0] == b'$') != (topic[0] == b'$') {
+        return SUCCESS;
+    }
+    let (mut s, mut t) = (0, 0);
+    while at(sub, s) != 0 {
+        if at(topic, t) == b'+' || at(topic, t) == b'#' {
+            return INVAL;
+        }
+        if at(sub, s) != at(topic, t) || at(topic, t) == 0 {
+            if at(sub, s) == b'+' {
+                // "+foo", "a/+foo" and "foo+" are no wildcards
+                if (s > 0 && sub[s - 1] != b'/') || (at(sub, s + 1) != 0 && at(sub, s + 1) != b'/') {
+                    return INVAL;
+                }
+                s += 1;
+                while at(topic, t) != 0 && at(topic, t) != b'/' {
+                    if at(topic, t) == b'+' || at(topic, t) == b'#' {
+                        return INVAL;
+                    }
+                    t += 1;
+                }
+                if at(topic, t) == 0 && at(sub, s) == 0 {
+                    *result = true;
+                    return SUCCESS;
+                }
+            } else if at(sub, s) == b'#' {
+                if (s > 0 && sub[s - 1] != b'/') || at(sub, s + 1) != 0 {
+                    return INVAL;
+                }
+                if topic[t..].iter().any(|c| *c == b'+' || *c == b'#') {
+                    return INVAL;
+                }
+                *result = true;
+                return SUCCESS;
+            } else {
+                // foo/bar matching foo/+/#
+                if at(topic, t) == 0 && s > 0 && sub[s - 1] == b'+' && at(sub, s) == b'/' && at(sub, s + 1) == b'#' {
+                    *result = true;
+                    return SUCCESS;
+                }
+                // No match, but the sub can still be invalid
+                while at(sub, s) != 0 {
+                    if at(sub, s) == b'#' && at(sub, s + 1) != 0 {
+                        return INVAL;
+                    }
+                    s += 1;
+                }
+                return SUCCESS;
+            }
+        } else {
+            // foo matching foo/#
+            if at(topic, t + 1) == 0 && at(sub, s + 1) == b'/' && at(sub, s + 2) == b'#' && at(sub, s + 3) == 0 {
+                *result = true;
+                return SUCCESS;
+            }
+            s += 1;
+            t += 1;
+            if at(sub, s) == 0 && at(topic, t) == 0 {
+                *result = true;
+                return SUCCESS;
+            } else if at(topic, t) == 0 && at(sub, s) == b'+' && at(sub, s + 1) == 0 {
+                if s > 0 && sub[s - 1] != b'/' {
+                    return INVAL;
+                }
+                *result = true;
+                return SUCCESS;
+            }
+        }
+    }
+    if topic[t..].iter().any(|c| *c == b'+' || *c == b'#') {
+        return INVAL;
+    }
+    SUCCESS
+}
+
+// The rules of the broker build of mosquitto: no wildcards in published topics, whole level
+// wildcards in filters, at most 65535 bytes and 200 separators. Empty topics pass.
+unsafe fn topic_check(topic: *const c_char, filter: bool) -> c_int {
+    let topic = match opt_string(topic) {
+        Some(topic) => topic,
+        None => return mosq_err_t_MOSQ_ERR_INVAL,
+    };
+    let valid = topic.is_empty()
+        || if filter {
+            crate::topic::is_valid_subscribe_filter(&topic)
+        } else {
+            crate::topic::is_valid_publish_topic(&topic)
+        };
+    if valid && topic.matches('/').count() <= 200 {
+        mosq_err_t_MOSQ_ERR_SUCCESS
+    } else {
+        mosq_err_t_MOSQ_ERR_INVAL
+    }
+}
+
+// Control characters and non-characters are malformed in MQTT strings, as in mosquitto
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_validate_utf8(str_: *const c_char, len: c_int) -> c_int {
+    if str_.is_null() || !(0..=65536).contains(&len) {
+        return mosq_err_t_MOSQ_ERR_INVAL;
+    }
+    let bytes = std::slice::from_raw_parts(str_ as *const u8, len as usize);
+    let valid = std::str::from_utf8(bytes).is_ok_and(|s| s.chars().all(|c| !c.is_control() && (c as u32 & 0xFFFE) != 0xFFFE));
+    if valid {
+        mosq_err_t_MOSQ_ERR_SUCCESS
+    } else {
+        mosq_err_t_MOSQ_ERR_MALFORMED_UTF8
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_pub_topic_check(topic: *const c_char) -> c_int {
+    topic_check(topic, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_sub_topic_check(topic: *const c_char) -> c_int {
+    topic_check(topic, true)
+}
+
+// Empty levels are NULL, as documented for mosquitto_sub_topic_tokenise
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_sub_topic_tokenise(subtopic: *const c_char, topics: *mut *mut *mut c_char, count: *mut c_int) -> c_int {
+    let subtopic = match opt_string(subtopic) {
+        Some(subtopic) => subtopic,
+        None => return mosq_err_t_MOSQ_ERR_INVAL,
+    };
+    let levels: Vec<&str> = subtopic.split('/').collect();
+    let array = mosquitto_calloc(levels.len(), std::mem::size_of::<*mut c_char>()) as *mut *mut c_char;
+    for (i, level) in levels.iter().enumerate() {
+        if !level.is_empty() {
+            let token = mosquitto_calloc(level.len() + 1, 1) as *mut c_char;
+            std::ptr::copy_nonoverlapping(level.as_ptr() as *const c_char, token, level.len());
+            *array.add(i) = token;
+        }
+    }
+    *topics = array;
+    *count = levels.len() as c_int;
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_sub_topic_tokens_free(topics: *mut *mut *mut c_char, count: c_int) -> c_int {
+    for i in 0..count as usize {
+        let token = *(*topics).add(i);
+        if !token.is_null() {
+            mosquitto_free(token as *mut c_void);
+        }
+    }
+    mosquitto_free(*topics as *mut c_void);
+    *topics = std::ptr::null_mut();
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_malloc(size: usize) -> *mut c_void {
+    ALLOCATIONS.with(|a| a.set(a.get() + 1));
+    libc::malloc(size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_calloc(nmemb: usize, size: usize) -> *mut c_void {
+    ALLOCATIONS.with(|a| a.set(a.get() + 1));
+    libc::calloc(nmemb, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_free(mem: *mut c_void) {
+    ALLOCATIONS.with(|a| a.set(a.get() - 1));
+    libc::free(mem)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_broker_publish(
+    clientid: *const c_char,
+    topic: *const c_char,
+    payloadlen: c_int,
+    payload: *mut c_void,
+    qos: c_int,
+    retain: bool,
+    properties: *mut mosquitto_property,
+) -> c_int {
+    let topic_ptr = topic as usize;
+    let topic = opt_string(topic).unwrap_or_default();
+    if topic.is_empty() || payloadlen < 0 || (payloadlen > 0) == payload.is_null() || !(0..=2).contains(&qos) {
+        return mosq_err_t_MOSQ_ERR_INVAL;
+    }
+    let client_id = opt_string(clientid);
+    let data = if payloadlen > 0 {
+        std::slice::from_raw_parts(payload as *const u8, payloadlen as usize).to_vec()
+    } else {
+        Vec::new()
+    };
+    PUBLISHED.with(|p| {
+        p.borrow_mut().push(Published {
+            client_id,
+            topic,
+            topic_ptr,
+            payload: data,
+            qos,
+            retain,
+            properties: properties_of(properties),
+        })
+    });
+    let rc = PUBLISH_RESULT.with(|r| r.get());
+    if rc != mosq_err_t_MOSQ_ERR_SUCCESS {
+        return rc;
+    }
+    // Like the broker, take ownership of the payload and properties on success
+    if !payload.is_null() {
+        mosquitto_free(payload);
+    }
+    let mut properties = properties;
+    mosquitto_property_free_all(&mut properties);
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+/// A property added with one of the mosquitto_property_add_* stubs, with its identifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StubProperty {
+    Int32(c_int, u32),
+    String(c_int, String),
+    Binary(c_int, Vec<u8>),
+    StringPair(c_int, String, String),
+}
+
+// The stubbed property list, mosquitto_property is opaque so the pointers are cast
+struct PropertyNode {
+    property: StubProperty,
+    next: *mut PropertyNode,
+}
+
+unsafe fn add_property(proplist: *mut *mut mosquitto_property, property: StubProperty) -> c_int {
+    if proplist.is_null() {
+        return mosq_err_t_MOSQ_ERR_INVAL;
+    }
+    ALLOCATIONS.with(|a| a.set(a.get() + 1));
+    let node = Box::into_raw(Box::new(PropertyNode { property, next: std::ptr::null_mut() }));
+    let mut last = proplist as *mut *mut PropertyNode;
+    while !(*last).is_null() {
+        last = &mut (**last).next;
+    }
+    *last = node;
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+/// The properties in a list built with the stubs
+pub unsafe fn properties_of(proplist: *const mosquitto_property) -> Vec<StubProperty> {
+    let mut properties = Vec::new();
+    let mut node = proplist as *const PropertyNode;
+    while !node.is_null() {
+        properties.push((*node).property.clone());
+        node = (*node).next;
+    }
+    properties
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_int32(proplist: *mut *mut mosquitto_property, identifier: c_int, value: u32) -> c_int {
+    add_property(proplist, StubProperty::Int32(identifier, value))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_string(proplist: *mut *mut mosquitto_property, identifier: c_int, value: *const c_char) -> c_int {
+    match opt_string(value) {
+        Some(value) => add_property(proplist, StubProperty::String(identifier, value)),
+        None => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_binary(proplist: *mut *mut mosquitto_property, identifier: c_int, value: *const c_void, len: u16) -> c_int {
+    if value.is_null() && len > 0 {
+        return mosq_err_t_MOSQ_ERR_INVAL;
+    }
+    let data = if len > 0 { std::slice::from_raw_parts(value as *const u8, len as usize).to_vec() } else { Vec::new() };
+    add_property(proplist, StubProperty::Binary(identifier, data))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_add_string_pair(proplist: *mut *mut mosquitto_property, identifier: c_int, name: *const c_char, value: *const c_char) -> c_int {
+    match (opt_string(name), opt_string(value)) {
+        (Some(name), Some(value)) => add_property(proplist, StubProperty::StringPair(identifier, name, value)),
+        _ => mosq_err_t_MOSQ_ERR_INVAL,
+    }
+}
+
+// Like mosquitto, the search starts at proplist, or after it with skip_first
+unsafe fn find_property(proplist: *const mosquitto_property, identifier: c_int, skip_first: bool) -> *const PropertyNode {
+    let mut node = proplist as *const PropertyNode;
+    if skip_first && !node.is_null() {
+        node = (*node).next;
+    }
+    while !node.is_null() {
+        let id = match &(*node).property {
+            StubProperty::Int32(id, _) | StubProperty::String(id, _) | StubProperty::Binary(id, _) | StubProperty::StringPair(id, _, _) => *id,
+        };
+        if id == identifier {
+            return node;
+        }
+        node = (*node).next;
+    }
+    std::ptr::null()
+}
+
+// The read functions hand out copies allocated with mosquitto_malloc
+unsafe fn copy_out(bytes: &[u8], nul: bool) -> *mut c_void {
+    let ptr = mosquitto_malloc(bytes.len() + nul as usize) as *mut u8;
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+    if nul {
+        *ptr.add(bytes.len()) = 0;
+    }
+    ptr as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_read_int32(proplist: *const mosquitto_property, identifier: c_int, value: *mut u32, skip_first: bool) -> *const mosquitto_property {
+    let node = find_property(proplist, identifier, skip_first);
+    if let (Some(StubProperty::Int32(_, v)), false) = (node.as_ref().map(|n| &n.property), value.is_null()) {
+        *value = *v;
+    }
+    node as *const mosquitto_property
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_read_string(proplist: *const mosquitto_property, identifier: c_int, value: *mut *mut c_char, skip_first: bool) -> *const mosquitto_property {
+    let node = find_property(proplist, identifier, skip_first);
+    if let (Some(StubProperty::String(_, v)), false) = (node.as_ref().map(|n| &n.property), value.is_null()) {
+        *value = copy_out(v.as_bytes(), true) as *mut c_char;
+    }
+    node as *const mosquitto_property
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_read_string_pair(proplist: *const mosquitto_property, identifier: c_int, name: *mut *mut c_char, value: *mut *mut c_char, skip_first: bool) -> *const mosquitto_property {
+    let node = find_property(proplist, identifier, skip_first);
+    if let Some(StubProperty::StringPair(_, n, v)) = node.as_ref().map(|n| &n.property) {
+        if !name.is_null() {
+            *name = copy_out(n.as_bytes(), true) as *mut c_char;
+        }
+        if !value.is_null() {
+            *value = copy_out(v.as_bytes(), true) as *mut c_char;
+        }
+    }
+    node as *const mosquitto_property
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_read_binary(proplist: *const mosquitto_property, identifier: c_int, value: *mut *mut c_void, len: *mut u16, skip_first: bool) -> *const mosquitto_property {
+    let node = find_property(proplist, identifier, skip_first);
+    if let (Some(StubProperty::Binary(_, v)), false) = (node.as_ref().map(|n| &n.property), value.is_null() || len.is_null()) {
+        *value = copy_out(v, false);
+        *len = v.len() as u16;
+    }
+    node as *const mosquitto_property
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_property_free_all(properties: *mut *mut mosquitto_property) {
+    if properties.is_null() {
+        return;
+    }
+    let mut node = *properties as *mut PropertyNode;
+    while !node.is_null() {
+        let next = (*node).next;
+        drop(Box::from_raw(node));
+        ALLOCATIONS.with(|a| a.set(a.get() - 1));
+        node = next;
+    }
+    *properties = std::ptr::null_mut();
+}
+
+/// Makes mosquitto_broker_publish fail with rc, like a broker out of memory, until the next reset
+pub fn fail_publishes(rc: c_int) {
+    PUBLISH_RESULT.with(|r| r.set(rc));
+}
+
+struct Registered {
+    event: c_int,
+    cb: MOSQ_FUNC_generic_callback,
+    event_data: usize,
+    userdata: usize,
+    // The topic given as event data for MOSQ_EVT_CONTROL, which mosquitto copies
+    topic: Option<String>,
+}
+
+const CONTROL: c_int = mosquitto_plugin_event_MOSQ_EVT_CONTROL as c_int;
+
+thread_local! {
+    static REGISTERED: RefCell<Vec<Registered>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The events callbacks are currently registered for, in registration order
+pub fn registered_events() -> Vec<c_int> {
+    REGISTERED.with(|r| r.borrow().iter().map(|r| r.event).collect())
+}
+
+/// The $CONTROL topics callbacks are currently registered for
+pub fn control_topics() -> Vec<String> {
+    REGISTERED.with(|r| r.borrow().iter().filter_map(|r| r.topic.clone()).collect())
+}
+
+/// Calls every callback registered for the event, like the broker does when it happens, and
+/// returns the first error, or success
+pub unsafe fn fire_event(event: c_int, event_data: *mut c_void) -> c_int {
+    // Control callbacks only get the messages to their topic
+    let topic = match event {
+        CONTROL if !event_data.is_null() => opt_string((*(event_data as *mut mosquitto_evt_control)).topic),
+        _ => None,
+    };
+    let callbacks: Vec<_> = REGISTERED.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|r| r.event == event && (event != CONTROL || r.topic == topic))
+            .map(|r| (r.cb, r.userdata))
+            .collect()
+    });
+    for (cb, userdata) in callbacks.into_iter().filter_map(|(cb, u)| cb.map(|cb| (cb, u))) {
+        let rc = cb(event, event_data, userdata as *mut c_void);
+        if rc != mosq_err_t_MOSQ_ERR_SUCCESS {
+            return rc;
+        }
+    }
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+// Callbacks are told apart by address like mosquitto does
+fn addr(cb: MOSQ_FUNC_generic_callback) -> usize {
+    cb.map_or(0, |f| f as usize)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_callback_register(
+    identifier: *mut mosquitto_plugin_id_t,
+    event: c_int,
+    cb_func: MOSQ_FUNC_generic_callback,
+    event_data: *const c_void,
+    userdata: *mut c_void,
+) -> c_int {
+    if identifier.is_null() || cb_func.is_none() {
+        return mosq_err_t_MOSQ_ERR_INVAL;
+    }
+    // Like mosquitto, control callbacks are told apart by topic, which has to look like
+    // $CONTROL/<name>/<version>
+    let topic = if event == CONTROL {
+        match opt_string(event_data as *const c_char) {
+            Some(topic) if topic.starts_with("$CONTROL/") && topic.len() >= "$CONTROL/A/v1".len() => Some(topic),
+            _ => return mosq_err_t_MOSQ_ERR_INVAL,
+        }
+    } else {
+        None
+    };
+    REGISTERED.with(|r| {
+        let mut r = r.borrow_mut();
+        let exists = match &topic {
+            Some(_) => r.iter().any(|r| r.event == event && r.topic == topic),
+            None => r.iter().any(|r| r.event == event && addr(r.cb) == addr(cb_func)),
+        };
+        if exists {
+            return mosq_err_t_MOSQ_ERR_ALREADY_EXISTS;
+        }
+        r.push(Registered {
+            event,
+            cb: cb_func,
+            event_data: event_data as usize,
+            userdata: userdata as usize,
+            topic,
+        });
+        mosq_err_t_MOSQ_ERR_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_callback_unregister(
+    identifier: *mut mosquitto_plugin_id_t,
+    event: c_int,
+    cb_func: MOSQ_FUNC_generic_callback,
+    event_data: *const c_void,
+) -> c_int {
+    if identifier.is_null() {
+        return mosq_err_t_MOSQ_ERR_INVAL;
+    }
+    REGISTERED.with(|r| {
+        let mut r = r.borrow_mut();
+        let len = r.len();
+        let topic = if event == CONTROL { opt_string(event_data as *const c_char) } else { None };
+        r.retain(|r| {
+            let same_data = match &topic {
+                Some(_) => r.topic == topic,
+                None => r.event_data == event_data as usize,
+            };
+            !(r.event == event && addr(r.cb) == addr(cb_func) && same_data)
+        });
+        if r.len() == len {
+            mosq_err_t_MOSQ_ERR_NOT_FOUND
+        } else {
+            mosq_err_t_MOSQ_ERR_SUCCESS
+        }
+    })
+}
+
+// Client accessors, needed to link anything that builds a MosquittoClient. The client pointer
+// is ignored and every client looks the same.
+
+/// A client to pass to the accessors, its pointer is never dereferenced
+pub fn client() -> crate::MosquittoClient {
+    crate::MosquittoClient {
+        client: std::ptr::NonNull::dangling().as_ptr(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_address(_client: *const mosquitto) -> *const c_char {
+    b"127.0.0.1\0".as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_clean_session(_client: *const mosquitto) -> bool {
+    CLEAN_SESSION.with(|c| c.get())
+}
+
+pub fn set_clean_session(clean_session: bool) {
+    CLEAN_SESSION.with(|c| c.set(clean_session));
+}
+
+/// The DER the X509 of mosquitto_client_certificate is serialized to
+#[allow(dead_code)]
+pub fn set_certificate(der: Option<Vec<u8>>) {
+    CERTIFICATE.with(|c| *c.borrow_mut() = der);
+}
+
+// The X509 is a counted allocation holding the DER, to catch missing X509_free calls
+#[cfg(feature = "tls")]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_certificate(_client: *const mosquitto) -> *mut c_void {
+    match CERTIFICATE.with(|c| c.borrow().clone()) {
+        Some(der) => {
+            ALLOCATIONS.with(|a| a.set(a.get() + 1));
+            Box::into_raw(Box::new(der)) as *mut c_void
+        }
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(feature = "tls")]
+#[no_mangle]
+pub unsafe extern "C" fn i2d_X509(x509: *mut c_void, out: *mut *mut u8) -> c_int {
+    let der = &*(x509 as *const Vec<u8>);
+    if !out.is_null() {
+        std::ptr::copy_nonoverlapping(der.as_ptr(), *out, der.len());
+        *out = (*out).add(der.len());
+    }
+    der.len() as c_int
+}
+
+#[cfg(feature = "tls")]
+#[no_mangle]
+pub unsafe extern "C" fn X509_free(x509: *mut c_void) {
+    ALLOCATIONS.with(|a| a.set(a.get() - 1));
+    drop(Box::from_raw(x509 as *mut Vec<u8>));
+}
+
+#[cfg(mosquitto_2_1)]
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_session_expiry_interval(_client: *const mosquitto) -> u32 {
+    3600
+}
+
+/// The id of every client, None making mosquitto_client_id return null
+pub fn set_client_id(client_id: Option<&[u8]>) {
+    let client_id = client_id.map(|id| std::ffi::CString::new(id).unwrap());
+    CLIENT_ID.with(|c| *c.borrow_mut() = Some(client_id));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_id(_client: *const mosquitto) -> *const c_char {
+    CLIENT_ID.with(|c| match &*c.borrow() {
+        None => b"stub-client\0".as_ptr() as *const c_char,
+        Some(client_id) => client_id.as_ref().map_or(std::ptr::null(), |id| id.as_ptr()),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_keepalive(_client: *const mosquitto) -> c_int {
+    KEEPALIVE.with(|k| k.get())
+}
+
+pub fn set_keepalive(keepalive: c_int) {
+    KEEPALIVE.with(|k| k.set(keepalive));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_protocol(_client: *const mosquitto) -> c_int {
+    mosquitto_protocol_mp_mqtt as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_protocol_version(_client: *const mosquitto) -> c_int {
+    5
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_sub_count(_client: *const mosquitto) -> c_int {
+    0
+}
+
+/// The username of every client, like mosquitto_set_username
+pub fn set_username(username: Option<&str>) {
+    let username = username.map(|u| std::ffi::CString::new(u).unwrap());
+    USERNAME.with(|u| *u.borrow_mut() = username);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_client_username(_client: *const mosquitto) -> *const c_char {
+    USERNAME.with(|u| u.borrow().as_ref().map_or(std::ptr::null(), |u| u.as_ptr()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mosquitto_set_username(_client: *mut mosquitto, username: *const c_char) -> c_int {
+    let username = if username.is_null() { None } else { Some(std::ffi::CStr::from_ptr(username).to_owned()) };
+    USERNAME.with(|u| *u.borrow_mut() = username);
+    mosq_err_t_MOSQ_ERR_SUCCESS
+}
+
+/// A ratelimit::Clock that only moves when advanced, shared between its clones
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ManualClock(std::rc::Rc<Cell<std::time::Instant>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock(std::rc::Rc::new(Cell::new(std::time::Instant::now())))
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+#[cfg(test)]
+impl crate::ratelimit::Clock for ManualClock {
+    fn now(&self) -> std::time::Instant {
+        self.0.get()
+    }
+}
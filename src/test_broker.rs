@@ -0,0 +1,449 @@
+// End to end tests of a plugin in a real mosquitto, with the "testing" feature in
+// dev-dependencies, e.g. in tests/broker.rs of the plugin crate:
+//
+//     let plugin = TestBroker::build_plugin(env!("CARGO_MANIFEST_DIR")).unwrap();
+//     let broker = TestBroker::builder(plugin)
+//         .opt("acl", "pattern readwrite devices/%u/%c/#")
+//         .start()
+//         .unwrap();
+//     let mut client = broker.connect("c1", Some("alice"), Some("ecila")).unwrap();
+//     assert!(client.subscribe("devices/alice/c1/#", 0).unwrap());
+//     assert!(!client.subscribe("devices/#", 0).unwrap());
+//     assert!(matches!(broker.connect("c2", Some("alice"), Some("guess")), Err(ConnectError::Refused(5))));
+//
+// build_plugin runs cargo build for the crate and returns its cdylib. The broker is started from
+// PATH, or from the MOSQUITTO environment variable, with a mosquitto.conf in a temporary directory
+// and a listener on a free port of 127.0.0.1, and killed when the TestBroker is dropped. Its log
+// is in TestBroker::log for checking what the plugin logged.
+//
+// The clients speak MQTT 3.1.1. A denied subscription is answered with the failure return code,
+// a denied publish is dropped silently, a receive timeout tells it didn't arrive.
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a client couldn't connect
+#[derive(Debug)]
+pub enum ConnectError {
+    Io(io::Error),
+    /// The return code of the CONNACK, 4 for a bad username or password, 5 for not authorized
+    Refused(u8),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Io(e) => write!(f, "{}", e),
+            ConnectError::Refused(code) => write!(f, "connection refused, return code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<io::Error> for ConnectError {
+    fn from(e: io::Error) -> Self {
+        ConnectError::Io(e)
+    }
+}
+
+/// The mosquitto.conf of a TestBroker, see TestBroker::builder
+pub struct TestBrokerBuilder {
+    plugin: PathBuf,
+    lines: Vec<String>,
+}
+
+impl TestBrokerBuilder {
+    /// `plugin_opt_<key> <value>`
+    pub fn opt(mut self, key: &str, value: &str) -> Self {
+        self.lines.push(format!("plugin_opt_{} {}", key, value));
+        self
+    }
+
+    /// Any other line of mosquitto.conf, e.g. `allow_anonymous true`. The listener and the
+    /// plugin are configured already.
+    pub fn config(mut self, line: &str) -> Self {
+        self.lines.push(line.to_string());
+        self
+    }
+
+    /// Starts the broker and waits until it accepts connections
+    pub fn start(self) -> io::Result<TestBroker> {
+        let dir = std::env::temp_dir().join(format!(
+            "mosquitto-plugin-broker-{}-{}",
+            std::process::id(),
+            next_id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let mut conf = format!(
+            "listener {} 127.0.0.1\nallow_anonymous false\nlog_type all\nplugin {}\n",
+            port,
+            self.plugin.display()
+        );
+        for line in &self.lines {
+            conf.push_str(line);
+            conf.push('\n');
+        }
+        let conf_path = dir.join("mosquitto.conf");
+        std::fs::write(&conf_path, conf)?;
+        let log_path = dir.join("mosquitto.log");
+        let log = std::fs::File::create(&log_path)?;
+        let executable = std::env::var_os("MOSQUITTO").unwrap_or_else(|| "mosquitto".into());
+        let child = Command::new(executable)
+            .arg("-c")
+            .arg(&conf_path)
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()?;
+        let mut broker = TestBroker {
+            child,
+            port,
+            dir,
+            log_path,
+        };
+        broker.wait_until_listening()?;
+        Ok(broker)
+    }
+}
+
+fn next_id() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A mosquitto process with the plugin loaded
+pub struct TestBroker {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+    log_path: PathBuf,
+}
+
+impl TestBroker {
+    /// Builds the crate in the directory, usually env!("CARGO_MANIFEST_DIR"), and returns the
+    /// path of its cdylib
+    pub fn build_plugin(manifest_dir: &str) -> io::Result<PathBuf> {
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let output = Command::new(cargo)
+            .arg("build")
+            .arg("--message-format=json")
+            .arg("--manifest-path")
+            .arg(Path::new(manifest_dir).join("Cargo.toml"))
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other("cargo build failed"));
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.contains("\"cdylib\""))
+            .filter_map(cdylib_of)
+            .next_back()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cdylib was built"))
+    }
+
+    /// The conf of a broker loading the plugin
+    pub fn builder(plugin: impl AsRef<Path>) -> TestBrokerBuilder {
+        TestBrokerBuilder {
+            plugin: plugin.as_ref().to_path_buf(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// What the broker logged so far
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(&self.log_path).unwrap_or_default()
+    }
+
+    /// Connects a client with a clean session
+    pub fn connect(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<TestClient, ConnectError> {
+        let stream = TcpStream::connect(("127.0.0.1", self.port))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut client = TestClient {
+            stream,
+            next_packet_id: 1,
+        };
+        client.send_connect(client_id, username, password)?;
+        let (header, body) = client.read_packet()?;
+        match (header >> 4, body.as_slice()) {
+            (2, [_, 0]) => Ok(client),
+            (2, [_, code]) => Err(ConnectError::Refused(*code)),
+            _ => Err(protocol_error("expected CONNACK").into()),
+        }
+    }
+
+    fn wait_until_listening(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        loop {
+            if TcpStream::connect(("127.0.0.1", self.port)).is_ok() {
+                return Ok(());
+            }
+            if let Some(status) = self.child.try_wait()? {
+                let message = format!("mosquitto exited with {}: {}", status, self.log());
+                return Err(io::Error::other(message));
+            }
+            if started.elapsed() > START_TIMEOUT {
+                let message = format!("mosquitto didn't start listening: {}", self.log());
+                return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for TestBroker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// The first file name of a compiler-artifact line of cargo build --message-format=json
+fn cdylib_of(line: &str) -> Option<PathBuf> {
+    let start = line.find("\"filenames\":[\"")? + "\"filenames\":[\"".len();
+    let end = start + line[start..].find('"')?;
+    Some(PathBuf::from(line[start..end].replace("\\\\", "\\")))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An MQTT 3.1.1 client connected to a TestBroker
+pub struct TestClient {
+    stream: TcpStream,
+    next_packet_id: u16,
+}
+
+impl TestClient {
+    /// Subscribes with the qos, whether the broker granted the subscription
+    pub fn subscribe(&mut self, filter: &str, qos: u8) -> io::Result<bool> {
+        let packet_id = self.packet_id();
+        let mut body = packet_id.to_be_bytes().to_vec();
+        put_str(&mut body, filter);
+        body.push(qos);
+        self.send(0x82, &body)?;
+        loop {
+            let (header, body) = self.read_packet()?;
+            if header >> 4 == 9 && body.get(..2) == Some(&packet_id.to_be_bytes()[..]) {
+                return Ok(body.get(2).is_some_and(|code| *code != 0x80));
+            }
+        }
+    }
+
+    /// Publishes with qos 0 or 1, for qos 1 until the broker acknowledged it. A publish the
+    /// plugin denies is acknowledged all the same.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], qos: u8) -> io::Result<()> {
+        let mut body = Vec::new();
+        put_str(&mut body, topic);
+        let packet_id = self.packet_id();
+        if qos > 0 {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        self.send(0x30 | (qos.min(1) << 1), &body)?;
+        if qos == 0 {
+            return Ok(());
+        }
+        loop {
+            let (header, body) = self.read_packet()?;
+            if header >> 4 == 4 && body == packet_id.to_be_bytes() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The topic and payload of the next message, None when none arrived within the timeout
+    pub fn receive(&mut self, timeout: Duration) -> io::Result<Option<(String, Vec<u8>)>> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        let received = loop {
+            let (header, body) = match self.read_packet() {
+                Ok(packet) => packet,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break Ok(None)
+                }
+                Err(e) => break Err(e),
+            };
+            if header >> 4 == 3 {
+                break self.received(header, &body).map(Some);
+            }
+        };
+        self.stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        received
+    }
+
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(0xe0, &[])
+    }
+
+    fn received(&mut self, header: u8, body: &[u8]) -> io::Result<(String, Vec<u8>)> {
+        let qos = (header >> 1) & 3;
+        let topic_len = usize::from(u16::from_be_bytes([
+            *body
+                .first()
+                .ok_or_else(|| protocol_error("short PUBLISH"))?,
+            *body.get(1).ok_or_else(|| protocol_error("short PUBLISH"))?,
+        ]));
+        let topic = body
+            .get(2..2 + topic_len)
+            .ok_or_else(|| protocol_error("short PUBLISH"))?;
+        let topic = String::from_utf8_lossy(topic).into_owned();
+        let mut payload = &body[2 + topic_len..];
+        if qos > 0 {
+            let packet_id = payload
+                .get(..2)
+                .ok_or_else(|| protocol_error("short PUBLISH"))?;
+            let ack = if qos == 1 { 0x40 } else { 0x50 };
+            self.send(ack, packet_id)?;
+            payload = &payload[2..];
+        }
+        Ok((topic, payload.to_vec()))
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    fn send_connect(
+        &mut self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> io::Result<()> {
+        let mut body = Vec::new();
+        put_str(&mut body, "MQTT");
+        body.push(4);
+        let mut flags = 0x02;
+        if username.is_some() {
+            flags |= 0x80;
+        }
+        if password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        body.extend_from_slice(&60u16.to_be_bytes());
+        put_str(&mut body, client_id);
+        for field in [username, password].iter().flatten() {
+            put_str(&mut body, field);
+        }
+        self.send(0x10, &body)
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+        let mut packet = vec![header];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            packet.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)
+    }
+
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        let header = byte[0];
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            self.stream.read_exact(&mut byte)?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        self.stream.read_exact(&mut body)?;
+        Ok((header, body))
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cdylibs_are_found_in_cargo_output() {
+        let line = r#"{"reason":"compiler-artifact","target":{"kind":["cdylib"],"crate_types":["cdylib"]},"filenames":["/work/target/debug/libexample_acl.so"],"fresh":true}"#;
+        assert_eq!(
+            cdylib_of(line),
+            Some(PathBuf::from("/work/target/debug/libexample_acl.so"))
+        );
+        assert_eq!(cdylib_of(r#"{"reason":"build-finished"}"#), None);
+    }
+
+    // A broker that answers every packet like mosquitto would for a plugin denying devices/#
+    #[test]
+    fn clients_speak_mqtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut peer = TestClient {
+                stream,
+                next_packet_id: 1,
+            };
+            let (header, body) = peer.read_packet().unwrap();
+            assert_eq!(header, 0x10);
+            assert!(body.ends_with(b"\x00\x02c1\x00\x05alice\x00\x05ecila"));
+            peer.send(0x20, &[0, 0]).unwrap();
+            let (header, body) = peer.read_packet().unwrap();
+            assert_eq!((header, &body[..2]), (0x82, &[0, 1][..]));
+            peer.send(0x90, &[0, 1, 0x80]).unwrap();
+            let (header, body) = peer.read_packet().unwrap();
+            assert_eq!(header, 0x32);
+            peer.send(0x40, &body[3..5]).unwrap();
+            peer.send(0x30, b"\x00\x01ahi").unwrap();
+        });
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut client = TestClient {
+            stream,
+            next_packet_id: 1,
+        };
+        client
+            .send_connect("c1", Some("alice"), Some("ecila"))
+            .unwrap();
+        assert_eq!(client.read_packet().unwrap(), (0x20, vec![0, 0]));
+        assert!(!client.subscribe("devices/#", 0).unwrap());
+        client.publish("a", b"1", 1).unwrap();
+        assert_eq!(
+            client.receive(READ_TIMEOUT).unwrap(),
+            Some(("a".to_string(), b"hi".to_vec()))
+        );
+        server.join().unwrap();
+    }
+}
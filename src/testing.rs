@@ -0,0 +1,468 @@
+// Unit tests of plugins without a broker, enabled with the "testing" feature, only ever in
+// dev-dependencies:
+//
+//     [dev-dependencies]
+//     mosquitto-plugin = { version = "...", features = ["testing"] }
+//
+// The feature links stand-ins for the functions of the broker into the test binary, which record
+// what the plugin publishes, logs and kicks. A plugin built with it would call those instead of
+// the broker, so it must not end up in [dependencies]. It needs resolver 2 (edition 2021, or
+// `resolver = "2"` in the workspace), older resolvers enable features of dev-dependencies in
+// cargo build as well.
+//
+//     let broker = MockBroker::new().with_client_id("lamp-1");
+//     let mut plugin: Plugin = broker.init(&[("acl", "pattern write devices/%c/#")]).unwrap();
+//     assert_eq!(broker.username_password(&mut plugin, Some("alice"), Some("secret")), AuthDecision::Allow);
+//     assert_eq!(broker.acl_check(&mut plugin, AclCheckAccessLevel::Write, "devices/lamp-1/on", b"1"), AuthDecision::Allow);
+//     assert_eq!(broker.published()[0].topic, "greeting");
+//
+// MockBroker is the one client connected to it, it is what the callbacks get as their
+// MosquittoClientContext. The calls go through the same steps as the generated callbacks, e.g.
+// subscriptions are checked with acl_check_subscribe and the client_registry is told about
+// logins and disconnects. Everything is recorded per thread, the tests of a crate run on their
+// own threads and don't see each other's calls.
+use crate::stub_ffi;
+use crate::{
+    certificate, AclCheckAccessLevel, AuthDecision, ClientLifecycle, ConnectInfo, DisconnectReason,
+    Error, InitError, MessageRewrite, MessageVeto, MosquittoClient, MosquittoClientContext,
+    MosquittoClientProtocol, MosquittoClientProtocolVersion, MosquittoMessage, MosquittoPlugin,
+    MosquittoPluginEvent, PluginContext, SubscriptionOptions, Success, QOS,
+};
+use std::net::IpAddr;
+use std::os::raw::{c_int, c_void};
+
+/// A message published through the broker, see MockBroker::published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedMessage {
+    /// The client it was sent to, None when it was routed to every subscriber
+    pub client_id: Option<String>,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: i32,
+    pub retain: bool,
+}
+
+/// A broker with a single client, for driving a plugin in tests
+pub struct MockBroker {
+    client: MosquittoClient,
+}
+
+impl Default for MockBroker {
+    fn default() -> Self {
+        MockBroker::new()
+    }
+}
+
+impl MockBroker {
+    /// Forgets what was recorded on this thread before. The client is called stub-client and has
+    /// no username until username_password.
+    pub fn new() -> MockBroker {
+        stub_ffi::reset();
+        MockBroker {
+            client: stub_ffi::client(),
+        }
+    }
+
+    pub fn with_client_id(self, client_id: &str) -> MockBroker {
+        stub_ffi::set_client_id(Some(client_id.as_bytes()));
+        self
+    }
+
+    /// The client id as the broker hands it over, which may be null or not UTF-8. Only the bytes
+    /// up to the first NUL are kept, like in a C string.
+    pub fn with_raw_client_id(self, client_id: Option<&[u8]>) -> MockBroker {
+        stub_ffi::set_client_id(
+            client_id.map(|id| id.split(|b| *b == 0).next().unwrap_or_default()),
+        );
+        self
+    }
+
+    /// The keepalive in seconds the client connects with, 60 by default
+    pub fn with_keepalive(self, keepalive: u16) -> MockBroker {
+        stub_ffi::set_keepalive(keepalive.into());
+        self
+    }
+
+    /// Whether the client asks for a clean session (clean start for MQTT v5), the default
+    pub fn with_clean_start(self, clean_start: bool) -> MockBroker {
+        stub_ffi::set_clean_session(clean_start);
+        self
+    }
+
+    /// The DER of the certificate the client presents, read with the "tls" feature
+    pub fn with_certificate(self, der: Vec<u8>) -> MockBroker {
+        stub_ffi::set_certificate(Some(der));
+        self
+    }
+
+    /// Creates the plugin with the options, without their plugin_opt_ prefix, like the broker
+    /// does with mosquitto.conf
+    pub fn init<P: MosquittoPlugin>(&self, opts: &[(&str, &str)]) -> Result<P, InitError> {
+        let context = PluginContext {
+            broker_version: crate::BrokerVersion::headers(),
+            handle: crate::raw::PluginHandle::from_identifier(
+                std::ptr::NonNull::dangling().as_ptr(),
+            ),
+        };
+        P::try_init(opts.iter().copied().collect(), &context)
+    }
+
+    /// The client connects with the username and password, checked by on_connect and then
+    /// username_password. The username is set on the client first, the plugin can change it.
+    pub fn username_password<P: MosquittoPlugin>(
+        &self,
+        plugin: &mut P,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        stub_ffi::set_username(username);
+        match plugin.on_connect(self, &ConnectInfo::of(self)) {
+            AuthDecision::Allow => {}
+            decision => return decision,
+        }
+        let decision = plugin.username_password(self, username, password);
+        if decision == AuthDecision::Allow {
+            if let Some(registry) = plugin.client_registry() {
+                registry.authenticated(self);
+            }
+            if let Some(clients) = plugin.connected_clients() {
+                clients.authenticated(self);
+            }
+        }
+        decision
+    }
+
+    /// The ACL check for reading, writing, subscribing to or unsubscribing from the topic. For
+    /// subscriptions the topic is the filter and the payload is ignored, an allowed one is
+    /// subscribed at QoS 0.
+    pub fn acl_check<P: MosquittoPlugin>(
+        &self,
+        plugin: &mut P,
+        level: AclCheckAccessLevel,
+        topic: &str,
+        payload: &[u8],
+    ) -> AuthDecision {
+        let decision = match level {
+            AclCheckAccessLevel::Subscribe => {
+                plugin.acl_check_subscribe(self, topic, SubscriptionOptions { qos: 0 })
+            }
+            AclCheckAccessLevel::Unsubscribe => plugin.acl_check_unsubscribe(self, topic),
+            level => plugin.acl_check(self, level, message(topic, payload)),
+        };
+        if decision == AuthDecision::Allow {
+            if let Some(clients) = plugin.connected_clients() {
+                match level {
+                    AclCheckAccessLevel::Subscribe => clients.subscribed(self, topic, 0),
+                    AclCheckAccessLevel::Unsubscribe => clients.unsubscribed(self, topic),
+                    _ => {}
+                }
+            }
+        }
+        decision
+    }
+
+    /// The client publishes a message that passed the ACL check, handed to on_message_mut and
+    /// through its default to on_message_check and on_message
+    pub fn publish<P: MosquittoPlugin>(
+        &self,
+        plugin: &mut P,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<MessageRewrite, MessageVeto> {
+        plugin.on_message_mut(self, message(topic, payload))
+    }
+
+    /// The client goes away, reason is the code of the disconnect event, e.g. 0 for a DISCONNECT
+    pub fn disconnect<P: MosquittoPlugin>(&self, plugin: &mut P, reason: i32) {
+        let reason = DisconnectReason::from(reason);
+        plugin.on_disconnect(self, reason);
+        if let Some(registry) = plugin.client_registry() {
+            registry.disconnected(self, reason);
+        }
+        if let Some(clients) = plugin.connected_clients() {
+            clients.disconnected(self, reason);
+        }
+    }
+
+    /// Every message published through the broker functions, oldest first
+    pub fn published(&self) -> Vec<PublishedMessage> {
+        stub_ffi::published()
+            .into_iter()
+            .map(|p| PublishedMessage {
+                client_id: p.client_id,
+                topic: p.topic,
+                payload: p.payload,
+                qos: p.qos,
+                retain: p.retain,
+            })
+            .collect()
+    }
+
+    /// Lines written to the broker log, with their MOSQ_LOG_* level
+    pub fn logged(&self) -> Vec<(i32, String)> {
+        stub_ffi::logged()
+    }
+
+    /// Client ids and usernames that were kicked, with their with_will flag
+    pub fn kicked(&self) -> Vec<(String, bool)> {
+        stub_ffi::kicked()
+    }
+}
+
+/// Calls the callbacks that plugins loaded with plugin_init registered for the event, like the
+/// broker does when it happens, and returns the first error code or MOSQ_ERR_SUCCESS. The client
+/// in the event can be any pointer that isn't null, the stand-ins ignore it.
+///
+/// # Safety
+/// event_data has to be null or point to the struct of the event, with pointers to memory that
+/// is valid for the length given next to them, and strings ending in a NUL
+pub unsafe fn fire_event(event: MosquittoPluginEvent, event_data: *mut c_void) -> c_int {
+    stub_ffi::fire_event(event.into(), event_data)
+}
+
+/// Buffers allocated with mosquitto_malloc and mosquitto_calloc on this thread that weren't freed
+/// with mosquitto_free, e.g. a rewritten topic that the broker would take over
+pub fn outstanding_allocations() -> isize {
+    stub_ffi::outstanding_allocations()
+}
+
+fn message<'a>(topic: &'a str, payload: &'a [u8]) -> MosquittoMessage<'a> {
+    MosquittoMessage {
+        topic,
+        payload,
+        qos: QOS::AtMostOnce,
+        retain: false,
+        properties: crate::properties::MessageProperties::none(),
+    }
+}
+
+impl MosquittoClientContext for MockBroker {
+    fn connection_id(&self) -> usize {
+        self.client.connection_id()
+    }
+
+    fn session_expiry(&self) -> Option<std::time::Duration> {
+        self.client.session_expiry()
+    }
+
+    fn get_address(&self) -> Option<IpAddr> {
+        self.client.get_address()
+    }
+
+    fn is_clean_session(&self) -> bool {
+        self.client.is_clean_session()
+    }
+
+    fn get_id(&self) -> String {
+        self.client.get_id()
+    }
+
+    fn get_keepalive(&self) -> i32 {
+        self.client.get_keepalive()
+    }
+
+    fn get_certificate(&self) -> Option<certificate::ClientCertificate> {
+        self.client.get_certificate()
+    }
+
+    fn get_protocol(&self) -> MosquittoClientProtocol {
+        self.client.get_protocol()
+    }
+
+    fn get_protocol_version(&self) -> MosquittoClientProtocolVersion {
+        self.client.get_protocol_version()
+    }
+
+    fn get_sub_count(&self) -> i32 {
+        self.client.get_sub_count()
+    }
+
+    fn get_username(&self) -> Option<String> {
+        self.client.get_username()
+    }
+
+    fn set_username(&self, username: &str) -> Result<Success, Error> {
+        self.client.set_username(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{self, AclPattern};
+    use crate::{MosquittoOpt, QOS};
+
+    struct Greeter {
+        patterns: Vec<AclPattern>,
+        disconnects: Vec<DisconnectReason>,
+    }
+
+    impl MosquittoPlugin for Greeter {
+        fn init(opts: MosquittoOpt) -> Self {
+            Greeter {
+                patterns: AclPattern::parse_list(opts.get("acl").copied().unwrap_or("")).unwrap(),
+                disconnects: Vec::new(),
+            }
+        }
+
+        fn username_password(
+            &mut self,
+            client: &dyn MosquittoClientContext,
+            username: Option<&str>,
+            password: Option<&str>,
+        ) -> AuthDecision {
+            if username.is_none() || username != password {
+                return AuthDecision::deny("wrong password");
+            }
+            self.broker_publish_to_client(
+                &client.get_id(),
+                "greeting",
+                b"hi",
+                QOS::AtMostOnce,
+                false,
+            )
+            .into()
+        }
+
+        fn acl_check(
+            &mut self,
+            client: &dyn MosquittoClientContext,
+            level: AclCheckAccessLevel,
+            msg: MosquittoMessage,
+        ) -> AuthDecision {
+            acl::check_client(&self.patterns, client, level, msg.topic).into()
+        }
+
+        fn on_message_check(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            message: MosquittoMessage,
+        ) -> Result<Success, MessageVeto> {
+            if message.payload.is_empty() {
+                return Err(MessageVeto::new(Error::AclDenied));
+            }
+            Ok(Success)
+        }
+
+        fn on_disconnect(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            reason: DisconnectReason,
+        ) {
+            self.disconnects.push(reason);
+        }
+    }
+
+    #[test]
+    fn drives_a_plugin_like_the_broker() {
+        let broker = MockBroker::new().with_client_id("lamp-1");
+        let mut plugin: Greeter = broker
+            .init(&[("acl", "pattern readwrite devices/%u/%c/#")])
+            .unwrap();
+        assert_eq!(
+            broker.username_password(&mut plugin, Some("alice"), Some("bob")),
+            AuthDecision::deny("wrong password")
+        );
+        assert!(broker.published().is_empty());
+        assert_eq!(
+            broker.username_password(&mut plugin, Some("alice"), Some("alice")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            broker.published(),
+            [PublishedMessage {
+                client_id: Some("lamp-1".to_string()),
+                topic: "greeting".to_string(),
+                payload: b"hi".to_vec(),
+                qos: 0,
+                retain: false,
+            }]
+        );
+        assert_eq!(broker.get_username().as_deref(), Some("alice"));
+
+        let write = AclCheckAccessLevel::Write;
+        assert_eq!(
+            broker.acl_check(&mut plugin, write, "devices/alice/lamp-1/on", b"1"),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            broker.acl_check(&mut plugin, write, "devices/bob/lamp-1/on", b"1"),
+            AuthDecision::deny("")
+        );
+        let subscribe = AclCheckAccessLevel::Subscribe;
+        assert_eq!(
+            broker.acl_check(&mut plugin, subscribe, "devices/alice/lamp-1/#", b""),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            broker.acl_check(&mut plugin, subscribe, "devices/#", b""),
+            AuthDecision::deny("")
+        );
+
+        assert!(broker
+            .publish(&mut plugin, "devices/alice/lamp-1/on", b"1")
+            .is_ok());
+        assert_eq!(
+            broker
+                .publish(&mut plugin, "devices/alice/lamp-1/on", b"")
+                .err(),
+            Some(MessageVeto::new(Error::AclDenied))
+        );
+        broker.disconnect(&mut plugin, 7);
+        assert_eq!(plugin.disconnects, [DisconnectReason::ConnectionLost]);
+    }
+
+    #[test]
+    fn every_broker_starts_empty() {
+        let broker = MockBroker::new();
+        broker.set_username("alice").unwrap();
+        crate::mosquitto_calls::log_printf(crate::MOSQ_LOG_INFO, "hello");
+        assert_eq!(broker.logged().len(), 1);
+        let broker = MockBroker::new();
+        assert_eq!(broker.get_id(), "stub-client");
+        assert_eq!(broker.get_username(), None);
+        assert!(broker.logged().is_empty());
+    }
+
+    #[test]
+    fn raw_events_are_checked_before_the_plugin_sees_them() {
+        let broker = MockBroker::new().with_raw_client_id(Some(b"lamp\xff"));
+        assert_eq!(broker.get_id(), "lamp\u{fffd}");
+        let mut id = 0u8;
+        let mut user_data: *mut c_void = std::ptr::null_mut();
+        unsafe {
+            crate::plugin_init::<Greeter>(
+                &mut id as *mut u8 as *mut c_void,
+                &mut user_data,
+                std::ptr::null_mut(),
+                0,
+            );
+        }
+        let alice = std::ffi::CString::new("alice").unwrap();
+        let not_utf8 = std::ffi::CString::new(&b"\xffalice"[..]).unwrap();
+        let mut event: crate::mosquitto_dev::mosquitto_evt_basic_auth =
+            unsafe { std::mem::zeroed() };
+        event.client = std::ptr::NonNull::dangling().as_ptr();
+        event.username = alice.as_ptr() as *mut _;
+        event.password = not_utf8.as_ptr() as *mut _;
+        let basic_auth = |event: &mut crate::mosquitto_dev::mosquitto_evt_basic_auth| unsafe {
+            fire_event(
+                MosquittoPluginEvent::MosqEvtBasicAuth,
+                event as *mut _ as *mut c_void,
+            )
+        };
+        assert_eq!(basic_auth(&mut event), Error::Auth as c_int);
+        assert!(broker.published().is_empty());
+
+        event.password = alice.as_ptr() as *mut _;
+        assert_eq!(basic_auth(&mut event), 0);
+        assert_eq!(
+            broker.published()[0].client_id.as_deref(),
+            Some("lamp\u{fffd}")
+        );
+        unsafe {
+            crate::plugin_cleanup::<Greeter>(user_data, std::ptr::null_mut(), 0);
+        }
+        assert_eq!(outstanding_allocations(), 0);
+    }
+}
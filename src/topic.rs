@@ -0,0 +1,557 @@
+// Helpers for working with MQTT topics and subscription patterns
+use crate::Error;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Returns true when every topic matched by the `requested` subscription pattern is also matched
+/// by the `granted` pattern, ie. a client allowed to subscribe to `granted` may subscribe to
+/// `requested` as well.
+///
+/// Follows the MQTT matching rules: `+` matches exactly one level, `#` matches the parent level and
+/// any number of levels below it, and topics starting with `$` are never matched by a wildcard in
+/// the first level.
+///
+/// ```
+/// use mosquitto_plugin::topic::pattern_is_subset_of;
+/// assert!(pattern_is_subset_of("devices/+/#", "devices/42/telemetry"));
+/// assert!(!pattern_is_subset_of("devices/+/telemetry", "devices/#"));
+/// ```
+pub fn pattern_is_subset_of(granted: &str, requested: &str) -> bool {
+    let granted: Vec<&str> = granted.split('/').collect();
+    let requested: Vec<&str> = requested.split('/').collect();
+
+    // A wildcard in the first level doesn't match $SYS and other $ topics
+    if requested[0].starts_with('$') && (granted[0] == "+" || granted[0] == "#") {
+        return false;
+    }
+
+    for (i, r) in requested.iter().enumerate() {
+        let g = match granted.get(i) {
+            Some(g) => *g,
+            None => return false,
+        };
+        match (g, *r) {
+            ("#", _) => return true,
+            (_, "#") => return false,
+            ("+", _) => {}
+            (_, "+") => return false,
+            (g, r) if g == r => {}
+            _ => return false,
+        }
+    }
+
+    // "a/#" also matches "a", any other extra levels in the granted pattern make it narrower
+    matches!(&granted[requested.len()..], [] | ["#"])
+}
+
+/// Returns true when some topic is matched by both patterns, e.g. `sensors/2/#` and
+/// `sensors/+/temp` both match `sensors/2/temp`. Follows the same rules as pattern_is_subset_of.
+///
+/// ```
+/// use mosquitto_plugin::topic::patterns_overlap;
+/// assert!(patterns_overlap("sensors/2/#", "sensors/+/temp"));
+/// assert!(!patterns_overlap("sensors/+/temp", "sensors/+/humidity"));
+/// ```
+pub fn patterns_overlap(a: &str, b: &str) -> bool {
+    let a: Vec<&str> = a.split('/').collect();
+    let b: Vec<&str> = b.split('/').collect();
+
+    // $ topics are only matched by patterns naming them
+    let wildcard = |level: &str| level == "+" || level == "#";
+    if (a[0].starts_with('$') && wildcard(b[0])) || (b[0].starts_with('$') && wildcard(a[0])) {
+        return false;
+    }
+
+    for (x, y) in a.iter().zip(&b) {
+        match (*x, *y) {
+            ("#", _) | (_, "#") => return true,
+            ("+", _) | (_, "+") => {}
+            (x, y) if x == y => {}
+            _ => return false,
+        }
+    }
+
+    // "a/#" also matches "a"
+    let (shorter, longer) = if a.len() <= b.len() {
+        (&a, &b)
+    } else {
+        (&b, &a)
+    };
+    matches!(&longer[shorter.len()..], [] | ["#"])
+}
+
+/// A collection of subscription patterns with a value each, matched against topics by walking a
+/// trie of topic levels. Finding the patterns matching a topic takes time proportional to the
+/// number of levels in the topic rather than to the number of patterns.
+///
+/// Shared subscriptions (`$share/<group>/<filter>`) match like their filter, but are kept apart
+/// from it and from the same filter shared in another group: each pattern is its own entry.
+///
+/// ```
+/// use mosquitto_plugin::topic::TopicMatcher;
+/// let mut acl = TopicMatcher::new();
+/// acl.insert("devices/+/telemetry", "read");
+/// acl.insert("devices/#", "admin");
+/// let mut matched: Vec<_> = acl.matches("devices/42/telemetry").collect();
+/// matched.sort();
+/// assert_eq!(matched, vec![&"admin", &"read"]);
+/// ```
+#[derive(Debug)]
+pub struct TopicMatcher<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    // Keyed by the full pattern, several patterns share a node when they only differ in $share
+    values: HashMap<String, T>,
+    children: HashMap<String, Node<T>>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            values: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Default for TopicMatcher<T> {
+    fn default() -> Self {
+        TopicMatcher::new()
+    }
+}
+
+/// Whether a message can be published to the topic: not empty, no wildcards, no NUL and at most
+/// 65535 bytes long, like mosquitto_pub_topic_check.
+pub fn is_valid_publish_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= u16::MAX as usize
+        && !topic.contains(&['+', '#', '\0'][..])
+}
+
+/// Whether a client can subscribe to the filter: not empty, `+` and `#` only as whole levels,
+/// `#` only last, no NUL and at most 65535 bytes long, like mosquitto_sub_topic_check.
+pub fn is_valid_subscribe_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.len() > u16::MAX as usize || filter.contains('\0') {
+        return false;
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let wildcard_ok = match level {
+            "+" => true,
+            "#" => levels.peek().is_none(),
+            _ => !level.contains(&['+', '#'][..]),
+        };
+        if !wildcard_ok {
+            return false;
+        }
+    }
+    true
+}
+
+/// Why the broker refused a topic or subscription filter, see mosquitto_calls::check_publish_topic
+/// and mosquitto_calls::check_subscribe_filter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicError {
+    /// Contains a NUL byte, which can't be passed to the broker
+    Nul,
+    /// A `+` or `#` in a topic that is published to
+    Wildcard,
+    /// A `+` or `#` that isn't a whole level, or a `#` that isn't the last level
+    MisplacedWildcard,
+    /// Longer than the 65535 bytes of an MQTT string
+    TooLong,
+    /// More than the 200 `/` separators the broker accepts
+    TooManyLevels,
+    /// Refused for a reason not listed here, e.g. by a newer broker
+    Invalid,
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TopicError::Nul => "topic contains a NUL byte",
+            TopicError::Wildcard => "wildcards can't be published to",
+            TopicError::MisplacedWildcard => "wildcards have to be whole levels, # the last one",
+            TopicError::TooLong => "topic is longer than 65535 bytes",
+            TopicError::TooManyLevels => "topic is nested deeper than the broker allows",
+            TopicError::Invalid => "topic refused by the broker",
+        })
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+impl From<TopicError> for Error {
+    fn from(error: TopicError) -> Error {
+        match error {
+            TopicError::Wildcard | TopicError::MisplacedWildcard => Error::TopicWildcard,
+            TopicError::TooLong => Error::TopicTooLong,
+            TopicError::TooManyLevels => Error::TopicTooDeep,
+            TopicError::Nul | TopicError::Invalid => Error::Inval,
+        }
+    }
+}
+
+fn strip_shared(pattern: &str) -> &str {
+    match pattern
+        .strip_prefix("$share/")
+        .and_then(|rest| rest.split_once('/'))
+    {
+        Some((_group, filter)) => filter,
+        None => pattern,
+    }
+}
+
+impl<T> TopicMatcher<T> {
+    pub fn new() -> TopicMatcher<T> {
+        TopicMatcher {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+
+    /// Adds a pattern, returns the value it replaced if the pattern was already present
+    pub fn insert(&mut self, pattern: &str, value: T) -> Option<T> {
+        let mut node = &mut self.root;
+        for level in strip_shared(pattern).split('/') {
+            node = node.children.entry(level.to_string()).or_default();
+        }
+        let old = node.values.insert(pattern.to_string(), value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Removes a pattern, returns its value if it was present
+    pub fn remove(&mut self, pattern: &str) -> Option<T> {
+        let levels: Vec<&str> = strip_shared(pattern).split('/').collect();
+        let removed = Self::remove_from(&mut self.root, &levels, pattern);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    // Removes the value and prunes the nodes left without values or children
+    fn remove_from(node: &mut Node<T>, levels: &[&str], pattern: &str) -> Option<T> {
+        match levels.split_first() {
+            None => node.values.remove(pattern),
+            Some((level, rest)) => {
+                let child = node.children.get_mut(*level)?;
+                let removed = Self::remove_from(child, rest, pattern);
+                if child.values.is_empty() && child.children.is_empty() {
+                    node.children.remove(*level);
+                }
+                removed
+            }
+        }
+    }
+
+    /// The value stored for exactly this pattern
+    pub fn get(&self, pattern: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for level in strip_shared(pattern).split('/') {
+            node = node.children.get(level)?;
+        }
+        node.values.get(pattern)
+    }
+
+    /// Values of all patterns matching the topic, in no particular order
+    pub fn matches<'a>(&'a self, topic: &str) -> impl Iterator<Item = &'a T> + 'a {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut matched = Vec::new();
+        Self::collect(&self.root, &levels, true, &mut matched);
+        matched.into_iter()
+    }
+
+    fn collect<'a>(node: &'a Node<T>, levels: &[&str], root: bool, out: &mut Vec<&'a T>) {
+        // Wildcards in the first level don't match $SYS and other $ topics
+        let dollar = root && levels.first().is_some_and(|l| l.starts_with('$'));
+        if !dollar {
+            // "#" also matches the parent level, "a/#" matches "a"
+            if let Some(hash) = node.children.get("#") {
+                out.extend(hash.values.values());
+            }
+        }
+        match levels.split_first() {
+            None => out.extend(node.values.values()),
+            Some((level, rest)) => {
+                if let Some(child) = node.children.get(*level) {
+                    Self::collect(child, rest, false, out);
+                }
+                if !dollar {
+                    if let Some(child) = node.children.get("+") {
+                        Self::collect(child, rest, false, out);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of patterns
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mosquitto_calls;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn publish_topics() {
+        assert!(is_valid_publish_topic("a/b"));
+        assert!(is_valid_publish_topic("/"));
+        assert!(is_valid_publish_topic("$SYS/broker"));
+        assert!(!is_valid_publish_topic(""));
+        assert!(!is_valid_publish_topic("a/+"));
+        assert!(!is_valid_publish_topic("a/#"));
+        assert!(!is_valid_publish_topic("a\0b"));
+        assert!(!is_valid_publish_topic(&"a".repeat(65536)));
+    }
+
+    #[test]
+    fn subscribe_filters() {
+        for filter in ["a/b", "+", "#", "a/+/c", "a/#", "+/+", "/", "$share/g/a/#"].iter() {
+            assert!(is_valid_subscribe_filter(filter), "{}", filter);
+        }
+        for filter in ["", "a+", "a/b#", "#/a", "a/#/", "a\0b"].iter() {
+            assert!(!is_valid_subscribe_filter(filter), "{:?}", filter);
+        }
+        assert!(!is_valid_subscribe_filter(&"a".repeat(65536)));
+    }
+
+    #[test]
+    fn subset_table() {
+        let cases = [
+            // granted, requested, expected
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            ("a/b/c", "a/b", false),
+            ("a/+", "a/b", true),
+            ("a/+", "a/+", true),
+            ("a/b", "a/+", false),
+            ("a/+", "a/b/c", false),
+            ("a/+", "a/#", false),
+            ("a/+/c", "a/b/c", true),
+            ("a/+/c", "a/+/c", true),
+            ("a/+/c", "a/+/d", false),
+            ("+/+", "a/b", true),
+            ("+/+", "+/b", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b", true),
+            ("a/#", "a/b/c/d", true),
+            ("a/#", "a/+", true),
+            ("a/#", "a/#", true),
+            ("a/#", "a/+/#", true),
+            ("a/#", "#", false),
+            ("a/#", "b/c", false),
+            ("a/b/#", "a/+", false),
+            ("a/b/#", "a", false),
+            ("#", "a/b/c", true),
+            ("#", "#", true),
+            ("#", "+/+", true),
+            ("+/#", "a", true),
+            ("+/#", "#", false),
+            ("a//b", "a//b", true),
+            ("a/+/b", "a//b", true),
+            ("/+", "/a", true),
+            ("+", "/a", false),
+        ];
+        for (granted, requested, expected) in cases.iter() {
+            assert_eq!(
+                pattern_is_subset_of(granted, requested),
+                *expected,
+                "granted {} requested {}",
+                granted,
+                requested
+            );
+        }
+    }
+
+    #[test]
+    fn overlap_table() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/+", "a/b", true),
+            ("a/+", "+/b", true),
+            ("a/+", "a/b/c", false),
+            ("a/+/c", "a/b/+", true),
+            ("a/+/c", "a/+/d", false),
+            ("a/2/#", "a/+/c", true),
+            ("a/#", "a", true),
+            ("a/b/#", "a", false),
+            ("a/b/#", "a/+", true),
+            ("#", "a/b", true),
+            ("+", "a/b", false),
+            ("+/#", "$SYS/a", false),
+            ("$SYS/#", "$SYS/+", true),
+        ];
+        for (a, b, expected) in cases.iter() {
+            assert_eq!(patterns_overlap(a, b), *expected, "{} and {}", a, b);
+            assert_eq!(patterns_overlap(b, a), *expected, "{} and {}", b, a);
+        }
+    }
+
+    #[test]
+    fn dollar_topics_are_not_matched_by_leading_wildcards() {
+        assert!(!pattern_is_subset_of("#", "$SYS/broker/uptime"));
+        assert!(!pattern_is_subset_of("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(!pattern_is_subset_of("#", "$SYS/#"));
+        assert!(pattern_is_subset_of("$SYS/#", "$SYS/broker/uptime"));
+        assert!(pattern_is_subset_of("$SYS/+/uptime", "$SYS/broker/uptime"));
+        assert!(pattern_is_subset_of("a/#", "a/$b"));
+    }
+
+    #[test]
+    fn matcher_table() {
+        let mut matcher = TopicMatcher::new();
+        for pattern in &[
+            "a/b",
+            "a/+",
+            "a/#",
+            "+/b",
+            "#",
+            "$SYS/#",
+            "$SYS/+/uptime",
+            "+/+/c",
+            "$share/group/x/+",
+        ] {
+            matcher.insert(pattern, *pattern);
+        }
+        let cases: &[(&str, &[&str])] = &[
+            ("a/b", &["a/b", "a/+", "a/#", "+/b", "#"]),
+            ("a", &["a/#", "#"]),
+            ("a/b/c", &["a/#", "+/+/c", "#"]),
+            ("b/c", &["#"]),
+            ("$SYS/broker/uptime", &["$SYS/#", "$SYS/+/uptime"]),
+            ("$SYS", &["$SYS/#"]),
+            ("$other/b", &[]),
+            ("x/y", &["$share/group/x/+", "#"]),
+        ];
+        for (topic, expected) in cases {
+            let mut matched: Vec<&str> = matcher.matches(topic).copied().collect();
+            matched.sort();
+            let mut expected = expected.to_vec();
+            expected.sort();
+            assert_eq!(matched, expected, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn insert_replace_and_remove() {
+        let mut matcher = TopicMatcher::new();
+        assert_eq!(matcher.insert("a/+/c", 1), None);
+        assert_eq!(matcher.insert("a/+/c", 2), Some(1));
+        assert_eq!(matcher.insert("a/#", 3), None);
+        assert_eq!(matcher.len(), 2);
+        assert_eq!(matcher.get("a/+/c"), Some(&2));
+
+        assert_eq!(matcher.remove("a/+"), None);
+        assert_eq!(matcher.remove("a/+/c"), Some(2));
+        assert_eq!(matcher.remove("a/+/c"), None);
+        assert_eq!(matcher.matches("a/b/c").collect::<Vec<_>>(), vec![&3]);
+        assert_eq!(matcher.remove("$share/g/a/#"), None);
+        assert_eq!(matcher.remove("a/#"), Some(3));
+        assert!(matcher.is_empty());
+        // removed branches are pruned
+        assert!(matcher.root.children.is_empty());
+    }
+
+    #[test]
+    fn shared_subscriptions_are_separate_patterns() {
+        let mut matcher = TopicMatcher::new();
+        assert_eq!(matcher.insert("$share/g1/a/b", 1), None);
+        assert_eq!(matcher.insert("$share/g2/a/b", 2), None);
+        assert_eq!(matcher.insert("a/b", 3), None);
+        assert_eq!(matcher.insert("$share/g1/a/b", 4), Some(1));
+        assert_eq!(matcher.len(), 3);
+        assert_eq!(matcher.get("$share/g2/a/b"), Some(&2));
+        assert_eq!(matcher.get("$share/g3/a/b"), None);
+
+        let mut matched: Vec<_> = matcher.matches("a/b").copied().collect();
+        matched.sort();
+        assert_eq!(matched, vec![2, 3, 4]);
+
+        assert_eq!(matcher.remove("$share/g2/a/b"), Some(2));
+        assert_eq!(matcher.remove("a/b"), Some(3));
+        assert_eq!(matcher.get("$share/g1/a/b"), Some(&4));
+        assert_eq!(matcher.remove("$share/g1/a/b"), Some(4));
+        assert!(matcher.is_empty());
+        assert!(matcher.root.children.is_empty());
+    }
+
+    #[test]
+    fn matcher_agrees_with_the_broker() {
+        // xorshift, deterministic random patterns and topics from a small alphabet so they
+        // overlap often. Every pattern is checked with mosquitto_topic_matches_sub, which knows
+        // nothing of shared subscriptions, so the test passes it the filter without
+        // $share/<group>/. The same filter in another group, or unshared, is another pattern.
+        let mut state = 0x853c_49e6_748f_ea9bu64;
+        let mut next = move |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        let levels = ["a", "b", "", "$s"];
+        let pattern_levels = ["a", "b", "", "$s", "+", "#"];
+        let groups = ["g1", "g2"];
+        for _ in 0..200 {
+            let mut matcher = TopicMatcher::new();
+            let mut patterns = BTreeMap::new();
+            for _ in 0..20 {
+                let len = next(4) + 1;
+                let mut pattern: Vec<&str> = (0..len)
+                    .map(|_| pattern_levels[next(pattern_levels.len())])
+                    .collect();
+                // "#" is only valid as the last level
+                if let Some(pos) = pattern.iter().position(|l| *l == "#") {
+                    pattern.truncate(pos + 1);
+                }
+                let pattern = pattern.join("/");
+                if pattern.is_empty() {
+                    continue;
+                }
+                let full = match next(4) {
+                    0 => format!("$share/{}/{}", groups[next(groups.len())], pattern),
+                    _ => pattern.clone(),
+                };
+                matcher.insert(&full, full.clone());
+                patterns.insert(full, pattern);
+            }
+            for _ in 0..20 {
+                let len = next(4) + 1;
+                let topic = (0..len)
+                    .map(|_| levels[next(levels.len())])
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if topic.is_empty() {
+                    continue;
+                }
+                let mut matched: Vec<&String> = matcher.matches(&topic).collect();
+                matched.sort();
+                let mut expected: Vec<&String> = patterns
+                    .iter()
+                    .filter(|(_, pattern)| mosquitto_calls::topic_matches(pattern, &topic).unwrap())
+                    .map(|(full, _)| full)
+                    .collect();
+                expected.sort();
+                assert_eq!(matched, expected, "{}", topic);
+            }
+        }
+    }
+}
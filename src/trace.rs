@@ -0,0 +1,139 @@
+// tracing integration, enabled with the "tracing" feature.
+//
+// MosquittoLayer formats tracing events, prefixed with the spans they happened in, and writes
+// them to the broker log through mosquitto_log_printf. When a span closes its duration is logged
+// at debug level, the callbacks generated by create_dynamic_library! each run inside a span so
+// this shows how long every acl_check, username_password etc. took.
+use crate::mosquitto_calls::log_printf;
+use crate::mosquitto_dev::*;
+use std::fmt::{self, Write};
+use std::sync::Once;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+static INIT: Once = Once::new();
+
+/// Installs a subscriber with the MosquittoLayer as the global default. Called by the generated
+/// mosquitto_plugin_init, only the first call has any effect so reloading the plugin, or a
+/// plugin that installed its own subscriber, is left alone.
+pub fn init() {
+    INIT.call_once(|| {
+        let subscriber = tracing_subscriber::registry().with(MosquittoLayer::default());
+        let _ = tracing::subscriber::set_global_default(subscriber);
+    });
+}
+
+/// Maps tracing levels onto the broker log levels
+pub fn mosquitto_log_level(level: &Level) -> u32 {
+    match *level {
+        Level::ERROR => MOSQ_LOG_ERR,
+        Level::WARN => MOSQ_LOG_WARNING,
+        Level::INFO => MOSQ_LOG_INFO,
+        Level::DEBUG | Level::TRACE => MOSQ_LOG_DEBUG,
+    }
+}
+
+/// tracing_subscriber Layer writing events to the broker log
+#[derive(Debug, Default)]
+pub struct MosquittoLayer;
+
+struct SpanData {
+    fields: String,
+    created: Instant,
+}
+
+#[derive(Default)]
+struct FieldFormatter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldFormatter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.record_debug(field, &format_args!("{}", value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push_str(", ");
+            }
+            let _ = write!(self.fields, "{}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl<S> Layer<S> for MosquittoLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut formatter = FieldFormatter::default();
+            attrs.record(&mut formatter);
+            span.extensions_mut().insert(SpanData {
+                fields: formatter.fields,
+                created: Instant::now(),
+            });
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if let Some(data) = extensions.get_mut::<SpanData>() {
+                let mut formatter = FieldFormatter {
+                    message: String::new(),
+                    fields: std::mem::take(&mut data.fields),
+                };
+                values.record(&mut formatter);
+                data.fields = formatter.fields;
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut line = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                line.push_str(span.name());
+                if let Some(data) = span.extensions().get::<SpanData>() {
+                    let _ = write!(line, "{{{}}}", data.fields);
+                }
+                line.push_str(": ");
+            }
+        }
+        let mut formatter = FieldFormatter::default();
+        event.record(&mut formatter);
+        line.push_str(&formatter.message);
+        if !formatter.fields.is_empty() {
+            let _ = write!(line, " {}", formatter.fields);
+        }
+        log_printf(mosquitto_log_level(event.metadata().level()), &line);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(data) = span.extensions().get::<SpanData>() {
+                log_printf(
+                    MOSQ_LOG_DEBUG,
+                    &format!(
+                        "{}{{{}}} took {:?}",
+                        span.name(),
+                        data.fields,
+                        data.created.elapsed()
+                    ),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,666 @@
+// Logins and ACL checks decided by an HTTP service of the user, enabled with the "webhook"
+// feature. Each check is POSTed as JSON to the configured URL:
+//
+//     auth:  {"clientid": "sensor-1", "username": "alice", "password": "secret", "address": "10.0.0.7"}
+//     acl:   {"clientid": "sensor-1", "username": "alice", "topic": "devices/sensor-1/temp", "acc": 2, "access": "write"}
+//
+// acc is the mosquitto access level, 1 read, 2 write, 4 subscribe and 8 unsubscribe, as in the
+// HTTP backend of mosquitto-go-auth. By default a 2xx answer has to be a JSON object like
+// `{"ok": true}` or `{"ok": false, "error": "wrong password"}`, which denies with the error as the
+// reason. A body without a boolean "ok" makes the check an error, not a login. With
+// ResponseMode::Status every 2xx answer allows whatever its body. 4xx answers deny. Timeouts,
+// unreachable services and 5xx answers are retried, when all attempts fail the check is an error.
+// Checks without a URL are deferred to the next plugin.
+//
+//     fn username_password(&mut self, client: &dyn MosquittoClientContext, username: Option<&str>, password: Option<&str>) -> AuthDecision {
+//         self.webhook.username_password(client, username, password)
+//     }
+//
+//     fn acl_check(&mut self, client: &dyn MosquittoClientContext, level: AclCheckAccessLevel, msg: MosquittoMessage) -> AuthDecision {
+//         self.webhook.acl_check(client, level, msg.topic)
+//     }
+//
+// The checks wait for the service on the broker thread. With mosquitto 2.1, with_threads asks
+// for logins on worker threads instead and completes them through delayed_auth. ACL checks can't
+// be delayed, caching them keeps the broker from waiting on every message. Cached ACL decisions
+// of a client are dropped when it connects and disconnects if the WebhookAuth is returned from
+// MosquittoPlugin::client_registry. Logins are cached by a SHA-256 digest of client id, username
+// and password, so the cache doesn't keep the passwords.
+//
+// Options, for WebhookAuth::from_opts: `webhook_auth_url`, `webhook_acl_url`, `webhook_timeout`
+// (default 5s), `webhook_retries` (1), `webhook_retry_delay` (100ms), `webhook_response_mode`
+// (json or status, default json), `webhook_auth_cache_ttl`
+// and `webhook_acl_cache_ttl` (0s, not cached), `webhook_cache_size` (10000 per cache),
+// `webhook_threads` (mosquitto 2.1 only, 0 asks on the broker thread) and `webhook_header_<name>`
+// for headers sent with every request, like `plugin_opt_webhook_header_Authorization Bearer ...`.
+use crate::acl_cache::AclCache;
+use crate::clients::ClientLifecycle;
+use crate::opts::{opt_or, OptError, OptValue};
+use crate::{
+    AclCheckAccessLevel, AuthDecision, DisconnectReason, Error, MosquittoClientContext,
+    MosquittoOpt,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const HEADER_OPT_PREFIX: &str = "webhook_header_";
+
+/// How the body of a 2xx answer is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// A JSON object with a boolean "ok" decides, anything else is an error
+    Json,
+    /// The status alone decides, the body is ignored
+    Status,
+}
+
+impl OptValue for ResponseMode {
+    const EXPECTED: &'static str = "json or status";
+
+    fn parse_opt(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(ResponseMode::Json),
+            "status" => Some(ResponseMode::Status),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+type Post = dyn Fn(&str, &str) -> Result<(u16, String), String> + Send + Sync;
+
+#[derive(Clone)]
+enum Transport {
+    Http,
+    // Answers requests without sending them, with the status and body of the answer
+    #[cfg(test)]
+    Fake(Arc<Post>),
+}
+
+// The part shared with the worker threads
+#[derive(Clone)]
+struct Hooks {
+    auth_url: Option<String>,
+    acl_url: Option<String>,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    response_mode: ResponseMode,
+    transport: Transport,
+}
+
+impl Hooks {
+    // The decision of the service at url, asking again after failures
+    fn ask(&self, url: &str, body: &Value) -> AuthDecision {
+        let body = body.to_string();
+        let mut attempt = 0;
+        loop {
+            match self.post(url, &body) {
+                Ok((status, answer)) if (200..300).contains(&status) => {
+                    return match self.response_mode {
+                        ResponseMode::Json => allowed(&answer),
+                        ResponseMode::Status => AuthDecision::Allow,
+                    }
+                }
+                Ok((status, _)) if (400..500).contains(&status) => {
+                    return AuthDecision::deny(format!("{} answered {}", url, status))
+                }
+                // 5xx and anything else unexpected is worth another try
+                Ok(_) | Err(_) if attempt < self.retries => {
+                    attempt += 1;
+                    std::thread::sleep(self.retry_delay);
+                }
+                Ok(_) | Err(_) => return AuthDecision::Error(Error::Unknown),
+            }
+        }
+    }
+
+    fn post(&self, url: &str, body: &str) -> Result<(u16, String), String> {
+        match &self.transport {
+            Transport::Http => {}
+            #[cfg(test)]
+            Transport::Fake(post) => return post(url, body),
+        }
+        let mut request = ureq::post(url)
+            .timeout(self.timeout)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+        match request.send_string(body) {
+            Ok(response) => {
+                let status = response.status();
+                let answer = response.into_string().map_err(|e| e.to_string())?;
+                Ok((status, answer))
+            }
+            Err(ureq::Error::Status(status, _)) => Ok((status, String::new())),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+// The decision in a 2xx answer in the go-auth JSON format, an error when there is none
+fn allowed(answer: &str) -> AuthDecision {
+    let answer: Value = match serde_json::from_str(answer) {
+        Ok(answer) => answer,
+        Err(_) => return AuthDecision::Error(Error::Unknown),
+    };
+    match answer.get("ok").and_then(Value::as_bool) {
+        Some(true) => AuthDecision::Allow,
+        Some(false) => {
+            let reason = answer.get("error").and_then(Value::as_str).unwrap_or("");
+            AuthDecision::deny(if reason.is_empty() {
+                "denied by the webhook"
+            } else {
+                reason
+            })
+        }
+        None => AuthDecision::Error(Error::Unknown),
+    }
+}
+
+type LoginKey = [u8; 32];
+
+// Every field is prefixed with whether it is there and its length, so no two logins share
+// the input of the digest
+fn login_key(client_id: &str, username: Option<&str>, password: Option<&str>) -> LoginKey {
+    let mut hasher = Sha256::new();
+    for field in [Some(client_id), username, password] {
+        match field {
+            Some(field) => {
+                hasher.update([1u8]);
+                hasher.update((field.len() as u64).to_le_bytes());
+                hasher.update(field.as_bytes());
+            }
+            None => hasher.update([0u8]),
+        }
+    }
+    hasher.finalize().into()
+}
+
+// Login decisions by the digest of client id, username and password
+struct LoginCache {
+    ttl: Duration,
+    size: usize,
+    entries: HashMap<LoginKey, (AuthDecision, Instant)>,
+}
+
+impl LoginCache {
+    fn get(&self, key: &LoginKey) -> Option<AuthDecision> {
+        match self.entries.get(key) {
+            Some((decision, expires)) if *expires > Instant::now() => Some(decision.clone()),
+            _ => None,
+        }
+    }
+
+    // Errors aren't cached. When it is full, new decisions aren't either until old ones expired.
+    fn insert(&mut self, key: LoginKey, decision: &AuthDecision) {
+        if matches!(decision, AuthDecision::Error(_)) || self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= self.size {
+            self.entries.retain(|_, (_, expires)| *expires > now);
+        }
+        if self.entries.len() < self.size {
+            self.entries.insert(key, (decision.clone(), now + self.ttl));
+        }
+    }
+}
+
+/// Asks HTTP services about logins and ACL checks
+pub struct WebhookAuth {
+    hooks: Arc<Hooks>,
+    logins: Arc<Mutex<LoginCache>>,
+    acls: Option<AclCache>,
+    #[cfg(mosquitto_2_1)]
+    pool: Option<crate::worker_pool::WorkerPool>,
+}
+
+impl fmt::Debug for WebhookAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebhookAuth")
+            .field("auth_url", &self.hooks.auth_url)
+            .field("acl_url", &self.hooks.acl_url)
+            .finish()
+    }
+}
+
+impl Default for WebhookAuth {
+    fn default() -> Self {
+        WebhookAuth::new()
+    }
+}
+
+impl WebhookAuth {
+    /// Defers every check until URLs are set, with a timeout of 5 seconds and one retry after
+    /// 100 ms, without caching
+    pub fn new() -> WebhookAuth {
+        WebhookAuth {
+            hooks: Arc::new(Hooks {
+                auth_url: None,
+                acl_url: None,
+                headers: Vec::new(),
+                timeout: Duration::from_secs(5),
+                retries: 1,
+                retry_delay: Duration::from_millis(100),
+                response_mode: ResponseMode::Json,
+                transport: Transport::Http,
+            }),
+            logins: Arc::new(Mutex::new(LoginCache {
+                ttl: Duration::ZERO,
+                size: 0,
+                entries: HashMap::new(),
+            })),
+            acls: None,
+            #[cfg(mosquitto_2_1)]
+            pool: None,
+        }
+    }
+
+    /// Logins are POSTed to url
+    pub fn with_auth_url(mut self, url: &str) -> Self {
+        Arc::make_mut(&mut self.hooks).auth_url = Some(url.to_string());
+        self
+    }
+
+    /// ACL checks are POSTed to url
+    pub fn with_acl_url(mut self, url: &str) -> Self {
+        Arc::make_mut(&mut self.hooks).acl_url = Some(url.to_string());
+        self
+    }
+
+    /// Sent with every request, e.g. an `Authorization` header for the service
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        let hooks = Arc::make_mut(&mut self.hooks);
+        hooks.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// How long to wait for an answer, per attempt
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        Arc::make_mut(&mut self.hooks).timeout = timeout;
+        self
+    }
+
+    /// How often to try again after failures, waiting delay before each retry
+    pub fn with_retries(mut self, retries: u32, delay: Duration) -> Self {
+        let hooks = Arc::make_mut(&mut self.hooks);
+        hooks.retries = retries;
+        hooks.retry_delay = delay;
+        self
+    }
+
+    /// How 2xx answers are read, ResponseMode::Json by default
+    pub fn with_response_mode(mut self, mode: ResponseMode) -> Self {
+        Arc::make_mut(&mut self.hooks).response_mode = mode;
+        self
+    }
+
+    /// Caches at most size login decisions for ttl
+    pub fn with_auth_cache(self, ttl: Duration, size: usize) -> Self {
+        {
+            let mut logins = self.logins.lock().unwrap_or_else(|e| e.into_inner());
+            logins.ttl = ttl;
+            logins.size = size;
+        }
+        self
+    }
+
+    /// Caches at most size ACL decisions for ttl, see acl_cache
+    pub fn with_acl_cache(mut self, ttl: Duration, size: usize) -> Self {
+        self.acls = Some(AclCache::new(ttl, size));
+        self
+    }
+
+    /// Asks about logins on threads worker threads, so the broker doesn't wait for them
+    #[cfg(mosquitto_2_1)]
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.pool = Some(crate::worker_pool::WorkerPool::new(threads, 256));
+        self
+    }
+
+    /// Reads the URLs, timeouts, retries, response mode, caches and headers from the options, see the top of
+    /// the module
+    pub fn from_opts(opts: &MosquittoOpt) -> Result<WebhookAuth, OptError> {
+        let mut webhook = WebhookAuth::new()
+            .with_timeout(opt_or(opts, "webhook_timeout", "5s")?)
+            .with_retries(
+                opt_or(opts, "webhook_retries", "1")?,
+                opt_or(opts, "webhook_retry_delay", "100ms")?,
+            )
+            .with_response_mode(opt_or(opts, "webhook_response_mode", "json")?);
+        if let Some(url) = opts.get("webhook_auth_url") {
+            webhook = webhook.with_auth_url(url);
+        }
+        if let Some(url) = opts.get("webhook_acl_url") {
+            webhook = webhook.with_acl_url(url);
+        }
+        let size = opt_or(opts, "webhook_cache_size", "10000")?;
+        let ttl: Duration = opt_or(opts, "webhook_auth_cache_ttl", "0s")?;
+        webhook = webhook.with_auth_cache(ttl, size);
+        let ttl: Duration = opt_or(opts, "webhook_acl_cache_ttl", "0s")?;
+        if !ttl.is_zero() {
+            webhook = webhook.with_acl_cache(ttl, size);
+        }
+        #[cfg(mosquitto_2_1)]
+        {
+            let threads: usize = opt_or(opts, "webhook_threads", "0")?;
+            if threads > 0 {
+                webhook = webhook.with_threads(threads);
+            }
+        }
+        for (key, value) in opts {
+            if let Some(name) = key.strip_prefix(HEADER_OPT_PREFIX) {
+                webhook = webhook.with_header(name, value);
+            }
+        }
+        Ok(webhook)
+    }
+
+    /// Asks the auth URL about the login, deferred without one
+    pub fn username_password(
+        &self,
+        client: &dyn MosquittoClientContext,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> AuthDecision {
+        let url = match &self.hooks.auth_url {
+            Some(url) => url.clone(),
+            None => return AuthDecision::Defer,
+        };
+        let client_id = client.get_id();
+        let key = login_key(&client_id, username, password);
+        if let Some(decision) = lock(&self.logins).get(&key) {
+            return decision;
+        }
+        let body = json!({
+            "clientid": client_id,
+            "username": username,
+            "password": password,
+            "address": client.get_address().map(|a| a.to_string()),
+        });
+        let hooks = Arc::clone(&self.hooks);
+        let logins = Arc::clone(&self.logins);
+        let check = move || {
+            let decision = hooks.ask(&url, &body);
+            lock(&logins).insert(key, &decision);
+            decision
+        };
+        #[cfg(mosquitto_2_1)]
+        if let Some(pool) = &self.pool {
+            return pool.submit_auth(client, check);
+        }
+        check()
+    }
+
+    /// Asks the ACL URL about the check, or the cache when it has been asked before. Deferred
+    /// without a URL.
+    pub fn acl_check(
+        &mut self,
+        client: &dyn MosquittoClientContext,
+        level: AclCheckAccessLevel,
+        topic: &str,
+    ) -> AuthDecision {
+        let url = match &self.hooks.acl_url {
+            Some(url) => url,
+            None => return AuthDecision::Defer,
+        };
+        let hooks = &self.hooks;
+        let client_id = client.get_id();
+        let ask = || {
+            let body = json!({
+                "clientid": client_id,
+                "username": client.get_username(),
+                "topic": topic,
+                "acc": level as u8,
+                "access": level.to_string().to_lowercase(),
+            });
+            hooks.ask(url, &body)
+        };
+        match &mut self.acls {
+            Some(cache) => cache.get_or_insert_with(&client_id, topic, level, ask),
+            None => ask(),
+        }
+    }
+}
+
+fn lock(logins: &Mutex<LoginCache>) -> std::sync::MutexGuard<'_, LoginCache> {
+    logins.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Drops the cached ACL decisions of a client when it logs in and when it disconnects
+impl ClientLifecycle for WebhookAuth {
+    fn authenticated(&mut self, client: &dyn MosquittoClientContext) {
+        if let Some(cache) = &mut self.acls {
+            cache.authenticated(client);
+        }
+    }
+
+    fn disconnected(&mut self, client: &dyn MosquittoClientContext, reason: DisconnectReason) {
+        if let Some(cache) = &mut self.acls {
+            cache.disconnected(client, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi::client;
+    use crate::AclCheckAccessLevel::{Subscribe, Write};
+
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    // Answers with what answer returns for the body, recording the requests
+    fn fake(
+        webhook: WebhookAuth,
+        answer: impl Fn(&str) -> Result<(u16, String), String> + Send + Sync + 'static,
+    ) -> (WebhookAuth, Requests) {
+        let requests = Requests::default();
+        let recorded = Arc::clone(&requests);
+        let mut webhook = webhook
+            .with_auth_url("http://auth.local/auth")
+            .with_acl_url("http://auth.local/acl")
+            .with_retries(2, Duration::ZERO);
+        Arc::make_mut(&mut webhook.hooks).transport =
+            Transport::Fake(Arc::new(move |url, body| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((url.to_string(), body.to_string()));
+                answer(body)
+            }));
+        (webhook, requests)
+    }
+
+    #[test]
+    fn answers_decide() {
+        let (webhook, requests) = fake(WebhookAuth::new(), |body| {
+            if body.contains("\"secret\"") {
+                Ok((200, r#"{"ok": true}"#.to_string()))
+            } else if body.contains("\"expired\"") {
+                Ok((
+                    200,
+                    r#"{"ok": false, "error": "password expired"}"#.to_string(),
+                ))
+            } else if body.contains("\"flaky\"") {
+                Err("connection refused".to_string())
+            } else {
+                Ok((403, String::new()))
+            }
+        });
+        let client = client();
+        assert_eq!(
+            webhook.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+        let body: Value = serde_json::from_str(&requests.lock().unwrap()[0].1).unwrap();
+        assert_eq!(
+            body.get("clientid").and_then(Value::as_str),
+            Some("stub-client")
+        );
+        assert_eq!(body.get("username").and_then(Value::as_str), Some("alice"));
+        assert_eq!(
+            body.get("address").and_then(Value::as_str),
+            Some("127.0.0.1")
+        );
+
+        assert_eq!(
+            webhook.username_password(&client, Some("alice"), Some("expired")),
+            AuthDecision::deny("password expired")
+        );
+        assert_eq!(
+            webhook.username_password(&client, Some("alice"), Some("guess")),
+            AuthDecision::deny("http://auth.local/auth answered 403")
+        );
+        // three attempts, then an error
+        requests.lock().unwrap().clear();
+        assert_eq!(
+            webhook.username_password(&client, Some("alice"), Some("flaky")),
+            AuthDecision::Error(Error::Unknown)
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        let unset = WebhookAuth::new();
+        assert_eq!(
+            unset.username_password(&client, Some("alice"), Some("secret")),
+            AuthDecision::Defer
+        );
+    }
+
+    #[test]
+    fn answers_without_a_decision_are_errors() {
+        let answers = [
+            "",
+            "OK",
+            "[]",
+            r#"{"error": "none"}"#,
+            r#"{"ok": "false"}"#,
+            r#"{"ok": 1}"#,
+        ];
+        for answer in answers {
+            let (webhook, requests) =
+                fake(WebhookAuth::new(), move |_| Ok((200, answer.to_string())));
+            assert_eq!(
+                webhook.username_password(&client(), Some("alice"), Some("secret")),
+                AuthDecision::Error(Error::Unknown),
+                "{:?}",
+                answer
+            );
+            // not worth asking again
+            assert_eq!(requests.lock().unwrap().len(), 1);
+        }
+
+        let webhook = WebhookAuth::new().with_response_mode(ResponseMode::Status);
+        let (webhook, _) = fake(webhook, |_| Ok((200, r#"{"ok": false}"#.to_string())));
+        assert_eq!(
+            webhook.username_password(&client(), Some("alice"), Some("secret")),
+            AuthDecision::Allow
+        );
+    }
+
+    #[test]
+    fn decisions_are_cached() {
+        let webhook = WebhookAuth::new()
+            .with_auth_cache(Duration::from_secs(60), 100)
+            .with_acl_cache(Duration::from_secs(60), 100);
+        let (mut webhook, requests) = fake(webhook, |body| {
+            if body.contains("\"acc\":4") {
+                Ok((200, r#"{"ok": true}"#.to_string()))
+            } else {
+                Ok((500, String::new()))
+            }
+        });
+        let client = client();
+        for _ in 0..2 {
+            assert_eq!(
+                webhook.acl_check(&client, Subscribe, "devices/#"),
+                AuthDecision::Allow
+            );
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        let body: Value = serde_json::from_str(&requests.lock().unwrap()[0].1).unwrap();
+        assert_eq!(
+            body.get("access").and_then(Value::as_str),
+            Some("subscribe")
+        );
+
+        // errors aren't cached
+        for _ in 0..2 {
+            assert_eq!(
+                webhook.acl_check(&client, Write, "devices/1"),
+                AuthDecision::Error(Error::Unknown)
+            );
+        }
+        assert_eq!(requests.lock().unwrap().len(), 7);
+
+        webhook.disconnected(&client, DisconnectReason::Normal);
+        webhook.acl_check(&client, Subscribe, "devices/#");
+        assert_eq!(requests.lock().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn logins_are_cached_by_their_digest() {
+        let webhook = WebhookAuth::new().with_auth_cache(Duration::from_secs(60), 100);
+        let (webhook, requests) = fake(webhook, |body| {
+            if body.contains("\"secret\"") {
+                Ok((200, r#"{"ok": true}"#.to_string()))
+            } else {
+                Ok((403, String::new()))
+            }
+        });
+        let client = client();
+        for _ in 0..2 {
+            assert_eq!(
+                webhook.username_password(&client, Some("alice"), Some("secret")),
+                AuthDecision::Allow
+            );
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        // another password is another login
+        assert!(matches!(
+            webhook.username_password(&client, Some("alice"), Some("guess")),
+            AuthDecision::Deny { .. }
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        // the fields can't run into each other
+        assert_ne!(
+            login_key("a", Some("b"), None),
+            login_key("a", None, Some("b"))
+        );
+        assert_ne!(login_key("ab", None, None), login_key("a", Some("b"), None));
+    }
+
+    #[test]
+    fn options_configure_the_hooks() {
+        let mut opts = HashMap::new();
+        opts.insert("webhook_acl_url", "http://auth.local/acl");
+        opts.insert("webhook_retries", "3");
+        opts.insert("webhook_header_X-Api-Key", "key");
+        let webhook = WebhookAuth::from_opts(&opts).unwrap();
+        assert_eq!(webhook.hooks.auth_url, None);
+        assert_eq!(webhook.hooks.retries, 3);
+        assert_eq!(
+            webhook.hooks.headers,
+            vec![("X-Api-Key".to_string(), "key".to_string())]
+        );
+        assert!(webhook.acls.is_none());
+        assert_eq!(webhook.hooks.response_mode, ResponseMode::Json);
+
+        opts.insert("webhook_response_mode", "status");
+        let webhook = WebhookAuth::from_opts(&opts).unwrap();
+        assert_eq!(webhook.hooks.response_mode, ResponseMode::Status);
+
+        opts.insert("webhook_response_mode", "body");
+        assert!(WebhookAuth::from_opts(&opts).is_err());
+        opts.insert("webhook_response_mode", "json");
+        opts.insert("webhook_timeout", "soon");
+        assert!(WebhookAuth::from_opts(&opts).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+// A fixed number of threads for blocking work the broker thread can't wait for, like password
+// hashing or asking a remote service:
+//
+//     let pool = WorkerPool::new(4, 64);
+//     pool.submit(move || backend.lookup(&client_id), |found| log_printf(MOSQ_LOG_INFO, ...))?;
+//
+// Jobs wait in a queue of bounded length, a full queue refuses new jobs rather than letting them
+// pile up. The completion gets the result of the job on the broker thread, on the next tick, so
+// it can call mosquitto functions like publishing or kicking clients. The plugin has to get the
+// tick event, see broker_handle. A job that panics is logged on the tick, its completion isn't
+// called. Completions still queued when the plugin is cleaned up are dropped.
+use crate::dynlib::panic_message;
+use crate::mosquitto_calls::log_printf;
+use crate::mosquitto_dev::MOSQ_LOG_ERR;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send>;
+type Completion = Box<dyn FnOnce() + Send>;
+
+static COMPLETIONS: Mutex<Vec<Completion>> = Mutex::new(Vec::new());
+
+/// Why a job wasn't accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError {
+    /// As many jobs as the queue holds are waiting already
+    Full,
+    /// The pool is shutting down
+    ShutDown,
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::Full => write!(f, "the worker pool queue is full"),
+            SubmitError::ShutDown => write!(f, "the worker pool is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+pub struct WorkerPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    completions: &'static Mutex<Vec<Completion>>,
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("threads", &self.workers.len())
+            .finish()
+    }
+}
+
+impl WorkerPool {
+    /// Starts threads workers, at least one, and a queue for queue_len jobs waiting for them
+    pub fn new(threads: usize, queue_len: usize) -> WorkerPool {
+        WorkerPool::with_completions(threads, queue_len, &COMPLETIONS)
+    }
+
+    fn with_completions(
+        threads: usize,
+        queue_len: usize,
+        completions: &'static Mutex<Vec<Completion>>,
+    ) -> WorkerPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::Builder::new()
+                    .name(format!("mosquitto-plugin-worker-{}", i))
+                    .spawn(move || work(&receiver))
+                    .expect("failed to start a worker thread")
+            })
+            .collect();
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+            completions,
+        }
+    }
+
+    /// Queues job for a worker thread, done is called with its result on the broker thread
+    pub fn submit<R: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> R + Send + 'static,
+        done: impl FnOnce(R) + Send + 'static,
+    ) -> Result<(), SubmitError> {
+        let completions = self.completions;
+        self.run(Box::new(move || {
+            let completion: Completion =
+                match std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)) {
+                    Ok(result) => Box::new(move || done(result)),
+                    Err(payload) => {
+                        let message = panic_message(payload.as_ref());
+                        Box::new(move || {
+                            log_printf(
+                                MOSQ_LOG_ERR,
+                                &format!("worker pool: job panicked: {}", message),
+                            )
+                        })
+                    }
+                };
+            lock(completions).push(completion);
+        }))
+    }
+
+    /// Decides username_password on a worker thread: returns AuthDecision::Error(AuthDelayed)
+    /// and completes the login through delayed_auth. A full queue denies the client right away.
+    #[cfg(mosquitto_2_1)]
+    pub fn submit_auth(
+        &self,
+        client: &dyn crate::MosquittoClientContext,
+        check: impl FnOnce() -> crate::AuthDecision + Send + 'static,
+    ) -> crate::AuthDecision {
+        let completion = crate::delayed_auth::AuthCompletion::for_client(client);
+        // Shared so a refused job doesn't complete the login a second time
+        let slot = Arc::new(Mutex::new(Some(completion)));
+        let job_slot = Arc::clone(&slot);
+        let job = move || {
+            let completion = job_slot.lock().unwrap_or_else(|e| e.into_inner()).take();
+            // A check that panics drops the completion, which refuses the client
+            if let Some(completion) = completion {
+                completion.decide(check());
+            }
+        };
+        match self.run(Box::new(job)) {
+            Ok(()) => crate::AuthDecision::Error(crate::Error::AuthDelayed),
+            Err(e) => {
+                if let Some(completion) = slot.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    completion.cancel();
+                }
+                crate::AuthDecision::deny(e.to_string())
+            }
+        }
+    }
+
+    fn run(&self, job: Job) -> Result<(), SubmitError> {
+        let sender = self.sender.as_ref().ok_or(SubmitError::ShutDown)?;
+        sender.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => SubmitError::Full,
+            TrySendError::Disconnected(_) => SubmitError::ShutDown,
+        })
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Waits for the queued jobs to finish
+    fn drop(&mut self) {
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is only held while waiting, the next job can be taken while this one runs
+        let job = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // Panics of the job itself are caught in submit, this only keeps the thread alive
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+    }
+}
+
+fn lock(completions: &Mutex<Vec<Completion>>) -> std::sync::MutexGuard<'_, Vec<Completion>> {
+    completions.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Called from the tick callback on the broker thread
+pub(crate) fn run_completions() {
+    run_queued(&COMPLETIONS);
+}
+
+// Called from plugin_cleanup, the completions may refer to code of the unloaded plugin
+pub(crate) fn discard_completions() {
+    lock(&COMPLETIONS).clear();
+}
+
+fn run_queued(completions: &Mutex<Vec<Completion>>) {
+    let completions = std::mem::take(&mut *lock(completions));
+    for completion in completions {
+        completion();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_ffi;
+    use std::sync::mpsc::channel;
+
+    // Completions of their own, the tick tests elsewhere run what is in COMPLETIONS
+    fn pool(threads: usize, queue_len: usize) -> (WorkerPool, &'static Mutex<Vec<Completion>>) {
+        let completions = Box::leak(Box::new(Mutex::new(Vec::new())));
+        (
+            WorkerPool::with_completions(threads, queue_len, completions),
+            completions,
+        )
+    }
+
+    #[test]
+    fn results_come_back_on_the_tick() {
+        stub_ffi::reset();
+        let (pool, completions) = pool(2, 4);
+        let (results, received) = channel();
+        for i in 0..3 {
+            let results = results.clone();
+            pool.submit(move || i * 10, move |r| results.send(r).unwrap())
+                .unwrap();
+        }
+        pool.submit(|| panic!("hash failed"), |_: ()| unreachable!())
+            .unwrap();
+        drop(pool);
+        assert!(received.try_recv().is_err());
+
+        let logged = stub_ffi::logged().len();
+        run_queued(completions);
+        let mut got: Vec<i32> = received.try_iter().collect();
+        got.sort();
+        assert_eq!(got, vec![0, 10, 20]);
+        assert_eq!(
+            stub_ffi::logged()[logged..],
+            [(
+                MOSQ_LOG_ERR as i32,
+                "worker pool: job panicked: hash failed".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn a_full_queue_refuses_jobs() {
+        let (pool, _) = pool(1, 1);
+        let (release, blocked) = channel::<()>();
+        let (started, running) = channel();
+        pool.submit(
+            move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap()
+            },
+            |_| {},
+        )
+        .unwrap();
+        running.recv().unwrap();
+        // The worker is busy, one job fits into the queue
+        pool.submit(|| (), |_| {}).unwrap();
+        assert_eq!(pool.submit(|| (), |_| {}), Err(SubmitError::Full));
+        release.send(()).unwrap();
+    }
+
+    #[cfg(mosquitto_2_1)]
+    #[test]
+    fn busy_pools_deny_logins() {
+        use crate::{AuthDecision, Error};
+        let (busy, _) = pool(1, 1);
+        let (release, blocked) = channel::<()>();
+        let (started, running) = channel();
+        busy.submit(
+            move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap()
+            },
+            |_| {},
+        )
+        .unwrap();
+        running.recv().unwrap();
+        busy.submit(|| (), |_| {}).unwrap();
+        let client = crate::stub_ffi::client();
+        assert_eq!(
+            busy.submit_auth(&client, || AuthDecision::Allow),
+            AuthDecision::deny(SubmitError::Full.to_string())
+        );
+        release.send(()).unwrap();
+        drop(busy);
+        assert_eq!(
+            pool(1, 4).0.submit_auth(&client, || AuthDecision::Allow),
+            AuthDecision::Error(Error::AuthDelayed)
+        );
+    }
+}
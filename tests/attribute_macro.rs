@@ -0,0 +1,35 @@
+// #[mosquitto_plugin] on the impl block, as an alternative to create_dynamic_library!
+use mosquitto_plugin::*;
+
+pub struct AclOnly;
+
+#[mosquitto_plugin(name = "acl-only", version = "1.2.3")]
+impl MosquittoPlugin for AclOnly {
+    fn init(_opts: MosquittoOpt) -> Self {
+        AclOnly
+    }
+
+    fn acl_check(
+        &mut self,
+        _client: &dyn MosquittoClientContext,
+        _acl: AclCheckAccessLevel,
+        msg: MosquittoMessage,
+    ) -> AuthDecision {
+        if msg.topic.starts_with("public/") {
+            AuthDecision::Allow
+        } else {
+            AuthDecision::deny("only public/ topics")
+        }
+    }
+}
+
+#[test]
+fn generates_the_plugin_symbols() {
+    let versions = [MOSQ_PLUGIN_VERSION as i32];
+    let version = unsafe { mosquitto_plugin_version(versions.len() as i32, versions.as_ptr()) };
+    assert_eq!(version, MOSQ_PLUGIN_VERSION as i32);
+    // Referencing them makes sure they exist with the signatures mosquitto expects
+    let _: extern "C" fn(*mut std::os::raw::c_void, *mut *mut std::os::raw::c_void, *mut mosquitto_opt, i32) -> i32 =
+        mosquitto_plugin_init;
+    let _: extern "C" fn(*mut std::os::raw::c_void, *mut mosquitto_opt, i32) -> i32 = mosquitto_plugin_cleanup;
+}
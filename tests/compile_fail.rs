@@ -0,0 +1,7 @@
+// Checks that misusing create_dynamic_library! gives a readable error pointing at the argument.
+// Regenerate the expected output with TRYBUILD=overwrite cargo test --test compile_fail
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
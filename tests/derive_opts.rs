@@ -0,0 +1,82 @@
+// #[derive(PluginOpts)], reading the options of init into a typed struct
+use mosquitto_plugin::opts::{OptError, PluginOpts};
+use mosquitto_plugin::MosquittoOpt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Where the plugin finds its users
+#[derive(Debug, PluginOpts)]
+pub(crate) struct Config {
+    pub db_host: String,
+    #[opt(name = "db_timeout", default = "30s")]
+    timeout: Duration,
+    #[opt(default = 3)]
+    retries: u8,
+    #[opt(default)]
+    max_clients: u32,
+    /// Only cached when given
+    cache_dir: Option<PathBuf>,
+    r#type: String,
+    verbose: Option<bool>,
+}
+
+fn opts<'a>(pairs: &[(&'a str, &'a str)]) -> MosquittoOpt<'a> {
+    pairs.iter().copied().collect()
+}
+
+#[test]
+fn defaults_fill_in_missing_options() {
+    let config = Config::from_opts(&opts(&[("db_host", "db.local"), ("type", "sql")])).unwrap();
+    assert_eq!(config.db_host, "db.local");
+    assert_eq!(config.timeout, Duration::from_secs(30));
+    assert_eq!(config.retries, 3);
+    assert_eq!(config.max_clients, 0);
+    assert_eq!(config.cache_dir, None);
+    assert_eq!(config.r#type, "sql");
+    assert_eq!(config.verbose, None);
+}
+
+#[test]
+fn given_options_are_parsed() {
+    let config = Config::from_opts(&opts(&[
+        ("db_host", "db.local"),
+        ("db_timeout", "500ms"),
+        ("retries", "5"),
+        ("max_clients", "100"),
+        ("cache_dir", "/var/cache/plugin"),
+        ("type", "sql"),
+        ("verbose", "true"),
+    ]))
+    .unwrap();
+    assert_eq!(config.timeout, Duration::from_millis(500));
+    assert_eq!(config.retries, 5);
+    assert_eq!(config.max_clients, 100);
+    assert_eq!(config.cache_dir, Some(PathBuf::from("/var/cache/plugin")));
+    assert_eq!(config.verbose, Some(true));
+}
+
+#[test]
+fn errors_name_the_option() {
+    let err = Config::from_opts(&opts(&[("type", "sql")])).unwrap_err();
+    assert!(matches!(err, OptError::Missing { ref key } if key == "db_host"));
+
+    let err = Config::from_opts(&opts(&[
+        ("db_host", "db.local"),
+        ("type", "sql"),
+        ("max_clients", "lots"),
+    ]))
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "option max_clients: expected a non-negative integer, got \"lots\""
+    );
+
+    // Renamed options are reported by the name in mosquitto.conf
+    let err = Config::from_opts(&opts(&[
+        ("db_host", "db.local"),
+        ("type", "sql"),
+        ("db_timeout", "1 minute"),
+    ]))
+    .unwrap_err();
+    assert!(matches!(err, OptError::Invalid { ref key, .. } if key == "db_timeout"));
+}
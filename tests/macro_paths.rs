@@ -0,0 +1,52 @@
+// create_dynamic_library! has to accept any type, not only an ident in scope at the crate root
+use mosquitto_plugin::*;
+
+mod plugins {
+    use mosquitto_plugin::*;
+
+    pub trait Backend: Default {
+        fn allowed(&self, username: &str) -> bool;
+    }
+
+    #[derive(Default)]
+    pub struct AllowAll;
+
+    impl Backend for AllowAll {
+        fn allowed(&self, _username: &str) -> bool {
+            true
+        }
+    }
+
+    pub struct Generic<B> {
+        pub backend: B,
+    }
+
+    impl<B: Backend> MosquittoPlugin for Generic<B> {
+        fn init(_opts: MosquittoOpt) -> Self {
+            Generic {
+                backend: B::default(),
+            }
+        }
+
+        fn username_password(
+            &mut self,
+            _client: &dyn MosquittoClientContext,
+            username: Option<&str>,
+            _password: Option<&str>,
+        ) -> AuthDecision {
+            match username {
+                Some(u) if self.backend.allowed(u) => AuthDecision::Allow,
+                _ => AuthDecision::deny("not allowed by the backend"),
+            }
+        }
+    }
+}
+
+create_dynamic_library!(plugins::Generic::<plugins::AllowAll>);
+
+#[test]
+fn generated_symbols_for_generic_path_type() {
+    let versions = [4, MOSQ_PLUGIN_VERSION as i32];
+    let version = unsafe { mosquitto_plugin_version(versions.len() as i32, versions.as_ptr()) };
+    assert_eq!(version, MOSQ_PLUGIN_VERSION as i32);
+}
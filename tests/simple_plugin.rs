@@ -0,0 +1,27 @@
+// create_simple_plugin! generates the same symbols as create_dynamic_library!
+use mosquitto_plugin::*;
+
+struct Users;
+
+impl SimpleAuthPlugin for Users {
+    fn init(_opts: MosquittoOpt) -> Self {
+        Users
+    }
+
+    fn verify(&self, username: &str, password: &str) -> bool {
+        password.chars().rev().eq(username.chars())
+    }
+
+    fn allowed(&self, username: &str, topic: &str, _access: AclCheckAccessLevel) -> bool {
+        topic.starts_with(username)
+    }
+}
+
+create_simple_plugin!(Users);
+
+#[test]
+fn generated_symbols_for_simple_plugin() {
+    let versions = [4, MOSQ_PLUGIN_VERSION as i32];
+    let version = unsafe { mosquitto_plugin_version(versions.len() as i32, versions.as_ptr()) };
+    assert_eq!(version, MOSQ_PLUGIN_VERSION as i32);
+}
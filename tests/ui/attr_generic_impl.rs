@@ -0,0 +1,12 @@
+use mosquitto_plugin::*;
+
+pub struct Plugin<T>(T);
+
+#[mosquitto_plugin]
+impl<T: Default> MosquittoPlugin for Plugin<T> {
+    fn init(_opts: MosquittoOpt) -> Self {
+        Plugin(T::default())
+    }
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use mosquitto_plugin::*;
+
+pub struct Plugin;
+
+#[mosquitto_plugin]
+impl Plugin {
+    pub fn init(_opts: MosquittoOpt) -> Self {
+        Plugin
+    }
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use mosquitto_plugin::*;
+
+#[mosquitto_plugin]
+pub struct Plugin;
+
+fn main() {}
@@ -0,0 +1,7 @@
+use mosquitto_plugin::*;
+
+pub struct NotAPlugin;
+
+create_dynamic_library!(NotAPlugin);
+
+fn main() {}
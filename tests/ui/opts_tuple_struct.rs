@@ -0,0 +1,6 @@
+use mosquitto_plugin::opts::PluginOpts;
+
+#[derive(PluginOpts)]
+pub struct Config(String, u16);
+
+fn main() {}
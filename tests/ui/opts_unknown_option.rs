@@ -0,0 +1,9 @@
+use mosquitto_plugin::opts::PluginOpts;
+
+#[derive(PluginOpts)]
+pub struct Config {
+    #[opt(defualt = "1883")]
+    port: u16,
+}
+
+fn main() {}